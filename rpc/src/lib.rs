@@ -12,4 +12,4 @@ pub mod criterion;
 pub mod message;
 
 pub const MAJOR_VERSION: u16 = 0;
-pub const MINOR_VERSION: u16 = 1;
+pub const MINOR_VERSION: u16 = 17;
@@ -2,7 +2,7 @@ use std::borrow::Cow;
 
 use chrono::{DateTime, Utc};
 
-use super::resource::{CResourceUpdate, ResourceKind, SResourceUpdate};
+use super::resource::{CResourceUpdate, DhtNode, ResourceKind, SResourceUpdate};
 use super::criterion::Criterion;
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
@@ -72,6 +72,22 @@ pub enum CMessage {
         size: u64,
         path: String,
     },
+    CreateTorrent {
+        serial: u64,
+        path: String,
+        #[serde(default)]
+        piece_size: Option<u32>,
+        #[serde(default)]
+        trackers: Vec<String>,
+        #[serde(default)]
+        web_seeds: Vec<String>,
+        #[serde(default)]
+        private: bool,
+        #[serde(default)]
+        comment: Option<String>,
+        #[serde(default = "default_start")]
+        start: bool,
+    },
     PauseTorrent {
         serial: u64,
         id: String,
@@ -84,6 +100,10 @@ pub enum CMessage {
         serial: u64,
         id: String,
     },
+    ScrapeTracker {
+        serial: u64,
+        id: String,
+    },
     AddTracker {
         serial: u64,
         id: String,
@@ -98,6 +118,44 @@ pub enum CMessage {
         serial: u64,
         ids: Vec<String>,
     },
+    DhtGet {
+        serial: u64,
+        #[serde(default)]
+        target: Option<String>,
+        #[serde(default)]
+        key: Option<String>,
+        #[serde(default)]
+        salt: String,
+    },
+    DhtPut {
+        serial: u64,
+        value: String,
+        #[serde(default)]
+        secret_key: Option<String>,
+        #[serde(default)]
+        salt: String,
+        #[serde(default)]
+        seq: i64,
+        #[serde(default)]
+        cas: Option<i64>,
+    },
+    DhtBootstrap {
+        serial: u64,
+        addr: String,
+    },
+    DhtTable {
+        serial: u64,
+    },
+    AddBan {
+        serial: u64,
+        ip: String,
+        /// Seconds until the ban is lifted, permanent if unset
+        #[serde(default)]
+        duration: Option<u64>,
+    },
+    ReloadIpFilter {
+        serial: u64,
+    },
 }
 
 /// Server -> client message
@@ -128,6 +186,20 @@ pub enum SMessage<'a> {
         token: String,
         size: u64,
     },
+    DhtItem {
+        serial: u64,
+        value: Option<String>,
+        seq: Option<i64>,
+    },
+    DhtStored {
+        serial: u64,
+        target: String,
+        nodes: u32,
+    },
+    DhtTable {
+        serial: u64,
+        nodes: Vec<DhtNode>,
+    },
 
     // Error messages
     UnknownResource(Error),
@@ -196,4 +268,23 @@ mod tests {
             unreachable!();
         }
     }
+
+    #[test]
+    fn test_seed_goals() {
+        let data = r#"
+            {
+                "type": "UPDATE_RESOURCE",
+                "serial": 0,
+                "resource": { "id": "t", "seed_ratio": 1.5, "seed_time": null }
+            }
+            "#;
+        let m = serde_json::from_str(data).unwrap();
+        if let CMessage::UpdateResource { resource: r, .. } = m {
+            assert_eq!(r.seed_ratio, Some(Some(1.5)));
+            assert_eq!(r.seed_time, Some(None));
+            assert_eq!(r.seed_idle, None);
+        } else {
+            unreachable!();
+        }
+    }
 }
@@ -2,7 +2,7 @@ use std::mem;
 use std::fmt;
 use std::borrow::Cow;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveTime, Utc, Weekday};
 use serde;
 use serde_json as json;
 use url::Url;
@@ -21,6 +21,8 @@ pub enum Resource {
     File(File),
     Peer(Peer),
     Tracker(Tracker),
+    Dht(Dht),
+    Ban(Ban),
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -33,6 +35,8 @@ pub enum ResourceKind {
     File,
     Piece,
     Tracker,
+    Dht,
+    Ban,
 }
 
 /// To increase server->client update efficiency, we
@@ -87,6 +91,25 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         download_token: String,
     },
+    ServerPorts {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        external_ip: Option<String>,
+        port_mappings: Vec<PortMapping>,
+    },
+    ServerAltThrottle {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        alt_throttle: bool,
+    },
+    ServerIpFilter {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        ip_filter_ranges: u64,
+    },
 
     TorrentStatus {
         id: String,
@@ -118,6 +141,12 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         sequential: bool,
     },
+    TorrentEndgame {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        endgame: bool,
+    },
     TorrentPriority {
         id: String,
         #[serde(rename = "type")]
@@ -136,6 +165,26 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         piece_field: String,
     },
+    TorrentQueue {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        queue_position: u32,
+    },
+    TorrentSeedGoals {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        seed_ratio: Option<f32>,
+        seed_time: Option<i64>,
+        seed_idle: Option<i64>,
+    },
+    TorrentMetadata {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        metadata_progress: Option<f32>,
+    },
 
     TrackerStatus {
         id: String,
@@ -143,6 +192,20 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         last_report: DateTime<Utc>,
         error: Option<String>,
+        #[serde(default)]
+        warning: Option<String>,
+        #[serde(default)]
+        next_announce: Option<DateTime<Utc>>,
+        #[serde(default)]
+        failures: u32,
+    },
+    TrackerScrape {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        complete: u32,
+        incomplete: u32,
+        downloaded: u32,
     },
 
     FilePriority {
@@ -177,6 +240,35 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         availability: f32,
     },
+    PeerQueue {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        queue_depth: u16,
+        rtt: Option<u32>,
+    },
+
+    BanExpires {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        expires: Option<DateTime<Utc>>,
+    },
+
+    DhtStats {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        buckets: Vec<u32>,
+        buckets6: Vec<u32>,
+        good_nodes: u32,
+        questionable_nodes: u32,
+        bad_nodes: u32,
+        transactions: u32,
+        stored_peers: Vec<StoredPeers>,
+        transferred_up: u64,
+        transferred_down: u64,
+    },
 }
 
 /// Collection of mutable fields that clients
@@ -188,6 +280,17 @@ pub struct CResourceUpdate {
     pub path: Option<String>,
     pub priority: Option<u8>,
     pub sequential: Option<bool>,
+    pub queue_position: Option<u32>,
+    pub alt_throttle: Option<bool>,
+    #[serde(deserialize_with = "deserialize_ratio")]
+    #[serde(default)]
+    pub seed_ratio: Option<Option<f32>>,
+    #[serde(deserialize_with = "deserialize_goal")]
+    #[serde(default)]
+    pub seed_time: Option<Option<i64>>,
+    #[serde(deserialize_with = "deserialize_goal")]
+    #[serde(default)]
+    pub seed_idle: Option<Option<i64>>,
     #[serde(deserialize_with = "deserialize_throttle")]
     #[serde(default)]
     pub throttle_up: Option<Option<i64>>,
@@ -212,9 +315,85 @@ pub struct Server {
     pub ses_transferred_down: u64,
     pub free_space: u64,
     pub started: DateTime<Utc>,
+    #[serde(default)]
+    pub external_ip: Option<String>,
+    #[serde(default)]
+    pub port_mappings: Vec<PortMapping>,
+    #[serde(default)]
+    pub alt_throttle: bool,
+    #[serde(default)]
+    pub alt_throttle_up: Option<i64>,
+    #[serde(default)]
+    pub alt_throttle_down: Option<i64>,
+    #[serde(default)]
+    pub throttle_schedule: Vec<ThrottlePeriod>,
+    #[serde(default)]
+    pub ip_filter_ranges: u64,
     pub user_data: json::Value,
 }
 
+/// A port which is forwarded by the local gateway
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PortMapping {
+    pub protocol: Protocol,
+    pub internal_port: u16,
+    pub external_port: Option<u16>,
+    pub method: Option<MappingMethod>,
+    pub status: MappingStatus,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum Protocol {
+    TCP,
+    UDP,
+}
+
+/// Protocol used to create a port mapping
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum MappingMethod {
+    UPnP,
+    NATPMP,
+    PCP,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum MappingStatus {
+    Pending,
+    Mapped,
+    Failed,
+}
+
+/// A weekly period during which the alternate throttle is enabled.
+/// Periods which end before they start continue into the next day.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ThrottlePeriod {
+    /// Days on which the period starts
+    pub days: Vec<Weekday>,
+    /// Local time, formatted as HH:MM:SS
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl ThrottlePeriod {
+    /// Whether the period covers the given local day and time
+    pub fn contains(&self, day: Weekday, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.days.contains(&day) && time >= self.start && time < self.end
+        } else {
+            (self.days.contains(&day) && time >= self.start)
+                || (self.days.contains(&day.pred()) && time < self.end)
+        }
+    }
+}
+
 impl Server {
     pub fn update(&mut self, update: SResourceUpdate) {
         match update {
@@ -248,6 +427,22 @@ impl Server {
             SResourceUpdate::ServerSpace { free_space, .. } => {
                 self.free_space = free_space;
             }
+            SResourceUpdate::ServerPorts {
+                external_ip,
+                port_mappings,
+                ..
+            } => {
+                self.external_ip = external_ip;
+                self.port_mappings = port_mappings;
+            }
+            SResourceUpdate::ServerAltThrottle { alt_throttle, .. } => {
+                self.alt_throttle = alt_throttle;
+            }
+            SResourceUpdate::ServerIpFilter {
+                ip_filter_ranges, ..
+            } => {
+                self.ip_filter_ranges = ip_filter_ranges;
+            }
             SResourceUpdate::Rate {
                 rate_up, rate_down, ..
             } => {
@@ -289,6 +484,18 @@ pub struct Torrent {
     pub piece_size: Option<u32>,
     pub piece_field: String,
     pub files: Option<u32>,
+    #[serde(default)]
+    pub queue_position: u32,
+    #[serde(default)]
+    pub seed_ratio: Option<f32>,
+    #[serde(default)]
+    pub seed_time: Option<i64>,
+    #[serde(default)]
+    pub seed_idle: Option<i64>,
+    #[serde(default)]
+    pub metadata_progress: Option<f32>,
+    #[serde(default)]
+    pub endgame: bool,
     pub user_data: json::Value,
 }
 
@@ -333,12 +540,33 @@ impl Torrent {
             SResourceUpdate::TorrentPicker { sequential, .. } => {
                 self.sequential = sequential;
             }
+            SResourceUpdate::TorrentEndgame { endgame, .. } => {
+                self.endgame = endgame;
+            }
             SResourceUpdate::TorrentPriority { priority, .. } => {
                 self.priority = priority;
             }
             SResourceUpdate::TorrentPieces { piece_field, .. } => {
                 self.piece_field = piece_field;
             }
+            SResourceUpdate::TorrentQueue { queue_position, .. } => {
+                self.queue_position = queue_position;
+            }
+            SResourceUpdate::TorrentSeedGoals {
+                seed_ratio,
+                seed_time,
+                seed_idle,
+                ..
+            } => {
+                self.seed_ratio = seed_ratio;
+                self.seed_time = seed_time;
+                self.seed_idle = seed_idle;
+            }
+            SResourceUpdate::TorrentMetadata {
+                metadata_progress, ..
+            } => {
+                self.metadata_progress = metadata_progress;
+            }
             _ => {}
         }
     }
@@ -356,6 +584,7 @@ pub enum Status {
     Seeding,
     Hashing,
     Error,
+    Queued,
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
@@ -420,6 +649,12 @@ pub struct Peer {
     pub rate_up: u64,
     pub rate_down: u64,
     pub availability: f32,
+    #[serde(default)]
+    pub web_seed: bool,
+    #[serde(default)]
+    pub queue_depth: u16,
+    #[serde(default)]
+    pub rtt: Option<u32>,
     pub user_data: json::Value,
 }
 
@@ -435,6 +670,12 @@ impl Peer {
             SResourceUpdate::PeerAvailability { availability, .. } => {
                 self.availability = availability;
             }
+            SResourceUpdate::PeerQueue {
+                queue_depth, rtt, ..
+            } => {
+                self.queue_depth = queue_depth;
+                self.rtt = rtt;
+            }
             _ => {}
         }
     }
@@ -449,6 +690,20 @@ pub struct Tracker {
     pub url: Option<Url>,
     pub last_report: DateTime<Utc>,
     pub error: Option<String>,
+    #[serde(default)]
+    pub warning: Option<String>,
+    #[serde(default)]
+    pub next_announce: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub failures: u32,
+    #[serde(default)]
+    pub tier: u32,
+    #[serde(default)]
+    pub complete: Option<u32>,
+    #[serde(default)]
+    pub incomplete: Option<u32>,
+    #[serde(default)]
+    pub downloaded: Option<u32>,
     pub user_data: json::Value,
 }
 
@@ -456,16 +711,141 @@ impl Tracker {
     pub fn update(&mut self, update: SResourceUpdate) {
         match update {
             SResourceUpdate::TrackerStatus {
-                last_report, error, ..
+                last_report,
+                error,
+                warning,
+                next_announce,
+                failures,
+                ..
             } => {
                 self.last_report = last_report;
                 self.error = error;
+                self.warning = warning;
+                self.next_announce = next_announce;
+                self.failures = failures;
+            }
+            SResourceUpdate::TrackerScrape {
+                complete,
+                incomplete,
+                downloaded,
+                ..
+            } => {
+                self.complete = Some(complete);
+                self.incomplete = Some(incomplete);
+                self.downloaded = Some(downloaded);
             }
             _ => {}
         }
     }
 }
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Dht {
+    pub id: String,
+    /// Number of nodes in each bucket of the IPv4 routing table
+    pub buckets: Vec<u32>,
+    /// Number of nodes in each bucket of the IPv6 routing table
+    pub buckets6: Vec<u32>,
+    pub good_nodes: u32,
+    pub questionable_nodes: u32,
+    pub bad_nodes: u32,
+    /// Outstanding queries sent to other nodes
+    pub transactions: u32,
+    pub stored_peers: Vec<StoredPeers>,
+    pub transferred_up: u64,
+    pub transferred_down: u64,
+    pub user_data: json::Value,
+}
+
+/// Peers announced to the DHT node for a torrent
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StoredPeers {
+    pub info_hash: String,
+    pub peers: u32,
+}
+
+/// A node of a DHT routing table
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DhtNode {
+    pub id: String,
+    pub addr: String,
+    /// Index of the node's bucket in the routing table of its address family
+    pub bucket: u32,
+    pub state: DhtNodeState,
+    pub last_updated: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum DhtNodeState {
+    Good,
+    Questionable,
+    Bad,
+}
+
+/// An IP which peers may not connect from
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Ban {
+    pub id: String,
+    pub ip: String,
+    pub reason: BanReason,
+    pub created: DateTime<Utc>,
+    /// Time the ban is lifted, or None if it is permanent
+    pub expires: Option<DateTime<Utc>>,
+    pub user_data: json::Value,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum BanReason {
+    /// The peer sent data which failed hash checks
+    Corrupt,
+    /// Added over RPC
+    Manual,
+}
+
+impl Ban {
+    pub fn update(&mut self, update: SResourceUpdate) {
+        if let SResourceUpdate::BanExpires { expires, .. } = update {
+            self.expires = expires;
+        }
+    }
+}
+
+impl Dht {
+    pub fn update(&mut self, update: SResourceUpdate) {
+        if let SResourceUpdate::DhtStats {
+            buckets,
+            buckets6,
+            good_nodes,
+            questionable_nodes,
+            bad_nodes,
+            transactions,
+            stored_peers,
+            transferred_up,
+            transferred_down,
+            ..
+        } = update
+        {
+            self.buckets = buckets;
+            self.buckets6 = buckets6;
+            self.good_nodes = good_nodes;
+            self.questionable_nodes = questionable_nodes;
+            self.bad_nodes = bad_nodes;
+            self.transactions = transactions;
+            self.stored_peers = stored_peers;
+            self.transferred_up = transferred_up;
+            self.transferred_down = transferred_down;
+        }
+    }
+}
+
 impl<'a> SResourceUpdate<'a> {
     pub fn id(&self) -> &str {
         match self {
@@ -476,19 +856,30 @@ impl<'a> SResourceUpdate<'a> {
             | &SResourceUpdate::ServerTransfer { ref id, .. }
             | &SResourceUpdate::ServerToken { ref id, .. }
             | &SResourceUpdate::ServerSpace { ref id, .. }
+            | &SResourceUpdate::ServerPorts { ref id, .. }
+            | &SResourceUpdate::ServerAltThrottle { ref id, .. }
+            | &SResourceUpdate::ServerIpFilter { ref id, .. }
             | &SResourceUpdate::TorrentStatus { ref id, .. }
             | &SResourceUpdate::TorrentTransfer { ref id, .. }
             | &SResourceUpdate::TorrentPeers { ref id, .. }
             | &SResourceUpdate::TorrentPicker { ref id, .. }
+            | &SResourceUpdate::TorrentEndgame { ref id, .. }
             | &SResourceUpdate::TorrentPriority { ref id, .. }
             | &SResourceUpdate::TorrentPath { ref id, .. }
             | &SResourceUpdate::TorrentPieces { ref id, .. }
+            | &SResourceUpdate::TorrentQueue { ref id, .. }
+            | &SResourceUpdate::TorrentSeedGoals { ref id, .. }
+            | &SResourceUpdate::TorrentMetadata { ref id, .. }
             | &SResourceUpdate::FilePriority { ref id, .. }
             | &SResourceUpdate::FileProgress { ref id, .. }
             | &SResourceUpdate::TrackerStatus { ref id, .. }
+            | &SResourceUpdate::TrackerScrape { ref id, .. }
             | &SResourceUpdate::PeerAvailability { ref id, .. }
+            | &SResourceUpdate::PeerQueue { ref id, .. }
             | &SResourceUpdate::PieceAvailable { ref id, .. }
-            | &SResourceUpdate::PieceDownloaded { ref id, .. } => id,
+            | &SResourceUpdate::PieceDownloaded { ref id, .. }
+            | &SResourceUpdate::BanExpires { ref id, .. }
+            | &SResourceUpdate::DhtStats { ref id, .. } => id,
         }
     }
 }
@@ -502,6 +893,8 @@ impl Resource {
             &Resource::Piece(ref t) => &t.id,
             &Resource::Peer(ref t) => &t.id,
             &Resource::Tracker(ref t) => &t.id,
+            &Resource::Dht(ref t) => &t.id,
+            &Resource::Ban(ref t) => &t.id,
         }
     }
 
@@ -523,6 +916,8 @@ impl Resource {
             &Resource::Piece(_) => ResourceKind::Piece,
             &Resource::Peer(_) => ResourceKind::Peer,
             &Resource::Tracker(_) => ResourceKind::Tracker,
+            &Resource::Dht(_) => ResourceKind::Dht,
+            &Resource::Ban(_) => ResourceKind::Ban,
         }
     }
 
@@ -534,6 +929,8 @@ impl Resource {
             &mut Resource::Piece(ref mut r) => &mut r.user_data,
             &mut Resource::Peer(ref mut r) => &mut r.user_data,
             &mut Resource::Tracker(ref mut r) => &mut r.user_data,
+            &mut Resource::Dht(ref mut r) => &mut r.user_data,
+            &mut Resource::Ban(ref mut r) => &mut r.user_data,
         }
     }
 
@@ -579,6 +976,20 @@ impl Resource {
         }
     }
 
+    pub fn as_dht(&self) -> &Dht {
+        match self {
+            &Resource::Dht(ref d) => d,
+            _ => panic!(),
+        }
+    }
+
+    pub fn as_ban(&self) -> &Ban {
+        match self {
+            &Resource::Ban(ref b) => b,
+            _ => panic!(),
+        }
+    }
+
     pub fn update(&mut self, update: SResourceUpdate) {
         match self {
             &mut Resource::Server(ref mut s) => {
@@ -599,6 +1010,12 @@ impl Resource {
             &mut Resource::Tracker(ref mut t) => {
                 t.update(update);
             }
+            &mut Resource::Dht(ref mut d) => {
+                d.update(update);
+            }
+            &mut Resource::Ban(ref mut b) => {
+                b.update(update);
+            }
         }
     }
 }
@@ -639,6 +1056,10 @@ impl fmt::Display for Resource {
                     }
                 }
                 write!(f, "\n")?;
+                write!(f, "  alternate throttle: {}", t.alt_throttle)?;
+                write!(f, "\n")?;
+                write!(f, "  filtered ranges: {}", t.ip_filter_ranges)?;
+                write!(f, "\n")?;
                 write!(f, "  uploaded: {} B", t.transferred_up)?;
                 write!(f, "\n")?;
                 write!(f, "  downloaded: {} B", t.transferred_down)?;
@@ -649,6 +1070,18 @@ impl fmt::Display for Resource {
                 write!(f, "\n")?;
                 write!(f, "  started at: {}", t.started)?;
                 write!(f, "\n")?;
+                if let Some(ref ip) = t.external_ip {
+                    write!(f, "  external ip: {}", ip)?;
+                    write!(f, "\n")?;
+                }
+                for m in &t.port_mappings {
+                    write!(f, "  port mapping: {:?} {}", m.protocol, m.internal_port)?;
+                    if let Some(p) = m.external_port {
+                        write!(f, " -> {}", p)?;
+                    }
+                    write!(f, " ({:?})", m.status)?;
+                    write!(f, "\n")?;
+                }
                 write!(f, "}}")?;
             }
             &Resource::Torrent(ref t) => {
@@ -680,12 +1113,32 @@ impl fmt::Display for Resource {
                 }
                 write!(f, "  priority: {}", t.priority)?;
                 write!(f, "\n")?;
+                write!(f, "  queue position: {}", t.queue_position)?;
+                write!(f, "\n")?;
+                if let Some(r) = t.seed_ratio {
+                    write!(f, "  seed ratio: {}", r)?;
+                    write!(f, "\n")?;
+                }
+                if let Some(s) = t.seed_time {
+                    write!(f, "  seed time: {} s", s)?;
+                    write!(f, "\n")?;
+                }
+                if let Some(s) = t.seed_idle {
+                    write!(f, "  seed idle: {} s", s)?;
+                    write!(f, "\n")?;
+                }
+                if let Some(p) = t.metadata_progress {
+                    write!(f, "  metadata progress: {}", p)?;
+                    write!(f, "\n")?;
+                }
                 write!(f, "  progress: {}", t.progress)?;
                 write!(f, "\n")?;
                 write!(f, "  availability: {}", t.availability)?;
                 write!(f, "\n")?;
                 write!(f, "  sequential: {}", t.sequential)?;
                 write!(f, "\n")?;
+                write!(f, "  endgame: {}", t.endgame)?;
+                write!(f, "\n")?;
                 write!(f, "  upload: {} B/s", t.rate_up)?;
                 write!(f, "\n")?;
                 write!(f, "  download: {} B/s", t.rate_down)?;
@@ -760,6 +1213,12 @@ impl fmt::Display for Resource {
             &Resource::Tracker(ref t) => {
                 write!(f, "{:#?}", t)?;
             }
+            &Resource::Dht(ref t) => {
+                write!(f, "{:#?}", t)?;
+            }
+            &Resource::Ban(ref t) => {
+                write!(f, "{:#?}", t)?;
+            }
         }
         Ok(())
     }
@@ -778,6 +1237,31 @@ where
     }
 }
 
+fn deserialize_ratio<'de, D>(de: D) -> Result<Option<Option<f32>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let deser_result = serde::Deserialize::deserialize(de)?;
+    match deser_result {
+        json::Value::Null => Ok(Some(None)),
+        json::Value::Number(ref n) => Ok(Some(Some(n.as_f64().unwrap() as f32))),
+        _ => Err(serde::de::Error::custom("Seed ratio must be number or null")),
+    }
+}
+
+fn deserialize_goal<'de, D>(de: D) -> Result<Option<Option<i64>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let deser_result = serde::Deserialize::deserialize(de)?;
+    match deser_result {
+        json::Value::Null => Ok(Some(None)),
+        json::Value::Number(ref i) if i.is_i64() => Ok(Some(Some(i.as_i64().unwrap()))),
+        json::Value::Number(_) => Err(serde::de::Error::custom("Seed goal must not be a float")),
+        _ => Err(serde::de::Error::custom("Seed goal must be number or null")),
+    }
+}
+
 // TODO: Proc macros to remove this shit
 
 impl Queryable for Resource {
@@ -789,6 +1273,8 @@ impl Queryable for Resource {
             &Resource::Piece(ref t) => t.field(f),
             &Resource::Peer(ref t) => t.field(f),
             &Resource::Tracker(ref t) => t.field(f),
+            &Resource::Dht(ref t) => t.field(f),
+            &Resource::Ban(ref t) => t.field(f),
         }
     }
 }
@@ -827,6 +1313,17 @@ impl Queryable for Server {
             "ses_transferred_up" => Some(Field::N(self.ses_transferred_up as i64)),
             "ses_transferred_down" => Some(Field::N(self.ses_transferred_down as i64)),
             "free_space" => Some(Field::N(self.free_space as i64)),
+            "external_ip" => Some(Field::O(Box::new(
+                self.external_ip.as_ref().map(|v| Field::S(v.as_str())),
+            ))),
+            "alt_throttle" => Some(Field::B(self.alt_throttle)),
+            "alt_throttle_up" => Some(Field::O(Box::new(
+                self.alt_throttle_up.map(|v| Field::N(v)),
+            ))),
+            "alt_throttle_down" => Some(Field::O(Box::new(
+                self.alt_throttle_down.map(|v| Field::N(v)),
+            ))),
+            "ip_filter_ranges" => Some(Field::N(self.ip_filter_ranges as i64)),
 
             "started" => Some(Field::D(self.started)),
 
@@ -872,6 +1369,13 @@ impl Queryable for Torrent {
                 self.piece_size.map(|v| Field::N(v as i64)),
             ))),
             "files" => Some(Field::O(Box::new(self.files.map(|v| Field::N(v as i64))))),
+            "queue_position" => Some(Field::N(self.queue_position as i64)),
+            "seed_ratio" => Some(Field::O(Box::new(self.seed_ratio.map(|v| Field::F(v))))),
+            "seed_time" => Some(Field::O(Box::new(self.seed_time.map(|v| Field::N(v))))),
+            "seed_idle" => Some(Field::O(Box::new(self.seed_idle.map(|v| Field::N(v))))),
+            "metadata_progress" => Some(Field::O(Box::new(
+                self.metadata_progress.map(|v| Field::F(v)),
+            ))),
 
             "created" => Some(Field::D(self.created)),
             "modified" => Some(Field::D(self.modified)),
@@ -880,6 +1384,7 @@ impl Queryable for Torrent {
             "availability" => Some(Field::F(self.availability)),
 
             "sequential" => Some(Field::B(self.sequential)),
+            "endgame" => Some(Field::B(self.endgame)),
 
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
@@ -936,6 +1441,11 @@ impl Queryable for Peer {
 
             "client_id" => Some(Field::S(&self.client_id)),
 
+            "web_seed" => Some(Field::B(self.web_seed)),
+
+            "queue_depth" => Some(Field::N(self.queue_depth as i64)),
+            "rtt" => Some(Field::O(Box::new(self.rtt.map(|v| Field::N(v as i64))))),
+
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
             _ => None,
@@ -955,7 +1465,39 @@ impl Queryable for Tracker {
                 self.error.as_ref().map(|v| Field::S(v.as_str())),
             ))),
 
+            "warning" => Some(Field::O(Box::new(
+                self.warning.as_ref().map(|v| Field::S(v.as_str())),
+            ))),
             "last_report" => Some(Field::D(self.last_report)),
+            "next_announce" => Some(Field::O(Box::new(self.next_announce.map(Field::D)))),
+            "failures" => Some(Field::N(self.failures as i64)),
+            "tier" => Some(Field::N(self.tier as i64)),
+            "complete" => Some(Field::O(Box::new(self.complete.map(|v| Field::N(v as i64))))),
+            "incomplete" => Some(Field::O(Box::new(
+                self.incomplete.map(|v| Field::N(v as i64)),
+            ))),
+            "downloaded" => Some(Field::O(Box::new(
+                self.downloaded.map(|v| Field::N(v as i64)),
+            ))),
+
+            _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
+
+            _ => None,
+        }
+    }
+}
+
+impl Queryable for Dht {
+    fn field(&self, f: &str) -> Option<Field> {
+        match f {
+            "id" => Some(Field::S(&self.id)),
+
+            "good_nodes" => Some(Field::N(self.good_nodes as i64)),
+            "questionable_nodes" => Some(Field::N(self.questionable_nodes as i64)),
+            "bad_nodes" => Some(Field::N(self.bad_nodes as i64)),
+            "transactions" => Some(Field::N(self.transactions as i64)),
+            "transferred_up" => Some(Field::N(self.transferred_up as i64)),
+            "transferred_down" => Some(Field::N(self.transferred_down as i64)),
 
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
@@ -964,6 +1506,32 @@ impl Queryable for Tracker {
     }
 }
 
+impl Queryable for Ban {
+    fn field(&self, f: &str) -> Option<Field> {
+        match f {
+            "id" => Some(Field::S(&self.id)),
+            "ip" => Some(Field::S(&self.ip)),
+            "reason" => Some(Field::S(self.reason.as_str())),
+
+            "created" => Some(Field::D(self.created)),
+            "expires" => Some(Field::O(Box::new(self.expires.map(Field::D)))),
+
+            _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
+
+            _ => None,
+        }
+    }
+}
+
+impl BanReason {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            BanReason::Corrupt => "corrupt",
+            BanReason::Manual => "manual",
+        }
+    }
+}
+
 impl Status {
     pub fn as_str(&self) -> &'static str {
         match *self {
@@ -975,6 +1543,7 @@ impl Status {
             Status::Hashing => "hashing",
             Status::Magnet => "magnet",
             Status::Error => "error",
+            Status::Queued => "queued",
         }
     }
 }
@@ -1020,6 +1589,13 @@ impl Default for Server {
             free_space: 0,
             download_token: "".to_owned(),
             started: Utc::now(),
+            external_ip: None,
+            port_mappings: Vec::new(),
+            alt_throttle: false,
+            alt_throttle_up: None,
+            alt_throttle_down: None,
+            throttle_schedule: Vec::new(),
+            ip_filter_ranges: 0,
             user_data: json::Value::Null,
         }
     }
@@ -1055,6 +1631,12 @@ impl Default for Torrent {
             piece_size: None,
             piece_field: "".to_owned(),
             files: None,
+            queue_position: 0,
+            seed_ratio: None,
+            seed_time: None,
+            seed_idle: None,
+            metadata_progress: None,
+            endgame: false,
             user_data: json::Value::Null,
         }
     }
@@ -1068,7 +1650,44 @@ impl Default for Tracker {
             url: None,
             last_report: Utc::now(),
             error: None,
+            warning: None,
+            next_announce: None,
+            failures: 0,
+            tier: 0,
+            complete: None,
+            incomplete: None,
+            downloaded: None,
             user_data: json::Value::Null,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveTime, Weekday};
+
+    use super::ThrottlePeriod;
+
+    #[test]
+    fn test_throttle_period() {
+        let p = ThrottlePeriod {
+            days: vec![Weekday::Mon, Weekday::Fri],
+            start: NaiveTime::from_hms(9, 0, 0),
+            end: NaiveTime::from_hms(17, 0, 0),
+        };
+        assert!(p.contains(Weekday::Mon, NaiveTime::from_hms(9, 0, 0)));
+        assert!(!p.contains(Weekday::Mon, NaiveTime::from_hms(17, 0, 0)));
+        assert!(!p.contains(Weekday::Tue, NaiveTime::from_hms(12, 0, 0)));
+
+        // Overnight periods continue into the next day
+        let p = ThrottlePeriod {
+            days: vec![Weekday::Sun],
+            start: NaiveTime::from_hms(22, 0, 0),
+            end: NaiveTime::from_hms(6, 0, 0),
+        };
+        assert!(p.contains(Weekday::Sun, NaiveTime::from_hms(23, 0, 0)));
+        assert!(p.contains(Weekday::Mon, NaiveTime::from_hms(5, 0, 0)));
+        assert!(!p.contains(Weekday::Sun, NaiveTime::from_hms(5, 0, 0)));
+        assert!(!p.contains(Weekday::Mon, NaiveTime::from_hms(22, 30, 0)));
+    }
+}
@@ -10,6 +10,9 @@ pub enum BEncode {
     String(Vec<u8>),
     List(Vec<BEncode>),
     Dict(BTreeMap<String, BEncode>),
+    /// Dictionary with keys which aren't valid UTF8,
+    /// such as BEP 52 piece layers
+    RawDict(BTreeMap<Vec<u8>, BEncode>),
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -86,6 +89,15 @@ impl BEncode {
         }
     }
 
+    /// Converts either kind of dictionary into one with raw keys
+    pub fn into_raw_dict(self) -> Option<BTreeMap<Vec<u8>, BEncode>> {
+        match self {
+            BEncode::Dict(v) => Some(v.into_iter().map(|(k, v)| (k.into_bytes(), v)).collect()),
+            BEncode::RawDict(v) => Some(v),
+            _ => None,
+        }
+    }
+
     #[allow(dead_code)]
     pub fn as_int(&self) -> Option<&i64> {
         match *self {
@@ -155,6 +167,15 @@ impl BEncode {
                 }
                 write!(w, "e")?;
             }
+            BEncode::RawDict(ref d) => {
+                write!(w, "d")?;
+                for (k, v) in d.iter() {
+                    write!(w, "{}:", k.len())?;
+                    w.write_all(k)?;
+                    v.encode(w)?;
+                }
+                write!(w, "e")?;
+            }
         };
         Ok(())
     }
@@ -185,14 +206,21 @@ pub fn decode<R: io::Read>(bytes: &mut R) -> Result<BEncode, BError> {
             let mut d = BTreeMap::new();
             loop {
                 let key = match decode(bytes) {
-                    Ok(BEncode::String(s)) => String::from_utf8(s).map_err(|_| BError::UTF8Decode)?,
+                    Ok(BEncode::String(s)) => s,
                     Ok(_) => return Err(BError::InvalidDict),
                     Err(BError::EOF) => break,
                     Err(e) => return Err(e),
                 };
                 d.insert(key, decode(bytes)?);
             }
-            Ok(BEncode::Dict(d))
+            if d.keys().all(|k| str::from_utf8(k).is_ok()) {
+                let d = d.into_iter()
+                    .map(|(k, v)| (String::from_utf8(k).unwrap(), v))
+                    .collect();
+                Ok(BEncode::Dict(d))
+            } else {
+                Ok(BEncode::RawDict(d))
+            }
         }
         Err(BError::EOF) | Ok(b'e') => Err(BError::EOF),
         Ok(d @ b'0'...b'9') => {
@@ -279,6 +307,13 @@ mod tests {
         encode_decode(&s2);
         encode_decode(&l);
         encode_decode(&d);
+
+        let mut map = BTreeMap::new();
+        map.insert(vec![0xff, 0], i.clone());
+        let rd = BEncode::RawDict(map);
+        decode_encode(b"d2:\xff\x00i-10ee");
+        encode_decode(&rd);
+        assert_eq!(d.into_raw_dict().unwrap()[&b"asdf"[..]], i);
     }
 
     fn encode_decode(b: &BEncode) {
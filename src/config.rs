@@ -1,4 +1,4 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::IpAddr;
 use std::{fs, process};
 use std::io::Read;
 
@@ -6,6 +6,7 @@ use toml;
 use shellexpand;
 
 use args;
+use rpc::resource::ThrottlePeriod;
 
 error_chain! {
     errors {
@@ -30,18 +31,24 @@ error_chain! {
 pub struct Config {
     pub port: u16,
     pub max_dl: u32,
+    pub max_active_seeds: Option<u32>,
+    pub max_active_total: Option<u32>,
+    pub stalled_rate: u64,
     pub trk: TrkConfig,
     pub dht: DhtConfig,
     pub rpc: RpcConfig,
     pub disk: DiskConfig,
     pub net: NetConfig,
     pub peer: PeerConfig,
+    pub seed: SeedConfig,
+    pub throttle: ThrottleConfig,
 }
 
 #[derive(Debug, Clone)]
 pub struct DhtConfig {
     pub port: u16,
-    pub bootstrap_node: Option<SocketAddr>,
+    /// host:port of the nodes used to bootstrap the DHT
+    pub bootstrap_nodes: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -51,6 +58,12 @@ pub struct ConfigFile {
     #[serde(default = "default_max_dl")]
     pub max_dl: u32,
     #[serde(default)]
+    pub max_active_seeds: Option<u32>,
+    #[serde(default)]
+    pub max_active_total: Option<u32>,
+    #[serde(default)]
+    pub stalled_rate: u64,
+    #[serde(default)]
     pub rpc: RpcConfig,
     #[serde(default)]
     pub tracker: TrkConfig,
@@ -62,6 +75,10 @@ pub struct ConfigFile {
     pub net: NetConfig,
     #[serde(default)]
     pub peer: PeerConfig,
+    #[serde(default)]
+    pub seed: SeedConfig,
+    #[serde(default)]
+    pub throttle: ThrottleConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -84,13 +101,23 @@ pub struct RpcConfig {
 pub struct TrkConfig {
     #[serde(default = "default_trk_port")]
     pub port: u16,
+    /// Interval in seconds between scrapes of all trackers, 0 disables scraping
+    #[serde(default = "default_scrape_interval")]
+    pub scrape_interval: u64,
+    /// Announce to a tracker in every announce-list tier,
+    /// rather than only in the first working tier
+    #[serde(default)]
+    pub announce_all_tiers: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DhtConfigFile {
     #[serde(default = "default_dht_port")]
     pub port: u16,
-    #[serde(default = "default_bootstrap_node")]
+    #[serde(default = "default_bootstrap_nodes")]
+    pub bootstrap_nodes: Vec<String>,
+    /// Deprecated single bootstrap node, used before bootstrap_nodes
+    #[serde(default)]
     pub bootstrap_node: Option<String>,
 }
 
@@ -112,12 +139,82 @@ pub struct NetConfig {
     pub max_open_sockets: usize,
     #[serde(default = "default_max_announces")]
     pub max_open_announces: usize,
+    #[serde(default = "default_tcp")]
+    pub tcp: bool,
+    #[serde(default = "default_utp")]
+    pub utp: bool,
+    #[serde(default = "default_lsd")]
+    pub lsd: bool,
+    #[serde(default = "default_port_mapping")]
+    pub port_mapping: bool,
+    #[serde(default)]
+    pub gateway: Option<IpAddr>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerConfig {
     #[serde(default = "default_prune_timeout")]
     pub prune_timeout: u64,
+    #[serde(default = "default_encryption")]
+    pub encryption: Encryption,
+    /// Seconds that IPs sending corrupt data are banned for
+    #[serde(default = "default_ban_duration")]
+    pub ban_duration: u64,
+    /// Paths of blocklists which peers are filtered with
+    #[serde(default)]
+    pub ip_filter: Vec<String>,
+}
+
+/// Default seeding goals, which torrents may override.
+/// Unset goals are never reached.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeedConfig {
+    #[serde(default)]
+    pub ratio: Option<f32>,
+    /// Total seconds spent seeding
+    #[serde(default)]
+    pub time: Option<u64>,
+    /// Seconds spent seeding without uploading anything
+    #[serde(default)]
+    pub idle: Option<u64>,
+    #[serde(default = "default_seed_action")]
+    pub action: SeedAction,
+}
+
+/// Alternate throttle limits, enabled manually or by the schedule
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThrottleConfig {
+    /// Alternate upload limit in bytes/sec, unset is unlimited
+    #[serde(default)]
+    pub alt_up: Option<i64>,
+    /// Alternate download limit in bytes/sec, unset is unlimited
+    #[serde(default)]
+    pub alt_down: Option<i64>,
+    /// Periods during which the alternate limits are enabled
+    #[serde(default)]
+    pub schedule: Vec<ThrottlePeriod>,
+}
+
+/// What to do with a torrent once it reaches a seeding goal
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeedAction {
+    Pause,
+    Remove,
+    /// Removes the torrent and its downloaded files
+    RemoveArtifacts,
+}
+
+/// Policy for MSE/PE obfuscation of peer connections
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encryption {
+    /// Attempt encrypted connections, falling back to plaintext
+    Prefer,
+    /// Only allow encrypted connections
+    Require,
+    /// Only allow plaintext connections
+    Disable,
 }
 
 impl ConfigFile {
@@ -175,24 +272,32 @@ impl Config {
     }
 
     pub fn from_file(mut file: ConfigFile) -> Config {
-        let addr = file.dht
-            .bootstrap_node
-            .and_then(|n| n.to_socket_addrs().ok())
-            .and_then(|mut a| a.next());
+        let mut bootstrap_nodes: Vec<_> = file.dht.bootstrap_node.into_iter().collect();
+        bootstrap_nodes.extend(file.dht.bootstrap_nodes);
         let dht = DhtConfig {
             port: file.dht.port,
-            bootstrap_node: addr,
+            bootstrap_nodes,
         };
         file.disk.session = shellexpand::tilde(&file.disk.session).into();
         file.disk.directory = shellexpand::tilde(&file.disk.directory).into();
+        file.peer.ip_filter = file.peer
+            .ip_filter
+            .iter()
+            .map(|p| shellexpand::tilde(p).into())
+            .collect();
         Config {
             port: file.port,
             max_dl: file.max_dl,
+            max_active_seeds: file.max_active_seeds,
+            max_active_total: file.max_active_total,
+            stalled_rate: file.stalled_rate,
             trk: file.tracker,
             rpc: file.rpc,
             disk: file.disk,
             net: file.net,
             peer: file.peer,
+            seed: file.seed,
+            throttle: file.throttle,
             dht,
         }
     }
@@ -207,6 +312,9 @@ fn default_max_dl() -> u32 {
 fn default_trk_port() -> u16 {
     16_362
 }
+fn default_scrape_interval() -> u64 {
+    1800
+}
 fn default_dht_port() -> u16 {
     16_309
 }
@@ -225,8 +333,13 @@ fn default_password() -> String {
 fn default_ssl() -> String {
     "".to_owned()
 }
-fn default_bootstrap_node() -> Option<String> {
-    None
+fn default_bootstrap_nodes() -> Vec<String> {
+    vec![
+        "router.bittorrent.com:6881".to_owned(),
+        "router.utorrent.com:6881".to_owned(),
+        "dht.transmissionbt.com:6881".to_owned(),
+        "dht.libtorrent.org:25401".to_owned(),
+    ]
 }
 fn default_session_dir() -> String {
     shellexpand::full("$XDG_DATA_HOME/synapse")
@@ -248,21 +361,49 @@ fn default_max_sockets() -> usize {
 fn default_max_announces() -> usize {
     50
 }
+fn default_tcp() -> bool {
+    true
+}
+fn default_utp() -> bool {
+    true
+}
+fn default_lsd() -> bool {
+    true
+}
+
+fn default_port_mapping() -> bool {
+    true
+}
+
 fn default_prune_timeout() -> u64 {
     15
 }
+fn default_encryption() -> Encryption {
+    Encryption::Prefer
+}
+fn default_ban_duration() -> u64 {
+    24 * 60 * 60
+}
+fn default_seed_action() -> SeedAction {
+    SeedAction::Pause
+}
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             port: default_port(),
             max_dl: default_max_dl(),
+            max_active_seeds: None,
+            max_active_total: None,
+            stalled_rate: 0,
             trk: Default::default(),
             rpc: Default::default(),
             disk: Default::default(),
             net: Default::default(),
             dht: Default::default(),
             peer: Default::default(),
+            seed: Default::default(),
+            throttle: Default::default(),
         }
     }
 }
@@ -284,6 +425,8 @@ impl Default for TrkConfig {
     fn default() -> TrkConfig {
         TrkConfig {
             port: default_trk_port(),
+            scrape_interval: default_scrape_interval(),
+            announce_all_tiers: false,
         }
     }
 }
@@ -292,7 +435,8 @@ impl Default for DhtConfigFile {
     fn default() -> DhtConfigFile {
         DhtConfigFile {
             port: default_dht_port(),
-            bootstrap_node: default_bootstrap_node(),
+            bootstrap_nodes: default_bootstrap_nodes(),
+            bootstrap_node: None,
         }
     }
 }
@@ -301,7 +445,7 @@ impl Default for DhtConfig {
     fn default() -> DhtConfig {
         DhtConfig {
             port: default_dht_port(),
-            bootstrap_node: None,
+            bootstrap_nodes: default_bootstrap_nodes(),
         }
     }
 }
@@ -322,6 +466,11 @@ impl Default for NetConfig {
             max_open_files: default_max_files(),
             max_open_sockets: default_max_sockets(),
             max_open_announces: default_max_announces(),
+            tcp: default_tcp(),
+            utp: default_utp(),
+            lsd: default_lsd(),
+            port_mapping: default_port_mapping(),
+            gateway: None,
         }
     }
 }
@@ -330,6 +479,30 @@ impl Default for PeerConfig {
     fn default() -> PeerConfig {
         PeerConfig {
             prune_timeout: default_prune_timeout(),
+            encryption: default_encryption(),
+            ban_duration: default_ban_duration(),
+            ip_filter: Vec::new(),
+        }
+    }
+}
+
+impl Default for SeedConfig {
+    fn default() -> SeedConfig {
+        SeedConfig {
+            ratio: None,
+            time: None,
+            idle: None,
+            action: default_seed_action(),
+        }
+    }
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> ThrottleConfig {
+        ThrottleConfig {
+            alt_up: None,
+            alt_down: None,
+            schedule: Vec::new(),
         }
     }
 }
@@ -1,16 +1,23 @@
 use std::cell::RefCell;
+use std::net::{SocketAddr, UdpSocket};
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time;
 
 use amy;
+use rand;
 
-use {disk, listener, rpc, torrent, tracker};
+use {disk, listener, portmap, rpc, torrent, tracker};
 use CONFIG;
 use control::cio::{self, ErrorKind, Result, ResultExt};
-use util::UHashMap;
+use socket::{utp, Socket};
+use util::{udp_bind_v6, FHashMap, MHashSet, UHashMap};
 
 const POLL_INT_MS: usize = 1000;
 const PRUNE_GOAL: usize = 50;
+/// Ids of uTP connections start from here, so that they can't
+/// collide with the ids of registered sockets.
+const UTP_ID_START: usize = ::std::usize::MAX / 2;
 
 /// Amy based CIO implementation. Currently the default one used.
 pub struct ACIO {
@@ -29,6 +36,9 @@ pub struct ACChans {
 
     pub lst_tx: amy::Sender<listener::Request>,
     pub lst_rx: amy::Receiver<listener::Message>,
+
+    pub pm_tx: amy::Sender<portmap::Request>,
+    pub pm_rx: amy::Receiver<portmap::Response>,
 }
 
 struct ACIOData {
@@ -37,16 +47,45 @@ struct ACIOData {
     peers: UHashMap<torrent::PeerConn>,
     events: Vec<cio::Event>,
     chans: ACChans,
+    /// uTP state, if uTP is enabled and available
+    utp: Option<UTP>,
+}
+
+/// uTP connections, which are multiplexed over UDP sockets
+/// bound to the peer port rather than registered individually.
+struct UTP {
+    sock: (usize, Arc<UdpSocket>),
+    sock6: Option<(usize, Arc<UdpSocket>)>,
+    /// Ids of connections by remote address and receive id
+    conns: FHashMap<(SocketAddr, u16), cio::PID>,
+    /// Incoming connections which have yet to complete a handshake
+    incoming: UHashMap<torrent::PeerConn>,
+    /// Info hashes of active torrents, used to complete MSE handshakes
+    torrents: Arc<MHashSet<[u8; 20]>>,
+    next_id: usize,
+    buf: Vec<u8>,
 }
 
 impl ACIO {
     pub fn new(poll: amy::Poller, reg: amy::Registrar, chans: ACChans) -> ACIO {
+        let utp = if CONFIG.net.utp {
+            match UTP::new(&reg) {
+                Ok(u) => Some(u),
+                Err(e) => {
+                    error!("uTP unavailable, using TCP: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
         let data = ACIOData {
             poll,
             reg,
             chans,
             peers: UHashMap::default(),
             events: Vec::new(),
+            utp,
         };
         ACIO {
             data: Rc::new(RefCell::new(data)),
@@ -74,6 +113,12 @@ impl ACIO {
             while let Ok(t) = d.chans.lst_rx.try_recv() {
                 events.push(cio::Event::Listener(Ok(Box::new(t))));
             }
+        } else if d.chans.pm_rx.get_id() == id {
+            while let Ok(t) = d.chans.pm_rx.try_recv() {
+                events.push(cio::Event::PortMap(Ok(t)));
+            }
+        } else if d.utp.as_ref().map(|u| u.has_sock(id)).unwrap_or(false) {
+            self.process_utp(id, events, &mut d);
         } else if d.peers.contains_key(&id) {
             if let Err(e) = self.process_peer_ev(not, events, &mut d.peers) {
                 d.remove_peer(id);
@@ -110,6 +155,85 @@ impl ACIO {
         }
         Ok(())
     }
+
+    /// Routes the datagrams received on a uTP socket to their
+    /// connections, then processes events for those connections.
+    fn process_utp(&self, sid: usize, events: &mut Vec<cio::Event>, d: &mut ACIOData) {
+        let mut pids = Vec::new();
+        {
+            let utp = match d.utp {
+                Some(ref mut u) => u,
+                None => return,
+            };
+            let sock = if utp.sock.0 == sid {
+                utp.sock.1.clone()
+            } else {
+                match utp.sock6 {
+                    Some((_, ref s)) => s.clone(),
+                    None => return,
+                }
+            };
+            while let Ok((len, addr)) = sock.recv_from(&mut utp.buf[..]) {
+                let (hdr, payload) = match utp::Header::decode(&utp.buf[..len]) {
+                    Some(p) => p,
+                    None => continue,
+                };
+                // SYNs carry the id which the initiator receives on
+                let id = if hdr.kind == utp::Type::Syn {
+                    hdr.conn_id.wrapping_add(1)
+                } else {
+                    hdr.conn_id
+                };
+                if let Some(&pid) = utp.conns.get(&(addr, id)) {
+                    let stream = match d.peers.get_mut(&pid) {
+                        Some(p) => p.sock_mut().utp_mut(),
+                        None => utp
+                            .incoming
+                            .get_mut(&pid)
+                            .and_then(|p| p.sock_mut().utp_mut()),
+                    };
+                    if let Some(s) = stream {
+                        s.process(&hdr, payload);
+                        pids.push(pid);
+                    }
+                } else if hdr.kind == utp::Type::Syn
+                    && d.peers.len() + utp.incoming.len() < CONFIG.net.max_open_sockets
+                {
+                    debug!("Accepted new uTP connection from {:?}!", addr);
+                    let stream = utp::Stream::accept(sock.clone(), addr, &hdr);
+                    let key = stream.key();
+                    let peer =
+                        torrent::PeerConn::new_incoming(Socket::from_utp(stream), &utp.torrents);
+                    if let Ok(peer) = peer {
+                        let pid = utp.next_id();
+                        utp.conns.insert(key, pid);
+                        utp.incoming.insert(pid, peer);
+                        pids.push(pid);
+                    }
+                }
+            }
+        }
+
+        pids.sort();
+        pids.dedup();
+        for pid in pids {
+            if d.peers.contains_key(&pid) {
+                let not = amy::Notification {
+                    id: pid,
+                    event: amy::Event::Both,
+                };
+                if let Err(e) = self.process_peer_ev(not, events, &mut d.peers) {
+                    d.remove_peer(pid);
+                    events.push(cio::Event::Peer {
+                        peer: pid,
+                        event: Err(e),
+                    });
+                }
+            } else if let Some(ref mut utp) = d.utp {
+                utp.process_incoming(pid, events);
+            }
+        }
+    }
 }
 
 impl cio::CIO for ACIO {
@@ -132,6 +256,8 @@ impl cio::CIO for ACIO {
                 error!("Failed to poll for events: {:?}", e);
             }
         }
+
+        self.data.borrow_mut().tick_utp(events);
     }
 
     fn add_peer(&mut self, mut peer: torrent::PeerConn) -> Result<cio::PID> {
@@ -157,11 +283,7 @@ impl cio::CIO for ACIO {
                 self.remove_peer(id);
             }
         }
-        let id = self.data
-            .borrow_mut()
-            .reg
-            .register(peer.sock(), amy::Event::Both)
-            .chain_err(|| ErrorKind::IO)?;
+        let id = self.data.borrow_mut().register_peer(&mut peer)?;
         peer.sock_mut().throttle.as_mut().map(|t| t.id = id);
         self.data.borrow_mut().peers.insert(id, peer);
         Ok(id)
@@ -255,6 +377,20 @@ impl cio::CIO for ACIO {
     fn msg_listener(&mut self, msg: listener::Request) {
         let mut d = self.data.borrow_mut();
 
+        // Incoming uTP connections are handshaken here rather
+        // than by the listener, so track torrents as well.
+        if let Some(ref mut utp) = d.utp {
+            match msg {
+                listener::Request::AddTorrent(hash) => {
+                    Arc::make_mut(&mut utp.torrents).insert(hash);
+                }
+                listener::Request::RemoveTorrent(hash) => {
+                    Arc::make_mut(&mut utp.torrents).remove(&hash);
+                }
+                listener::Request::IpFilter(_) | listener::Request::Shutdown => {}
+            }
+        }
+
         if d.chans.lst_tx.send(msg).is_err() {
             d.events.push(cio::Event::Listener(Err(ErrorKind::Channel(
                 "Couldn't send to disk chan",
@@ -262,6 +398,16 @@ impl cio::CIO for ACIO {
         }
     }
 
+    fn msg_portmap(&mut self, msg: portmap::Request) {
+        let mut d = self.data.borrow_mut();
+
+        if d.chans.pm_tx.send(msg).is_err() {
+            d.events.push(cio::Event::PortMap(Err(ErrorKind::Channel(
+                "Couldn't send to port mapping chan",
+            ).into())));
+        }
+    }
+
     fn set_timer(&mut self, interval: usize) -> Result<cio::TID> {
         self.data
             .borrow_mut()
@@ -278,9 +424,80 @@ impl cio::CIO for ACIO {
 }
 
 impl ACIOData {
+    /// Registers a peer's socket, or for uTP peers starts the
+    /// connection if needed and assigns it an id.
+    fn register_peer(&mut self, peer: &mut torrent::PeerConn) -> Result<cio::PID> {
+        if peer.sock().utp().is_none() {
+            return self.reg
+                .register(peer.sock(), amy::Event::Both)
+                .chain_err(|| ErrorKind::IO);
+        }
+        let addr = peer.sock().addr();
+        let sock = self.utp.as_ref().and_then(|u| u.sock(addr.is_ipv6()));
+        let utp = match (sock, self.utp.as_mut()) {
+            (Some(sock), Some(utp)) => {
+                if peer.sock().utp().map(|s| s.idle()).unwrap_or(false) {
+                    let mut id = rand::random();
+                    while utp.conns.contains_key(&(addr, id)) {
+                        id = rand::random();
+                    }
+                    peer.sock_mut().utp_connect(sock, id);
+                }
+                utp
+            }
+            _ => {
+                // uTP isn't available for the peer, so use TCP instead
+                if !CONFIG.net.tcp {
+                    return Err(ErrorKind::IO.into());
+                }
+                peer.sock_mut().fallback_tcp().chain_err(|| ErrorKind::IO)?;
+                return self.reg
+                    .register(peer.sock(), amy::Event::Both)
+                    .chain_err(|| ErrorKind::IO);
+            }
+        };
+        let pid = utp.next_id();
+        if let Some(s) = peer.sock().utp() {
+            utp.conns.insert(s.key(), pid);
+        }
+        Ok(pid)
+    }
+
+    /// Resends timed out uTP packets, removing connections
+    /// which have failed.
+    fn tick_utp(&mut self, events: &mut Vec<cio::Event>) {
+        let mut failed = Vec::new();
+        if let Some(ref mut utp) = self.utp {
+            for &pid in utp.conns.values() {
+                let peer = match self.peers.get_mut(&pid) {
+                    Some(p) => Some(p),
+                    None => utp.incoming.get_mut(&pid),
+                };
+                if let Some(Err(e)) = peer.and_then(|p| p.sock_mut().utp_mut()).map(|s| s.tick()) {
+                    failed.push((pid, e));
+                }
+            }
+        }
+        for (pid, e) in failed {
+            if self.peers.contains_key(&pid) {
+                self.remove_peer(pid);
+                events.push(cio::Event::Peer {
+                    peer: pid,
+                    event: Err(cio::Error::with_chain(e, ErrorKind::IO)),
+                });
+            } else if let Some(ref mut utp) = self.utp {
+                utp.remove_incoming(pid);
+            }
+        }
+    }
+
     fn remove_peer(&mut self, pid: cio::PID) {
         if let Some(p) = self.peers.remove(&pid) {
-            if let Err(e) = self.reg.deregister(p.sock()) {
+            if let Some(s) = p.sock().utp() {
+                if let Some(ref mut utp) = self.utp {
+                    utp.conns.remove(&s.key());
+                }
+            } else if let Err(e) = self.reg.deregister(p.sock()) {
                 error!("Failed to deregister sock: {:?}", e);
             }
             self.events.push(cio::Event::Peer {
@@ -290,3 +507,82 @@ impl ACIOData {
         }
     }
 }
+
+impl UTP {
+    fn new(reg: &amy::Registrar) -> ::std::io::Result<UTP> {
+        let sock = UdpSocket::bind(("0.0.0.0", CONFIG.port))?;
+        sock.set_nonblocking(true)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+        let sock6 = match udp_bind_v6(CONFIG.port).and_then(|s| {
+            s.set_nonblocking(true)?;
+            Ok((reg.register(&s, amy::Event::Read)?, Arc::new(s)))
+        }) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                info!("IPv6 uTP unavailable: {}", e);
+                None
+            }
+        };
+        Ok(UTP {
+            sock: (id, Arc::new(sock)),
+            sock6,
+            conns: FHashMap::default(),
+            incoming: UHashMap::default(),
+            torrents: Arc::new(MHashSet::default()),
+            next_id: UTP_ID_START,
+            buf: vec![0u8; 65_536],
+        })
+    }
+
+    fn has_sock(&self, id: usize) -> bool {
+        self.sock.0 == id || self.sock6.as_ref().map(|s| s.0 == id).unwrap_or(false)
+    }
+
+    fn sock(&self, v6: bool) -> Option<Arc<UdpSocket>> {
+        if v6 {
+            self.sock6.as_ref().map(|s| s.1.clone())
+        } else {
+            Some(self.sock.1.clone())
+        }
+    }
+
+    fn next_id(&mut self) -> cio::PID {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Advances the handshake of an incoming connection, passing it
+    /// on as if it came from the listener once complete.
+    fn process_incoming(&mut self, pid: cio::PID, events: &mut Vec<cio::Event>) {
+        let res = match self.incoming.get_mut(&pid) {
+            Some(p) => p.readable(),
+            None => return,
+        };
+        match res {
+            Ok(Some(hs)) => {
+                debug!("Completed uTP handshake({:?}) with peer, transferring!", hs);
+                let peer = self.remove_incoming(pid).unwrap();
+                let hsd = hs.get_handshake_data();
+                events.push(cio::Event::Listener(Ok(Box::new(listener::Message {
+                    peer,
+                    hash: hsd.0,
+                    id: hsd.1,
+                    rsv: hsd.2,
+                }))));
+            }
+            Ok(None) => {}
+            Err(_) => {
+                debug!("uTP peer connection failed!");
+                self.remove_incoming(pid);
+            }
+        }
+    }
+
+    fn remove_incoming(&mut self, pid: cio::PID) -> Option<torrent::PeerConn> {
+        let peer = self.incoming.remove(&pid)?;
+        if let Some(s) = peer.sock().utp() {
+            self.conns.remove(&s.key());
+        }
+        Some(peer)
+    }
+}
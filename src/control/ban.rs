@@ -0,0 +1,126 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Utc};
+
+use rpc::resource::{self, BanReason};
+use util;
+
+/// IPs which peers may not connect from, banned either for
+/// sending corrupt data or by a client.
+pub struct BanList {
+    bans: HashMap<IpAddr, Ban>,
+}
+
+struct Ban {
+    reason: BanReason,
+    created: DateTime<Utc>,
+    expires: Option<DateTime<Utc>>,
+}
+
+impl BanList {
+    pub fn new() -> BanList {
+        BanList {
+            bans: HashMap::new(),
+        }
+    }
+
+    /// Returns true if the IP is banned and the ban hasn't expired
+    pub fn banned(&self, ip: &IpAddr) -> bool {
+        self.bans
+            .get(ip)
+            .map(|b| b.expires.map(|e| e > Utc::now()).unwrap_or(true))
+            .unwrap_or(false)
+    }
+
+    /// Bans an IP for duration seconds, or permanently if None.
+    /// Bans for corrupt data only ever extend an existing ban,
+    /// while manual bans replace its expiry. Returns true if
+    /// the IP wasn't already banned.
+    pub fn ban(&mut self, ip: IpAddr, reason: BanReason, duration: Option<u64>) -> bool {
+        let now = Utc::now();
+        let expires = duration.map(|d| now + Duration::seconds(d as i64));
+        if let Some(b) = self.bans.get_mut(&ip) {
+            b.expires = match (reason, b.expires, expires) {
+                (BanReason::Manual, _, e) => e,
+                (BanReason::Corrupt, Some(cur), Some(new)) => Some(cmp::max(cur, new)),
+                (BanReason::Corrupt, _, _) => None,
+            };
+            return false;
+        }
+        self.bans.insert(
+            ip,
+            Ban {
+                reason,
+                created: now,
+                expires,
+            },
+        );
+        true
+    }
+
+    /// Lifts a ban, returning true if the IP was banned
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.bans.remove(ip).is_some()
+    }
+
+    /// Removes expired bans, returning their IPs
+    pub fn expire(&mut self) -> Vec<IpAddr> {
+        let now = Utc::now();
+        let expired: Vec<_> = self.bans
+            .iter()
+            .filter(|&(_, b)| b.expires.map(|e| e <= now).unwrap_or(false))
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in &expired {
+            self.bans.remove(ip);
+        }
+        expired
+    }
+
+    pub fn expires(&self, ip: &IpAddr) -> Option<DateTime<Utc>> {
+        self.bans.get(ip).and_then(|b| b.expires)
+    }
+
+    pub fn rpc_info(&self, ip: &IpAddr) -> Option<resource::Resource> {
+        self.bans.get(ip).map(|b| {
+            resource::Resource::Ban(resource::Ban {
+                id: util::ban_rpc_id(ip),
+                ip: ip.to_string(),
+                reason: b.reason,
+                created: b.created,
+                expires: b.expires,
+                user_data: Default::default(),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BanList;
+    use rpc::resource::BanReason;
+
+    #[test]
+    fn test_ban() {
+        let mut b = BanList::new();
+        let ip = "1.2.3.4".parse().unwrap();
+        assert!(!b.banned(&ip));
+        assert!(b.ban(ip, BanReason::Corrupt, Some(60)));
+        assert!(b.banned(&ip));
+        let e = b.expires(&ip);
+
+        // Corrupt data bans never shorten a ban
+        assert!(!b.ban(ip, BanReason::Corrupt, Some(1)));
+        assert_eq!(b.expires(&ip), e);
+        assert!(!b.ban(ip, BanReason::Manual, None));
+        assert_eq!(b.expires(&ip), None);
+        assert!(b.expire().is_empty());
+
+        assert!(!b.ban(ip, BanReason::Manual, Some(0)));
+        assert!(!b.banned(&ip));
+        assert_eq!(b.expire(), vec![ip]);
+        assert!(!b.unban(&ip));
+    }
+}
@@ -1,4 +1,4 @@
-use {disk, listener, rpc, torrent, tracker};
+use {disk, listener, portmap, rpc, torrent, tracker};
 
 error_chain! {
     errors {
@@ -37,6 +37,7 @@ pub enum Event {
     Tracker(Result<tracker::Response>),
     Disk(Result<disk::Response>),
     Listener(Result<Box<listener::Message>>),
+    PortMap(Result<portmap::Response>),
 }
 
 /// Control IO trait used as an abstraction boundary between
@@ -76,6 +77,9 @@ pub trait CIO {
     /// Sends a message to the listener worker
     fn msg_listener(&mut self, msg: listener::Request);
 
+    /// Sends a message to the port mapping worker
+    fn msg_portmap(&mut self, msg: portmap::Request);
+
     /// Sets a timer in milliseconds
     fn set_timer(&mut self, interval: usize) -> Result<TID>;
 
@@ -86,7 +90,7 @@ pub trait CIO {
 #[cfg(test)]
 pub mod test {
     use super::{Event, Result, CIO, PID, TID};
-    use {disk, listener, rpc, torrent, tracker};
+    use {disk, listener, portmap, rpc, torrent, tracker};
     use std::collections::HashMap;
     use std::sync::{Arc, Mutex, MutexGuard};
 
@@ -103,6 +107,7 @@ pub mod test {
         pub trk_msgs: Vec<tracker::Request>,
         pub disk_msgs: Vec<disk::Request>,
         pub listener_msgs: Vec<listener::Request>,
+        pub portmap_msgs: Vec<portmap::Request>,
         pub timers: usize,
         pub peer_cnt: usize,
     }
@@ -117,6 +122,7 @@ pub mod test {
                 trk_msgs: Vec::new(),
                 disk_msgs: Vec::new(),
                 listener_msgs: Vec::new(),
+                portmap_msgs: Vec::new(),
                 timers: 0,
                 peer_cnt: 0,
             };
@@ -133,6 +139,7 @@ pub mod test {
             d.trk_msgs.clear();
             d.disk_msgs.clear();
             d.listener_msgs.clear();
+            d.portmap_msgs.clear();
         }
 
         pub fn data(&mut self) -> MutexGuard<TCIOD> {
@@ -204,6 +211,11 @@ pub mod test {
             d.listener_msgs.push(msg);
         }
 
+        fn msg_portmap(&mut self, msg: portmap::Request) {
+            let mut d = self.data.lock().unwrap();
+            d.portmap_msgs.push(msg);
+        }
+
         fn set_timer(&mut self, _: usize) -> Result<TID> {
             let mut d = self.data.lock().unwrap();
             let timer = d.timers;
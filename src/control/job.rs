@@ -1,6 +1,12 @@
+use std::time;
+
+use chrono::{Datelike, Local};
+
 use torrent::Torrent;
-use control::cio;
+use control::{cio, CJob, Control};
+use config::SeedAction;
 use util::UHashMap;
+use CONFIG;
 
 pub trait Job<T: cio::CIO> {
     fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>);
@@ -16,6 +22,14 @@ impl<T: cio::CIO> Job<T> for TrackerUpdate {
     }
 }
 
+pub struct ScrapeUpdate;
+
+impl<T: cio::CIO> CJob<T> for ScrapeUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.scrape_trackers();
+    }
+}
+
 pub struct UnchokeUpdate;
 
 impl<T: cio::CIO> Job<T> for UnchokeUpdate {
@@ -26,6 +40,26 @@ impl<T: cio::CIO> Job<T> for UnchokeUpdate {
     }
 }
 
+pub struct PEXUpdate;
+
+impl<T: cio::CIO> Job<T> for PEXUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.update_pex();
+        }
+    }
+}
+
+pub struct WebSeedUpdate;
+
+impl<T: cio::CIO> Job<T> for WebSeedUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.update_webseeds();
+        }
+    }
+}
+
 pub struct SessionUpdate;
 
 impl<T: cio::CIO> Job<T> for SessionUpdate {
@@ -85,3 +119,75 @@ impl<T: cio::CIO> Job<T> for TorrentStatusUpdate {
         self.transferred.retain(|id, _| torrents.contains_key(id));
     }
 }
+
+/// Accounts for seeding time and applies the seeding
+/// goal action to torrents which reached a goal.
+pub struct SeedUpdate {
+    last_updated: time::Instant,
+}
+
+impl SeedUpdate {
+    pub fn new() -> SeedUpdate {
+        SeedUpdate {
+            last_updated: time::Instant::now(),
+        }
+    }
+}
+
+impl<T: cio::CIO> CJob<T> for SeedUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        let elapsed = self.last_updated.elapsed().as_secs();
+        // Carry over partial seconds to the next update
+        self.last_updated += time::Duration::from_secs(elapsed);
+        let mut done = Vec::new();
+        for (id, torrent) in control.torrents.iter_mut() {
+            if torrent.update_seeding(elapsed) {
+                info!("{} reached its seeding goal", torrent);
+                done.push(*id);
+            }
+        }
+        for id in done {
+            match CONFIG.seed.action {
+                SeedAction::Pause => {
+                    if let Some(t) = control.torrents.get_mut(&id) {
+                        t.pause();
+                    }
+                }
+                SeedAction::Remove => control.remove_torrent(id, false),
+                SeedAction::RemoveArtifacts => control.remove_torrent(id, true),
+            }
+        }
+    }
+}
+
+/// Toggles the alternate throttle limits as the configured
+/// schedule periods begin and end. Manual changes are kept
+/// until the next such transition.
+pub struct ThrottleScheduleUpdate {
+    scheduled: Option<bool>,
+}
+
+impl ThrottleScheduleUpdate {
+    pub fn new() -> ThrottleScheduleUpdate {
+        ThrottleScheduleUpdate { scheduled: None }
+    }
+}
+
+impl<T: cio::CIO> CJob<T> for ThrottleScheduleUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        if CONFIG.throttle.schedule.is_empty() {
+            return;
+        }
+        let now = Local::now();
+        let (day, time) = (now.weekday(), now.time());
+        let scheduled = CONFIG
+            .throttle
+            .schedule
+            .iter()
+            .any(|p| p.contains(day, time));
+        if self.scheduled != Some(scheduled) {
+            self.scheduled = Some(scheduled);
+            control.set_alt_throttle(scheduled);
+        }
+    }
+}
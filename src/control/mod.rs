@@ -1,20 +1,24 @@
 use std::{fs, io, mem, process, time};
+use std::collections::HashMap;
 use std::io::Read;
-use std::sync::atomic;
+use std::sync::{atomic, Arc};
 use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
 
 use chrono::Utc;
 use {amy, bincode};
 
-use {disk, listener, rpc, stat, tracker, CONFIG, DL_TOKEN, SHUTDOWN};
-use util::{self, hash_to_id, id_to_hash, io_err, io_err_val, random_string, FHashSet, MHashMap,
-           UHashMap};
-use torrent::{self, peer, Torrent};
+use {disk, listener, portmap, rpc, stat, tracker, CONFIG, DL_TOKEN, SHUTDOWN};
+use util::{self, id_to_hash, io_err, io_err_val, random_string, MHashMap, UHashMap};
+use torrent::{self, peer, Info, Torrent};
 use throttle::Throttler;
+use ipfilter::IpFilter;
 
 pub mod cio;
 pub mod acio;
+mod ban;
 mod job;
+mod queue;
 
 /// Tracker update job interval
 const TRK_JOB_SECS: u64 = 60;
@@ -30,8 +34,20 @@ const TX_JOB_MS: u64 = 500;
 const TOKEN_JOB_SECS: u64 = 60 * 60 * 4;
 /// Interval to check space on disk
 const SPACE_JOB_SECS: u64 = 10;
-/// Interval to enqueue new torrents
-const ENQUEUE_JOB_SECS: u64 = 5;
+/// Interval to update which torrents are queued
+const QUEUE_JOB_SECS: u64 = 5;
+/// Interval to send PEX updates to peers
+const PEX_JOB_SECS: u64 = 60;
+/// Interval to start fetches from idle web seeds
+const WEBSEED_JOB_SECS: u64 = 5;
+/// Interval to check seeding goals
+const SEED_JOB_SECS: u64 = 30;
+/// Interval to check the alternate throttle schedule
+const SCHED_JOB_SECS: u64 = 30;
+/// Interval to update RPC of DHT stats
+const DHT_JOB_SECS: u64 = 10;
+/// Interval to lift expired bans
+const BAN_JOB_SECS: u64 = 60;
 
 /// Interval to requery all jobs and execute if needed
 const JOB_INT_MS: usize = 500;
@@ -44,9 +60,11 @@ pub struct Control<T: cio::CIO> {
     stat: stat::EMA,
     jobs: JobManager<T>,
     torrents: UHashMap<Torrent<T>>,
-    queue: Queue,
+    queue: queue::Queue,
     peers: UHashMap<usize>,
     hash_idx: MHashMap<[u8; 20], usize>,
+    bans: ban::BanList,
+    ip_filter: Arc<IpFilter>,
     data: ServerData,
     db: amy::Sender<disk::Request>,
 }
@@ -64,11 +82,13 @@ struct ServerData {
     free_space: u64,
     throttle_ul: Option<i64>,
     throttle_dl: Option<i64>,
-}
-
-struct Queue {
-    active_dl: FHashSet<usize>,
-    inactive_dl: [FHashSet<usize>; 6],
+    #[serde(skip)]
+    external_ip: Option<String>,
+    #[serde(skip)]
+    port_mappings: Vec<rpc::resource::PortMapping>,
+    /// Whether the alternate throttle limits are in effect
+    #[serde(skip)]
+    alt_throttle: bool,
 }
 
 pub trait CJob<T: cio::CIO> {
@@ -111,10 +131,31 @@ impl<T: cio::CIO> Control<T> {
             job::TorrentTxUpdate::new(),
             time::Duration::from_millis(TX_JOB_MS),
         );
+        jobs.add_job(job::PEXUpdate, time::Duration::from_secs(PEX_JOB_SECS));
+        jobs.add_job(
+            job::WebSeedUpdate,
+            time::Duration::from_secs(WEBSEED_JOB_SECS),
+        );
 
         jobs.add_cjob(TokenUpdate, time::Duration::from_secs(TOKEN_JOB_SECS));
         jobs.add_cjob(SpaceUpdate, time::Duration::from_secs(SPACE_JOB_SECS));
-        jobs.add_cjob(EnqueueUpdate, time::Duration::from_secs(ENQUEUE_JOB_SECS));
+        jobs.add_cjob(QueueUpdate, time::Duration::from_secs(QUEUE_JOB_SECS));
+        jobs.add_cjob(DhtUpdate, time::Duration::from_secs(DHT_JOB_SECS));
+        jobs.add_cjob(BanUpdate, time::Duration::from_secs(BAN_JOB_SECS));
+        jobs.add_cjob(
+            job::SeedUpdate::new(),
+            time::Duration::from_secs(SEED_JOB_SECS),
+        );
+        jobs.add_cjob(
+            job::ThrottleScheduleUpdate::new(),
+            time::Duration::from_secs(SCHED_JOB_SECS),
+        );
+        if CONFIG.trk.scrape_interval > 0 {
+            jobs.add_cjob(
+                job::ScrapeUpdate,
+                time::Duration::from_secs(CONFIG.trk.scrape_interval),
+            );
+        }
         let job_timer = cio.set_timer(JOB_INT_MS)
             .map_err(|_| io_err_val("timer failure!"))?;
         Ok(Control {
@@ -126,10 +167,12 @@ impl<T: cio::CIO> Control<T> {
             torrents,
             peers,
             hash_idx,
+            bans: ban::BanList::new(),
+            ip_filter: Arc::new(IpFilter::new()),
             stat: stat::EMA::new(),
             data: Default::default(),
             db,
-            queue: Queue::new(),
+            queue: queue::Queue::new(queue::Limits::from_config()),
         })
     }
 
@@ -139,6 +182,10 @@ impl<T: cio::CIO> Control<T> {
         }
         debug!("Initialized!");
         self.send_rpc_info();
+        if !CONFIG.peer.ip_filter.is_empty() {
+            self.cio.msg_disk(disk::Request::load_ip_filter());
+        }
+        self.update_queue();
         let mut events = Vec::with_capacity(20);
         loop {
             self.cio.poll(&mut events);
@@ -183,8 +230,7 @@ impl<T: cio::CIO> Control<T> {
             fs::File::open(pb).map(|mut f| bincode::deserialize_from(&mut f, bincode::Infinite))
         {
             self.data = data;
-            self.throttler.set_ul_rate(self.data.throttle_ul);
-            self.throttler.set_dl_rate(self.data.throttle_dl);
+            self.apply_throttle();
         } else {
             error!("No server data found, regenerating!");
             self.data = ServerData::new();
@@ -200,6 +246,9 @@ impl<T: cio::CIO> Control<T> {
                 process::exit(1);
             }
         }
+        let torrents = &self.torrents;
+        self.queue
+            .sort_by_key(|id| torrents.get(id).map(|t| t.queue_position()));
         Ok(())
     }
 
@@ -221,8 +270,10 @@ impl<T: cio::CIO> Control<T> {
         if let Some(t) = Torrent::deserialize(tid, &data, throttle, self.cio.new_handle()) {
             trace!("Succesfully parsed torrent file {:?}", dir.path());
             self.hash_idx.insert(t.info().hash, tid);
+            self.cio
+                .msg_listener(listener::Request::AddTorrent(t.info().hash));
             self.tid_cnt += 1;
-            self.queue.add(tid, t.priority());
+            self.queue.add(tid);
             self.torrents.insert(tid, t);
         } else {
             error!("Failed to deserialize torrent {:?}", dir.file_name());
@@ -262,6 +313,13 @@ impl<T: cio::CIO> Control<T> {
                 error!("listener error: {:?}", e);
                 trace!("listener error: {:?}", e.backtrace());
             }
+            cio::Event::PortMap(Ok(e)) => {
+                self.handle_pm_ev(e);
+            }
+            cio::Event::PortMap(Err(e)) => {
+                error!("port mapping error: {:?}", e);
+                trace!("port mapping error: {:?}", e.backtrace());
+            }
             cio::Event::Timer(t) => {
                 if t == self.throttler.id() {
                     let (ul, dl) = self.throttler.update();
@@ -302,10 +360,79 @@ impl<T: cio::CIO> Control<T> {
                     return;
                 }
             }
-            tracker::Response::DHT { tid, peers } => (tid, peers),
+            tracker::Response::DHT { tid, peers } | tracker::Response::LSD { tid, peers } => {
+                (tid, peers)
+            }
+            tracker::Response::WebSeed { tid, id, resp } => {
+                if let Some(torrent) = self.torrents.get_mut(&tid) {
+                    torrent.webseed_response(id, resp);
+                }
+                return;
+            }
+            tracker::Response::Scrape { url, resp } => {
+                match resp {
+                    Ok(stats) => for (tid, s) in stats {
+                        if let Some(torrent) = self.torrents.get_mut(&tid) {
+                            torrent.set_scrape_response(&url, s);
+                        }
+                    },
+                    Err(e) => debug!("Failed to scrape tracker {}: {}", url, e),
+                }
+                return;
+            }
+            tracker::Response::DHTGet {
+                client,
+                serial,
+                item,
+            } => {
+                self.cio.msg_rpc(rpc::CtlMessage::DhtItem {
+                    client,
+                    serial,
+                    item,
+                });
+                return;
+            }
+            tracker::Response::DHTPut {
+                client,
+                serial,
+                target,
+                nodes,
+            } => {
+                self.cio.msg_rpc(rpc::CtlMessage::DhtStored {
+                    client,
+                    serial,
+                    target,
+                    nodes,
+                });
+                return;
+            }
+            tracker::Response::DHTStats(stats) => {
+                self.update_rpc_dht(stats);
+                return;
+            }
+            tracker::Response::DHTTable {
+                client,
+                serial,
+                nodes,
+            } => {
+                self.cio.msg_rpc(rpc::CtlMessage::DhtTable {
+                    client,
+                    serial,
+                    nodes,
+                });
+                return;
+            }
         };
+        self.add_peers(id, &peers);
+    }
+
+    /// Connects to the given peers for the torrent with id
+    fn add_peers(&mut self, id: usize, peers: &[SocketAddr]) {
         trace!("Adding peers!");
-        for ip in &peers {
+        for ip in peers {
+            if !self.allowed(&ip.ip()) {
+                continue;
+            }
             trace!("Adding peer({:?})!", ip);
             if let Ok(peer) = peer::PeerConn::new_outgoing(ip) {
                 trace!("Added peer({:?})!", ip);
@@ -331,8 +458,99 @@ impl<T: cio::CIO> Control<T> {
                 self.data.free_space = space;
                 self.update_rpc_space();
             }
-        } else if let Some(torrent) = self.torrents.get_mut(&resp.tid()) {
-            torrent.handle_disk_resp(resp);
+        } else if let disk::Response::IpFilter(filter) = resp {
+            self.set_ip_filter(filter);
+        } else if let disk::Response::Created {
+            context,
+            path,
+            info,
+        } = resp
+        {
+            self.torrent_created(context, path, info);
+        } else if let disk::Response::StreamEnded(ctx) = resp {
+            let hash_idx = &self.hash_idx;
+            let torrents = &mut self.torrents;
+            let res = id_to_hash(&ctx.torrent_id)
+                .and_then(|d| hash_idx.get(d.as_ref()))
+                .and_then(|i| torrents.get_mut(i));
+            if let Some(t) = res {
+                t.end_stream(&ctx.id, &ctx.ranges);
+            }
+        } else {
+            let bans = match self.torrents.get_mut(&resp.tid()) {
+                Some(torrent) => {
+                    torrent.handle_disk_resp(resp);
+                    torrent.take_bans()
+                }
+                None => return,
+            };
+            let duration = CONFIG.peer.ban_duration;
+            for ip in bans {
+                self.ban_ip(ip, rpc::resource::BanReason::Corrupt, Some(duration));
+            }
+        }
+    }
+
+    /// Bans an IP, disconnecting its peers, and returns the ban's RPC id
+    fn ban_ip(
+        &mut self,
+        ip: IpAddr,
+        reason: rpc::resource::BanReason,
+        duration: Option<u64>,
+    ) -> String {
+        let id = util::ban_rpc_id(&ip);
+        if self.bans.ban(ip, reason, duration) {
+            if let Some(r) = self.bans.rpc_info(&ip) {
+                self.cio.msg_rpc(rpc::CtlMessage::Extant(vec![r]));
+            }
+        } else {
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                rpc::resource::SResourceUpdate::BanExpires {
+                    id: id.clone(),
+                    kind: rpc::resource::ResourceKind::Ban,
+                    expires: self.bans.expires(&ip),
+                },
+            ]));
+        }
+        for torrent in self.torrents.values_mut() {
+            torrent.disconnect_ips(|a| *a == ip);
+        }
+        id
+    }
+
+    /// Replaces the IP filter, disconnecting any peers it blocks
+    fn set_ip_filter(&mut self, filter: IpFilter) {
+        info!("Using IP filter of {} ranges", filter.ranges());
+        let filter = Arc::new(filter);
+        for torrent in self.torrents.values_mut() {
+            torrent.disconnect_ips(|a| filter.blocked(a));
+        }
+        self.cio.msg_listener(listener::Request::IpFilter(filter.clone()));
+        self.cio.msg_trk(tracker::Request::IpFilter(filter.clone()));
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerIpFilter {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                ip_filter_ranges: filter.ranges() as u64,
+            },
+        ]));
+        self.ip_filter = filter;
+    }
+
+    /// Whether peers may connect to or from the IP, it being
+    /// neither banned nor filtered
+    fn allowed(&self, ip: &IpAddr) -> bool {
+        !self.bans.banned(ip) && !self.ip_filter.blocked(ip)
+    }
+
+    fn expire_bans(&mut self) {
+        let ids: Vec<_> = self.bans
+            .expire()
+            .iter()
+            .map(util::ban_rpc_id)
+            .collect();
+        if !ids.is_empty() {
+            self.cio.msg_rpc(rpc::CtlMessage::Removed(ids));
         }
     }
 
@@ -352,15 +570,31 @@ impl<T: cio::CIO> Control<T> {
         let p = &mut self.peers;
         let t = &mut self.torrents;
 
-        p.get(&peer)
+        let res = p.get(&peer)
             .cloned()
-            .and_then(|id| t.get_mut(&id))
-            .map(|torrent| {
-                if torrent.peer_ev(peer, ev).is_err() {
-                    p.remove(&peer);
-                    torrent.update_rpc_peers();
-                }
+            .and_then(|id| t.get_mut(&id).map(|torrent| (id, torrent)))
+            .map(|(id, torrent)| {
+                let retry = match torrent.peer_ev(peer, ev) {
+                    Ok(()) => None,
+                    Err(conn) => {
+                        p.remove(&peer);
+                        torrent.update_rpc_peers();
+                        conn
+                    }
+                };
+                (id, retry, torrent.take_pex_peers())
             });
+
+        if let Some((tid, retry, pex)) = res {
+            if let Some(conn) = retry {
+                debug!("Retrying peer {:?}", conn.sock().addr());
+                self.add_peer(tid, conn);
+            }
+            if !pex.is_empty() {
+                debug!("Adding {} peers from PEX", pex.len());
+                self.add_peers(tid, &pex);
+            }
+        }
     }
 
     fn flush_blocked_peers(&mut self) {
@@ -369,11 +603,13 @@ impl<T: cio::CIO> Control<T> {
         self.cio.flush_peers(self.throttler.flush_ul());
     }
 
+    /// Adds a torrent, which seeds immediately if it's complete
     fn add_torrent(
         &mut self,
         info: torrent::Info,
         path: Option<String>,
         start: bool,
+        complete: bool,
         client: usize,
         serial: u64,
     ) {
@@ -382,32 +618,78 @@ impl<T: cio::CIO> Control<T> {
             info!("Tried to add torrent that already exists!");
             return;
         }
-        let id = hash_to_id(&info.hash);
+        let id = info.id();
         let tid = self.tid_cnt;
         let throttle = self.throttler.get_throttle(tid);
-        let t = Torrent::new(tid, path, info, throttle, self.cio.new_handle(), start);
+        let cio = self.cio.new_handle();
+        let t = if complete {
+            Torrent::new_complete(tid, path, info, throttle, cio, start)
+        } else {
+            Torrent::new(tid, path, info, throttle, cio, start)
+        };
         self.hash_idx.insert(t.info().hash, tid);
+        self.cio
+            .msg_listener(listener::Request::AddTorrent(t.info().hash));
         self.tid_cnt += 1;
-        self.queue.add(tid, t.priority());
+        self.queue.add(tid);
         self.torrents.insert(tid, t);
+        self.update_queue();
         self.cio
             .msg_rpc(rpc::CtlMessage::Uploaded { id, client, serial })
     }
 
+    fn torrent_created(&mut self, ctx: disk::CreateCtx, path: PathBuf, info: io::Result<Info>) {
+        let (client, serial) = (ctx.client, ctx.serial);
+        match info {
+            Ok(info) => {
+                info!("Created torrent {} from {:?}", info.name, path);
+                if self.hash_idx.contains_key(&info.hash) {
+                    let id = info.id();
+                    self.cio
+                        .msg_rpc(rpc::CtlMessage::Uploaded { id, client, serial });
+                    return;
+                }
+                // Torrents are downloaded to the directory containing their files
+                let dir = path.parent().and_then(|p| p.to_str()).map(String::from);
+                self.add_torrent(info, dir, ctx.start, true, client, serial);
+            }
+            Err(e) => {
+                error!("Failed to create torrent from {:?}: {}", path, e);
+                self.cio.msg_rpc(rpc::CtlMessage::Failed {
+                    client,
+                    serial,
+                    reason: format!("Failed to create torrent: {}", e),
+                });
+            }
+        }
+    }
+
+    fn remove_torrent(&mut self, tid: usize, artifacts: bool) {
+        if let Some(mut t) = self.torrents.remove(&tid) {
+            self.hash_idx.remove(&t.info().hash);
+            self.cio
+                .msg_listener(listener::Request::RemoveTorrent(t.info().hash));
+            self.queue.remove(tid);
+            t.delete(artifacts);
+        }
+        self.update_queue();
+    }
+
     fn handle_rpc_ev(&mut self, req: rpc::Message) -> bool {
         debug!("Handling rpc reqest!");
         match req {
             rpc::Message::UpdateTorrent(u) => {
-                let hash_idx = &self.hash_idx;
-                let torrents = &mut self.torrents;
                 let res = id_to_hash(&u.id)
-                    .and_then(|d| hash_idx.get(d.as_ref()))
-                    .and_then(|i| torrents.get_mut(i));
-                if let Some(t) = res {
-                    let old_pri = t.priority();
-                    t.rpc_update(u);
-                    let new_pri = t.priority();
-                    self.queue.modify_pri(t.id(), new_pri, old_pri);
+                    .and_then(|d| self.hash_idx.get(d.as_ref()))
+                    .cloned();
+                if let Some(tid) = res {
+                    if let Some(pos) = u.queue_position {
+                        self.queue.set_position(tid, pos as usize);
+                    }
+                    if let Some(t) = self.torrents.get_mut(&tid) {
+                        t.rpc_update(u);
+                    }
+                    self.update_queue();
                 }
             }
             rpc::Message::Torrent {
@@ -416,7 +698,22 @@ impl<T: cio::CIO> Control<T> {
                 start,
                 client,
                 serial,
-            } => self.add_torrent(info, path, start, client, serial),
+            } => self.add_torrent(info, path, start, false, client, serial),
+            rpc::Message::CreateTorrent {
+                info,
+                path,
+                client,
+                serial,
+                start,
+            } => {
+                let context = disk::CreateCtx {
+                    client,
+                    serial,
+                    start,
+                };
+                self.cio
+                    .msg_disk(disk::Request::create(context, path, info));
+            }
             rpc::Message::UpdateFile {
                 id,
                 torrent_id,
@@ -431,6 +728,20 @@ impl<T: cio::CIO> Control<T> {
                     t.rpc_update_file(id, priority);
                 }
             }
+            rpc::Message::Stream {
+                id,
+                torrent_id,
+                ranges,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let res = id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i));
+                if let Some(t) = res {
+                    t.stream(&id, &ranges);
+                }
+            }
             rpc::Message::AddPeer {
                 id,
                 client,
@@ -467,21 +778,26 @@ impl<T: cio::CIO> Control<T> {
                 id,
                 throttle_up,
                 throttle_down,
+                alt_throttle,
             } => {
-                let tu = throttle_up.unwrap_or(self.throttler.ul_rate());
-                let td = throttle_down.unwrap_or(self.throttler.dl_rate());
-                self.throttler.set_ul_rate(tu);
-                self.throttler.set_dl_rate(td);
-                self.data.throttle_ul = tu;
-                self.data.throttle_dl = td;
-                self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-                    rpc::resource::SResourceUpdate::Throttle {
-                        id,
-                        kind: rpc::resource::ResourceKind::Server,
-                        throttle_up: tu,
-                        throttle_down: td,
-                    },
-                ]));
+                if throttle_up.is_some() || throttle_down.is_some() {
+                    let tu = throttle_up.unwrap_or(self.data.throttle_ul);
+                    let td = throttle_down.unwrap_or(self.data.throttle_dl);
+                    self.data.throttle_ul = tu;
+                    self.data.throttle_dl = td;
+                    self.apply_throttle();
+                    self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                        rpc::resource::SResourceUpdate::Throttle {
+                            id,
+                            kind: rpc::resource::ResourceKind::Server,
+                            throttle_up: tu,
+                            throttle_down: td,
+                        },
+                    ]));
+                }
+                if let Some(alt) = alt_throttle {
+                    self.set_alt_throttle(alt);
+                }
             }
             rpc::Message::RemoveTorrent {
                 id,
@@ -489,12 +805,12 @@ impl<T: cio::CIO> Control<T> {
                 serial,
                 artifacts,
             } => {
-                let hash_idx = &mut self.hash_idx;
-                let torrents = &mut self.torrents;
-                id_to_hash(&id)
-                    .and_then(|d| hash_idx.remove(d.as_ref()))
-                    .and_then(|i| torrents.remove(&i))
-                    .map(|mut t| t.delete(artifacts));
+                let res = id_to_hash(&id)
+                    .and_then(|d| self.hash_idx.get(d.as_ref()))
+                    .cloned();
+                if let Some(tid) = res {
+                    self.remove_torrent(tid, artifacts);
+                }
                 self.cio
                     .msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial });
             }
@@ -505,6 +821,7 @@ impl<T: cio::CIO> Control<T> {
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i))
                     .map(|t| t.pause());
+                self.update_queue();
             }
             rpc::Message::Resume(id) => {
                 let hash_idx = &mut self.hash_idx;
@@ -513,6 +830,7 @@ impl<T: cio::CIO> Control<T> {
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i))
                     .map(|t| t.resume());
+                self.update_queue();
             }
             rpc::Message::Validate(ids) => {
                 let hash_idx = &mut self.hash_idx;
@@ -562,12 +880,78 @@ impl<T: cio::CIO> Control<T> {
                     .and_then(|i| torrents.get_mut(i))
                     .map(|t| t.update_tracker_req(&id));
             }
+            rpc::Message::ScrapeTracker { id, torrent_id } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .map(|t| t.scrape_tracker_req(&id));
+            }
+            rpc::Message::DhtGet {
+                client,
+                serial,
+                key,
+            } => {
+                self.cio.msg_trk(tracker::Request::DHTGet {
+                    client,
+                    serial,
+                    key,
+                });
+            }
+            rpc::Message::DhtPut {
+                client,
+                serial,
+                item,
+                cas,
+            } => {
+                self.cio.msg_trk(tracker::Request::DHTPut {
+                    client,
+                    serial,
+                    item,
+                    cas,
+                });
+            }
+            rpc::Message::DhtBootstrap(addr) => {
+                self.cio.msg_trk(tracker::Request::AddNode(addr));
+            }
+            rpc::Message::DhtTable { client, serial } => {
+                self.cio.msg_trk(tracker::Request::DHTTable { client, serial });
+            }
+            rpc::Message::AddBan {
+                ip,
+                duration,
+                client,
+                serial,
+            } => {
+                let id = self.ban_ip(ip, rpc::resource::BanReason::Manual, duration);
+                self.cio
+                    .msg_rpc(rpc::CtlMessage::Uploaded { id, client, serial });
+            }
+            rpc::Message::RemoveBan {
+                id,
+                ip,
+                client,
+                serial,
+            } => {
+                if self.bans.unban(&ip) {
+                    self.cio.msg_rpc(rpc::CtlMessage::Removed(vec![id.clone()]));
+                }
+                self.cio
+                    .msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial });
+            }
+            rpc::Message::ReloadIpFilter => {
+                self.cio.msg_disk(disk::Request::load_ip_filter());
+            }
         }
         false
     }
 
     fn add_peer_rpc(&mut self, id: usize, peer: peer::PeerConn) -> Option<String> {
         trace!("Adding peer to torrent {:?}!", id);
+        if !self.allowed(&peer.sock().addr().ip()) {
+            return None;
+        }
         if let Some(torrent) = self.torrents.get_mut(&id) {
             if let Some(pid) = torrent.add_peer(peer) {
                 self.peers.insert(pid, id);
@@ -579,9 +963,11 @@ impl<T: cio::CIO> Control<T> {
 
     fn add_peer(&mut self, id: usize, peer: peer::PeerConn) {
         trace!("Adding peer to torrent {:?}!", id);
+        if !self.allowed(&peer.sock().addr().ip()) {
+            return;
+        }
         if let Some(torrent) = self.torrents.get_mut(&id) {
-            if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
-                self.queue.add(id, torrent.priority());
+            if torrent.status().queued {
                 return;
             }
             if let Some(pid) = torrent.add_peer(peer) {
@@ -592,9 +978,12 @@ impl<T: cio::CIO> Control<T> {
 
     fn add_inc_peer(&mut self, id: usize, peer: peer::PeerConn, cid: [u8; 20], rsv: [u8; 8]) {
         trace!("Adding peer to torrent {:?}!", id);
+        if !self.allowed(&peer.sock().addr().ip()) {
+            debug!("Rejecting banned or filtered peer {}", peer.sock().addr());
+            return;
+        }
         if let Some(torrent) = self.torrents.get_mut(&id) {
-            if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
-                self.queue.add(id, torrent.priority());
+            if torrent.status().queued {
                 return;
             }
             if let Some(pid) = torrent.add_inc_peer(peer, cid, rsv) {
@@ -603,6 +992,83 @@ impl<T: cio::CIO> Control<T> {
         }
     }
 
+    /// Starts and stops torrents according to the queue
+    fn update_queue(&mut self) {
+        let torrents = &mut self.torrents;
+        let changes = self.queue.update(|id| torrents.get(&id).map(queue_entry));
+        for (id, active) in changes {
+            if let Some(t) = torrents.get_mut(&id) {
+                t.set_queued(!active);
+            }
+        }
+        for (pos, id) in self.queue.ids().iter().enumerate() {
+            if let Some(t) = torrents.get_mut(id) {
+                t.set_queue_position(pos as u32);
+            }
+        }
+    }
+
+    /// Applies either the regular or alternate limits to the throttler
+    fn apply_throttle(&mut self) {
+        let (ul, dl) = if self.data.alt_throttle {
+            (CONFIG.throttle.alt_up, CONFIG.throttle.alt_down)
+        } else {
+            (self.data.throttle_ul, self.data.throttle_dl)
+        };
+        self.throttler.set_ul_rate(ul);
+        self.throttler.set_dl_rate(dl);
+    }
+
+    /// Scrapes every tracker, batching the torrents which share one.
+    fn scrape_trackers(&mut self) {
+        let mut batches = HashMap::new();
+        for (id, torrent) in &self.torrents {
+            for trk in torrent.trackers() {
+                batches
+                    .entry(trk.url.as_ref().clone())
+                    .or_insert_with(Vec::new)
+                    .push((*id, torrent.info().hash));
+            }
+        }
+        for (url, torrents) in batches {
+            for req in tracker::Request::scrape(url, torrents) {
+                self.cio.msg_trk(req);
+            }
+        }
+    }
+
+    fn set_alt_throttle(&mut self, enabled: bool) {
+        if self.data.alt_throttle == enabled {
+            return;
+        }
+        info!(
+            "{} alternate throttle limits",
+            if enabled { "Enabling" } else { "Disabling" }
+        );
+        self.data.alt_throttle = enabled;
+        self.apply_throttle();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerAltThrottle {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                alt_throttle: enabled,
+            },
+        ]));
+    }
+
+    fn handle_pm_ev(&mut self, resp: portmap::Response) {
+        self.data.external_ip = resp.external_ip.map(|ip| ip.to_string());
+        self.data.port_mappings = resp.mappings;
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerPorts {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                external_ip: self.data.external_ip.clone(),
+                port_mappings: self.data.port_mappings.clone(),
+            },
+        ]));
+    }
+
     fn update_rpc_space(&mut self) {
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             rpc::resource::SResourceUpdate::ServerSpace {
@@ -613,6 +1079,32 @@ impl<T: cio::CIO> Control<T> {
         ]));
     }
 
+    fn update_rpc_dht(&mut self, stats: tracker::DHTStats) {
+        let stored_peers = stats
+            .peers
+            .iter()
+            .map(|(hash, &peers)| rpc::resource::StoredPeers {
+                info_hash: util::hash_to_id(hash),
+                peers: peers as u32,
+            })
+            .collect();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::DhtStats {
+                id: util::dht_rpc_id(&self.data.id),
+                kind: rpc::resource::ResourceKind::Dht,
+                buckets: stats.buckets.iter().map(|&n| n as u32).collect(),
+                buckets6: stats.buckets6.iter().map(|&n| n as u32).collect(),
+                good_nodes: stats.good as u32,
+                questionable_nodes: stats.questionable as u32,
+                bad_nodes: stats.bad as u32,
+                transactions: stats.transactions as u32,
+                stored_peers,
+                transferred_up: stats.bytes_out,
+                transferred_down: stats.bytes_in,
+            },
+        ]));
+    }
+
     fn update_rpc_tx(&mut self) {
         self.stat.tick();
         if self.stat.active() {
@@ -637,8 +1129,8 @@ impl<T: cio::CIO> Control<T> {
             id: self.data.id.clone(),
             rate_up: 0,
             rate_down: 0,
-            throttle_up: self.throttler.ul_rate(),
-            throttle_down: self.throttler.dl_rate(),
+            throttle_up: self.data.throttle_ul,
+            throttle_down: self.data.throttle_dl,
             transferred_up: self.data.ul,
             transferred_down: self.data.dl,
             ses_transferred_up: self.data.session_ul,
@@ -646,9 +1138,20 @@ impl<T: cio::CIO> Control<T> {
             free_space: self.data.free_space,
             started: Utc::now(),
             download_token: DL_TOKEN.lock().unwrap().clone(),
+            external_ip: self.data.external_ip.clone(),
+            port_mappings: self.data.port_mappings.clone(),
+            alt_throttle: self.data.alt_throttle,
+            ip_filter_ranges: self.ip_filter.ranges() as u64,
+            alt_throttle_up: CONFIG.throttle.alt_up,
+            alt_throttle_down: CONFIG.throttle.alt_down,
+            throttle_schedule: CONFIG.throttle.schedule.clone(),
             ..Default::default()
         });
-        self.cio.msg_rpc(rpc::CtlMessage::Extant(vec![res]));
+        let dht = rpc::resource::Resource::Dht(rpc::resource::Dht {
+            id: util::dht_rpc_id(&self.data.id),
+            ..Default::default()
+        });
+        self.cio.msg_rpc(rpc::CtlMessage::Extant(vec![res, dht]));
     }
 }
 
@@ -659,6 +1162,7 @@ impl<T: cio::CIO> Drop for Control<T> {
         self.cio.msg_rpc(rpc::CtlMessage::Shutdown);
         self.cio.msg_trk(tracker::Request::Shutdown);
         self.cio.msg_listener(listener::Request::Shutdown);
+        self.cio.msg_portmap(portmap::Request::Shutdown);
         self.cio.msg_disk(disk::Request::shutdown());
     }
 }
@@ -674,57 +1178,9 @@ impl ServerData {
             free_space: 0,
             throttle_ul: Some(-1),
             throttle_dl: Some(-1),
-        }
-    }
-}
-
-impl Queue {
-    fn new() -> Queue {
-        let inactive_dl = [
-            FHashSet::default(),
-            FHashSet::default(),
-            FHashSet::default(),
-            FHashSet::default(),
-            FHashSet::default(),
-            FHashSet::default(),
-        ];
-        Queue {
-            active_dl: FHashSet::default(),
-            inactive_dl,
-        }
-    }
-
-    fn dl_full(&self) -> bool {
-        self.active_dl.len() == CONFIG.max_dl as usize
-    }
-
-    fn modify_pri(&mut self, id: usize, pri: u8, old_pri: u8) {
-        let pri = pri as usize;
-        let old_pri = old_pri as usize;
-        self.inactive_dl[old_pri].remove(&id);
-        self.inactive_dl[pri].insert(id);
-    }
-
-    fn add(&mut self, id: usize, pri: u8) {
-        let pri = pri as usize;
-        if self.dl_full() {
-            self.inactive_dl[pri].insert(id);
-        } else {
-            self.active_dl.insert(id);
-        }
-    }
-
-    fn enqueue<F: FnMut(usize)>(&mut self, mut f: F) {
-        while !self.dl_full() && self.inactive_dl.iter().any(|q| !q.is_empty()) {
-            for i in (0..self.inactive_dl.len()).rev() {
-                if !self.inactive_dl[i].is_empty() {
-                    let next = { *self.inactive_dl[i].iter().next().unwrap() };
-                    self.inactive_dl[i].remove(&next);
-                    self.active_dl.insert(next);
-                    f(next);
-                    break;
-                }
-            }
+            external_ip: None,
+            port_mappings: Vec::new(),
+            alt_throttle: false,
         }
     }
 }
@@ -794,20 +1250,43 @@ impl<T: cio::CIO> CJob<T> for SpaceUpdate {
     }
 }
 
-pub struct EnqueueUpdate;
+pub struct DhtUpdate;
 
-impl<T: cio::CIO> CJob<T> for EnqueueUpdate {
+impl<T: cio::CIO> CJob<T> for DhtUpdate {
     fn update(&mut self, control: &mut Control<T>) {
-        let queue = &mut control.queue;
-        let torrents = &mut control.torrents;
+        control.cio.msg_trk(tracker::Request::DHTStats);
+    }
+}
 
-        queue.active_dl.retain(|tid| match torrents.get(tid) {
-            Some(t) => !t.status().completed(),
-            None => false,
-        });
-        for q in &mut queue.inactive_dl {
-            q.retain(|tid| torrents.contains_key(tid));
-        }
-        queue.enqueue(|tid| torrents.get_mut(&tid).unwrap().update_tracker());
+pub struct BanUpdate;
+
+impl<T: cio::CIO> CJob<T> for BanUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.expire_bans();
+    }
+}
+
+pub struct QueueUpdate;
+
+impl<T: cio::CIO> CJob<T> for QueueUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.update_queue();
+    }
+}
+
+fn queue_entry<T: cio::CIO>(t: &Torrent<T>) -> queue::Entry {
+    let status = t.status();
+    let (ul, dl) = t.get_last_tx_rate();
+    let (kind, rate) = if status.paused || status.error.is_some() {
+        (queue::Kind::Stopped, 0)
+    } else if status.completed() {
+        (queue::Kind::Seed, ul)
+    } else {
+        (queue::Kind::Download, dl)
+    };
+    queue::Entry {
+        priority: t.priority(),
+        kind,
+        rate,
     }
 }
@@ -0,0 +1,247 @@
+use std::cmp;
+use std::time;
+
+use util::UHashMap;
+use CONFIG;
+
+/// Time a torrent must be active before it can be considered stalled
+const STALL_SECS: u64 = 60;
+
+/// Decides which torrents are active, based on their queue position,
+/// priority and the configured limits. Inactive torrents are queued,
+/// neither announcing nor connecting to peers.
+pub struct Queue {
+    limits: Limits,
+    /// Torrent ids in queue order
+    order: Vec<usize>,
+    /// Active torrents and the time they were activated
+    active: UHashMap<time::Instant>,
+}
+
+pub struct Limits {
+    pub downloads: Option<u32>,
+    pub seeds: Option<u32>,
+    pub total: Option<u32>,
+    /// Active torrents slower than this(bytes/sec) don't count
+    /// towards the limits, 0 disables this
+    pub stalled_rate: u64,
+}
+
+/// State of a torrent which is relevant to queueing
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    pub priority: u8,
+    pub kind: Kind,
+    /// Download rate for downloads, upload rate for seeds
+    pub rate: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// Paused or errored, these keep their current state
+    /// and never count towards the limits
+    Stopped,
+    Download,
+    Seed,
+}
+
+impl Queue {
+    pub fn new(limits: Limits) -> Queue {
+        Queue {
+            limits,
+            order: Vec::new(),
+            active: UHashMap::default(),
+        }
+    }
+
+    /// Adds a torrent to the back of the queue
+    pub fn add(&mut self, id: usize) {
+        if !self.order.contains(&id) {
+            self.order.push(id);
+        }
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        self.order.retain(|i| *i != id);
+        self.active.remove(&id);
+    }
+
+    /// Torrent ids in queue order
+    pub fn ids(&self) -> &[usize] {
+        &self.order
+    }
+
+    /// Moves a torrent to pos, with 0 being the front of the queue
+    pub fn set_position(&mut self, id: usize, pos: usize) {
+        if let Some(cur) = self.order.iter().position(|i| *i == id) {
+            self.order.remove(cur);
+            let pos = cmp::min(pos, self.order.len());
+            self.order.insert(pos, id);
+        }
+    }
+
+    /// Reorders the queue, used to restore positions on startup
+    pub fn sort_by_key<K: Ord, F: FnMut(&usize) -> K>(&mut self, f: F) {
+        self.order.sort_by_key(f);
+    }
+
+    /// Determines which torrents should be active, returning the
+    /// ids of those which changed state and whether they're now active.
+    /// Torrents for which f returns None are removed from the queue.
+    pub fn update<F: FnMut(usize) -> Option<Entry>>(&mut self, mut f: F) -> Vec<(usize, bool)> {
+        let now = time::Instant::now();
+        let mut entries = Vec::with_capacity(self.order.len());
+        for id in &self.order {
+            if let Some(e) = f(*id) {
+                entries.push((*id, e));
+            }
+        }
+        self.order = entries.iter().map(|&(id, _)| id).collect();
+        // Higher priorities are activated first, the sort being
+        // stable keeps queue order within a priority.
+        entries.sort_by_key(|&(_, e)| cmp::Reverse(e.priority));
+
+        let (mut dl, mut seed, mut total) = (0, 0, 0);
+        let mut changes = Vec::new();
+        for (id, e) in entries {
+            let was_active = self.active.contains_key(&id);
+            let active = match e.kind {
+                Kind::Stopped => was_active,
+                _ if was_active && self.stalled(id, e.rate, now) => true,
+                Kind::Download if below(dl, self.limits.downloads) => {
+                    below(total, self.limits.total) && {
+                        dl += 1;
+                        total += 1;
+                        true
+                    }
+                }
+                Kind::Seed if below(seed, self.limits.seeds) => {
+                    below(total, self.limits.total) && {
+                        seed += 1;
+                        total += 1;
+                        true
+                    }
+                }
+                _ => false,
+            };
+            if active && !was_active {
+                self.active.insert(id, now);
+                changes.push((id, true));
+            } else if !active && was_active {
+                self.active.remove(&id);
+                changes.push((id, false));
+            }
+        }
+        let order = &self.order;
+        self.active.retain(|id, _| order.contains(id));
+        changes
+    }
+
+    fn stalled(&self, id: usize, rate: u64, now: time::Instant) -> bool {
+        let min = time::Duration::from_secs(STALL_SECS);
+        let since = self.active.get(&id).map(|a| now.duration_since(*a));
+        self.limits.stalled_rate != 0 && rate < self.limits.stalled_rate
+            && since.map(|s| s >= min).unwrap_or(false)
+    }
+}
+
+impl Limits {
+    pub fn from_config() -> Limits {
+        Limits {
+            downloads: Some(CONFIG.max_dl),
+            seeds: CONFIG.max_active_seeds,
+            total: CONFIG.max_active_total,
+            stalled_rate: CONFIG.stalled_rate,
+        }
+    }
+}
+
+fn below(count: u32, limit: Option<u32>) -> bool {
+    limit.map(|l| count < l).unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use super::{Entry, Kind, Limits, Queue};
+
+    fn queue(downloads: u32, seeds: Option<u32>, total: Option<u32>) -> Queue {
+        Queue::new(Limits {
+            downloads: Some(downloads),
+            seeds,
+            total,
+            stalled_rate: 100,
+        })
+    }
+
+    fn entry(kind: Kind) -> Option<Entry> {
+        Some(Entry {
+            priority: 3,
+            kind,
+            rate: 1000,
+        })
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut q = queue(2, Some(1), Some(2));
+        for id in 0..5 {
+            q.add(id);
+        }
+        let kinds = [
+            Kind::Download,
+            Kind::Seed,
+            Kind::Seed,
+            Kind::Download,
+            Kind::Stopped,
+        ];
+        let changes = q.update(|id| entry(kinds[id]));
+        assert_eq!(changes, vec![(0, true), (1, true)]);
+
+        // Moving a download to the front displaces the seed
+        q.set_position(3, 0);
+        assert_eq!(q.ids(), &[3, 0, 1, 2, 4]);
+        let changes = q.update(|id| entry(kinds[id]));
+        assert_eq!(changes, vec![(3, true), (1, false)]);
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut q = queue(1, None, None);
+        q.add(0);
+        q.add(1);
+        let changes = q.update(|id| {
+            Some(Entry {
+                priority: id as u8 + 1,
+                kind: Kind::Download,
+                rate: 0,
+            })
+        });
+        assert_eq!(changes, vec![(1, true)]);
+        // Removed torrents free their slot
+        let changes = q.update(|id| if id == 0 { entry(Kind::Download) } else { None });
+        assert_eq!(changes, vec![(0, true)]);
+        assert_eq!(q.ids(), &[0]);
+    }
+
+    #[test]
+    fn test_stalled() {
+        let mut q = queue(1, None, None);
+        q.add(0);
+        q.add(1);
+        assert_eq!(q.update(|_| entry(Kind::Download)), vec![(0, true)]);
+        // Only torrents which have been active for a while can stall
+        let slow = |id: usize| {
+            Some(Entry {
+                priority: 3,
+                kind: Kind::Download,
+                rate: if id == 0 { 10 } else { 1000 },
+            })
+        };
+        assert!(q.update(&slow).is_empty());
+        let start = time::Instant::now() - time::Duration::from_secs(super::STALL_SECS);
+        q.active.insert(0, start);
+        assert_eq!(q.update(&slow), vec![(1, true)]);
+    }
+}
@@ -12,8 +12,9 @@ use nix::libc;
 
 use super::{FileCache, JOB_TIME_SLICE};
 use torrent::{Info, LocIter};
+use ipfilter::IpFilter;
 use socket::TSocket;
-use util::{awrite, hash_to_id, io_err, IOR};
+use util::{awrite, hash_to_id, io_err, sha1_hash, IOR};
 use CONFIG;
 
 static MP_BOUNDARY: &'static str = "qxyllcqgNchqyob";
@@ -70,11 +71,19 @@ pub enum Request {
         info: Arc<Info>,
         path: Option<String>,
         piece: u32,
+        /// Whether block hashes should be returned for a valid piece
+        hash_blocks: bool,
     },
     WriteFile {
         data: Vec<u8>,
         path: PathBuf,
     },
+    Create {
+        context: CreateCtx,
+        path: PathBuf,
+        info: Arc<Info>,
+        hashes: Vec<Vec<u8>>,
+    },
     Download {
         client: TSocket,
         path: String,
@@ -87,8 +96,11 @@ pub enum Request {
         buf_max: usize,
         buf: Box<[u8; 16_384]>,
         file_len: u64,
+        stream: Option<StreamCtx>,
     },
     FreeSpace,
+    /// Loads the IP filter lists in the config
+    LoadIpFilter,
     Shutdown,
 }
 
@@ -105,6 +117,9 @@ pub enum Response {
         tid: usize,
         piece: u32,
         valid: bool,
+        /// SHA1 hashes of each block of the piece, given if the piece
+        /// is invalid or they were requested
+        blocks: Vec<[u8; 20]>,
     },
     ValidationUpdate {
         tid: usize,
@@ -115,6 +130,14 @@ pub enum Response {
         path: String,
     },
     FreeSpace(u64),
+    IpFilter(IpFilter),
+    Created {
+        context: CreateCtx,
+        path: PathBuf,
+        info: io::Result<Info>,
+    },
+    /// The HTTP download of a streamed file ended
+    StreamEnded(StreamCtx),
     Error {
         tid: usize,
         err: io::Error,
@@ -129,6 +152,21 @@ pub struct Ctx {
     pub length: u32,
 }
 
+/// Context of a torrent creation, returned with the result
+pub struct CreateCtx {
+    pub client: usize,
+    pub serial: u64,
+    pub start: bool,
+}
+
+/// Context of a file streamed over HTTP, returned once its download ends
+#[derive(Clone)]
+pub struct StreamCtx {
+    pub torrent_id: String,
+    pub id: String,
+    pub ranges: Vec<(u64, u64)>,
+}
+
 pub enum JobRes {
     Resp(Response),
     Update(Request, Response),
@@ -180,17 +218,28 @@ impl Request {
         }
     }
 
+    pub fn create(context: CreateCtx, path: PathBuf, info: Info) -> Request {
+        Request::Create {
+            context,
+            path,
+            info: Arc::new(info),
+            hashes: Vec::new(),
+        }
+    }
+
     pub fn validate_piece(
         tid: usize,
         info: Arc<Info>,
         path: Option<String>,
         piece: u32,
+        hash_blocks: bool,
     ) -> Request {
         Request::ValidatePiece {
             tid,
             info,
             path,
             piece,
+            hash_blocks,
         }
     }
 
@@ -216,6 +265,7 @@ impl Request {
         mut ranges: Vec<HttpRange>,
         mut ranged: bool,
         len: u64,
+        stream: Option<StreamCtx>,
     ) -> Request {
         let lines = if ranged {
             if ranges.len() == 1 {
@@ -288,9 +338,14 @@ impl Request {
             buf_idx: 0,
             buf_max: data.len(),
             file_len: len,
+            stream,
         }
     }
 
+    pub fn load_ip_filter() -> Request {
+        Request::LoadIpFilter
+    }
+
     pub fn shutdown() -> Request {
         Request::Shutdown
     }
@@ -307,6 +362,10 @@ impl Request {
                     return io_err("couldn't stat fs");
                 }
             }
+            Request::LoadIpFilter => {
+                let filter = IpFilter::load(&CONFIG.peer.ip_filter);
+                return Ok(JobRes::Resp(Response::IpFilter(filter)));
+            }
             Request::WriteFile { path, data } => {
                 let mut p = path.clone();
                 p.set_extension("temp");
@@ -333,7 +392,7 @@ impl Request {
                 locations,
                 path,
                 ..
-            } => for loc in locations {
+            } => for loc in locations.filter(|loc| !loc.padding()) {
                 let mut pb = path::PathBuf::from(path.as_ref().unwrap_or(dd));
                 pb.push(loc.path());
                 fc.get_file_range(
@@ -358,6 +417,12 @@ impl Request {
                 ..
             } => {
                 for loc in locations {
+                    if loc.padding() {
+                        for b in &mut data[loc.start..loc.end] {
+                            *b = 0;
+                        }
+                        continue;
+                    }
                     let mut pb = path::PathBuf::from(path.as_ref().unwrap_or(dd));
                     pb.push(loc.path());
                     fc.get_file_range(&pb, None, loc.offset, loc.end - loc.start, true, |b| {
@@ -444,6 +509,7 @@ impl Request {
                 info,
                 path,
                 piece,
+                hash_blocks,
             } => {
                 // TODO: what to do if piece is REALLY big
                 let mut buf = vec![0u8; info.piece_len as usize];
@@ -452,10 +518,13 @@ impl Request {
 
                 let mut f = fs::OpenOptions::new().read(true).open(&pb);
 
-                let mut ctx = sha1::Sha1::new();
                 let locs = Info::piece_disk_locs(&info, piece);
                 let mut pos = 0;
                 for loc in locs {
+                    if loc.padding() {
+                        pos = zero_fill(&mut buf, pos, &loc);
+                        continue;
+                    }
                     if loc.path() != cf {
                         pb = path::PathBuf::from(path.as_ref().unwrap_or(dd));
                         pb.push(loc.path());
@@ -468,21 +537,27 @@ impl Request {
                         .map(|file| file.seek(SeekFrom::Start(loc.offset)))
                         .ok();
                     if let Ok(Ok(amnt)) = f.as_mut().map(|file| file.read(&mut buf[pos..])) {
-                        ctx.update(&buf[pos..pos + amnt]);
                         pos += amnt;
                     } else {
                         return Ok(JobRes::Resp(Response::PieceValidated {
                             tid,
                             piece,
                             valid: false,
+                            blocks: Vec::new(),
                         }));
                     }
                 }
-                let digest = ctx.digest();
+                let valid = info.piece_valid(piece, &buf[..pos]);
+                let blocks = if !valid || hash_blocks {
+                    buf[..pos].chunks(16_384).map(sha1_hash).collect()
+                } else {
+                    Vec::new()
+                };
                 return Ok(JobRes::Resp(Response::PieceValidated {
                     tid,
                     piece,
-                    valid: &digest.bytes() == &info.hashes[piece as usize][..],
+                    valid,
+                    blocks,
                 }));
             }
             Request::Validate {
@@ -504,10 +579,13 @@ impl Request {
                     && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
                 {
                     let mut valid = true;
-                    let mut ctx = sha1::Sha1::new();
                     let locs = Info::piece_disk_locs(&info, idx);
                     let mut pos = 0;
                     for loc in locs {
+                        if loc.padding() {
+                            pos = zero_fill(&mut buf, pos, &loc);
+                            continue;
+                        }
                         if loc.path() != cf {
                             pb = path::PathBuf::from(path.as_ref().unwrap_or(dd));
                             pb.push(loc.path());
@@ -520,14 +598,12 @@ impl Request {
                             .map(|file| file.seek(SeekFrom::Start(loc.offset)))
                             .ok();
                         if let Ok(Ok(amnt)) = f.as_mut().map(|file| file.read(&mut buf[pos..])) {
-                            ctx.update(&buf[pos..pos + amnt]);
                             pos += amnt;
                         } else {
                             valid = false;
                         }
                     }
-                    let digest = ctx.digest();
-                    if !valid || &digest.bytes() != &info.hashes[idx as usize][..] {
+                    if !valid || !info.piece_valid(idx, &buf[..pos]) {
                         invalid.push(idx);
                     }
 
@@ -552,6 +628,69 @@ impl Request {
                     ));
                 }
             }
+            Request::Create {
+                context,
+                path,
+                mut info,
+                mut hashes,
+            } => {
+                if info.files.is_empty() {
+                    let res = Arc::get_mut(&mut info).unwrap().add_files(&path);
+                    if let Err(e) = res {
+                        return Ok(JobRes::Resp(Response::created(context, path, Err(e))));
+                    }
+                    hashes.reserve(info.pieces() as usize);
+                }
+                let base = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+                let mut buf = vec![0u8; info.piece_len as usize];
+                let mut cf = PathBuf::new();
+                let mut f = None;
+
+                let start = time::Instant::now();
+
+                while (hashes.len() as u32) < info.pieces()
+                    && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
+                {
+                    let mut ctx = sha1::Sha1::new();
+                    let locs = Info::piece_disk_locs(&info, hashes.len() as u32);
+                    for loc in locs {
+                        if f.is_none() || loc.path() != cf {
+                            match fs::File::open(base.join(loc.path())) {
+                                Ok(file) => f = Some(file),
+                                Err(e) => {
+                                    return Ok(JobRes::Resp(Response::created(
+                                        context,
+                                        path,
+                                        Err(e),
+                                    )))
+                                }
+                            }
+                            cf = loc.path().to_owned();
+                        }
+                        let data = &mut buf[..loc.end - loc.start];
+                        let file = f.as_mut().unwrap();
+                        let res = file.seek(SeekFrom::Start(loc.offset))
+                            .and_then(|_| file.read_exact(data));
+                        if let Err(e) = res {
+                            return Ok(JobRes::Resp(Response::created(context, path, Err(e))));
+                        }
+                        ctx.update(data);
+                    }
+                    hashes.push(ctx.digest().bytes().to_vec());
+                }
+                if hashes.len() as u32 == info.pieces() {
+                    let mut info = Arc::try_unwrap(info).unwrap_or_else(|i| (*i).clone());
+                    info.set_hashes(hashes);
+                    return Ok(JobRes::Resp(Response::created(context, path, Ok(info))));
+                } else {
+                    return Ok(JobRes::Paused(Request::Create {
+                        context,
+                        path,
+                        info,
+                        hashes,
+                    }));
+                }
+            }
             Request::Download {
                 mut client,
                 path,
@@ -564,6 +703,7 @@ impl Request {
                 mut buf_idx,
                 mut buf_max,
                 mut buf,
+                stream,
             } => {
                 let start = time::Instant::now();
                 while start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE) {
@@ -592,6 +732,7 @@ impl Request {
                                             buf_max,
                                             buf,
                                             file_len,
+                                            stream,
                                         },
                                     )))
                                 }
@@ -666,6 +807,7 @@ impl Request {
                     buf_idx,
                     buf_max,
                     buf,
+                    stream,
                 }));
             }
             Request::Shutdown => unreachable!(),
@@ -687,6 +829,14 @@ impl Request {
         }
     }
 
+    /// Context of a streamed file download
+    pub fn stream(&self) -> Option<StreamCtx> {
+        match *self {
+            Request::Download { ref stream, .. } => stream.clone(),
+            _ => None,
+        }
+    }
+
     pub fn tid(&self) -> Option<usize> {
         match *self {
             Request::Read { ref context, .. } => Some(context.tid),
@@ -697,8 +847,10 @@ impl Request {
             | Request::Move { tid, .. }
             | Request::Write { tid, .. } => Some(tid),
             Request::WriteFile { .. }
+            | Request::Create { .. }
             | Request::Download { .. }
             | Request::Shutdown
+            | Request::LoadIpFilter
             | Request::FreeSpace => None,
         }
     }
@@ -732,6 +884,12 @@ impl Location {
     pub fn path(&self) -> &Path {
         &self.info.files[self.file].path
     }
+
+    /// Whether the location is in a padding file, which is all zeros
+    /// and never written to disk
+    pub fn padding(&self) -> bool {
+        self.info.files[self.file].padding
+    }
 }
 
 impl fmt::Debug for Location {
@@ -761,6 +919,14 @@ impl Response {
         Response::ValidationComplete { tid, invalid }
     }
 
+    pub fn created(context: CreateCtx, path: PathBuf, info: io::Result<Info>) -> Response {
+        Response::Created {
+            context,
+            path,
+            info,
+        }
+    }
+
     pub fn tid(&self) -> usize {
         match *self {
             Response::Read { ref context, .. } => context.tid,
@@ -769,7 +935,10 @@ impl Response {
             | Response::ValidationUpdate { tid, .. }
             | Response::PieceValidated { tid, .. }
             | Response::Error { tid, .. } => tid,
-            Response::FreeSpace(_) => unreachable!(),
+            Response::FreeSpace(_)
+            | Response::IpFilter(_)
+            | Response::Created { .. }
+            | Response::StreamEnded(_) => unreachable!(),
         }
     }
 }
@@ -791,3 +960,13 @@ impl Ctx {
         }
     }
 }
+
+/// Zeroes the part of a piece buffer covered by a padding
+/// location, returning the position after it
+fn zero_fill(buf: &mut [u8], pos: usize, loc: &Location) -> usize {
+    let end = pos + loc.end - loc.start;
+    for b in &mut buf[pos..end] {
+        *b = 0;
+    }
+    end
+}
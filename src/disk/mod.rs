@@ -5,6 +5,8 @@ pub use self::job::Request;
 pub use self::job::Response;
 pub use self::job::Location;
 pub use self::job::Ctx;
+pub use self::job::CreateCtx;
+pub use self::job::StreamCtx;
 
 use std::collections::VecDeque;
 use std::{fs, io, thread};
@@ -77,6 +79,7 @@ impl Disk {
         let mut rotate = 1;
         while let Some(j) = self.active.pop_front() {
             let tid = j.tid();
+            let stream = j.stream();
             match j.execute(&mut self.files) {
                 Ok(JobRes::Resp(r)) => {
                     self.ch.send(r).ok();
@@ -99,13 +102,14 @@ impl Disk {
                 Ok(JobRes::Blocked((id, s))) => {
                     self.blocked.insert(id, s);
                 }
-                Ok(JobRes::Done) => {}
+                Ok(JobRes::Done) => self.stream_ended(stream),
                 Err(e) => {
                     if let Some(t) = tid {
                         self.ch.send(Response::error(t, e)).ok();
                     } else {
                         error!("Disk job failed: {}", e);
                     }
+                    self.stream_ended(stream);
                 }
             }
             match self.poll.wait(0) {
@@ -168,7 +172,9 @@ impl Disk {
             }
         }
         while let Ok(mut r) = self.jobs.try_recv() {
+            let stream = r.stream();
             if r.register(&self.reg).is_err() {
+                self.stream_ended(stream);
                 continue;
             }
             match r.execute(&mut self.files) {
@@ -180,12 +186,20 @@ impl Disk {
                 }
                 Err(e) => {
                     error!("Disk job failed: {}", e);
+                    self.stream_ended(stream);
                 }
-                _ => {}
+                _ => self.stream_ended(stream),
             }
         }
         false
     }
+
+    /// Notifies the torrent of a streamed file that its download ended
+    fn stream_ended(&self, stream: Option<StreamCtx>) {
+        if let Some(ctx) = stream {
+            self.ch.send(Response::StreamEnded(ctx)).ok();
+        }
+    }
 }
 
 pub fn start(
@@ -4,7 +4,7 @@ use std::io;
 
 use {amy, ctrlc};
 
-use {args, control, disk, listener, log, rpc, throttle, tracker};
+use {args, control, disk, listener, log, portmap, rpc, throttle, tracker};
 use {CONFIG, SHUTDOWN, THROT_TOKS};
 use control::acio;
 
@@ -61,6 +61,7 @@ fn init_threads() -> io::Result<Vec<thread::JoinHandle<()>>> {
     let (lh, lhj) = listener::Listener::start(&mut creg)?;
     let (rh, rhj) = rpc::RPC::start(&mut creg, disk_broadcast.try_clone()?)?;
     let (th, thj) = tracker::Tracker::start(&mut creg, disk_broadcast.try_clone()?)?;
+    let (ph, phj) = portmap::PortMapper::start(&mut creg)?;
     let chans = acio::ACChans {
         disk_tx: dh.tx,
         disk_rx: dh.rx,
@@ -70,6 +71,8 @@ fn init_threads() -> io::Result<Vec<thread::JoinHandle<()>>> {
         trk_rx: th.rx,
         lst_tx: lh.tx,
         lst_rx: lh.rx,
+        pm_tx: ph.tx,
+        pm_rx: ph.rx,
     };
     let (tx, rx) = mpsc::channel();
     let cdb = disk_broadcast.try_clone()?;
@@ -91,5 +94,5 @@ fn init_threads() -> io::Result<Vec<thread::JoinHandle<()>>> {
         .unwrap();
     rx.recv().unwrap()?;
 
-    Ok(vec![chj, dhj, lhj, rhj, thj])
+    Ok(vec![chj, dhj, lhj, rhj, thj, phj])
 }
@@ -0,0 +1,333 @@
+//! Blocklists of peer addresses, in the eMule ipfilter.dat,
+//! PeerGuardian P2P or CIDR formats.
+
+use std::{cmp, fs, io};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use libflate::gzip;
+
+/// Blocked address ranges, which are sorted and non overlapping
+/// so that lookups can binary search them.
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    v4: Vec<(Ipv4Addr, Ipv4Addr)>,
+    v6: Vec<(Ipv6Addr, Ipv6Addr)>,
+}
+
+impl IpFilter {
+    pub fn new() -> IpFilter {
+        Default::default()
+    }
+
+    /// Loads the lists at each path, which may be gzipped.
+    /// Lists which can't be read are logged and skipped.
+    pub fn load(paths: &[String]) -> IpFilter {
+        let mut filter = IpFilter::new();
+        for path in paths {
+            match filter.load_file(path) {
+                Ok(n) => info!("Loaded {} IP filter ranges from {}", n, path),
+                Err(e) => error!("Failed to load IP filter {}: {}", path, e),
+            }
+        }
+        filter.merge();
+        filter
+    }
+
+    /// Parses a single list
+    #[cfg(test)]
+    pub fn from_list(list: &str) -> IpFilter {
+        let mut filter = IpFilter::new();
+        filter.parse(list);
+        filter.merge();
+        filter
+    }
+
+    fn load_file(&mut self, path: &str) -> io::Result<usize> {
+        let mut data = Vec::new();
+        fs::File::open(path)?.read_to_end(&mut data)?;
+        let list = decode_list(data)?;
+        Ok(self.parse(&list))
+    }
+
+    /// Adds the ranges of a list, returning the number added.
+    /// The filter must be merged before it's used.
+    fn parse(&mut self, list: &str) -> usize {
+        let mut added = 0;
+        let mut invalid = 0;
+        for line in list.lines() {
+            match parse_line(line) {
+                Ok(Some((IpAddr::V4(s), IpAddr::V4(e)))) => self.v4.push((s, e)),
+                Ok(Some((IpAddr::V6(s), IpAddr::V6(e)))) => self.v6.push((s, e)),
+                Ok(_) => continue,
+                Err(()) => {
+                    invalid += 1;
+                    continue;
+                }
+            }
+            added += 1;
+        }
+        if invalid > 0 {
+            info!("Skipped {} invalid IP filter lines", invalid);
+        }
+        added
+    }
+
+    fn merge(&mut self) {
+        merge_ranges(&mut self.v4);
+        merge_ranges(&mut self.v6);
+    }
+
+    /// Returns true if the IP is in a blocked range
+    pub fn blocked(&self, ip: &IpAddr) -> bool {
+        match *ip {
+            IpAddr::V4(ref a) => contains(&self.v4, a),
+            IpAddr::V6(ref a) => match mapped_v4(a) {
+                Some(ref a) => contains(&self.v4, a),
+                None => contains(&self.v6, a),
+            },
+        }
+    }
+
+    /// Number of distinct blocked ranges
+    pub fn ranges(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+}
+
+/// Decompresses gzipped lists, detected by their magic bytes
+fn decode_list(data: Vec<u8>) -> io::Result<String> {
+    let data = if data.starts_with(&[0x1f, 0x8b]) {
+        let mut buf = Vec::new();
+        gzip::Decoder::new(&data[..])?.read_to_end(&mut buf)?;
+        buf
+    } else {
+        data
+    };
+    // Descriptions aren't always UTF-8, but only addresses matter
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Parses a line of any format, giving None for comments and for
+/// eMule ranges whose access level allows them.
+fn parse_line(line: &str) -> Result<Option<(IpAddr, IpAddr)>, ()> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+        return Ok(None);
+    }
+    // P2P: "description:start-end", which only has IPv4 ranges,
+    // so anything after the last colon is the range.
+    if let Some(i) = line.rfind(':') {
+        if let Some(r) = parse_range(&line[i + 1..]) {
+            return Ok(Some(r));
+        }
+    }
+    // eMule: "start - end , level , description", levels
+    // above 127 being allowed rather than blocked.
+    let mut fields = line.split(',');
+    let range = fields.next().unwrap_or("");
+    if let Some(level) = fields.next() {
+        match level.trim().parse::<u32>() {
+            Ok(l) if l > 127 => return Ok(None),
+            Ok(_) => {}
+            Err(_) => return Err(()),
+        }
+    }
+    // CIDR: "ip/prefix" or a single address
+    parse_range(range).map(Some).ok_or(())
+}
+
+/// Parses "start-end", "ip/prefix" or a single address into an
+/// inclusive range.
+fn parse_range(s: &str) -> Option<(IpAddr, IpAddr)> {
+    let s = s.trim();
+    if let Some(i) = s.find('-') {
+        return match (parse_ip(&s[..i])?, parse_ip(&s[i + 1..])?) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => Some((
+                IpAddr::V4(cmp::min(a, b)),
+                IpAddr::V4(cmp::max(a, b)),
+            )),
+            (IpAddr::V6(a), IpAddr::V6(b)) => Some((
+                IpAddr::V6(cmp::min(a, b)),
+                IpAddr::V6(cmp::max(a, b)),
+            )),
+            _ => None,
+        };
+    }
+    if let Some(i) = s.find('/') {
+        let prefix: u32 = s[i + 1..].trim().parse().ok()?;
+        return match parse_ip(&s[..i])? {
+            IpAddr::V4(ip) if prefix <= 32 => {
+                let mask = if prefix == 0 {
+                    0
+                } else {
+                    !0u32 << (32 - prefix)
+                };
+                let ip = u32::from(ip);
+                Some((
+                    IpAddr::V4(Ipv4Addr::from(ip & mask)),
+                    IpAddr::V4(Ipv4Addr::from(ip | !mask)),
+                ))
+            }
+            IpAddr::V6(ip) if prefix <= 128 => {
+                let (mut start, mut end) = (ip.octets(), ip.octets());
+                for (i, (s, e)) in start.iter_mut().zip(end.iter_mut()).enumerate() {
+                    let bits = cmp::min(prefix.saturating_sub(i as u32 * 8), 8);
+                    let mask = if bits == 0 { 0 } else { 0xFFu8 << (8 - bits) };
+                    *s &= mask;
+                    *e |= !mask;
+                }
+                Some((
+                    IpAddr::V6(Ipv6Addr::from(start)),
+                    IpAddr::V6(Ipv6Addr::from(end)),
+                ))
+            }
+            _ => None,
+        };
+    }
+    parse_ip(s).map(|ip| (ip, ip))
+}
+
+/// Parses an address, allowing the zero padded octets eMule uses
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    if s.contains(':') {
+        return s.parse().ok().map(IpAddr::V6);
+    }
+    let mut octets = [0u8; 4];
+    let mut parts = s.split('.');
+    for o in &mut octets {
+        *o = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+/// Sorts ranges, joining any which overlap
+fn merge_ranges<T: Ord + Copy>(ranges: &mut Vec<(T, T)>) {
+    ranges.sort();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        if let Some(last) = merged.last_mut() {
+            if start <= last.1 {
+                last.1 = cmp::max(last.1, end);
+                continue;
+            }
+        }
+        merged.push((start, end));
+    }
+    *ranges = merged;
+}
+
+fn contains<T: Ord>(ranges: &[(T, T)], ip: &T) -> bool {
+    match ranges.binary_search_by(|r| r.0.cmp(ip)) {
+        Ok(_) => true,
+        // Only the last range starting before the IP can hold it
+        Err(i) => i > 0 && *ip <= ranges[i - 1].1,
+    }
+}
+
+/// Converts IPv4 mapped IPv6 addresses(::ffff:a.b.c.d) to IPv4
+fn mapped_v4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let s = ip.segments();
+    if s[..5] != [0; 5] || s[5] != 0xFFFF {
+        return None;
+    }
+    let o = ip.octets();
+    Some(Ipv4Addr::new(o[12], o[13], o[14], o[15]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_list, IpFilter};
+    use std::io::Write;
+    use libflate::gzip;
+
+    fn filter(list: &str) -> IpFilter {
+        IpFilter::from_list(list)
+    }
+
+    fn blocked(f: &IpFilter, ip: &str) -> bool {
+        f.blocked(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_emule() {
+        let f = filter(
+            "# comment\n\
+             001.002.003.000 - 001.002.003.255 , 000 , Some range\n\
+             010.000.000.000 - 010.255.255.255 , 200 , Allowed\n\
+             005.000.000.001 - 005.000.000.001 , 100 , Single\n",
+        );
+        assert_eq!(f.ranges(), 2);
+        assert!(blocked(&f, "1.2.3.0"));
+        assert!(blocked(&f, "1.2.3.255"));
+        assert!(blocked(&f, "5.0.0.1"));
+        assert!(!blocked(&f, "1.2.4.0"));
+        assert!(!blocked(&f, "5.0.0.2"));
+        assert!(!blocked(&f, "10.1.1.1"));
+    }
+
+    #[test]
+    fn test_p2p() {
+        let f = filter(
+            "Some org, Inc:1.2.3.0-1.2.3.255\n\
+             Another: with colons-and dashes:7.0.0.0-7.0.0.9\n\
+             garbage\n",
+        );
+        assert_eq!(f.ranges(), 2);
+        assert!(blocked(&f, "1.2.3.4"));
+        assert!(blocked(&f, "7.0.0.9"));
+        assert!(!blocked(&f, "7.0.0.10"));
+        // Mapped addresses use the IPv4 ranges
+        assert!(blocked(&f, "::ffff:1.2.3.4"));
+    }
+
+    #[test]
+    fn test_cidr() {
+        let f = filter(
+            "192.168.0.0/16\n\
+             8.8.8.8\n\
+             2001:db8::/32\n\
+             2001:db9::1 - 2001:db9::ff\n",
+        );
+        assert_eq!(f.ranges(), 4);
+        assert!(blocked(&f, "192.168.255.1"));
+        assert!(!blocked(&f, "192.169.0.0"));
+        assert!(blocked(&f, "8.8.8.8"));
+        assert!(!blocked(&f, "8.8.8.9"));
+        assert!(blocked(&f, "2001:db8:ffff::1"));
+        assert!(!blocked(&f, "2001:db7::1"));
+        assert!(blocked(&f, "2001:db9::80"));
+        assert!(!blocked(&f, "2001:db9::100"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let f = filter(
+            "1.0.0.0-1.0.0.100\n\
+             1.0.0.50-1.0.0.200\n\
+             1.0.0.10-1.0.0.20\n\
+             2.0.0.0/8\n\
+             0.0.0.0-0.0.0.0\n",
+        );
+        assert_eq!(f.ranges(), 3);
+        assert!(blocked(&f, "1.0.0.150"));
+        assert!(blocked(&f, "1.0.0.200"));
+        assert!(!blocked(&f, "1.0.0.201"));
+        assert!(blocked(&f, "0.0.0.0"));
+        assert!(!blocked(&f, "3.0.0.0"));
+    }
+
+    #[test]
+    fn test_gzip() {
+        let list = b"1.2.3.0/24\n";
+        let mut enc = gzip::Encoder::new(Vec::new()).unwrap();
+        enc.write_all(list).unwrap();
+        let data = enc.finish().into_result().unwrap();
+        assert_eq!(decode_list(data).unwrap(), "1.2.3.0/24\n");
+        assert_eq!(decode_list(list.to_vec()).unwrap(), "1.2.3.0/24\n");
+    }
+}
@@ -1,20 +1,28 @@
 use std::{fmt, thread};
 use std::io::{self, ErrorKind};
 use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::sync::Arc;
 
 use amy::{self, Poller, Registrar};
 
+use ipfilter::IpFilter;
+use socket::Socket;
 use torrent::peer::PeerConn;
 use {handle, CONFIG};
-use util::UHashMap;
+use util::{tcp_listen_v6, MHashSet, UHashMap};
 
 pub struct Listener {
-    listener: TcpListener,
-    lid: usize,
+    /// IPv4 and, if available, IPv6 listeners. None are used
+    /// if TCP is disabled.
+    listeners: Vec<(usize, TcpListener)>,
     incoming: UHashMap<PeerConn>,
     poll: Poller,
     reg: Registrar,
     ch: handle::Handle<Request, Message>,
+    /// Info hashes of active torrents, used to complete MSE handshakes
+    torrents: Arc<MHashSet<[u8; 20]>>,
+    /// Blocklist which connections are refused by
+    filter: Arc<IpFilter>,
 }
 
 pub struct Message {
@@ -36,6 +44,9 @@ impl fmt::Debug for Message {
 
 #[derive(Debug)]
 pub enum Request {
+    AddTorrent([u8; 20]),
+    RemoveTorrent([u8; 20]),
+    IpFilter(Arc<IpFilter>),
     Shutdown,
 }
 
@@ -47,21 +58,33 @@ impl Listener {
     ) -> io::Result<(handle::Handle<Message, Request>, thread::JoinHandle<()>)> {
         let poll = Poller::new()?;
         let mut reg = poll.get_registrar()?;
-        let ip = Ipv4Addr::new(0, 0, 0, 0);
-        let port = CONFIG.port;
-        let listener = TcpListener::bind(SocketAddrV4::new(ip, port))?;
-        listener.set_nonblocking(true)?;
-        let lid = reg.register(&listener, amy::Event::Both)?;
+        let mut listeners = Vec::new();
+        if CONFIG.net.tcp {
+            let ip = Ipv4Addr::new(0, 0, 0, 0);
+            let port = CONFIG.port;
+            let listener = TcpListener::bind(SocketAddrV4::new(ip, port))?;
+            listener.set_nonblocking(true)?;
+            listeners.push((reg.register(&listener, amy::Event::Both)?, listener));
+            match tcp_listen_v6(port).and_then(|l| {
+                l.set_nonblocking(true)?;
+                let id = reg.register(&l, amy::Event::Both)?;
+                Ok((id, l))
+            }) {
+                Ok(l) => listeners.push(l),
+                Err(e) => info!("IPv6 listener unavailable: {}", e),
+            }
+        }
 
         let (ch, dh) = handle::Handle::new(creg, &mut reg)?;
         let th = dh.run("listener", move |h| {
             Listener {
-                listener,
-                lid,
+                listeners,
                 incoming: UHashMap::default(),
                 poll,
                 reg,
                 ch: h,
+                torrents: Arc::new(MHashSet::default()),
+                filter: Arc::new(IpFilter::new()),
             }.run()
         })?;
         Ok((ch, th))
@@ -72,9 +95,9 @@ impl Listener {
         while let Ok(res) = self.poll.wait(POLL_INT_MS) {
             for not in res {
                 match not.id {
-                    id if id == self.lid => self.handle_conn(),
+                    id if self.listeners.iter().any(|l| l.0 == id) => self.handle_conn(id),
                     id if id == self.ch.rx.get_id() => {
-                        if let Ok(Request::Shutdown) = self.ch.recv() {
+                        if self.handle_ctl() {
                             return;
                         }
                     }
@@ -84,13 +107,41 @@ impl Listener {
         }
     }
 
-    fn handle_conn(&mut self) {
+    /// Processes control requests, returning true on shutdown.
+    fn handle_ctl(&mut self) -> bool {
+        while let Ok(r) = self.ch.recv() {
+            match r {
+                Request::AddTorrent(hash) => {
+                    Arc::make_mut(&mut self.torrents).insert(hash);
+                }
+                Request::RemoveTorrent(hash) => {
+                    Arc::make_mut(&mut self.torrents).remove(&hash);
+                }
+                Request::IpFilter(filter) => {
+                    self.filter = filter;
+                }
+                Request::Shutdown => return true,
+            }
+        }
+        false
+    }
+
+    fn handle_conn(&mut self, lid: usize) {
         loop {
-            match self.listener.accept() {
+            let res = match self.listeners.iter().find(|l| l.0 == lid) {
+                Some(&(_, ref l)) => l.accept(),
+                None => break,
+            };
+            match res {
+                Ok((_, ip)) if self.filter.blocked(&ip.ip()) => {
+                    debug!("Refused filtered connection from {:?}", ip);
+                }
                 Ok((conn, ip)) => {
                     debug!("Accepted new connection from {:?}!", ip);
-                    let peer = PeerConn::new_incoming(conn).unwrap();
-                    let pid = self.reg.register(peer.sock(), amy::Event::Read).unwrap();
+                    let peer = Socket::from_stream(conn)
+                        .and_then(|s| PeerConn::new_incoming(s, &self.torrents))
+                        .unwrap();
+                    let pid = self.reg.register(peer.sock(), amy::Event::Both).unwrap();
                     self.incoming.insert(pid, peer);
                 }
                 Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
@@ -27,6 +27,7 @@ extern crate http_range;
 extern crate httparse;
 #[macro_use]
 extern crate lazy_static;
+extern crate libflate;
 extern crate memmap;
 extern crate metrohash;
 extern crate net2;
@@ -58,6 +59,8 @@ mod disk;
 mod tracker;
 mod control;
 mod listener;
+mod ipfilter;
+mod portmap;
 mod rpc;
 mod throttle;
 mod config;
@@ -72,7 +75,9 @@ use std::process;
 
 pub const DHT_EXT: (usize, u8) = (7, 1);
 pub const EXT_PROTO: (usize, u8) = (5, 0x10);
+pub const V2_EXT: (usize, u8) = (7, 0x10);
 pub const UT_META_ID: u8 = 9;
+pub const UT_PEX_ID: u8 = 10;
 
 /// Throttler max token amount
 pub const THROT_TOKS: usize = 2 * 1024 * 1024;
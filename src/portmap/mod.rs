@@ -0,0 +1,305 @@
+mod natpmp;
+mod upnp;
+
+use std::{io, thread, time};
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use amy;
+
+use handle;
+use rpc::resource::{MappingMethod, MappingStatus, PortMapping, Protocol};
+use CONFIG;
+
+const POLL_INT_MS: usize = 1000;
+/// Lifetime requested for mappings, which are renewed halfway through it
+const LIFETIME_SECS: u32 = 7200;
+/// Delay before retrying gateway discovery or failed mappings
+const RETRY_SECS: u64 = 300;
+
+/// Maps the peer, DHT and tracker ports on the local gateway
+/// using NAT-PMP, PCP or UPnP IGD, renewing them until shutdown.
+pub struct PortMapper {
+    poll: amy::Poller,
+    ch: handle::Handle<Request, Response>,
+    gateway: Option<Gateway>,
+    mappings: Vec<Mapping>,
+    external_ip: Option<IpAddr>,
+    /// Earliest time at which gateway discovery may be attempted
+    next_discovery: time::Instant,
+}
+
+#[derive(Debug)]
+pub enum Request {
+    Shutdown,
+}
+
+/// Status of the gateway and all mappings, sent whenever it changes.
+#[derive(Debug)]
+pub struct Response {
+    pub external_ip: Option<IpAddr>,
+    pub mappings: Vec<PortMapping>,
+}
+
+enum Gateway {
+    NATPMP(natpmp::Client),
+    UPnP(upnp::Client),
+}
+
+struct Mapping {
+    status: PortMapping,
+    renew: time::Instant,
+}
+
+impl PortMapper {
+    pub fn start(
+        creg: &mut amy::Registrar,
+    ) -> io::Result<(handle::Handle<Response, Request>, thread::JoinHandle<()>)> {
+        let poll = amy::Poller::new()?;
+        let mut reg = poll.get_registrar()?;
+        let (ch, dh) = handle::Handle::new(creg, &mut reg)?;
+        let th = dh.run("portmap", move |h| {
+            let now = time::Instant::now();
+            let mappings = if CONFIG.net.port_mapping {
+                ports()
+                    .into_iter()
+                    .map(|(protocol, port)| Mapping {
+                        status: PortMapping {
+                            protocol,
+                            internal_port: port,
+                            external_port: None,
+                            method: None,
+                            status: MappingStatus::Pending,
+                        },
+                        renew: now,
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            PortMapper {
+                poll,
+                ch: h,
+                gateway: None,
+                mappings,
+                external_ip: None,
+                next_discovery: now,
+            }.run()
+        })?;
+        Ok((ch, th))
+    }
+
+    pub fn run(&mut self) {
+        debug!("Initialized!");
+        loop {
+            match self.poll.wait(POLL_INT_MS) {
+                Ok(events) => for event in events {
+                    if event.id == self.ch.rx.get_id() && self.handle_ctl() {
+                        self.unmap_all();
+                        return;
+                    }
+                },
+                Err(e) => {
+                    error!("Failed to poll for events: {:?}", e);
+                }
+            }
+            self.refresh();
+        }
+    }
+
+    /// Processes control requests, returning true on shutdown.
+    fn handle_ctl(&mut self) -> bool {
+        while let Ok(r) = self.ch.recv() {
+            match r {
+                Request::Shutdown => return true,
+            }
+        }
+        false
+    }
+
+    /// Discovers the gateway if needed and requests all
+    /// mappings which are due to be renewed.
+    fn refresh(&mut self) {
+        let now = time::Instant::now();
+        if self.mappings.iter().all(|m| m.renew > now) {
+            return;
+        }
+        if self.gateway.is_none() {
+            if now < self.next_discovery {
+                return;
+            }
+            self.gateway = discover();
+            if self.gateway.is_none() {
+                self.next_discovery = now + time::Duration::from_secs(RETRY_SECS);
+                for m in &mut self.mappings {
+                    m.status.status = MappingStatus::Failed;
+                    m.status.external_port = None;
+                    m.status.method = None;
+                }
+                self.send_status();
+                return;
+            }
+        }
+
+        let mut failed = true;
+        if let Some(ref mut gateway) = self.gateway {
+            let method = gateway.method();
+            for m in self.mappings.iter_mut().filter(|m| m.renew <= now) {
+                let (proto, port) = (m.status.protocol, m.status.internal_port);
+                match gateway.map(proto, port, LIFETIME_SECS) {
+                    Ok((ext, lifetime)) => {
+                        debug!("Mapped {:?} port {} to {} via {:?}", proto, port, ext, method);
+                        // Permanent mappings are still periodically renewed,
+                        // in case the gateway restarted.
+                        let lifetime = if lifetime == 0 { LIFETIME_SECS } else { lifetime };
+                        m.renew = now + time::Duration::from_secs(lifetime as u64 / 2);
+                        m.status.status = MappingStatus::Mapped;
+                        m.status.external_port = Some(ext);
+                        m.status.method = Some(method);
+                        failed = false;
+                    }
+                    Err(e) => {
+                        info!("Failed to map {:?} port {}: {}", proto, port, e);
+                        m.renew = now + time::Duration::from_secs(RETRY_SECS);
+                        m.status.status = MappingStatus::Failed;
+                        m.status.external_port = None;
+                        m.status.method = None;
+                    }
+                }
+            }
+            self.external_ip = gateway.external_ip().or(self.external_ip);
+        }
+        // The gateway may have changed, so rediscover it next time
+        if failed {
+            self.gateway = None;
+        }
+        self.send_status();
+    }
+
+    fn unmap_all(&mut self) {
+        if let Some(ref mut gateway) = self.gateway {
+            for m in &self.mappings {
+                if m.status.status != MappingStatus::Mapped {
+                    continue;
+                }
+                let (proto, port) = (m.status.protocol, m.status.internal_port);
+                if let Err(e) = gateway.unmap(proto, port) {
+                    debug!("Failed to unmap {:?} port {}: {}", proto, port, e);
+                }
+            }
+        }
+    }
+
+    fn send_status(&mut self) {
+        self.ch
+            .send(Response {
+                external_ip: self.external_ip,
+                mappings: self.mappings.iter().map(|m| m.status.clone()).collect(),
+            })
+            .ok();
+    }
+}
+
+impl Gateway {
+    fn method(&self) -> MappingMethod {
+        match *self {
+            Gateway::NATPMP(ref c) if c.pcp() => MappingMethod::PCP,
+            Gateway::NATPMP(_) => MappingMethod::NATPMP,
+            Gateway::UPnP(_) => MappingMethod::UPnP,
+        }
+    }
+
+    fn map(&mut self, proto: Protocol, port: u16, lifetime: u32) -> io::Result<(u16, u32)> {
+        match *self {
+            Gateway::NATPMP(ref mut c) => c.map(proto, port, lifetime),
+            Gateway::UPnP(ref c) => c.map(proto, port, lifetime),
+        }
+    }
+
+    fn unmap(&mut self, proto: Protocol, port: u16) -> io::Result<()> {
+        match *self {
+            Gateway::NATPMP(ref mut c) => c.map(proto, port, 0).map(|_| ()),
+            Gateway::UPnP(ref c) => c.unmap(proto, port),
+        }
+    }
+
+    fn external_ip(&mut self) -> Option<IpAddr> {
+        match *self {
+            Gateway::NATPMP(ref c) => c.external_ip(),
+            Gateway::UPnP(ref c) => c.external_ip().ok(),
+        }
+    }
+}
+
+/// Ports which should be reachable from outside, by protocol.
+fn ports() -> Vec<(Protocol, u16)> {
+    let mut ports = Vec::new();
+    if CONFIG.net.tcp {
+        ports.push((Protocol::TCP, CONFIG.port));
+    }
+    if CONFIG.net.utp {
+        ports.push((Protocol::UDP, CONFIG.port));
+    }
+    ports.push((Protocol::UDP, CONFIG.dht.port));
+    ports.push((Protocol::UDP, CONFIG.trk.port));
+    ports
+}
+
+/// Finds a gateway, preferring NAT-PMP/PCP over UPnP.
+fn discover() -> Option<Gateway> {
+    let gateway = CONFIG.net.gateway.or_else(|| default_gateway().map(IpAddr::V4));
+    if let Some(ip) = gateway {
+        match natpmp::Client::discover(SocketAddr::new(ip, natpmp::PORT)) {
+            Ok(c) => return Some(Gateway::NATPMP(c)),
+            Err(e) => debug!("NAT-PMP/PCP unavailable: {}", e),
+        }
+    }
+    match upnp::Client::discover(CONFIG.net.gateway) {
+        Ok(c) => Some(Gateway::UPnP(c)),
+        Err(e) => {
+            info!("No port mapping gateway found: {}", e);
+            None
+        }
+    }
+}
+
+/// Reads the IPv4 default gateway from the kernel routing table.
+fn default_gateway() -> Option<Ipv4Addr> {
+    let mut routes = String::new();
+    File::open("/proc/net/route")
+        .and_then(|mut f| f.read_to_string(&mut routes))
+        .ok()?;
+    parse_routes(&routes)
+}
+
+fn parse_routes(routes: &str) -> Option<Ipv4Addr> {
+    for line in routes.lines().skip(1) {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            continue;
+        }
+        // Addresses are in host byte order
+        match u32::from_str_radix(fields[2], 16) {
+            Ok(0) | Err(_) => continue,
+            Ok(gw) => return Some(Ipv4Addr::from(u32::from_be(gw))),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::parse_routes;
+
+    #[test]
+    fn test_parse_routes() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                      eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                      eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\n";
+        assert_eq!(parse_routes(routes), Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(parse_routes("Iface\tDestination\tGateway\n"), None);
+    }
+}
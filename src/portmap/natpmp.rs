@@ -0,0 +1,255 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+use rand::random;
+
+use rpc::resource::Protocol;
+use util::io_err;
+
+pub const PORT: u16 = 5351;
+/// Initial response timeout, doubled for each retry
+const TIMEOUT_MS: u64 = 250;
+const RETRIES: u32 = 3;
+
+const VERSION_NATPMP: u8 = 0;
+const VERSION_PCP: u8 = 2;
+const OP_MAP: u8 = 1;
+const RESP: u8 = 0x80;
+const NATPMP_UNSUPP_VERSION: u16 = 1;
+
+/// NAT-PMP(RFC 6886) or PCP(RFC 6887) client, depending on
+/// which the gateway supports.
+pub struct Client {
+    sock: UdpSocket,
+    pcp: bool,
+    local: IpAddr,
+    external_ip: Option<IpAddr>,
+    /// Identifies our PCP mappings, so they can be renewed
+    nonce: [u8; 12],
+}
+
+impl Client {
+    /// Determines whether the gateway supports NAT-PMP or PCP by
+    /// requesting its external address.
+    pub fn discover(gateway: SocketAddr) -> io::Result<Client> {
+        let sock = UdpSocket::bind(("0.0.0.0", 0))?;
+        sock.connect(gateway)?;
+        let local = sock.local_addr()?.ip();
+        let resp = request(&sock, &[VERSION_NATPMP, 0])?;
+        let mut client = Client {
+            sock,
+            pcp: false,
+            local,
+            external_ip: None,
+            nonce: random(),
+        };
+        if resp.len() >= 4 && resp[0] == VERSION_PCP {
+            // PCP only gateways reject NAT-PMP requests this way
+            client.pcp = true;
+        } else if resp.len() >= 12 && resp[0] == VERSION_NATPMP && resp[1] == RESP {
+            match BigEndian::read_u16(&resp[2..4]) {
+                0 => {
+                    let ip = Ipv4Addr::new(resp[8], resp[9], resp[10], resp[11]);
+                    client.external_ip = Some(IpAddr::V4(ip));
+                }
+                NATPMP_UNSUPP_VERSION => client.pcp = true,
+                _ => return io_err("NAT-PMP gateway refused request"),
+            }
+        } else {
+            return io_err("Invalid NAT-PMP response");
+        }
+        Ok(client)
+    }
+
+    pub fn pcp(&self) -> bool {
+        self.pcp
+    }
+
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip
+    }
+
+    /// Maps port to the same external port if possible, returning
+    /// the external port and lifetime of the mapping. A lifetime of
+    /// 0 deletes the mapping.
+    pub fn map(&mut self, proto: Protocol, port: u16, lifetime: u32) -> io::Result<(u16, u32)> {
+        if self.pcp {
+            self.map_pcp(proto, port, lifetime)
+        } else {
+            self.map_natpmp(proto, port, lifetime)
+        }
+    }
+
+    fn map_natpmp(&mut self, proto: Protocol, port: u16, lifetime: u32) -> io::Result<(u16, u32)> {
+        let op = match proto {
+            Protocol::UDP => 1,
+            Protocol::TCP => 2,
+        };
+        let mut req = [0u8; 12];
+        req[1] = op;
+        BigEndian::write_u16(&mut req[4..6], port);
+        BigEndian::write_u16(&mut req[6..8], if lifetime == 0 { 0 } else { port });
+        BigEndian::write_u32(&mut req[8..12], lifetime);
+
+        let resp = request(&self.sock, &req)?;
+        if resp.len() < 16 || resp[1] != RESP | op || BigEndian::read_u16(&resp[8..10]) != port {
+            return io_err("Invalid NAT-PMP response");
+        }
+        if BigEndian::read_u16(&resp[2..4]) != 0 {
+            return io_err("NAT-PMP gateway refused mapping");
+        }
+        Ok((
+            BigEndian::read_u16(&resp[10..12]),
+            BigEndian::read_u32(&resp[12..16]),
+        ))
+    }
+
+    fn map_pcp(&mut self, proto: Protocol, port: u16, lifetime: u32) -> io::Result<(u16, u32)> {
+        let mut req = [0u8; 60];
+        req[0] = VERSION_PCP;
+        req[1] = OP_MAP;
+        BigEndian::write_u32(&mut req[4..8], lifetime);
+        req[8..24].copy_from_slice(&ip_to_bytes(self.local));
+        req[24..36].copy_from_slice(&self.nonce);
+        req[36] = match proto {
+            Protocol::TCP => 6,
+            Protocol::UDP => 17,
+        };
+        BigEndian::write_u16(&mut req[40..42], port);
+        BigEndian::write_u16(&mut req[42..44], port);
+        req[44..60].copy_from_slice(&ip_to_bytes(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))));
+
+        let resp = request(&self.sock, &req)?;
+        if resp.len() < 60 || resp[0] != VERSION_PCP || resp[1] != RESP | OP_MAP
+            || resp[24..36] != self.nonce || BigEndian::read_u16(&resp[40..42]) != port
+        {
+            return io_err("Invalid PCP response");
+        }
+        if resp[3] != 0 {
+            return io_err("PCP gateway refused mapping");
+        }
+        if lifetime != 0 {
+            self.external_ip = Some(ip_from_bytes(&resp[44..60]));
+        }
+        Ok((
+            BigEndian::read_u16(&resp[42..44]),
+            BigEndian::read_u32(&resp[4..8]),
+        ))
+    }
+}
+
+/// Sends a request, retrying with an increasing timeout until a
+/// response is received.
+fn request(sock: &UdpSocket, req: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = [0u8; 1100];
+    for i in 0..RETRIES {
+        sock.set_read_timeout(Some(Duration::from_millis(TIMEOUT_MS << i)))?;
+        sock.send(req)?;
+        match sock.recv(&mut buf) {
+            Ok(len) => return Ok(buf[..len].to_vec()),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => return Err(e),
+        }
+    }
+    io_err("Gateway did not respond")
+}
+
+/// Encodes an address as an IPv6 address, using IPv4 mapping
+/// for IPv4 addresses.
+fn ip_to_bytes(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn ip_from_bytes(b: &[u8]) -> IpAddr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&b[..16]);
+    let ip = Ipv6Addr::from(octets);
+    match ip.to_ipv4() {
+        Some(v4) if octets[10] == 0xFF && octets[11] == 0xFF => IpAddr::V4(v4),
+        _ => IpAddr::V6(ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+    use std::thread;
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    use super::{ip_to_bytes, Client};
+    use rpc::resource::Protocol;
+
+    /// Starts a stand-in gateway, which answers requests using f.
+    fn gateway<F>(f: F) -> UdpSocket
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + 'static,
+    {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = sock.try_clone().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1100];
+            while let Ok((len, addr)) = server.recv_from(&mut buf) {
+                let resp = f(&buf[..len]);
+                server.send_to(&resp, addr).unwrap();
+            }
+        });
+        sock
+    }
+
+    #[test]
+    fn test_natpmp() {
+        let gw = gateway(|req| {
+            let mut resp = vec![0u8; 16];
+            resp[1] = 0x80 | req[1];
+            if req[1] == 0 {
+                resp[8..12].copy_from_slice(&[203, 0, 113, 5]);
+                resp.truncate(12);
+            } else {
+                resp[8..10].copy_from_slice(&req[4..6]);
+                BigEndian::write_u16(&mut resp[10..12], 40000);
+                resp[12..16].copy_from_slice(&req[8..12]);
+            }
+            resp
+        });
+        let mut client = Client::discover(gw.local_addr().unwrap()).unwrap();
+        assert!(!client.pcp());
+        let ext = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5));
+        assert_eq!(client.external_ip(), Some(ext));
+        assert_eq!(client.map(Protocol::TCP, 6881, 7200).unwrap(), (40000, 7200));
+        assert_eq!(client.map(Protocol::UDP, 6881, 0).unwrap(), (40000, 0));
+    }
+
+    #[test]
+    fn test_pcp() {
+        let gw = gateway(|req| {
+            let mut resp = vec![0u8; 60];
+            resp[0] = 2;
+            if req[0] == 0 {
+                // Unsupported version
+                resp[3] = 1;
+                resp.truncate(24);
+            } else {
+                resp[1] = 0x80 | req[1];
+                resp[4..8].copy_from_slice(&req[4..8]);
+                resp[24..44].copy_from_slice(&req[24..44]);
+                let ext = ip_to_bytes(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7)));
+                resp[44..60].copy_from_slice(&ext);
+            }
+            resp
+        });
+        let mut client = Client::discover(gw.local_addr().unwrap()).unwrap();
+        assert!(client.pcp());
+        assert_eq!(client.external_ip(), None);
+        assert_eq!(client.map(Protocol::UDP, 6881, 3600).unwrap(), (6881, 3600));
+        let ext = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+        assert_eq!(client.external_ip(), Some(ext));
+    }
+}
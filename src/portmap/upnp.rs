@@ -0,0 +1,401 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use httparse;
+use url::Url;
+
+use rpc::resource::Protocol;
+use util::{io_err, io_err_val};
+
+const SSDP_PORT: u16 = 1900;
+const SEARCH_TIMEOUT_MS: u64 = 2000;
+const HTTP_TIMEOUT_MS: u64 = 3000;
+const MAX_RESP_LEN: usize = 64 * 1024;
+/// UPnP error returned by gateways which only support static mappings
+const ONLY_PERMANENT_LEASES: &'static str = "725";
+
+const SERVICES: [&'static str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// UPnP Internet Gateway Device client.
+pub struct Client {
+    control: Url,
+    service: &'static str,
+    /// Address the gateway sees us as, used as the mapping target
+    local: IpAddr,
+}
+
+impl Client {
+    /// Searches for a gateway using SSDP, unicasting the search
+    /// to gateway if given.
+    pub fn discover(gateway: Option<IpAddr>) -> io::Result<Client> {
+        let target = match gateway {
+            Some(ip) => SocketAddr::new(ip, SSDP_PORT),
+            None => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), SSDP_PORT),
+        };
+        let location = search(target)?;
+        Client::from_description(&location)
+    }
+
+    /// Creates a client from the location of the gateway's
+    /// device description.
+    pub fn from_description(location: &str) -> io::Result<Client> {
+        let url = Url::parse(location).map_err(|_| io_err_val("Invalid gateway location"))?;
+        let (code, body, local) = http(&url, "GET", None, "")?;
+        if code != 200 {
+            return io_err("Failed to fetch gateway description");
+        }
+        for block in body.split("<service>").skip(1) {
+            let service = find_tag(block, "serviceType")
+                .and_then(|st| SERVICES.iter().find(|s| **s == st.trim()));
+            let control = find_tag(block, "controlURL");
+            if let (Some(service), Some(control)) = (service, control) {
+                let base = match find_tag(&body, "URLBase").map(|b| Url::parse(b.trim())) {
+                    Some(Ok(b)) => b,
+                    _ => url.clone(),
+                };
+                let control = base.join(control.trim())
+                    .map_err(|_| io_err_val("Invalid gateway control URL"))?;
+                return Ok(Client {
+                    control,
+                    service,
+                    local,
+                });
+            }
+        }
+        io_err("Gateway has no WAN connection service")
+    }
+
+    pub fn external_ip(&self) -> io::Result<IpAddr> {
+        let body = self.soap("GetExternalIPAddress", &[])?
+            .map_err(|c| upnp_err("GetExternalIPAddress", &c))?;
+        find_tag(&body, "NewExternalIPAddress")
+            .and_then(|ip| ip.trim().parse().ok())
+            .ok_or_else(|| io_err_val("Invalid external IP"))
+    }
+
+    /// Maps port to the same external port, returning the
+    /// external port and the lifetime of the mapping, which
+    /// is 0 if it is permanent.
+    pub fn map(&self, proto: Protocol, port: u16, lifetime: u32) -> io::Result<(u16, u32)> {
+        let lifetime = match self.add_mapping(proto, port, lifetime)? {
+            Ok(_) => lifetime,
+            Err(ref code) if code == ONLY_PERMANENT_LEASES => {
+                self.add_mapping(proto, port, 0)?
+                    .map_err(|c| upnp_err("AddPortMapping", &c))?;
+                0
+            }
+            Err(code) => return Err(upnp_err("AddPortMapping", &code)),
+        };
+        Ok((port, lifetime))
+    }
+
+    pub fn unmap(&self, proto: Protocol, port: u16) -> io::Result<()> {
+        let port = port.to_string();
+        let args = [
+            ("NewRemoteHost", ""),
+            ("NewExternalPort", &port[..]),
+            ("NewProtocol", proto_name(proto)),
+        ];
+        self.soap("DeletePortMapping", &args)?
+            .map_err(|c| upnp_err("DeletePortMapping", &c))?;
+        Ok(())
+    }
+
+    fn add_mapping(
+        &self,
+        proto: Protocol,
+        port: u16,
+        lifetime: u32,
+    ) -> io::Result<Result<String, String>> {
+        let port = port.to_string();
+        let local = self.local.to_string();
+        let lifetime = lifetime.to_string();
+        let args = [
+            ("NewRemoteHost", ""),
+            ("NewExternalPort", &port[..]),
+            ("NewProtocol", proto_name(proto)),
+            ("NewInternalPort", &port),
+            ("NewInternalClient", &local),
+            ("NewEnabled", "1"),
+            ("NewPortMappingDescription", "synapse"),
+            ("NewLeaseDuration", &lifetime),
+        ];
+        self.soap("AddPortMapping", &args)
+    }
+
+    /// Performs a SOAP action, returning the response body, or
+    /// the UPnP error code if the gateway rejected the action.
+    fn soap(&self, action: &str, args: &[(&str, &str)]) -> io::Result<Result<String, String>> {
+        let mut body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{} xmlns:u=\"{}\">",
+            action, self.service
+        );
+        for &(name, value) in args {
+            body.push_str(&format!("<{0}>{1}</{0}>", name, value));
+        }
+        body.push_str(&format!("</u:{}></s:Body></s:Envelope>\r\n", action));
+        let headers = format!(
+            "Content-Type: text/xml; charset=\"utf-8\"\r\nSOAPAction: \"{}#{}\"\r\n",
+            self.service, action
+        );
+        let (code, resp, _) = http(&self.control, "POST", Some(&headers), &body)?;
+        if code == 200 {
+            Ok(Ok(resp))
+        } else {
+            let err = find_tag(&resp, "errorCode").unwrap_or("").trim();
+            Ok(Err(err.to_owned()))
+        }
+    }
+}
+
+/// Sends an SSDP search for gateways to target, returning the
+/// location of the first gateway to respond.
+fn search(target: SocketAddr) -> io::Result<String> {
+    let sock = UdpSocket::bind(("0.0.0.0", 0))?;
+    let msg = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: 239.255.255.250:{}\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: 2\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+        SSDP_PORT
+    );
+    sock.send_to(msg.as_bytes(), target)?;
+
+    let deadline = Instant::now() + Duration::from_millis(SEARCH_TIMEOUT_MS);
+    let mut buf = [0u8; 2048];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return io_err("No UPnP gateway found");
+        }
+        sock.set_read_timeout(Some(deadline - now))?;
+        let len = match sock.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        let mut headers = [httparse::EMPTY_HEADER; 24];
+        let mut resp = httparse::Response::new(&mut headers);
+        if let Ok(httparse::Status::Complete(_)) = resp.parse(&buf[..len]) {
+            let location = resp.headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case("location"))
+                .and_then(|h| String::from_utf8(h.value.to_vec()).ok());
+            if let (Some(200), Some(loc)) = (resp.code, location) {
+                return Ok(loc.trim().to_owned());
+            }
+        }
+    }
+}
+
+/// Performs a blocking HTTP request, returning the status code, body,
+/// and local address of the connection.
+fn http(
+    url: &Url,
+    method: &str,
+    headers: Option<&str>,
+    body: &str,
+) -> io::Result<(u16, String, IpAddr)> {
+    let timeout = Duration::from_millis(HTTP_TIMEOUT_MS);
+    let addr = url.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io_err_val("Invalid gateway address"))?;
+    let mut conn = TcpStream::connect_timeout(&addr, timeout)?;
+    conn.set_read_timeout(Some(timeout))?;
+    conn.set_write_timeout(Some(timeout))?;
+    let local = conn.local_addr()?.ip();
+
+    let mut path = url.path().to_owned();
+    if let Some(q) = url.query() {
+        path.push('?');
+        path.push_str(q);
+    }
+    let req = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n{}\r\n{}",
+        method,
+        path,
+        addr,
+        body.len(),
+        headers.unwrap_or(""),
+        body
+    );
+    conn.write_all(req.as_bytes())?;
+
+    let mut data = Vec::new();
+    conn.take(MAX_RESP_LEN as u64).read_to_end(&mut data)?;
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut resp = httparse::Response::new(&mut headers);
+    let idx = match resp.parse(&data) {
+        Ok(httparse::Status::Complete(idx)) => idx,
+        _ => return io_err("Invalid HTTP response from gateway"),
+    };
+    let chunked = resp.headers.iter().any(|h| {
+        h.name.eq_ignore_ascii_case("transfer-encoding")
+            && String::from_utf8_lossy(h.value).contains("chunked")
+    });
+    let body = if chunked {
+        dechunk(&data[idx..])
+    } else {
+        data[idx..].to_vec()
+    };
+    Ok((
+        resp.code.unwrap_or(0),
+        String::from_utf8_lossy(&body).into_owned(),
+        local,
+    ))
+}
+
+fn dechunk(mut data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    while let Some(end) = data.windows(2).position(|w| w == b"\r\n") {
+        let size = String::from_utf8_lossy(&data[..end]);
+        let size = size.split(';').next().unwrap_or("").trim();
+        let len = match usize::from_str_radix(size, 16) {
+            Ok(0) | Err(_) => break,
+            Ok(l) => l,
+        };
+        let start = end + 2;
+        if data.len() < start + len {
+            break;
+        }
+        body.extend_from_slice(&data[start..start + len]);
+        data = &data[(start + len + 2).min(data.len())..];
+    }
+    body
+}
+
+/// Returns the contents of the first element with the given name.
+fn find_tag<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}", tag);
+    let mut idx = 0;
+    while let Some(i) = xml[idx..].find(&open) {
+        let start = idx + i + open.len();
+        idx = start;
+        match xml[start..].chars().next() {
+            Some('>') | Some(' ') => {}
+            _ => continue,
+        }
+        let content = start + xml[start..].find('>')? + 1;
+        let end = content + xml[content..].find("</")?;
+        return Some(&xml[content..end]);
+    }
+    None
+}
+
+fn proto_name(proto: Protocol) -> &'static str {
+    match proto {
+        Protocol::TCP => "TCP",
+        Protocol::UDP => "UDP",
+    }
+}
+
+fn upnp_err(action: &str, code: &str) -> io::Error {
+    let reason = format!("{} failed with UPnP error {}", action, code);
+    io::Error::new(io::ErrorKind::Other, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, Ipv4Addr, TcpListener, UdpSocket};
+    use std::thread;
+
+    use super::{dechunk, find_tag, search, Client};
+    use rpc::resource::Protocol;
+
+    const DESCRIPTION: &'static str = "<?xml version=\"1.0\"?>\
+        <root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+        <controlURL>/l3f</controlURL></service>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <controlURL>/ctl/IPConn</controlURL></service>\
+        </serviceList></device></root>";
+
+    /// Starts a stand-in gateway serving its description and
+    /// answering SOAP actions, which only supports permanent leases.
+    fn gateway() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let mut data = Vec::new();
+                let mut buf = [0u8; 4096];
+                while !String::from_utf8_lossy(&data).contains("Envelope>")
+                    && !(data.starts_with(b"GET") && data.ends_with(b"\r\n\r\n"))
+                {
+                    let n = conn.read(&mut buf).unwrap();
+                    data.extend_from_slice(&buf[..n]);
+                }
+                let req = String::from_utf8(data).unwrap();
+                let (code, body) = if req.starts_with("GET /desc.xml") {
+                    (200, DESCRIPTION.to_owned())
+                } else if !req.starts_with("POST /ctl/IPConn") {
+                    (404, String::new())
+                } else if req.contains("#GetExternalIPAddress") {
+                    let ip = "<NewExternalIPAddress>203.0.113.9</NewExternalIPAddress>";
+                    (200, ip.to_owned())
+                } else if req.contains("#AddPortMapping")
+                    && !req.contains("<NewLeaseDuration>0<")
+                {
+                    (500, "<UPnPError><errorCode>725</errorCode></UPnPError>".to_owned())
+                } else {
+                    (200, String::new())
+                };
+                write!(
+                    conn,
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\n\r\n{}",
+                    code,
+                    body.len(),
+                    body
+                ).unwrap();
+            }
+        });
+        format!("http://{}/desc.xml", addr)
+    }
+
+    #[test]
+    fn test_search() {
+        let gw = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = gw.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (len, from) = gw.recv_from(&mut buf).unwrap();
+            assert!(buf[..len].starts_with(b"M-SEARCH"));
+            let resp = "HTTP/1.1 200 OK\r\nLOCATION: http://127.0.0.1:1/desc.xml\r\n\r\n";
+            gw.send_to(resp.as_bytes(), from).unwrap();
+        });
+        assert_eq!(search(addr).unwrap(), "http://127.0.0.1:1/desc.xml");
+    }
+
+    #[test]
+    fn test_client() {
+        let client = Client::from_description(&gateway()).unwrap();
+        assert_eq!(client.control.path(), "/ctl/IPConn");
+        let ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 9));
+        assert_eq!(client.external_ip().unwrap(), ip);
+        assert_eq!(client.map(Protocol::TCP, 6881, 3600).unwrap(), (6881, 0));
+        client.unmap(Protocol::TCP, 6881).unwrap();
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(find_tag("<a><b x=\"1\">c</b></a>", "b"), Some("c"));
+        assert_eq!(find_tag("<bb>d</bb><b>c</b>", "b"), Some("c"));
+        assert_eq!(find_tag("<a></a>", "b"), None);
+        assert_eq!(dechunk(b"4\r\nabcd\r\n2;x\r\nef\r\n0\r\n\r\n"), b"abcdef");
+    }
+}
@@ -8,7 +8,8 @@ mod transfer;
 
 use std::{io, result, str, thread};
 use std::io::Write;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
+use std::path::PathBuf;
 
 use amy;
 use serde_json;
@@ -26,6 +27,7 @@ use self::transfer::{TransferResult, Transfers};
 use bencode;
 use handle;
 use torrent;
+use tracker;
 use disk;
 use socket::TSocket;
 use util::UHashMap;
@@ -93,6 +95,27 @@ pub enum CtlMessage {
         client: usize,
         serial: u64,
     },
+    Failed {
+        client: usize,
+        serial: u64,
+        reason: String,
+    },
+    DhtItem {
+        client: usize,
+        serial: u64,
+        item: Option<tracker::Item>,
+    },
+    DhtStored {
+        client: usize,
+        serial: u64,
+        target: [u8; 20],
+        nodes: usize,
+    },
+    DhtTable {
+        client: usize,
+        serial: u64,
+        nodes: Vec<resource::DhtNode>,
+    },
     Shutdown,
 }
 
@@ -103,12 +126,19 @@ pub enum Message {
         id: String,
         throttle_up: Option<Option<i64>>,
         throttle_down: Option<Option<i64>>,
+        alt_throttle: Option<bool>,
     },
     UpdateFile {
         id: String,
         torrent_id: String,
         priority: u8,
     },
+    /// Byte ranges(offset and length) of a file being downloaded over HTTP
+    Stream {
+        id: String,
+        torrent_id: String,
+        ranges: Vec<(u64, u64)>,
+    },
     RemoveTorrent {
         id: String,
         client: usize,
@@ -140,6 +170,10 @@ pub enum Message {
         id: String,
         torrent_id: String,
     },
+    ScrapeTracker {
+        id: String,
+        torrent_id: String,
+    },
     RemoveTracker {
         id: String,
         torrent_id: String,
@@ -153,6 +187,43 @@ pub enum Message {
         path: Option<String>,
         start: bool,
     },
+    CreateTorrent {
+        info: torrent::Info,
+        /// File or directory the torrent is created from
+        path: PathBuf,
+        client: usize,
+        serial: u64,
+        start: bool,
+    },
+    DhtGet {
+        client: usize,
+        serial: u64,
+        key: tracker::Key,
+    },
+    DhtPut {
+        client: usize,
+        serial: u64,
+        item: tracker::Item,
+        cas: Option<i64>,
+    },
+    DhtBootstrap(SocketAddr),
+    DhtTable {
+        client: usize,
+        serial: u64,
+    },
+    AddBan {
+        ip: IpAddr,
+        duration: Option<u64>,
+        client: usize,
+        serial: u64,
+    },
+    RemoveBan {
+        id: String,
+        ip: IpAddr,
+        client: usize,
+        serial: u64,
+    },
+    ReloadIpFilter,
 }
 
 #[allow(dead_code)]
@@ -441,9 +512,25 @@ impl RPC {
                                 },
                             ]
                         };
+                        let stream = self.processor.get_dl_torrent(&id).map(|torrent_id| {
+                            disk::StreamCtx {
+                                torrent_id,
+                                id: id.clone(),
+                                ranges: r.iter().map(|r| (r.start, r.length)).collect(),
+                            }
+                        });
+                        if let Some(ref ctx) = stream {
+                            self.ch
+                                .send(Message::Stream {
+                                    id: ctx.id.clone(),
+                                    torrent_id: ctx.torrent_id.clone(),
+                                    ranges: ctx.ranges.clone(),
+                                })
+                                .ok();
+                        }
                         debug!("Initiating DL");
                         self.disk
-                            .send(disk::Request::download(conn, path, r, ranged, size))
+                            .send(disk::Request::download(conn, path, r, ranged, size, stream))
                             .ok();
                     } else {
                         debug!("ID {} invalid, stopping DL", id);
@@ -2,7 +2,7 @@ use std::collections::{HashMap, HashSet};
 use std::mem;
 use std::io::Read;
 use std::fs::OpenOptions;
-use std::path::Path;
+use std::path::{Component, Path};
 use std::borrow::Cow;
 
 use amy;
@@ -16,9 +16,10 @@ use super::proto::criterion::{self, Criterion, Operation};
 use super::proto::resource::{merge_json, Resource, ResourceKind, SResourceUpdate};
 use super::{CtlMessage, Message};
 use CONFIG;
-use disk;
+use {bencode, disk, tracker};
 use torrent::info::Info;
-use util::{random_string, FHashMap, FHashSet, MHashSet, SHashMap};
+use util::{hash_to_id, id_to_hash, id_to_hash_v2, random_string, FHashMap, FHashSet, MHashSet,
+           SHashMap};
 
 const USER_DATA_FILE: &'static str = "rpc_user_data";
 type RpcDiskFmt = SHashMap<Vec<u8>>;
@@ -112,7 +113,7 @@ impl Processor {
             resources: SHashMap::default(),
             tokens: SHashMap::default(),
             torrent_idx: SHashMap::default(),
-            kinds: vec![MHashSet::default(); 6],
+            kinds: vec![MHashSet::default(); 8],
             db,
             user_data,
         }
@@ -132,6 +133,14 @@ impl Processor {
         }
     }
 
+    /// Returns the torrent id of a file being downloaded
+    pub fn get_dl_torrent(&self, id: &str) -> Option<String> {
+        match self.resources.get(id) {
+            Some(&Resource::File(ref f)) => Some(f.torrent_id.clone()),
+            _ => None,
+        }
+    }
+
     pub fn get_transfer(&mut self, tok: String) -> Option<(usize, u64, TransferKind)> {
         let mut res = None;
         let rem = match self.tokens.get(&tok) {
@@ -247,6 +256,7 @@ impl Processor {
                             id: resource.id,
                             throttle_up: resource.throttle_up,
                             throttle_down: resource.throttle_down,
+                            alt_throttle: resource.alt_throttle,
                         });
                     }
                     Some(_) => {}
@@ -287,10 +297,21 @@ impl Processor {
                         serial,
                     });
                 }
+                Some(&Resource::Ban(ref b)) => match b.ip.parse() {
+                    Ok(ip) => {
+                        rmsg = Some(Message::RemoveBan {
+                            id,
+                            ip,
+                            client,
+                            serial,
+                        });
+                    }
+                    Err(_) => error!("Ban resource has invalid ip {}", b.ip),
+                },
                 Some(_) => {
                     resp.push(SMessage::InvalidResource(Error {
                         serial: Some(serial),
-                        reason: format!("Only torrents, trackers, and peers may be removed"),
+                        reason: format!("Only torrents, trackers, peers, and bans may be removed"),
                     }));
                 }
                 None => {
@@ -454,6 +475,22 @@ impl Processor {
                     reason: format!("Unknown resource {}", id),
                 })),
             },
+            CMessage::ScrapeTracker { serial, id } => match self.resources.get(&id) {
+                Some(&Resource::Tracker(ref t)) => {
+                    rmsg = Some(Message::ScrapeTracker {
+                        id,
+                        torrent_id: t.torrent_id.clone(),
+                    })
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "SCRAPE_TRACKER not used with tracker".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
             CMessage::ValidateResources { serial, mut ids } => {
                 ids.retain(|id| match self.resources.get(id) {
                     Some(&Resource::Torrent(_)) => true,
@@ -515,6 +552,103 @@ impl Processor {
                     TransferKind::UploadFiles { size, path },
                 ));
             }
+            CMessage::CreateTorrent {
+                serial,
+                path,
+                piece_size,
+                trackers,
+                web_seeds,
+                private,
+                comment,
+                start,
+            } => {
+                let res = create_info(&path, piece_size, trackers, web_seeds, private, comment);
+                match res {
+                    Ok(info) => {
+                        rmsg = Some(Message::CreateTorrent {
+                            info,
+                            path: Path::new(&CONFIG.disk.directory).join(path),
+                            client,
+                            serial,
+                            start,
+                        })
+                    }
+                    Err(reason) => {
+                        resp.push(SMessage::InvalidRequest(Error {
+                            serial: Some(serial),
+                            reason,
+                        }));
+                    }
+                }
+            }
+            CMessage::DhtGet {
+                serial,
+                target,
+                key,
+                salt,
+            } => match dht_key(target, key, salt) {
+                Ok(key) => rmsg = Some(Message::DhtGet { client, serial, key }),
+                Err(reason) => {
+                    resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason,
+                    }));
+                }
+            },
+            CMessage::DhtPut {
+                serial,
+                value,
+                secret_key,
+                salt,
+                seq,
+                cas,
+            } => match dht_item(value, secret_key, salt, seq) {
+                Ok(item) => {
+                    rmsg = Some(Message::DhtPut {
+                        client,
+                        serial,
+                        item,
+                        cas,
+                    })
+                }
+                Err(reason) => {
+                    resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason,
+                    }));
+                }
+            },
+            CMessage::DhtBootstrap { serial, addr } => match addr.parse() {
+                Ok(addr) => rmsg = Some(Message::DhtBootstrap(addr)),
+                Err(_) => resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: format!("Invalid node address: {}", addr),
+                })),
+            },
+            CMessage::DhtTable { serial } => {
+                rmsg = Some(Message::DhtTable { client, serial });
+            }
+            CMessage::AddBan {
+                serial,
+                ip,
+                duration,
+            } => match ip.parse() {
+                Ok(ip) => {
+                    rmsg = Some(Message::AddBan {
+                        ip,
+                        duration,
+                        client,
+                        serial,
+                    })
+                }
+                Err(_) => resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: format!("Invalid IP address: {}", ip),
+                })),
+            },
+            CMessage::ReloadIpFilter { .. } => {
+                rmsg = Some(Message::ReloadIpFilter);
+            }
         }
         (resp, rmsg)
     }
@@ -631,6 +765,55 @@ impl Processor {
                     debug!("Failed to get resource uploaded: {}!", id);
                 }
             }
+            CtlMessage::Failed {
+                client,
+                serial,
+                reason,
+            } => {
+                msgs.push((
+                    client,
+                    SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason,
+                    }),
+                ));
+            }
+            CtlMessage::DhtItem {
+                client,
+                serial,
+                item,
+            } => {
+                msgs.push((
+                    client,
+                    SMessage::DhtItem {
+                        serial,
+                        value: item.as_ref().map(|i| dht_value(i.value())),
+                        seq: item.as_ref().and_then(|i| i.seq()),
+                    },
+                ));
+            }
+            CtlMessage::DhtStored {
+                client,
+                serial,
+                target,
+                nodes,
+            } => {
+                msgs.push((
+                    client,
+                    SMessage::DhtStored {
+                        serial,
+                        target: hash_to_id(&target),
+                        nodes: nodes as u32,
+                    },
+                ));
+            }
+            CtlMessage::DhtTable {
+                client,
+                serial,
+                nodes,
+            } => {
+                msgs.push((client, SMessage::DhtTable { serial, nodes }));
+            }
             CtlMessage::Shutdown => unreachable!(),
         }
         msgs
@@ -704,3 +887,104 @@ impl Filter {
         self.criteria.iter().all(|c| c.matches(r))
     }
 }
+
+fn create_info(
+    path: &str,
+    piece_size: Option<u32>,
+    trackers: Vec<String>,
+    web_seeds: Vec<String>,
+    private: bool,
+    comment: Option<String>,
+) -> Result<Info, String> {
+    let p = Path::new(path);
+    if p.is_absolute() || p.components().any(|c| c == Component::ParentDir) {
+        return Err(format!(
+            "Path must be relative to the download directory: {}",
+            path
+        ));
+    }
+    let piece_len = match piece_size {
+        Some(s) if s < 16_384 || !s.is_power_of_two() => {
+            return Err(format!(
+                "Piece size must be a power of two of at least 16384: {}",
+                s
+            ));
+        }
+        Some(s) => s,
+        None => 0,
+    };
+    let mut tracker_urls = Vec::new();
+    for uri in trackers {
+        match Url::parse(&uri) {
+            Ok(u) => tracker_urls.push(u),
+            Err(_) => return Err(format!("Invalid tracker URI: {}", uri)),
+        }
+    }
+    let mut web_seed_urls = Vec::new();
+    for uri in web_seeds {
+        match Url::parse(&uri) {
+            Ok(ref u) if u.scheme() == "http" || u.scheme() == "https" => {
+                web_seed_urls.push(u.clone())
+            }
+            _ => return Err(format!("Invalid web seed URI: {}", uri)),
+        }
+    }
+    Info::create(
+        Path::new(path),
+        piece_len,
+        tracker_urls,
+        web_seed_urls,
+        private,
+        comment,
+    ).map_err(|e| format!("Invalid path: {}", e))
+}
+
+/// Parses the key of a DHT item, either the target of an
+/// immutable item or the public key of a mutable one.
+fn dht_key(
+    target: Option<String>,
+    key: Option<String>,
+    salt: String,
+) -> Result<tracker::Key, String> {
+    match (target, key) {
+        (Some(t), None) => match id_to_hash(&t) {
+            Some(t) => Ok(tracker::Key::Immutable(t)),
+            None => Err(format!("Invalid target: {}", t)),
+        },
+        (None, Some(k)) => match id_to_hash_v2(&k) {
+            Some(k) => Ok(tracker::Key::Mutable {
+                k: k.to_vec(),
+                salt: salt.into_bytes(),
+            }),
+            None => Err(format!("Invalid public key: {}", k)),
+        },
+        _ => Err("Either a target or a public key must be given".to_owned()),
+    }
+}
+
+/// Builds a DHT item storing the value as a bencoded string,
+/// which is mutable and signed if a secret key is given.
+fn dht_item(
+    value: String,
+    secret_key: Option<String>,
+    salt: String,
+    seq: i64,
+) -> Result<tracker::Item, String> {
+    let v = bencode::BEncode::String(value.into_bytes()).encode_to_buf();
+    let item = match secret_key {
+        Some(sk) => id_to_hash_v2(&sk)
+            .and_then(|seed| tracker::Item::mutable(&seed, salt.into_bytes(), seq, v))
+            .ok_or_else(|| "Invalid secret key".to_owned())?,
+        None => tracker::Item::immutable(v),
+    };
+    item.verify().map_err(|e| format!("Invalid item: {}", e))?;
+    Ok(item)
+}
+
+/// Returns the value of a DHT item, bencoded unless it's a string
+fn dht_value(v: &[u8]) -> String {
+    match bencode::decode_buf(v).ok().and_then(|b| b.into_bytes()) {
+        Some(s) => String::from_utf8_lossy(&s).into_owned(),
+        None => String::from_utf8_lossy(v).into_owned(),
+    }
+}
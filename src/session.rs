@@ -1,11 +1,23 @@
 pub mod torrent {
-    pub use self::ver_fa1b6f as current;
+    pub use self::ver_a5c3d1 as current;
     pub use self::current::Session;
     use bincode;
 
     pub fn load(data: &[u8]) -> Option<Session> {
-        if let Ok(m) = bincode::deserialize::<ver_fa1b6f::Session>(data) {
+        if let Ok(m) = bincode::deserialize::<ver_a5c3d1::Session>(data) {
             Some(m)
+        } else if let Ok(m) = bincode::deserialize::<ver_b07c4e::Session>(data) {
+            info!("Migrating torrent session from vb07c4e");
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_5ad9e1::Session>(data) {
+            info!("Migrating torrent session from v5ad9e1");
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_c8e0a4::Session>(data) {
+            info!("Migrating torrent session from vc8e0a4");
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_fa1b6f::Session>(data) {
+            info!("Migrating torrent session from vfa1b6f");
+            Some(m.migrate())
         } else if let Ok(m) = bincode::deserialize::<ver_6e27af::Session>(data) {
             info!("Migrating torrent session from v6e27af");
             Some(m.migrate())
@@ -29,11 +41,12 @@ pub mod torrent {
         }
     }
 
-    pub mod ver_fa1b6f {
+    pub mod ver_a5c3d1 {
         use torrent::Bitfield;
 
         use chrono::{DateTime, Utc};
 
+        use std::collections::BTreeMap;
         use std::path::PathBuf;
 
         #[derive(Serialize, Deserialize)]
@@ -50,6 +63,12 @@ pub mod torrent {
             pub throttle_ul: Option<i64>,
             pub throttle_dl: Option<i64>,
             pub trackers: Vec<String>,
+            pub queue_position: u32,
+            pub seed_ratio: Option<f32>,
+            pub seed_time: Option<i64>,
+            pub seed_idle: Option<i64>,
+            pub seeding_time: u64,
+            pub idle_time: u64,
         }
 
         #[derive(Clone, Serialize, Deserialize)]
@@ -62,16 +81,21 @@ pub mod torrent {
             pub total_len: u64,
             pub hashes: Vec<Vec<u8>>,
             pub hash: [u8; 20],
+            pub hash_v2: Option<[u8; 32]>,
+            pub piece_layers: BTreeMap<[u8; 32], Vec<u8>>,
             pub files: Vec<File>,
             pub private: bool,
             pub be_name: Option<Vec<u8>>,
             pub piece_idx: Vec<(usize, u64)>,
+            pub web_seeds: Vec<String>,
         }
 
         #[derive(Serialize, Deserialize, Clone, Debug)]
         pub struct File {
             pub path: PathBuf,
             pub length: u64,
+            pub padding: bool,
+            pub pieces_root: Option<[u8; 32]>,
         }
 
         #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -92,6 +116,283 @@ pub mod torrent {
         }
     }
 
+    pub mod ver_b07c4e {
+        pub use super::ver_a5c3d1 as next;
+        pub use self::next::{Status, StatusState};
+
+        use torrent::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::collections::BTreeMap;
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub queue_position: u32,
+            pub seed_ratio: Option<f32>,
+            pub seed_time: Option<i64>,
+            pub seed_idle: Option<i64>,
+            pub seeding_time: u64,
+            pub idle_time: u64,
+        }
+
+        #[derive(Clone, Serialize, Deserialize)]
+        pub struct Info {
+            pub name: String,
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub piece_len: u32,
+            pub total_len: u64,
+            pub hashes: Vec<Vec<u8>>,
+            pub hash: [u8; 20],
+            pub files: Vec<File>,
+            pub private: bool,
+            pub be_name: Option<Vec<u8>>,
+            pub piece_idx: Vec<(usize, u64)>,
+            pub web_seeds: Vec<String>,
+        }
+
+        #[derive(Serialize, Deserialize, Clone, Debug)]
+        pub struct File {
+            pub path: PathBuf,
+            pub length: u64,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: next::Info {
+                        name: self.info.name,
+                        announce: self.info.announce,
+                        creator: self.info.creator,
+                        comment: self.info.comment,
+                        piece_len: self.info.piece_len,
+                        total_len: self.info.total_len,
+                        hashes: self.info.hashes,
+                        hash: self.info.hash,
+                        hash_v2: None,
+                        piece_layers: BTreeMap::new(),
+                        files: self.info
+                            .files
+                            .into_iter()
+                            .map(|f| next::File {
+                                path: f.path,
+                                length: f.length,
+                                padding: false,
+                                pieces_root: None,
+                            })
+                            .collect(),
+                        private: self.info.private,
+                        be_name: self.info.be_name,
+                        piece_idx: self.info.piece_idx,
+                        web_seeds: self.info.web_seeds,
+                    },
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    queue_position: self.queue_position,
+                    seed_ratio: self.seed_ratio,
+                    seed_time: self.seed_time,
+                    seed_idle: self.seed_idle,
+                    seeding_time: self.seeding_time,
+                    idle_time: self.idle_time,
+                }.migrate()
+            }
+        }
+    }
+
+    pub mod ver_5ad9e1 {
+        pub use super::ver_b07c4e as next;
+        pub use self::next::{File, Info, Status, StatusState};
+
+        use torrent::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub queue_position: u32,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    queue_position: self.queue_position,
+                    seed_ratio: None,
+                    seed_time: None,
+                    seed_idle: None,
+                    seeding_time: 0,
+                    idle_time: 0,
+                }.migrate()
+            }
+        }
+    }
+
+    pub mod ver_c8e0a4 {
+        pub use super::ver_5ad9e1 as next;
+        pub use self::next::{File, Info, Status, StatusState};
+
+        use torrent::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    queue_position: 0,
+                }.migrate()
+            }
+        }
+    }
+
+    pub mod ver_fa1b6f {
+        pub use super::ver_c8e0a4 as next;
+        pub use self::next::{File, Status, StatusState};
+
+        use torrent::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+        }
+
+        #[derive(Clone, Serialize, Deserialize)]
+        pub struct Info {
+            pub name: String,
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub piece_len: u32,
+            pub total_len: u64,
+            pub hashes: Vec<Vec<u8>>,
+            pub hash: [u8; 20],
+            pub files: Vec<File>,
+            pub private: bool,
+            pub be_name: Option<Vec<u8>>,
+            pub piece_idx: Vec<(usize, u64)>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: next::Info {
+                        name: self.info.name,
+                        announce: self.info.announce,
+                        creator: self.info.creator,
+                        comment: self.info.comment,
+                        piece_len: self.info.piece_len,
+                        total_len: self.info.total_len,
+                        hashes: self.info.hashes,
+                        hash: self.info.hash,
+                        files: self.info.files,
+                        private: self.info.private,
+                        be_name: self.info.be_name,
+                        piece_idx: self.info.piece_idx,
+                        web_seeds: vec![],
+                    },
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                }.migrate()
+            }
+        }
+    }
+
     pub mod ver_6e27af {
         pub use super::ver_fa1b6f as next;
         pub use self::next::{File, Status, StatusState};
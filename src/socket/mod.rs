@@ -0,0 +1,551 @@
+mod mse;
+pub mod utp;
+
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::Arc;
+use std::{cmp, mem};
+
+use nix::libc;
+use net2::{TcpBuilder, TcpStreamExt};
+use openssl::ssl::{HandshakeError, MidHandshakeSslStream, SslAcceptor, SslConnector, SslMethod,
+                   SslStream};
+
+use config::Encryption;
+use throttle::Throttle;
+use util::{self, MHashSet};
+use self::mse::{Peek, RC4};
+
+/// Wrapper type over Mio sockets, allowing for use of UDP/TCP, encryption,
+/// rate limiting, etc.
+pub struct Socket {
+    conn: Conn,
+    addr: SocketAddr,
+    pub throttle: Option<Throttle>,
+    crypt: Crypt,
+    /// Plaintext received during an MSE handshake which has
+    /// yet to be read
+    buf: Vec<u8>,
+}
+
+/// Transport used by a peer connection
+enum Conn {
+    TCP(TcpStream),
+    UTP(utp::Stream),
+}
+
+enum Crypt {
+    Plain,
+    Handshake(Box<mse::Handshake>),
+    RC4 { rx: RC4, tx: RC4 },
+}
+
+impl Socket {
+    pub fn new(addr: &SocketAddr) -> io::Result<Socket> {
+        Ok(Socket {
+            conn: Conn::TCP(connect_tcp(addr)?),
+            throttle: None,
+            addr: *addr,
+            crypt: Crypt::Plain,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Creates an outgoing uTP socket, which connects once it
+    /// is started over a UDP socket.
+    pub fn new_utp(addr: &SocketAddr) -> Socket {
+        Socket::from_utp(utp::Stream::new(*addr))
+    }
+
+    pub fn from_utp(stream: utp::Stream) -> Socket {
+        Socket {
+            addr: stream.addr(),
+            conn: Conn::UTP(stream),
+            throttle: None,
+            crypt: Crypt::Plain,
+            buf: Vec::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn empty() -> Socket {
+        let conn = TcpBuilder::new_v4().unwrap().to_tcp_stream().unwrap();
+        Socket {
+            conn: Conn::TCP(conn),
+            throttle: None,
+            addr: "127.0.0.1:0".parse().unwrap(),
+            crypt: Crypt::Plain,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn from_stream(conn: TcpStream) -> io::Result<Socket> {
+        conn.set_nonblocking(true)?;
+        let addr = conn.peer_addr()?;
+        Ok(Socket {
+            conn: Conn::TCP(conn),
+            throttle: None,
+            addr: addr,
+            crypt: Crypt::Plain,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Returns the uTP stream of the socket, if it uses uTP
+    pub fn utp(&self) -> Option<&utp::Stream> {
+        match self.conn {
+            Conn::UTP(ref s) => Some(s),
+            Conn::TCP(_) => None,
+        }
+    }
+
+    pub fn utp_mut(&mut self) -> Option<&mut utp::Stream> {
+        match self.conn {
+            Conn::UTP(ref mut s) => Some(s),
+            Conn::TCP(_) => None,
+        }
+    }
+
+    /// Starts connecting an outgoing uTP socket
+    pub fn utp_connect(&mut self, sock: Arc<UdpSocket>, recv_id: u16) {
+        if let Conn::UTP(ref mut s) = self.conn {
+            s.connect(sock, recv_id);
+        }
+    }
+
+    /// Replaces the transport of an unstarted uTP socket with TCP
+    pub fn fallback_tcp(&mut self) -> io::Result<()> {
+        self.conn = Conn::TCP(connect_tcp(&self.addr)?);
+        Ok(())
+    }
+
+    /// Begins an MSE handshake as the initiator for
+    /// the torrent with the given info hash.
+    pub fn initiate_mse(&mut self, hash: [u8; 20], policy: Encryption) -> io::Result<()> {
+        let hs = mse::Handshake::initiate(hash, policy)?;
+        self.crypt = Crypt::Handshake(Box::new(hs));
+        Ok(())
+    }
+
+    /// Prepares the socket to receive either a plaintext or MSE
+    /// handshake for any of the given info hashes.
+    pub fn accept_mse(
+        &mut self,
+        hashes: Arc<MHashSet<[u8; 20]>>,
+        policy: Encryption,
+    ) -> io::Result<()> {
+        let hs = mse::Handshake::accept(hashes, policy)?;
+        self.crypt = Crypt::Handshake(Box::new(hs));
+        Ok(())
+    }
+
+    pub fn handshaking(&self) -> bool {
+        match self.crypt {
+            Crypt::Handshake(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn encrypted(&self) -> bool {
+        match self.crypt {
+            Crypt::Plain => false,
+            _ => true,
+        }
+    }
+
+    /// Advances any in progress MSE handshake, returning true
+    /// once the socket is ready to be used for regular IO.
+    pub fn handshake(&mut self) -> io::Result<bool> {
+        let res = match self.crypt {
+            Crypt::Handshake(ref mut hs) => hs.advance(&mut self.conn)?,
+            _ => return Ok(true),
+        };
+        match res {
+            Some(est) => {
+                self.buf = est.data;
+                self.crypt = match est.cipher {
+                    Some((rx, tx)) => Crypt::RC4 { rx, tx },
+                    None => Crypt::Plain,
+                };
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Note that uTP sockets have no fd of their own, and can't be
+/// registered for events.
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self.conn {
+            Conn::TCP(ref c) => c.as_raw_fd(),
+            Conn::UTP(_) => -1,
+        }
+    }
+}
+
+impl io::Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Conn::TCP(ref mut c) => c.read(buf),
+            Conn::UTP(ref mut c) => c.read(buf),
+        }
+    }
+}
+
+impl io::Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Conn::TCP(ref mut c) => c.write(buf),
+            Conn::UTP(ref mut c) => c.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Conn::TCP(ref mut c) => c.flush(),
+            Conn::UTP(ref mut c) => c.flush(),
+        }
+    }
+}
+
+impl Peek for Conn {
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Conn::TCP(ref c) => c.peek(buf),
+            Conn::UTP(ref c) => c.peek(buf),
+        }
+    }
+}
+
+impl io::Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.buf.is_empty() {
+            let amnt = cmp::min(buf.len(), self.buf.len());
+            buf[..amnt].copy_from_slice(&self.buf[..amnt]);
+            self.buf.drain(..amnt);
+            return Ok(amnt);
+        }
+        match self.crypt {
+            Crypt::Plain => read_throttled(&mut self.conn, &mut self.throttle, buf),
+            Crypt::Handshake(_) => Err(io::Error::new(ErrorKind::WouldBlock, "")),
+            Crypt::RC4 { ref mut rx, .. } => {
+                let amnt = read_throttled(&mut self.conn, &mut self.throttle, buf)?;
+                rx.process(&mut buf[..amnt]);
+                Ok(amnt)
+            }
+        }
+    }
+}
+
+impl io::Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.crypt {
+            Crypt::Plain => write_throttled(&mut self.conn, &mut self.throttle, buf),
+            Crypt::Handshake(_) => Err(io::Error::new(ErrorKind::WouldBlock, "")),
+            Crypt::RC4 { ref mut tx, .. } => {
+                // Since the keystream can't be rewound, keep a copy of
+                // the cipher so that it can be resynced to however
+                // much data was actually written.
+                let prev = tx.clone();
+                let mut data = buf.to_vec();
+                tx.process(&mut data);
+                match write_throttled(&mut self.conn, &mut self.throttle, &data) {
+                    Ok(amnt) => {
+                        if amnt != data.len() {
+                            *tx = prev;
+                            tx.skip(amnt);
+                        }
+                        Ok(amnt)
+                    }
+                    Err(e) => {
+                        *tx = prev;
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.flush()
+    }
+}
+
+fn connect_tcp(addr: &SocketAddr) -> io::Result<TcpStream> {
+    let sock = (match *addr {
+        SocketAddr::V4(..) => TcpBuilder::new_v4(),
+        SocketAddr::V6(..) => TcpBuilder::new_v6(),
+    })?;
+    let conn = sock.to_tcp_stream()?;
+    conn.set_nonblocking(true)?;
+    if let Err(e) = conn.connect(addr) {
+        // OSX gives the AddrNotAvailable error sometimes
+        if Some(libc::EINPROGRESS) != e.raw_os_error() && e.kind() != ErrorKind::AddrNotAvailable {
+            return Err(e);
+        }
+    }
+    Ok(conn)
+}
+
+fn read_throttled(
+    conn: &mut Conn,
+    throttle: &mut Option<Throttle>,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    // Don't bother rate limiting small requests
+    if buf.len() < 20 {
+        return conn.read(buf);
+    }
+    if let Some(ref mut t) = *throttle {
+        match t.get_bytes_dl(buf.len()) {
+            Ok(()) => match conn.read(buf) {
+                Ok(amnt) => {
+                    t.restore_bytes_dl(buf.len() - amnt);
+                    Ok(amnt)
+                }
+                Err(e) => {
+                    t.restore_bytes_dl(buf.len());
+                    Err(e)
+                }
+            },
+            Err(()) => Err(io::Error::new(ErrorKind::WouldBlock, "")),
+        }
+    } else {
+        conn.read(buf)
+    }
+}
+
+fn write_throttled(
+    conn: &mut Conn,
+    throttle: &mut Option<Throttle>,
+    buf: &[u8],
+) -> io::Result<usize> {
+    if buf.len() < 20 {
+        return conn.write(buf);
+    }
+    if let Some(ref mut t) = *throttle {
+        match t.get_bytes_ul(buf.len()) {
+            Ok(()) => match conn.write(buf) {
+                Ok(amnt) => {
+                    t.restore_bytes_ul(buf.len() - amnt);
+                    Ok(amnt)
+                }
+                Err(e) => {
+                    t.restore_bytes_ul(buf.len());
+                    Err(e)
+                }
+            },
+            Err(()) => Err(io::Error::new(ErrorKind::WouldBlock, "")),
+        }
+    } else {
+        conn.write(buf)
+    }
+}
+
+pub struct TSocket {
+    conn: TConn,
+    fd: i32,
+    /// Whether this is a dual stack IPv6 socket, which connects to
+    /// IPv4 addresses through their IPv4 mapped form
+    v6: bool,
+}
+
+enum TConn {
+    Empty,
+    Plain(TcpStream),
+    // SSL Preconnection state
+    SSLP { host: String, conn: TcpStream },
+    // SSL Connecting state
+    SSLC(MidHandshakeSslStream<TcpStream>),
+    SSL(SslStream<TcpStream>),
+}
+
+impl TSocket {
+    /// Creates a socket which may connect to either IPv4 or IPv6
+    /// addresses, falling back to IPv4 only if dual stack sockets
+    /// are unavailable.
+    pub fn new(host: Option<String>) -> io::Result<TSocket> {
+        let dual = TcpBuilder::new_v6().and_then(|b| {
+            b.only_v6(false)?;
+            b.to_tcp_stream()
+        });
+        let (conn, v6) = match dual {
+            Ok(conn) => (conn, true),
+            Err(_) => (TcpBuilder::new_v4()?.to_tcp_stream()?, false),
+        };
+        conn.set_nonblocking(true)?;
+        let fd = conn.as_raw_fd();
+        let sock = match host {
+            Some(h) => TSocket {
+                conn: TConn::SSLP { host: h, conn },
+                fd,
+                v6,
+            },
+            None => TSocket {
+                conn: TConn::Plain(conn),
+                fd,
+                v6,
+            },
+        };
+        Ok(sock)
+    }
+
+    pub fn from_plain(stream: TcpStream) -> io::Result<TSocket> {
+        stream.set_nonblocking(true)?;
+        let fd = stream.as_raw_fd();
+        let v6 = stream.local_addr().map(|a| a.is_ipv6()).unwrap_or(false);
+        Ok(TSocket {
+            conn: TConn::Plain(stream),
+            fd,
+            v6,
+        })
+    }
+
+    pub fn from_ssl(stream: TcpStream, acceptor: &SslAcceptor) -> io::Result<TSocket> {
+        stream.set_nonblocking(true)?;
+        let fd = stream.as_raw_fd();
+        let v6 = stream.local_addr().map(|a| a.is_ipv6()).unwrap_or(false);
+
+        let conn = match acceptor.accept(stream) {
+            Ok(c) => TConn::SSL(c),
+            Err(HandshakeError::WouldBlock(s)) => TConn::SSLC(s),
+            Err(_) => return util::io_err("SSL Connection failed!"),
+        };
+        Ok(TSocket { conn, fd, v6 })
+    }
+
+    pub fn connect(&mut self, addr: SocketAddr) -> io::Result<()> {
+        let addr = match addr {
+            SocketAddr::V4(a) if self.v6 => {
+                SocketAddr::new(IpAddr::V6(a.ip().to_ipv6_mapped()), a.port())
+            }
+            a => a,
+        };
+        let c = mem::replace(&mut self.conn, TConn::Empty);
+        self.conn = match c {
+            TConn::Plain(c) => {
+                if let Err(e) = c.connect(addr) {
+                    if Some(libc::EINPROGRESS) != e.raw_os_error() {
+                        return Err(e);
+                    }
+                }
+                TConn::Plain(c)
+            }
+            TConn::SSLP { host, conn } => {
+                if let Err(e) = conn.connect(addr) {
+                    if Some(libc::EINPROGRESS) != e.raw_os_error() {
+                        return Err(e);
+                    }
+                }
+                let connector = if let Ok(b) = SslConnector::builder(SslMethod::tls()) {
+                    b.build()
+                } else {
+                    return util::io_err("SSL Connection failed!");
+                };
+                match connector.connect(&host, conn) {
+                    Ok(s) => TConn::SSL(s),
+                    Err(HandshakeError::WouldBlock(s)) => TConn::SSLC(s),
+                    Err(_) => return util::io_err("SSL Connection failed!"),
+                }
+            }
+            _ => return util::io_err("Socket in failed state!"),
+        };
+        Ok(())
+    }
+}
+
+impl io::Read for TSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let c = mem::replace(&mut self.conn, TConn::Empty);
+        let res;
+        self.conn = match c {
+            TConn::Plain(mut c) => {
+                res = c.read(buf);
+                TConn::Plain(c)
+            }
+            TConn::SSLC(conn) => match conn.handshake() {
+                Ok(s) => {
+                    res = Ok(::std::usize::MAX);
+                    TConn::SSL(s)
+                }
+                Err(HandshakeError::WouldBlock(s)) => {
+                    res = Err(io::Error::from(io::ErrorKind::WouldBlock));
+                    TConn::SSLC(s)
+                }
+                Err(_) => {
+                    res = util::io_err("SSL Connection failed!");
+                    TConn::Empty
+                }
+            },
+            TConn::SSL(mut conn) => {
+                res = conn.read(buf);
+                TConn::SSL(conn)
+            }
+            _ => return util::io_err("Socket in failed state!"),
+        };
+
+        if let Ok(::std::usize::MAX) = res {
+            debug!("SSL upgrade succeeded!");
+            self.read(buf)
+        } else {
+            res
+        }
+    }
+}
+
+impl io::Write for TSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let c = mem::replace(&mut self.conn, TConn::Empty);
+        let res;
+        self.conn = match c {
+            TConn::Plain(mut c) => {
+                res = c.write(buf);
+                TConn::Plain(c)
+            }
+            TConn::SSLC(conn) => match conn.handshake() {
+                Ok(s) => {
+                    res = Ok(::std::usize::MAX);
+                    TConn::SSL(s)
+                }
+                Err(HandshakeError::WouldBlock(s)) => {
+                    res = Err(io::Error::from(io::ErrorKind::WouldBlock));
+                    TConn::SSLC(s)
+                }
+                Err(_) => return util::io_err("SSL Connection failed!"),
+            },
+            TConn::SSL(mut conn) => {
+                res = conn.write(buf);
+                TConn::SSL(conn)
+            }
+            _ => return util::io_err("Socket in failed state!"),
+        };
+
+        if let Ok(::std::usize::MAX) = res {
+            debug!("SSL upgrade succeeded!");
+            self.write(buf)
+        } else {
+            res
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.conn {
+            TConn::Plain(ref mut c) => c.flush(),
+            TConn::SSL(ref mut c) => c.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl AsRawFd for TSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
@@ -0,0 +1,642 @@
+//! Message stream encryption (MSE/PE), used to obfuscate peer connections.
+//! The handshake is a DH key exchange followed by an RC4 stream keyed from
+//! the shared secret and the torrent's info hash.
+
+use std::{cmp, mem};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use openssl::bn::{BigNum, BigNumContext, MsbOption};
+use rand::{self, Rng};
+use sha1;
+
+use config::Encryption;
+use util::{find_subseq, io_err, io_err_val, MHashSet};
+
+/// 768 bit prime used for the key exchange
+const PRIME: &'static str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+                             020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+                             4FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+const KEY_LEN: usize = 96;
+const PRIV_KEY_BITS: i32 = 160;
+const MAX_PAD: usize = 512;
+const VC: [u8; 8] = [0u8; 8];
+/// Amount of initial RC4 keystream discarded
+const RC4_DISCARD: usize = 1024;
+const CRYPTO_PLAIN: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+const BT_PROTO: &'static [u8] = b"\x13BitTorrent protocol";
+
+/// A stream whose received data can be inspected without consuming it
+pub trait Peek: Read + Write {
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+impl Peek for TcpStream {
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        TcpStream::peek(self, buf)
+    }
+}
+
+#[derive(Clone)]
+pub struct RC4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+pub struct Handshake {
+    role: Role,
+    state: State,
+    policy: Encryption,
+    private: BigNum,
+    secret: Vec<u8>,
+    skey: [u8; 20],
+    select: u32,
+    rx: Option<RC4>,
+    tx: Option<RC4>,
+    /// Data which has been read for the current state
+    buf: Vec<u8>,
+    /// Data waiting to be written
+    out: Vec<u8>,
+    /// Initial payload sent by the initiator
+    payload: Vec<u8>,
+}
+
+/// Result of a completed handshake
+pub struct Established {
+    /// RC4 streams for reading and writing, or None if the
+    /// connection will continue in plaintext
+    pub cipher: Option<(RC4, RC4)>,
+    /// Plaintext data received during the handshake
+    pub data: Vec<u8>,
+}
+
+enum Role {
+    Initiator,
+    Receiver(Arc<MHashSet<[u8; 20]>>),
+}
+
+enum State {
+    /// Determining whether or not the remote is using a plaintext handshake
+    Detect,
+    PublicKey,
+    /// Scanning past PadA for HASH('req1', S)
+    SyncReq,
+    /// Obfuscated SKEY, VC, crypto_provide and len(PadC)
+    Provide,
+    PadC(usize),
+    IALen,
+    IA(usize),
+    /// Scanning past PadB for the encrypted VC
+    SyncVC,
+    /// crypto_select and len(PadD)
+    Select,
+    PadD(usize),
+    Done,
+}
+
+impl RC4 {
+    pub fn new(key: &[u8]) -> RC4 {
+        let mut s = [0u8; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        RC4 { s, i: 0, j: 0 }
+    }
+
+    pub fn process(&mut self, data: &mut [u8]) {
+        for b in data {
+            *b ^= self.next_byte();
+        }
+    }
+
+    pub fn skip(&mut self, amnt: usize) {
+        for _ in 0..amnt {
+            self.next_byte();
+        }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        self.i = self.i.wrapping_add(1);
+        self.j = self.j.wrapping_add(self.s[self.i as usize]);
+        self.s.swap(self.i as usize, self.j as usize);
+        let idx = self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+        self.s[idx as usize]
+    }
+}
+
+impl Handshake {
+    /// Creates a handshake for an outgoing connection to a peer of
+    /// the torrent with the given info hash.
+    pub fn initiate(skey: [u8; 20], policy: Encryption) -> io::Result<Handshake> {
+        let mut hs = Handshake::new(Role::Initiator, State::PublicKey, policy)?;
+        hs.skey = skey;
+        hs.write_public_key()?;
+        Ok(hs)
+    }
+
+    /// Creates a handshake for an incoming connection, which may be
+    /// for any of the info hashes provided.
+    pub fn accept(skeys: Arc<MHashSet<[u8; 20]>>, policy: Encryption) -> io::Result<Handshake> {
+        Handshake::new(Role::Receiver(skeys), State::Detect, policy)
+    }
+
+    fn new(role: Role, state: State, policy: Encryption) -> io::Result<Handshake> {
+        let mut private = BigNum::new().map_err(|_| io_err_val("MSE key generation failed"))?;
+        private
+            .rand(PRIV_KEY_BITS, MsbOption::MAYBE_ZERO, false)
+            .map_err(|_| io_err_val("MSE key generation failed"))?;
+        Ok(Handshake {
+            role,
+            state,
+            policy,
+            private,
+            secret: Vec::new(),
+            skey: [0u8; 20],
+            select: 0,
+            rx: None,
+            tx: None,
+            buf: Vec::new(),
+            out: Vec::new(),
+            payload: Vec::new(),
+        })
+    }
+
+    /// Advances the handshake as far as possible, returning the negotiated
+    /// stream once it has completed.
+    pub fn advance<S: Peek>(&mut self, conn: &mut S) -> io::Result<Option<Established>> {
+        loop {
+            self.flush(conn)?;
+            let progressed = match self.state {
+                State::Detect => self.detect(conn)?,
+                State::PublicKey => self.read_public_key(conn)?,
+                State::SyncReq => self.sync_req(conn)?,
+                State::Provide => self.read_provide(conn)?,
+                State::PadC(len) => self.read_pad_c(conn, len)?,
+                State::IALen => self.read_ia_len(conn)?,
+                State::IA(len) => self.read_ia(conn, len)?,
+                State::SyncVC => self.sync_vc(conn)?,
+                State::Select => self.read_select(conn)?,
+                State::PadD(len) => self.read_pad_d(conn, len)?,
+                State::Done => false,
+            };
+            if !progressed {
+                break;
+            }
+        }
+
+        if let State::Done = self.state {
+            if self.out.is_empty() {
+                let cipher = if self.select == CRYPTO_RC4 {
+                    Some((self.rx.take().unwrap(), self.tx.take().unwrap()))
+                } else {
+                    None
+                };
+                return Ok(Some(Established {
+                    cipher,
+                    data: mem::replace(&mut self.payload, Vec::new()),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    fn detect<S: Peek>(&mut self, conn: &mut S) -> io::Result<bool> {
+        let mut data = [0u8; 20];
+        let amnt = match conn.peek(&mut data) {
+            Ok(0) => return io_err("EOF"),
+            Ok(a) => a,
+            Err(ref e) if blocked(e) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if amnt < BT_PROTO.len() {
+            // A short read which doesn't match the plaintext handshake
+            // can only be the start of a public key
+            if data[..amnt] == BT_PROTO[..amnt] {
+                return Ok(false);
+            }
+        } else if &data[..] == BT_PROTO {
+            if self.policy == Encryption::Require {
+                return io_err("Plaintext handshake rejected");
+            }
+            self.select = CRYPTO_PLAIN;
+            self.state = State::Done;
+            return Ok(true);
+        }
+        self.state = State::PublicKey;
+        Ok(true)
+    }
+
+    fn read_public_key<S: Peek>(&mut self, conn: &mut S) -> io::Result<bool> {
+        if !self.fill(conn, KEY_LEN)? {
+            return Ok(false);
+        }
+        self.secret = self.compute_secret()?;
+        self.buf.clear();
+        match self.role {
+            Role::Initiator => {
+                let mut rx = RC4::new(&hash(&[b"keyB", &self.secret, &self.skey]));
+                let mut tx = RC4::new(&hash(&[b"keyA", &self.secret, &self.skey]));
+                rx.skip(RC4_DISCARD);
+                tx.skip(RC4_DISCARD);
+
+                let provide = if self.policy == Encryption::Require {
+                    CRYPTO_RC4
+                } else {
+                    CRYPTO_RC4 | CRYPTO_PLAIN
+                };
+                let mut data = Vec::with_capacity(16);
+                data.extend_from_slice(&VC);
+                data.write_u32::<BigEndian>(provide).unwrap();
+                // No PadC and no initial payload, the BT handshake will be
+                // sent through the stream afterwards.
+                data.write_u16::<BigEndian>(0).unwrap();
+                data.write_u16::<BigEndian>(0).unwrap();
+                tx.process(&mut data);
+
+                let req1 = hash(&[b"req1", &self.secret]);
+                let req2 = hash(&[b"req2", &self.skey]);
+                let req3 = hash(&[b"req3", &self.secret]);
+                self.out.extend_from_slice(&req1);
+                self.out.extend(req2.iter().zip(req3.iter()).map(|(a, b)| a ^ b));
+                self.out.extend_from_slice(&data);
+
+                self.rx = Some(rx);
+                self.tx = Some(tx);
+                self.state = State::SyncVC;
+            }
+            Role::Receiver(_) => {
+                self.write_public_key()?;
+                self.state = State::SyncReq;
+            }
+        }
+        Ok(true)
+    }
+
+    fn sync_req<S: Peek>(&mut self, conn: &mut S) -> io::Result<bool> {
+        let req1 = hash(&[b"req1", &self.secret]);
+        if !sync(conn, &req1)? {
+            return Ok(false);
+        }
+        self.state = State::Provide;
+        Ok(true)
+    }
+
+    fn read_provide<S: Peek>(&mut self, conn: &mut S) -> io::Result<bool> {
+        if !self.fill(conn, 34)? {
+            return Ok(false);
+        }
+        let req3 = hash(&[b"req3", &self.secret]);
+        let mut req2 = [0u8; 20];
+        for (i, b) in req2.iter_mut().enumerate() {
+            *b = self.buf[i] ^ req3[i];
+        }
+        self.skey = match self.role {
+            Role::Receiver(ref skeys) => match skeys
+                .iter()
+                .find(|skey| hash(&[b"req2", &skey[..]]) == req2)
+            {
+                Some(skey) => *skey,
+                None => return io_err("MSE handshake for unknown torrent"),
+            },
+            Role::Initiator => unreachable!(),
+        };
+
+        let mut rx = RC4::new(&hash(&[b"keyA", &self.secret, &self.skey]));
+        let mut tx = RC4::new(&hash(&[b"keyB", &self.secret, &self.skey]));
+        rx.skip(RC4_DISCARD);
+        tx.skip(RC4_DISCARD);
+
+        let mut data = [0u8; 14];
+        data.copy_from_slice(&self.buf[20..34]);
+        rx.process(&mut data);
+        if data[..8] != VC {
+            return io_err("MSE verification constant invalid");
+        }
+        let provide = (&data[8..12]).read_u32::<BigEndian>().unwrap();
+        let pad_len = (&data[12..14]).read_u16::<BigEndian>().unwrap() as usize;
+        if pad_len > MAX_PAD {
+            return io_err("MSE padding too long");
+        }
+        self.select = if provide & CRYPTO_RC4 != 0 {
+            CRYPTO_RC4
+        } else if provide & CRYPTO_PLAIN != 0 && self.policy == Encryption::Prefer {
+            CRYPTO_PLAIN
+        } else {
+            return io_err("No acceptable MSE crypto method provided");
+        };
+
+        self.rx = Some(rx);
+        self.tx = Some(tx);
+        self.buf.clear();
+        self.state = State::PadC(pad_len);
+        Ok(true)
+    }
+
+    fn read_pad_c<S: Peek>(&mut self, conn: &mut S, len: usize) -> io::Result<bool> {
+        if !self.fill(conn, len)? {
+            return Ok(false);
+        }
+        self.decrypt_buf();
+        self.buf.clear();
+        self.state = State::IALen;
+        Ok(true)
+    }
+
+    fn read_ia_len<S: Peek>(&mut self, conn: &mut S) -> io::Result<bool> {
+        if !self.fill(conn, 2)? {
+            return Ok(false);
+        }
+        self.decrypt_buf();
+        let len = (&self.buf[..]).read_u16::<BigEndian>().unwrap() as usize;
+        self.buf.clear();
+        self.state = State::IA(len);
+        Ok(true)
+    }
+
+    fn read_ia<S: Peek>(&mut self, conn: &mut S, len: usize) -> io::Result<bool> {
+        if !self.fill(conn, len)? {
+            return Ok(false);
+        }
+        self.decrypt_buf();
+        self.payload = mem::replace(&mut self.buf, Vec::new());
+
+        let mut data = Vec::with_capacity(14);
+        data.extend_from_slice(&VC);
+        data.write_u32::<BigEndian>(self.select).unwrap();
+        data.write_u16::<BigEndian>(0).unwrap();
+        self.tx.as_mut().unwrap().process(&mut data);
+        self.out.extend_from_slice(&data);
+        self.state = State::Done;
+        Ok(true)
+    }
+
+    fn sync_vc<S: Peek>(&mut self, conn: &mut S) -> io::Result<bool> {
+        let mut vc = VC;
+        self.rx.clone().unwrap().process(&mut vc);
+        if !sync(conn, &vc)? {
+            return Ok(false);
+        }
+        self.rx.as_mut().unwrap().skip(VC.len());
+        self.state = State::Select;
+        Ok(true)
+    }
+
+    fn read_select<S: Peek>(&mut self, conn: &mut S) -> io::Result<bool> {
+        if !self.fill(conn, 6)? {
+            return Ok(false);
+        }
+        self.decrypt_buf();
+        let select = (&self.buf[..4]).read_u32::<BigEndian>().unwrap();
+        let pad_len = (&self.buf[4..6]).read_u16::<BigEndian>().unwrap() as usize;
+        if pad_len > MAX_PAD {
+            return io_err("MSE padding too long");
+        }
+        self.select = match select {
+            CRYPTO_RC4 => CRYPTO_RC4,
+            CRYPTO_PLAIN if self.policy == Encryption::Prefer => CRYPTO_PLAIN,
+            _ => return io_err("Peer selected an invalid MSE crypto method"),
+        };
+        self.buf.clear();
+        self.state = State::PadD(pad_len);
+        Ok(true)
+    }
+
+    fn read_pad_d<S: Peek>(&mut self, conn: &mut S, len: usize) -> io::Result<bool> {
+        if !self.fill(conn, len)? {
+            return Ok(false);
+        }
+        self.decrypt_buf();
+        self.buf.clear();
+        self.state = State::Done;
+        Ok(true)
+    }
+
+    fn write_public_key(&mut self) -> io::Result<()> {
+        let mut ctx = BigNumContext::new().map_err(|_| io_err_val("MSE DH failure"))?;
+        let mut public = BigNum::new().map_err(|_| io_err_val("MSE DH failure"))?;
+        let p = BigNum::from_hex_str(PRIME).map_err(|_| io_err_val("MSE DH failure"))?;
+        let g = BigNum::from_u32(GENERATOR).map_err(|_| io_err_val("MSE DH failure"))?;
+        public
+            .mod_exp(&g, &self.private, &p, &mut ctx)
+            .map_err(|_| io_err_val("MSE DH failure"))?;
+        self.out.extend_from_slice(&pad_key(&public.to_vec()));
+
+        let mut rng = rand::thread_rng();
+        let mut pad = vec![0u8; rng.gen_range(0, MAX_PAD + 1)];
+        rng.fill_bytes(&mut pad);
+        self.out.extend_from_slice(&pad);
+        Ok(())
+    }
+
+    fn compute_secret(&self) -> io::Result<Vec<u8>> {
+        let mut ctx = BigNumContext::new().map_err(|_| io_err_val("MSE DH failure"))?;
+        let mut secret = BigNum::new().map_err(|_| io_err_val("MSE DH failure"))?;
+        let p = BigNum::from_hex_str(PRIME).map_err(|_| io_err_val("MSE DH failure"))?;
+        let remote = BigNum::from_slice(&self.buf[..KEY_LEN])
+            .map_err(|_| io_err_val("MSE DH failure"))?;
+        secret
+            .mod_exp(&remote, &self.private, &p, &mut ctx)
+            .map_err(|_| io_err_val("MSE DH failure"))?;
+        Ok(pad_key(&secret.to_vec()))
+    }
+
+    fn decrypt_buf(&mut self) {
+        self.rx.as_mut().unwrap().process(&mut self.buf);
+    }
+
+    /// Reads until the buffer holds exactly len bytes, returning
+    /// whether or not this was accomplished.
+    fn fill<S: Peek>(&mut self, conn: &mut S, len: usize) -> io::Result<bool> {
+        let mut data = [0u8; MAX_PAD];
+        while self.buf.len() < len {
+            let amnt = cmp::min(len - self.buf.len(), data.len());
+            match conn.read(&mut data[..amnt]) {
+                Ok(0) => return io_err("EOF"),
+                Ok(a) => self.buf.extend_from_slice(&data[..a]),
+                Err(ref e) if blocked(e) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    fn flush<S: Peek>(&mut self, conn: &mut S) -> io::Result<()> {
+        while !self.out.is_empty() {
+            match conn.write(&self.out) {
+                Ok(0) => return io_err("EOF"),
+                Ok(a) => {
+                    self.out.drain(..a);
+                }
+                Err(ref e) if blocked(e) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Consumes data up to and including the given pattern, provided that it
+/// occurs within the maximum padding length.
+fn sync<S: Peek>(conn: &mut S, pattern: &[u8]) -> io::Result<bool> {
+    let mut data = [0u8; MAX_PAD + 20];
+    let window = MAX_PAD + pattern.len();
+    let amnt = match conn.peek(&mut data[..window]) {
+        Ok(0) => return io_err("EOF"),
+        Ok(a) => a,
+        Err(ref e) if blocked(e) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    match find_subseq(&data[..amnt], pattern) {
+        Some(pos) => {
+            let mut consumed = 0;
+            let len = pos + pattern.len();
+            while consumed < len {
+                match conn.read(&mut data[consumed..len]) {
+                    Ok(0) => return io_err("EOF"),
+                    Ok(a) => consumed += a,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(true)
+        }
+        None if amnt == window => io_err("MSE synchronization failed"),
+        None => Ok(false),
+    }
+}
+
+fn blocked(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::NotConnected
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut ctx = sha1::Sha1::new();
+    for part in parts {
+        ctx.update(part);
+    }
+    ctx.digest().bytes()
+}
+
+/// Left pads a big endian number to the DH key length.
+fn pad_key(key: &[u8]) -> Vec<u8> {
+    let mut data = vec![0u8; KEY_LEN - key.len()];
+    data.extend_from_slice(key);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Established, Handshake, RC4};
+    use config::Encryption;
+    use util::MHashSet;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+
+    fn conn_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+        a.set_nonblocking(true).unwrap();
+        b.set_nonblocking(true).unwrap();
+        (a, b)
+    }
+
+    fn run(
+        a: &mut TcpStream,
+        ha: &mut Handshake,
+        b: &mut TcpStream,
+        hb: &mut Handshake,
+    ) -> (Established, Established) {
+        let (mut ea, mut eb) = (None, None);
+        for _ in 0..1000 {
+            if ea.is_none() {
+                ea = ha.advance(a).unwrap();
+            }
+            if eb.is_none() {
+                eb = hb.advance(b).unwrap();
+            }
+            if ea.is_some() && eb.is_some() {
+                return (ea.unwrap(), eb.unwrap());
+            }
+        }
+        panic!("Handshake did not complete!");
+    }
+
+    #[test]
+    fn test_rc4() {
+        let mut data = *b"Plaintext";
+        RC4::new(b"Key").process(&mut data);
+        assert_eq!(data, [0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+    }
+
+    #[test]
+    fn test_handshake() {
+        let hash = [7u8; 20];
+        let mut skeys = MHashSet::default();
+        skeys.insert([1u8; 20]);
+        skeys.insert(hash);
+
+        let (mut a, mut b) = conn_pair();
+        let mut ha = Handshake::initiate(hash, Encryption::Require).unwrap();
+        let mut hb = Handshake::accept(Arc::new(skeys), Encryption::Prefer).unwrap();
+        let (ea, eb) = run(&mut a, &mut ha, &mut b, &mut hb);
+        assert!(ea.data.is_empty());
+        assert!(eb.data.is_empty());
+
+        let (_, mut atx) = ea.cipher.unwrap();
+        let (mut brx, _) = eb.cipher.unwrap();
+        let mut data = *b"\x13BitTorrent protocol";
+        atx.process(&mut data);
+        a.write_all(&data).unwrap();
+        let mut recv = [0u8; 20];
+        ::std::thread::sleep(::std::time::Duration::from_millis(50));
+        b.read_exact(&mut recv).unwrap();
+        brx.process(&mut recv);
+        assert_eq!(&recv[..], b"\x13BitTorrent protocol");
+    }
+
+    #[test]
+    fn test_handshake_unknown_hash() {
+        let (mut a, mut b) = conn_pair();
+        let mut ha = Handshake::initiate([7u8; 20], Encryption::Prefer).unwrap();
+        let mut hb = Handshake::accept(Arc::new(MHashSet::default()), Encryption::Prefer).unwrap();
+        for _ in 0..1000 {
+            ha.advance(&mut a).unwrap();
+            if hb.advance(&mut b).is_err() {
+                return;
+            }
+        }
+        panic!("Handshake for unknown hash succeeded!");
+    }
+
+    #[test]
+    fn test_detect_plaintext() {
+        let (mut a, mut b) = conn_pair();
+        a.write_all(b"\x13BitTorrent protocol").unwrap();
+        ::std::thread::sleep(::std::time::Duration::from_millis(50));
+
+        let mut hb = Handshake::accept(Arc::new(MHashSet::default()), Encryption::Prefer).unwrap();
+        let est = hb.advance(&mut b).unwrap().unwrap();
+        assert!(est.cipher.is_none());
+        // Nothing should have been consumed
+        let mut recv = [0u8; 20];
+        b.read_exact(&mut recv).unwrap();
+        assert_eq!(&recv[..], b"\x13BitTorrent protocol");
+
+        let mut hb = Handshake::accept(Arc::new(MHashSet::default()), Encryption::Require).unwrap();
+        let (mut a, mut b) = conn_pair();
+        a.write_all(b"\x13BitTorrent protocol").unwrap();
+        ::std::thread::sleep(::std::time::Duration::from_millis(50));
+        assert!(hb.advance(&mut b).is_err());
+    }
+}
@@ -0,0 +1,705 @@
+//! uTP(BEP 29) streams. Streams don't own a socket, instead the
+//! owner of the UDP socket they share routes datagrams to them by
+//! address and connection id.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::cmp;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rand;
+
+const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+/// Maximum payload of a packet, keeping datagrams below common MTUs
+const MAX_PAYLOAD: usize = 1400 - HEADER_LEN;
+/// LEDBAT target queuing delay
+const TARGET_DELAY_US: u32 = 100_000;
+/// Maximum congestion window growth per RTT, in bytes
+const MAX_CWND_INCREASE: f64 = 3000.0;
+const MIN_WINDOW: usize = 2 * MAX_PAYLOAD;
+const MAX_WINDOW: usize = 1024 * 1024;
+/// Size of the receive buffer advertised to the remote end
+const RECV_WINDOW: usize = 1024 * 1024;
+/// Maximum number of out of order packets buffered
+const MAX_REORDER: u16 = 256;
+const INIT_TIMEOUT_MS: u64 = 1000;
+const MIN_TIMEOUT_MS: u64 = 500;
+/// Number of consecutive timeouts after which a connection fails
+const MAX_TIMEOUTS: u32 = 5;
+/// Number of SYN retransmissions before a connection attempt fails
+const MAX_SYN_TIMEOUTS: u32 = 2;
+/// Period over which the minimum delay is tracked, in seconds
+const BASE_DELAY_SECS: u64 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Type {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub kind: Type,
+    pub conn_id: u16,
+    /// Send time of the packet in microseconds
+    pub ts: u32,
+    /// Delay measured by the sender for the last packet it received
+    pub ts_diff: u32,
+    pub wnd: u32,
+    pub seq: u16,
+    pub ack: u16,
+}
+
+/// A uTP connection, which implements Read and Write in the same
+/// non blocking fashion as a TCP stream.
+pub struct Stream {
+    sock: Option<Arc<UdpSocket>>,
+    addr: SocketAddr,
+    state: State,
+    recv_id: u16,
+    send_id: u16,
+    /// Sequence number of the next packet sent
+    seq: u16,
+    /// Sequence number of the last packet received in order
+    ack: u16,
+    /// Sent packets which have yet to be acknowledged, in order
+    unacked: VecDeque<Packet>,
+    in_flight: usize,
+    /// Packets received out of order
+    reorder: HashMap<u16, Vec<u8>>,
+    /// Sequence number of the FIN packet, if received
+    fin: Option<u16>,
+    buf: VecDeque<u8>,
+    /// LEDBAT congestion window in bytes
+    cwnd: usize,
+    /// Receive window advertised by the remote end
+    peer_wnd: usize,
+    rtt: u64,
+    rtt_var: u64,
+    timeout: Duration,
+    /// Time at which the oldest unacknowledged packet is resent
+    resend: Option<Instant>,
+    timeouts: u32,
+    dup_acks: u32,
+    delay: BaseDelay,
+    /// Delay measured for the last packet received, echoed back
+    reply_micro: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// Outgoing connection which hasn't been started
+    Idle,
+    SynSent,
+    Connected,
+    Reset,
+}
+
+struct Packet {
+    kind: Type,
+    seq: u16,
+    data: Vec<u8>,
+    sent: Instant,
+    transmissions: u32,
+}
+
+/// Tracks the minimum delay over the last two periods, used as
+/// the baseline from which queuing delay is measured.
+struct BaseDelay {
+    cur: u32,
+    prev: u32,
+    started: Instant,
+}
+
+impl Header {
+    /// Parses a datagram, returning its header and payload.
+    pub fn decode(buf: &[u8]) -> Option<(Header, &[u8])> {
+        if buf.len() < HEADER_LEN || buf[0] & 0xF != VERSION {
+            return None;
+        }
+        let kind = match buf[0] >> 4 {
+            0 => Type::Data,
+            1 => Type::Fin,
+            2 => Type::State,
+            3 => Type::Reset,
+            4 => Type::Syn,
+            _ => return None,
+        };
+        let mut r = &buf[2..HEADER_LEN];
+        let hdr = Header {
+            kind,
+            conn_id: r.read_u16::<BigEndian>().unwrap(),
+            ts: r.read_u32::<BigEndian>().unwrap(),
+            ts_diff: r.read_u32::<BigEndian>().unwrap(),
+            wnd: r.read_u32::<BigEndian>().unwrap(),
+            seq: r.read_u16::<BigEndian>().unwrap(),
+            ack: r.read_u16::<BigEndian>().unwrap(),
+        };
+        // Skip over extensions, such as selective acks
+        let mut ext = buf[1];
+        let mut pos = HEADER_LEN;
+        while ext != 0 {
+            if buf.len() < pos + 2 || buf.len() < pos + 2 + buf[pos + 1] as usize {
+                return None;
+            }
+            ext = buf[pos];
+            pos += 2 + buf[pos + 1] as usize;
+        }
+        Some((hdr, &buf[pos..]))
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push((self.kind as u8) << 4 | VERSION);
+        buf.push(0);
+        buf.write_u16::<BigEndian>(self.conn_id).unwrap();
+        buf.write_u32::<BigEndian>(self.ts).unwrap();
+        buf.write_u32::<BigEndian>(self.ts_diff).unwrap();
+        buf.write_u32::<BigEndian>(self.wnd).unwrap();
+        buf.write_u16::<BigEndian>(self.seq).unwrap();
+        buf.write_u16::<BigEndian>(self.ack).unwrap();
+    }
+}
+
+impl Stream {
+    /// Creates an outgoing stream, which will connect once
+    /// started with a socket.
+    pub fn new(addr: SocketAddr) -> Stream {
+        Stream {
+            sock: None,
+            addr,
+            state: State::Idle,
+            recv_id: 0,
+            send_id: 0,
+            seq: 1,
+            ack: 0,
+            unacked: VecDeque::new(),
+            in_flight: 0,
+            reorder: HashMap::new(),
+            fin: None,
+            buf: VecDeque::new(),
+            cwnd: MIN_WINDOW,
+            peer_wnd: RECV_WINDOW,
+            rtt: 0,
+            rtt_var: 0,
+            timeout: Duration::from_millis(INIT_TIMEOUT_MS),
+            resend: None,
+            timeouts: 0,
+            dup_acks: 0,
+            delay: BaseDelay::new(),
+            reply_micro: 0,
+        }
+    }
+
+    /// Accepts an incoming connection initiated by the given SYN.
+    pub fn accept(sock: Arc<UdpSocket>, addr: SocketAddr, syn: &Header) -> Stream {
+        let mut s = Stream::new(addr);
+        s.sock = Some(sock);
+        s.state = State::Connected;
+        s.recv_id = syn.conn_id.wrapping_add(1);
+        s.send_id = syn.conn_id;
+        s.seq = rand::random();
+        s.ack = syn.seq;
+        s.peer_wnd = syn.wnd as usize;
+        s.reply_micro = now_micros().wrapping_sub(syn.ts);
+        s.send_state();
+        s
+    }
+
+    /// Starts connecting an outgoing stream over sock, using the
+    /// given id to receive packets.
+    pub fn connect(&mut self, sock: Arc<UdpSocket>, recv_id: u16) {
+        self.sock = Some(sock);
+        self.recv_id = recv_id;
+        self.send_id = recv_id.wrapping_add(1);
+        self.state = State::SynSent;
+        self.queue(Type::Syn, Vec::new());
+    }
+
+    /// Returns true if the stream is outgoing and has yet to be started
+    pub fn idle(&self) -> bool {
+        self.state == State::Idle
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Key used to route received packets to the stream
+    pub fn key(&self) -> (SocketAddr, u16) {
+        (self.addr, self.recv_id)
+    }
+
+    /// Processes a packet received for the stream
+    pub fn process(&mut self, hdr: &Header, payload: &[u8]) {
+        self.reply_micro = now_micros().wrapping_sub(hdr.ts);
+        self.peer_wnd = hdr.wnd as usize;
+        match (self.state, hdr.kind) {
+            (_, Type::Reset) => {
+                self.state = State::Reset;
+                return;
+            }
+            (State::SynSent, Type::State) => {
+                self.state = State::Connected;
+                self.ack = hdr.seq.wrapping_sub(1);
+            }
+            (State::Connected, Type::Syn) => {
+                // Our response to the SYN was lost
+                self.send_state();
+                return;
+            }
+            (State::Connected, _) => {}
+            _ => return,
+        }
+
+        self.process_ack(hdr);
+        match hdr.kind {
+            Type::Data => {
+                self.receive(hdr.seq, payload);
+                self.send_state();
+            }
+            Type::Fin => {
+                self.fin = Some(hdr.seq);
+                self.receive(hdr.seq, &[]);
+                self.send_state();
+            }
+            _ => {}
+        }
+    }
+
+    /// Resends packets which have timed out, returning an error
+    /// if the connection should be considered failed.
+    pub fn tick(&mut self) -> io::Result<()> {
+        match self.state {
+            State::Reset => return Err(io::Error::new(ErrorKind::ConnectionReset, "uTP reset")),
+            State::Idle => return Ok(()),
+            _ => {}
+        }
+        match self.resend {
+            Some(r) if Instant::now() >= r => {}
+            _ => return Ok(()),
+        }
+        self.timeouts += 1;
+        let max = if self.state == State::SynSent {
+            MAX_SYN_TIMEOUTS
+        } else {
+            MAX_TIMEOUTS
+        };
+        if self.timeouts > max {
+            return Err(io::Error::new(ErrorKind::TimedOut, "uTP timeout"));
+        }
+        self.cwnd = MIN_WINDOW;
+        self.timeout *= 2;
+        self.resend_oldest();
+        Ok(())
+    }
+
+    fn process_ack(&mut self, hdr: &Header) {
+        let now = Instant::now();
+        let mut acked = 0;
+        let mut rtt = None;
+        while self.unacked
+            .front()
+            .map(|p| seq_le(p.seq, hdr.ack))
+            .unwrap_or(false)
+        {
+            let p = self.unacked.pop_front().unwrap();
+            self.in_flight -= p.data.len();
+            acked += p.data.len();
+            if p.transmissions == 1 {
+                rtt = Some(now.duration_since(p.sent));
+            }
+        }
+        if let Some(rtt) = rtt {
+            self.update_rtt(rtt);
+        }
+
+        if acked > 0 || (self.state == State::Connected && hdr.kind == Type::State) {
+            self.timeouts = 0;
+        }
+        if acked > 0 {
+            self.dup_acks = 0;
+            self.update_cwnd(acked, hdr.ts_diff);
+            self.resend = if self.unacked.is_empty() {
+                None
+            } else {
+                Some(now + self.timeout)
+            };
+        } else if hdr.kind == Type::State && !self.unacked.is_empty() {
+            self.dup_acks += 1;
+            if self.dup_acks == 3 {
+                // Fast retransmit
+                self.cwnd = cmp::max(self.cwnd / 2, MIN_WINDOW);
+                self.resend_oldest();
+            }
+        }
+    }
+
+    fn receive(&mut self, seq: u16, payload: &[u8]) {
+        if seq == self.ack.wrapping_add(1) {
+            // Data past the advertised window is dropped unacked, to be
+            // resent once the window reopens
+            if self.buf.len() + payload.len() > RECV_WINDOW {
+                return;
+            }
+            self.buf.extend(payload);
+            self.ack = seq;
+            loop {
+                let next = self.ack.wrapping_add(1);
+                match self.reorder.get(&next) {
+                    Some(data) if self.buf.len() + data.len() <= RECV_WINDOW => {}
+                    _ => break,
+                }
+                let data = self.reorder.remove(&next).unwrap();
+                self.buf.extend(data);
+                self.ack = next;
+            }
+            if !self.reorder.is_empty() {
+                let ack = self.ack;
+                self.reorder.retain(|&s, _| !seq_le(s, ack));
+            }
+        } else if seq != self.ack && seq_le(self.ack, seq)
+            && seq.wrapping_sub(self.ack) <= MAX_REORDER
+        {
+            self.reorder.insert(seq, payload.to_vec());
+        }
+    }
+
+    fn update_rtt(&mut self, rtt: Duration) {
+        let sample = rtt.as_secs() * 1000 + u64::from(rtt.subsec_nanos()) / 1_000_000;
+        if self.rtt == 0 {
+            self.rtt = sample;
+            self.rtt_var = sample / 2;
+        } else {
+            let delta = if self.rtt > sample {
+                self.rtt - sample
+            } else {
+                sample - self.rtt
+            };
+            self.rtt_var = (3 * self.rtt_var + delta) / 4;
+            self.rtt = (7 * self.rtt + sample) / 8;
+        }
+        let timeout = cmp::max(self.rtt + 4 * self.rtt_var, MIN_TIMEOUT_MS);
+        self.timeout = Duration::from_millis(timeout);
+    }
+
+    /// Adjusts the congestion window per LEDBAT, growing it while the
+    /// measured queuing delay is below target and shrinking it above.
+    fn update_cwnd(&mut self, acked: usize, delay: u32) {
+        let base = self.delay.update(delay);
+        let queuing = f64::from(delay.wrapping_sub(base));
+        let target = f64::from(TARGET_DELAY_US);
+        let off_target = (target - queuing.min(2.0 * target)) / target;
+        let gain = MAX_CWND_INCREASE * off_target * acked as f64 / self.cwnd as f64;
+        let cwnd = (self.cwnd as f64 + gain).max(MIN_WINDOW as f64);
+        self.cwnd = cmp::min(cwnd as usize, MAX_WINDOW);
+    }
+
+    fn window(&self) -> usize {
+        cmp::min(self.cwnd, self.peer_wnd).saturating_sub(self.in_flight)
+    }
+
+    fn queue(&mut self, kind: Type, data: Vec<u8>) {
+        let mut p = Packet {
+            kind,
+            seq: self.seq,
+            data,
+            sent: Instant::now(),
+            transmissions: 0,
+        };
+        self.seq = self.seq.wrapping_add(1);
+        self.in_flight += p.data.len();
+        self.send_packet(&mut p);
+        if self.resend.is_none() {
+            self.resend = Some(Instant::now() + self.timeout);
+        }
+        self.unacked.push_back(p);
+    }
+
+    fn resend_oldest(&mut self) {
+        if let Some(mut p) = self.unacked.pop_front() {
+            self.send_packet(&mut p);
+            self.unacked.push_front(p);
+        }
+        self.dup_acks = 0;
+        self.resend = Some(Instant::now() + self.timeout);
+    }
+
+    fn send_packet(&self, p: &mut Packet) {
+        p.sent = Instant::now();
+        p.transmissions += 1;
+        let mut buf = Vec::with_capacity(HEADER_LEN + p.data.len());
+        self.header(p.kind, p.seq).encode(&mut buf);
+        buf.extend_from_slice(&p.data);
+        self.send(&buf);
+    }
+
+    fn send_state(&self) {
+        let mut buf = Vec::with_capacity(HEADER_LEN);
+        self.header(Type::State, self.seq).encode(&mut buf);
+        self.send(&buf);
+    }
+
+    fn header(&self, kind: Type, seq: u16) -> Header {
+        Header {
+            kind,
+            conn_id: if kind == Type::Syn {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+            ts: now_micros(),
+            ts_diff: self.reply_micro,
+            wnd: RECV_WINDOW.saturating_sub(self.buf.len()) as u32,
+            seq,
+            ack: self.ack,
+        }
+    }
+
+    fn send(&self, data: &[u8]) {
+        // Lost packets will just be resent later
+        if let Some(ref s) = self.sock {
+            if let Err(e) = s.send_to(data, self.addr) {
+                if e.kind() != ErrorKind::WouldBlock {
+                    debug!("Failed to send uTP packet: {}", e);
+                }
+            }
+        }
+    }
+
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buf.is_empty() {
+            return self.empty_res();
+        }
+        let amnt = cmp::min(buf.len(), self.buf.len());
+        for (i, b) in self.buf.iter().take(amnt).enumerate() {
+            buf[i] = *b;
+        }
+        Ok(amnt)
+    }
+
+    fn empty_res(&self) -> io::Result<usize> {
+        match self.state {
+            State::Reset => Err(io::Error::new(ErrorKind::ConnectionReset, "uTP reset")),
+            _ if self.fin.map(|f| f == self.ack).unwrap_or(false) => Ok(0),
+            _ => Err(io::Error::new(ErrorKind::WouldBlock, "")),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let amnt = self.peek(buf)?;
+        let was_full = self.buf.len() + MAX_PAYLOAD > RECV_WINDOW;
+        self.buf.drain(..amnt);
+        if was_full && amnt > 0 {
+            // Let the remote end know the window has reopened
+            self.send_state();
+        }
+        Ok(amnt)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.state {
+            State::Connected => {}
+            State::Reset => {
+                return Err(io::Error::new(ErrorKind::ConnectionReset, "uTP reset"));
+            }
+            _ => return Err(io::Error::new(ErrorKind::WouldBlock, "")),
+        }
+        let amnt = cmp::min(buf.len(), self.window());
+        if amnt == 0 {
+            return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+        }
+        for chunk in buf[..amnt].chunks(MAX_PAYLOAD) {
+            self.queue(Type::Data, chunk.to_vec());
+        }
+        Ok(amnt)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if self.state == State::Connected {
+            let mut buf = Vec::with_capacity(HEADER_LEN);
+            self.header(Type::Fin, self.seq).encode(&mut buf);
+            self.send(&buf);
+        }
+    }
+}
+
+impl BaseDelay {
+    fn new() -> BaseDelay {
+        BaseDelay {
+            cur: ::std::u32::MAX,
+            prev: ::std::u32::MAX,
+            started: Instant::now(),
+        }
+    }
+
+    /// Records a delay sample, returning the current base delay
+    fn update(&mut self, sample: u32) -> u32 {
+        if self.started.elapsed() > Duration::from_secs(BASE_DELAY_SECS) {
+            self.prev = self.cur;
+            self.cur = ::std::u32::MAX;
+            self.started = Instant::now();
+        }
+        self.cur = cmp::min(self.cur, sample);
+        cmp::min(self.cur, self.prev)
+    }
+}
+
+/// Returns true if sequence number a is before or equal to b
+fn seq_le(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+fn now_micros() -> u32 {
+    let d = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    (d.as_secs() * 1_000_000 + u64::from(d.subsec_nanos() / 1000)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::UdpSocket;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{seq_le, Header, Stream, Type, RECV_WINDOW};
+
+    fn recv(sock: &UdpSocket, buf: &mut [u8]) -> Option<usize> {
+        sock.recv_from(buf).ok().map(|(len, _)| len)
+    }
+
+    #[test]
+    fn test_header() {
+        let hdr = Header {
+            kind: Type::Data,
+            conn_id: 12,
+            ts: 1,
+            ts_diff: 2,
+            wnd: 3,
+            seq: 65535,
+            ack: 5,
+        };
+        let mut buf = Vec::new();
+        hdr.encode(&mut buf);
+        buf.extend_from_slice(b"abc");
+        assert_eq!(Header::decode(&buf), Some((hdr, &b"abc"[..])));
+        buf[1] = 1;
+        assert_eq!(Header::decode(&buf), None);
+        assert!(seq_le(65535, 0));
+        assert!(!seq_le(0, 65535));
+    }
+
+    #[test]
+    fn test_transfer() {
+        let a = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let b = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        for s in &[&a, &b] {
+            s.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        }
+        let mut buf = vec![0u8; 2000];
+
+        let mut client = Stream::new(b.local_addr().unwrap());
+        client.connect(a.clone(), 10);
+        assert!(client.write(b"hi").is_err());
+
+        let len = recv(&b, &mut buf).unwrap();
+        let (syn, _) = Header::decode(&buf[..len]).unwrap();
+        assert_eq!(syn.kind, Type::Syn);
+        let mut server = Stream::accept(b.clone(), a.local_addr().unwrap(), &syn);
+        assert_eq!(server.key(), (a.local_addr().unwrap(), 11));
+
+        let len = recv(&a, &mut buf).unwrap();
+        let (state, p) = Header::decode(&buf[..len]).unwrap();
+        client.process(&state, p);
+
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let amnt = client.write(&data).unwrap();
+        assert!(amnt > 0);
+        while let Some(len) = recv(&b, &mut buf) {
+            let (hdr, p) = Header::decode(&buf[..len]).unwrap();
+            assert_eq!(hdr.conn_id, 11);
+            server.process(&hdr, p);
+            if server.buf.len() == amnt {
+                break;
+            }
+        }
+        let mut out = vec![0u8; amnt];
+        assert_eq!(server.read(&mut out).unwrap(), amnt);
+        assert_eq!(&out[..], &data[..amnt]);
+        assert_eq!(
+            server.read(&mut out).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+
+        drop(client);
+        while let Some(len) = recv(&b, &mut buf) {
+            let (hdr, p) = Header::decode(&buf[..len]).unwrap();
+            server.process(&hdr, p);
+            if hdr.kind == Type::Fin {
+                break;
+            }
+        }
+        assert_eq!(server.read(&mut out).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut s = Stream::new("127.0.0.1:0".parse().unwrap());
+        s.ack = 10;
+        s.receive(12, b"c");
+        s.receive(11, b"b");
+        assert_eq!(s.ack, 12);
+        // Duplicates of the last packet received in order are dropped
+        s.receive(12, b"x");
+        assert!(s.reorder.is_empty());
+
+        // Wrapping around to the duplicate's sequence number
+        // mustn't pull in stale data
+        let mut seq = 12u16;
+        while seq != 11 {
+            seq = seq.wrapping_add(1);
+            s.receive(seq, b"d");
+        }
+        assert_eq!(s.ack, 11);
+        assert_eq!(s.buf.len(), 2 + 65_535);
+        assert!(s.buf.iter().skip(2).all(|&b| b == b'd'));
+    }
+
+    #[test]
+    fn test_recv_window() {
+        let mut s = Stream::new("127.0.0.1:0".parse().unwrap());
+        s.ack = 10;
+        s.buf.extend(vec![0u8; RECV_WINDOW - 2]);
+        s.receive(12, b"cc");
+        s.receive(11, b"bbb");
+        assert_eq!(s.ack, 10);
+        s.receive(11, b"b");
+        // The buffered packet no longer fits either
+        assert_eq!(s.ack, 11);
+        assert_eq!(s.buf.len(), RECV_WINDOW - 1);
+
+        let mut buf = [0u8; 16];
+        s.read(&mut buf).unwrap();
+        s.receive(12, b"cc");
+        assert_eq!(s.ack, 12);
+        assert!(s.buf.len() <= RECV_WINDOW);
+    }
+}
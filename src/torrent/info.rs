@@ -1,6 +1,6 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::BTreeMap;
-use std::{cmp, fmt, mem};
+use std::{cmp, fmt, fs, io, mem};
 use std::sync::Arc;
 
 use base32;
@@ -9,7 +9,16 @@ use url::Url;
 
 use disk;
 use bencode::BEncode;
-use util::{hash_to_id, id_to_hash, sha1_hash};
+use util::{self, hash_to_id, id_to_hash, id_to_hash_v2, io_err, sha1_hash, sha256_hash};
+
+/// Smallest piece length chosen for created torrents
+const MIN_PIECE_LEN: u64 = 16_384;
+/// Largest piece length chosen for created torrents
+const MAX_PIECE_LEN: u64 = 16 * 1024 * 1024;
+/// Number of pieces created torrents aim for
+const TARGET_PIECES: u64 = 1500;
+/// Size of the leaves of BEP 52 merkle trees
+const MERKLE_BLOCK: u64 = 16_384;
 
 #[derive(Clone)]
 pub struct Info {
@@ -21,11 +30,18 @@ pub struct Info {
     pub total_len: u64,
     pub hashes: Vec<Vec<u8>>,
     pub hash: [u8; 20],
+    /// BEP 52 info hash of v2 and hybrid torrents. For v2 only
+    /// torrents `hash` is this truncated to 20 bytes.
+    pub hash_v2: Option<[u8; 32]>,
+    /// BEP 52 piece layers, keyed by the pieces root of their file
+    pub piece_layers: BTreeMap<[u8; 32], Vec<u8>>,
     pub files: Vec<File>,
     pub private: bool,
     pub be_name: Option<Vec<u8>>,
     pub piece_idx: Vec<(usize, u64)>,
     pub url_list: Vec<Vec<Url>>,
+    /// BEP 19 HTTP web seeds
+    pub web_seeds: Vec<Url>,
 }
 
 impl fmt::Debug for Info {
@@ -54,33 +70,46 @@ impl fmt::Debug for Info {
 pub struct File {
     pub path: PathBuf,
     pub length: u64,
+    /// BEP 47 padding file, which only aligns the next file
+    /// to a piece boundary and is never written to disk
+    pub padding: bool,
+    /// BEP 52 merkle root of the file's blocks
+    pub pieces_root: Option<[u8; 32]>,
 }
 
 impl File {
+    pub fn new(path: PathBuf, length: u64) -> File {
+        File {
+            path,
+            length,
+            padding: false,
+            pieces_root: None,
+        }
+    }
+
     fn from_bencode(data: BEncode) -> Result<File, &'static str> {
         let mut d = data.into_dict().ok_or("File must be a dictionary type!")?;
-        match (d.remove("name"), d.remove("path"), d.remove("length")) {
-            (Some(v), None, Some(l)) => {
-                let f = File {
-                    path: PathBuf::from(v.into_string().ok_or("Path must be a valid string.")?),
-                    length: l.into_int().ok_or("File length must be a valid int")? as u64,
-                };
-                Ok(f)
-            }
+        let padding = d.remove("attr")
+            .and_then(|a| a.into_bytes())
+            .map(|a| a.contains(&b'p'))
+            .unwrap_or(false);
+        let mut f = match (d.remove("name"), d.remove("path"), d.remove("length")) {
+            (Some(v), None, Some(l)) => File::new(
+                PathBuf::from(v.into_string().ok_or("Path must be a valid string.")?),
+                l.into_int().ok_or("File length must be a valid int")? as u64,
+            ),
             (None, Some(path), Some(l)) => {
                 let mut p = PathBuf::new();
                 for dir in path.into_list().ok_or("File path should be a list")? {
                     p.push(dir.into_string()
                         .ok_or("File path parts should be strings")?);
                 }
-                let f = File {
-                    path: p,
-                    length: l.into_int().ok_or("File length must be a valid int")? as u64,
-                };
-                Ok(f)
+                File::new(p, l.into_int().ok_or("File length must be a valid int")? as u64)
             }
-            _ => Err("File dict must contain length and name or path"),
-        }
+            _ => return Err("File dict must contain length and name or path"),
+        };
+        f.padding = padding;
+        Ok(f)
     }
 }
 
@@ -94,23 +123,32 @@ impl Info {
         if url.scheme() != "magnet" {
             return Err("magnet URL must use magnet URL scheme");
         };
-        let hash = url.query_pairs()
+        let hash_v1 = url.query_pairs()
             .find(|&(ref k, ref v)| k == "xt" && v.starts_with("urn:btih:"))
             .and_then(|(_, ref v)| {
-                id_to_hash(&v[9..]).or_else(|| {
-                    base32::decode(base32::Alphabet::RFC4648 { padding: true }, &v[9..]).and_then(
-                        |b| {
-                            if b.len() != 20 {
-                                return None;
-                            }
-                            let mut a = [0; 20];
-                            (&mut a[..]).copy_from_slice(&b);
-                            Some(a)
-                        },
-                    )
-                })
-            })
-            .ok_or("No hash found in magnet")?;
+                let id = &v[9..];
+                if id.len() != 40 {
+                    base32::decode(base32::Alphabet::RFC4648 { padding: true }, id).and_then(|b| {
+                        if b.len() != 20 {
+                            return None;
+                        }
+                        let mut a = [0; 20];
+                        (&mut a[..]).copy_from_slice(&b);
+                        Some(a)
+                    })
+                } else {
+                    id_to_hash(id)
+                }
+            });
+        // BEP 52 hashes are multihashes, 0x12 being SHA-256 and 0x20 its length
+        let hash_v2 = url.query_pairs()
+            .find(|&(ref k, ref v)| k == "xt" && v.starts_with("urn:btmh:1220"))
+            .and_then(|(_, ref v)| id_to_hash_v2(&v[13..]));
+        let hash = match (hash_v1, hash_v2) {
+            (Some(h), _) => h,
+            (None, Some(h)) => truncate_hash(&h),
+            (None, None) => return Err("No hash found in magnet"),
+        };
         let announce = url.query_pairs()
             .find(|&(ref k, _)| k == "tr")
             .and_then(|(_, ref v)| Url::parse(v).map(|u| Some(u)).unwrap_or(None));
@@ -118,6 +156,11 @@ impl Info {
             .find(|&(ref k, _)| k == "dn")
             .map(|(_, ref v)| v.to_string())
             .unwrap_or_else(|| "".to_owned());
+        let web_seeds = url.query_pairs()
+            .filter(|&(ref k, _)| k == "ws")
+            .filter_map(|(_, ref v)| Url::parse(v).ok())
+            .filter(is_web_seed)
+            .collect();
         Ok(Info {
             name,
             comment: None,
@@ -127,14 +170,88 @@ impl Info {
             total_len: 0,
             hashes: vec![],
             hash,
+            hash_v2,
+            piece_layers: BTreeMap::new(),
             files: vec![],
             private: false,
             be_name: None,
             piece_idx: vec![],
             url_list: vec![],
+            web_seeds,
+        })
+    }
+
+    /// Creates the info of a new torrent for the file or directory at
+    /// path. Files are added with `add_files` and hashes with `set_hashes`,
+    /// a piece_len of 0 is chosen based on the size of the files.
+    pub fn create(
+        path: &Path,
+        piece_len: u32,
+        trackers: Vec<Url>,
+        web_seeds: Vec<Url>,
+        private: bool,
+        comment: Option<String>,
+    ) -> Result<Info, &'static str> {
+        let name = path.file_name()
+            .and_then(|n| n.to_str())
+            .ok_or("Path must have a valid UTF8 name")?
+            .to_owned();
+        Ok(Info {
+            be_name: Some(name.clone().into_bytes()),
+            name,
+            comment,
+            creator: Some(format!("synapse {}", env!("CARGO_PKG_VERSION"))),
+            announce: trackers.get(0).cloned(),
+            piece_len,
+            total_len: 0,
+            hashes: vec![],
+            hash: [0u8; 20],
+            hash_v2: None,
+            piece_layers: BTreeMap::new(),
+            files: vec![],
+            private,
+            piece_idx: vec![],
+            // Each tracker gets its own tier, like most torrent creators
+            url_list: if trackers.len() > 1 {
+                trackers.into_iter().map(|t| vec![t]).collect()
+            } else {
+                vec![]
+            },
+            web_seeds,
         })
     }
 
+    /// Adds the files at path, which this info was created for. Pieces
+    /// are left with empty hashes.
+    pub fn add_files(&mut self, path: &Path) -> io::Result<()> {
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut files = Vec::new();
+        find_files(path, &mut files)?;
+        for file in files {
+            let length = fs::metadata(&file)?.len();
+            let path = file.strip_prefix(base).unwrap().to_path_buf();
+            self.files.push(File::new(path, length));
+        }
+        self.total_len = self.files.iter().map(|f| f.length).sum();
+        if self.total_len == 0 {
+            return io_err("Torrent files must not be empty");
+        }
+        if self.piece_len == 0 {
+            self.piece_len = auto_piece_len(self.total_len);
+        }
+        let pl = u64::from(self.piece_len);
+        let pieces = ((self.total_len + pl - 1) / pl) as usize;
+        self.hashes = vec![vec![]; pieces];
+        self.piece_idx = Info::generate_piece_idx(pieces, pl, &self.files);
+        Ok(())
+    }
+
+    /// Sets the piece hashes of a created torrent, and with them its info hash
+    pub fn set_hashes(&mut self, hashes: Vec<Vec<u8>>) {
+        self.hashes = hashes;
+        self.hash = sha1_hash(&self.to_bencode().encode_to_buf());
+    }
+
     pub fn complete(&self) -> bool {
         !self.hashes.is_empty()
     }
@@ -148,6 +265,37 @@ impl Info {
                 BEncode::String(url.as_str().as_bytes().to_owned()),
             )
         });
+        if !self.url_list.is_empty() {
+            let tiers = self.url_list
+                .iter()
+                .map(|tier| {
+                    BEncode::List(tier.iter()
+                        .map(|url| BEncode::String(url.as_str().as_bytes().to_owned()))
+                        .collect())
+                })
+                .collect();
+            torrent.insert("announce-list".to_owned(), BEncode::List(tiers));
+        }
+        if let Some(ref c) = self.comment {
+            torrent.insert("comment".to_owned(), BEncode::from_str(c));
+        }
+        if let Some(ref c) = self.creator {
+            torrent.insert("created by".to_owned(), BEncode::from_str(c));
+        }
+        if !self.web_seeds.is_empty() {
+            let urls = self.web_seeds
+                .iter()
+                .map(|url| BEncode::String(url.as_str().as_bytes().to_owned()))
+                .collect();
+            torrent.insert("url-list".to_owned(), BEncode::List(urls));
+        }
+        if !self.piece_layers.is_empty() {
+            let layers = self.piece_layers
+                .iter()
+                .map(|(root, layer)| (root.to_vec(), BEncode::String(layer.clone())))
+                .collect();
+            torrent.insert("piece layers".to_owned(), BEncode::RawDict(layers));
+        }
         torrent.insert("info".to_owned(), info);
         BEncode::Dict(torrent)
     }
@@ -164,12 +312,19 @@ impl Info {
             "piece length".to_owned(),
             BEncode::Int(i64::from(self.piece_len)),
         );
+        if self.hash_v2.is_some() {
+            info.insert("meta version".to_owned(), BEncode::Int(2));
+            info.insert("file tree".to_owned(), self.file_tree());
+        }
+        if !self.has_v1() {
+            return BEncode::Dict(info);
+        }
         let mut pieces = Vec::with_capacity(self.hashes.len() * 20);
         for h in &self.hashes {
             pieces.extend_from_slice(h);
         }
         info.insert("pieces".to_owned(), BEncode::String(pieces));
-        if self.files.len() == 1 {
+        if self.files.len() == 1 && self.files[0].path == Path::new(&self.name) {
             info.insert(
                 "length".to_owned(),
                 BEncode::Int(self.files[0].length as i64),
@@ -180,17 +335,17 @@ impl Info {
                 .map(|f| {
                     let mut fb = BTreeMap::new();
                     fb.insert("length".to_owned(), BEncode::Int(f.length as i64));
-                    fb.insert(
-                        "path".to_owned(),
-                        BEncode::String(
-                            f.path
-                                .clone()
-                                .into_os_string()
-                                .into_string()
-                                .unwrap()
-                                .into_bytes(),
-                        ),
-                    );
+                    if f.padding {
+                        fb.insert("attr".to_owned(), BEncode::from_str("p"));
+                    }
+                    // Paths include the torrent name, which is
+                    // not part of the encoded path list
+                    let path = f.path
+                        .iter()
+                        .skip(1)
+                        .map(|p| BEncode::String(p.to_string_lossy().into_owned().into_bytes()))
+                        .collect();
+                    fb.insert("path".to_owned(), BEncode::List(path));
                     BEncode::Dict(fb)
                 })
                 .collect();
@@ -199,6 +354,56 @@ impl Info {
         BEncode::Dict(info)
     }
 
+    /// Encodes the BEP 52 file tree, a dictionary of path components
+    /// with files as dictionaries under an empty key
+    fn file_tree(&self) -> BEncode {
+        let single = self.files.len() == 1 && self.files[0].path == Path::new(&self.name);
+        let mut tree = BTreeMap::new();
+        for f in self.files.iter().filter(|f| !f.padding) {
+            let mut fb = BTreeMap::new();
+            fb.insert("length".to_owned(), BEncode::Int(f.length as i64));
+            if let Some(ref root) = f.pieces_root {
+                fb.insert("pieces root".to_owned(), BEncode::String(root.to_vec()));
+            }
+            let parts: Vec<_> = f.path
+                .iter()
+                .skip(if single { 0 } else { 1 })
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect();
+            let mut node = &mut tree;
+            for part in parts {
+                let next = node.entry(part)
+                    .or_insert_with(|| BEncode::Dict(BTreeMap::new()));
+                node = match *next {
+                    BEncode::Dict(ref mut d) => d,
+                    _ => unreachable!(),
+                };
+            }
+            node.insert("".to_owned(), BEncode::Dict(fb));
+        }
+        BEncode::Dict(tree)
+    }
+
+    /// Whether the torrent has v1 piece hashes, i.e. isn't v2 only
+    pub fn has_v1(&self) -> bool {
+        self.hash_v2.is_none() || self.hashes.iter().any(|h| h.len() == 20)
+    }
+
+    /// Whether a hash identifies this torrent, which v2 torrents
+    /// can be by both their v1 and truncated v2 hash
+    pub fn matches(&self, hash: &[u8; 20]) -> bool {
+        self.hash == *hash || self.hash_v2.map(|h| h[..20] == hash[..]).unwrap_or(false)
+    }
+
+    /// The RPC id of the torrent, which is its full v2 hash
+    /// if the truncated one is used on the wire
+    pub fn id(&self) -> String {
+        match self.hash_v2 {
+            Some(ref h) if h[..20] == self.hash[..] => hash_to_id(h),
+            _ => hash_to_id(&self.hash),
+        }
+    }
+
     pub fn from_bencode(data: BEncode) -> Result<Info, &'static str> {
         data.into_dict()
             .and_then(|mut d| d.remove("info").and_then(|i| i.into_dict()).map(|i| (d, i)))
@@ -206,7 +411,11 @@ impl Info {
             .and_then(|(mut d, mut i)| {
                 let mut info_bytes = Vec::new();
                 BEncode::Dict(i.clone()).encode(&mut info_bytes).unwrap();
-                let hash = sha1_hash(&info_bytes);
+                let hash_v2 = match i.remove("meta version").and_then(|v| v.into_int()) {
+                    None | Some(1) => None,
+                    Some(2) => Some(sha256_hash(&info_bytes)),
+                    Some(_) => return Err("Unsupported meta version"),
+                };
 
                 let a = d.remove("announce")
                     .and_then(BEncode::into_string)
@@ -216,23 +425,35 @@ impl Info {
                 let pl = i.remove("piece length")
                     .and_then(|i| i.into_int())
                     .ok_or("Info must specify piece length")? as u64;
-                let hashes = i.remove("pieces")
-                    .and_then(|p| p.into_bytes())
-                    .and_then(|p| {
-                        let mut v = Vec::new();
-                        let mut s = &p[..];
-                        while s.len() >= 20 {
-                            let mut next = vec![0u8; 20];
-                            next.clone_from_slice(&s[..20]);
-                            v.push(next);
-                            s = &s[20..];
-                        }
-                        if s.len() != 0 {
-                            return None;
-                        }
-                        Some(v)
-                    })
-                    .ok_or("Info must provide valid hashes")?;
+                if hash_v2.is_some() && (pl < MERKLE_BLOCK || !pl.is_power_of_two()) {
+                    return Err("v2 piece length must be a power of two of at least 16 KiB");
+                }
+                // v2 only torrents have no v1 hashes, and are identified
+                // by their truncated v2 hash instead
+                let v1_hashes = match (i.remove("pieces"), hash_v2) {
+                    (Some(p), _) => Some(p.into_bytes()
+                        .and_then(|p| {
+                            let mut v = Vec::new();
+                            let mut s = &p[..];
+                            while s.len() >= 20 {
+                                let mut next = vec![0u8; 20];
+                                next.clone_from_slice(&s[..20]);
+                                v.push(next);
+                                s = &s[20..];
+                            }
+                            if s.len() != 0 {
+                                return None;
+                            }
+                            Some(v)
+                        })
+                        .ok_or("Info must provide valid hashes")?),
+                    (None, Some(_)) => None,
+                    (None, None) => return Err("Info must provide valid hashes"),
+                };
+                let hash = match hash_v2 {
+                    Some(ref h) if v1_hashes.is_none() => truncate_hash(h),
+                    _ => sha1_hash(&info_bytes),
+                };
 
                 let private = if let Some(v) = i.remove("private") {
                     v.into_int()
@@ -248,14 +469,48 @@ impl Info {
                     None
                 };
 
-                let files = parse_bencode_files(i)?;
+                let piece_layers = d.remove("piece layers")
+                    .and_then(BEncode::into_raw_dict)
+                    .unwrap_or_else(BTreeMap::new)
+                    .into_iter()
+                    .filter_map(|(root, layer)| {
+                        let layer = layer.into_bytes()?;
+                        if root.len() != 32 || layer.len() % 32 != 0 {
+                            return None;
+                        }
+                        let mut r = [0u8; 32];
+                        r.copy_from_slice(&root);
+                        Some((r, layer))
+                    })
+                    .collect();
+
+                let files = match i.remove("file tree") {
+                    Some(tree) => {
+                        let name = i.get("name")
+                            .and_then(|n| n.as_str())
+                            .ok_or("v2 info must have a name")?
+                            .to_owned();
+                        let mut tree_files = Vec::new();
+                        parse_file_tree(tree, &name, &mut tree_files)?;
+                        if v1_hashes.is_some() {
+                            let mut files = parse_bencode_files(i)?;
+                            merge_file_tree(&mut files, tree_files)?;
+                            files
+                        } else {
+                            pad_files(tree_files, pl, &name)
+                        }
+                    }
+                    None if hash_v2.is_some() => return Err("v2 info must have a file tree"),
+                    None => parse_bencode_files(i)?,
+                };
+                check_piece_layers(&files, pl, &piece_layers)?;
+                let hashes = match v1_hashes {
+                    Some(hashes) => hashes,
+                    None => v2_hashes(&files, pl, &piece_layers),
+                };
+
                 let name = if files.is_empty() {
-                    files[0]
-                        .path
-                        .clone()
-                        .into_os_string()
-                        .into_string()
-                        .map_err(|_| "Only UTF8 paths are accepted")?
+                    return Err("Torrent must contain files");
                 } else if !files[0].path.has_root() {
                     let mut piter = files[0].path.components();
                     piter
@@ -288,6 +543,17 @@ impl Info {
                     })
                     .collect();
 
+                // url-list may either be a single URL or a list of them
+                let web_seeds = match d.remove("url-list") {
+                    Some(BEncode::String(s)) => vec![BEncode::String(s)],
+                    Some(BEncode::List(l)) => l,
+                    _ => vec![],
+                }.into_iter()
+                    .filter_map(BEncode::into_string)
+                    .filter_map(|s| Url::parse(&s).ok())
+                    .filter(is_web_seed)
+                    .collect();
+
                 Ok(Info {
                     name,
                     comment,
@@ -296,12 +562,15 @@ impl Info {
                     piece_len: pl as u32,
                     hashes,
                     hash,
+                    hash_v2,
+                    piece_layers,
                     files,
                     total_len,
                     private,
                     be_name,
                     piece_idx,
                     url_list,
+                    web_seeds,
                 })
             })
     }
@@ -332,17 +601,14 @@ impl Info {
             total_len: 16_384 * pieces as u64,
             hashes: vec![vec![0u8]; pieces],
             hash: [0u8; 20],
-            files: vec![
-                File {
-                    path: PathBuf::new(),
-                    length: 16_384 * pieces as u64,
-                };
-                1
-            ],
+            hash_v2: None,
+            piece_layers: BTreeMap::new(),
+            files: vec![File::new(PathBuf::new(), 16_384 * pieces as u64)],
             private: false,
             be_name: None,
             piece_idx: vec![],
             url_list: vec![],
+            web_seeds: vec![],
         }
     }
 
@@ -357,11 +623,14 @@ impl Info {
             total_len: 16_384 * pieces as u64 * scale as u64,
             hashes: vec![vec![0u8]; pieces as usize],
             hash: [0u8; 20],
+            hash_v2: None,
+            piece_layers: BTreeMap::new(),
             files: vec![],
             private: false,
             be_name: None,
             piece_idx: vec![],
             url_list: vec![],
+            web_seeds: vec![],
         }
     }
 
@@ -396,6 +665,144 @@ impl Info {
         self.hashes.len() as u32
     }
 
+    /// Checks piece data against its hash, which is SHA-1 for v1 and hybrid
+    /// torrents and the merkle root of the piece's blocks for v2 torrents.
+    pub fn piece_valid(&self, idx: u32, data: &[u8]) -> bool {
+        let expected = &self.hashes[idx as usize];
+        match expected.len() {
+            20 => sha1_hash(data)[..] == expected[..],
+            32 => {
+                let (file, offset) = self.piece_file(idx);
+                let f = &self.files[file];
+                // Padding following the file isn't part of its tree
+                let len = cmp::min(data.len() as u64, f.length - offset) as usize;
+                let leaves: Vec<_> = data[..len]
+                    .chunks(MERKLE_BLOCK as usize)
+                    .map(sha256_hash)
+                    .collect();
+                let pl = u64::from(self.piece_len);
+                let width = if f.length <= pl {
+                    (f.length + MERKLE_BLOCK - 1) / MERKLE_BLOCK
+                } else {
+                    pl / MERKLE_BLOCK
+                };
+                util::merkle_root(&leaves, width as usize, [0; 32])[..] == expected[..]
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether a piece's hash is known, which it isn't for v2 pieces
+    /// until their file's piece layer is received
+    pub fn piece_verifiable(&self, idx: u32) -> bool {
+        !self.hashes[idx as usize].is_empty()
+    }
+
+    /// Length of a piece's data, excluding padding at its end
+    pub fn piece_data_len(info: &Arc<Info>, idx: u32) -> u32 {
+        Info::piece_disk_locs(info, idx)
+            .filter(|loc| !info.files[loc.file].padding)
+            .map(|loc| loc.end as u32)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Index of the piece layer in BEP 52 merkle trees,
+    /// the layer of 16 KiB blocks being 0
+    pub fn piece_layer_idx(&self) -> u32 {
+        (u64::from(self.piece_len) / MERKLE_BLOCK).trailing_zeros()
+    }
+
+    /// Pieces roots and piece counts of files which need a piece layer,
+    /// but don't have one
+    pub fn missing_layers(&self) -> Vec<([u8; 32], u32)> {
+        let pl = u64::from(self.piece_len);
+        self.files
+            .iter()
+            .filter(|f| f.length > pl)
+            .filter_map(|f| f.pieces_root.map(|r| (r, ((f.length + pl - 1) / pl) as u32)))
+            .filter(|&(ref r, _)| !self.piece_layers.contains_key(r))
+            .collect()
+    }
+
+    /// Sets the piece layer of a file as received from a peer,
+    /// returning whether it was valid
+    pub fn set_piece_layer(&mut self, root: &[u8; 32], layer: &[u8]) -> bool {
+        let pl = u64::from(self.piece_len);
+        let file = match self.files
+            .iter()
+            .position(|f| f.pieces_root.as_ref() == Some(root) && f.length > pl)
+        {
+            Some(f) => f,
+            None => return false,
+        };
+        let pieces = ((self.files[file].length + pl - 1) / pl) as usize;
+        // Responses are padded to a power of two
+        if layer.len() < pieces * 32 || layer_root(&layer[..pieces * 32], pl) != *root {
+            return false;
+        }
+        let layer = &layer[..pieces * 32];
+        if !self.has_v1() {
+            let start = (self.files[..file].iter().map(|f| f.length).sum::<u64>() / pl) as usize;
+            for (i, h) in layer.chunks(32).enumerate() {
+                self.hashes[start + i] = h.to_vec();
+            }
+        }
+        self.piece_layers.insert(*root, layer.to_vec());
+        true
+    }
+
+    /// Returns hashes of a file's merkle tree for a BEP 52 hash request,
+    /// followed by the uncle hashes of `proof_layers` layers above them.
+    /// Only requests based on the piece layer can be served.
+    pub fn layer_hashes(
+        &self,
+        root: &[u8; 32],
+        base_layer: u32,
+        index: u32,
+        length: u32,
+        proof_layers: u32,
+    ) -> Option<Vec<u8>> {
+        let layer = self.piece_layers.get(root)?;
+        if base_layer != self.piece_layer_idx() || !length.is_power_of_two()
+            || index % length != 0
+        {
+            return None;
+        }
+        let leaves = to_hashes(layer);
+        let width = (u64::from(self.piece_len) / MERKLE_BLOCK) as usize;
+        let pad = util::merkle_root(&[], width, [0; 32]);
+        let tree = util::merkle_layers(&leaves, leaves.len(), pad);
+        let (index, length) = (index as usize, length as usize);
+        if index + length > tree[0].len() {
+            return None;
+        }
+        let mut hashes = Vec::with_capacity((length + proof_layers as usize) * 32);
+        for h in &tree[0][index..index + length] {
+            hashes.extend_from_slice(h);
+        }
+        let mut level = length.trailing_zeros() as usize;
+        let mut pos = index / length;
+        for _ in 0..proof_layers {
+            if level + 1 >= tree.len() {
+                break;
+            }
+            hashes.extend_from_slice(&tree[level][pos ^ 1]);
+            level += 1;
+            pos /= 2;
+        }
+        Some(hashes)
+    }
+
+    /// The file a piece starts in and the offset in it
+    fn piece_file(&self, idx: u32) -> (usize, u64) {
+        let (mut file, offset) = self.piece_idx[idx as usize];
+        while self.files[file].length == 0 {
+            file += 1;
+        }
+        (file, offset)
+    }
+
     /// Calculates the file offsets for a given block at index/begin
     pub fn block_disk_locs(info: &Arc<Info>, index: u32, begin: u32) -> LocIter {
         let len = info.block_len(index, begin);
@@ -497,6 +904,187 @@ impl Iterator for LocIter {
     }
 }
 
+fn is_web_seed(url: &Url) -> bool {
+    url.scheme() == "http" || url.scheme() == "https"
+}
+
+/// Recursively finds all files at path, in sorted order. Symlinked
+/// directories under path are skipped, as they may form cycles.
+fn find_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if fs::metadata(path)?.is_dir() {
+        let mut entries = fs::read_dir(path)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            if fs::symlink_metadata(&entry)?.file_type().is_symlink()
+                && fs::metadata(&entry).map(|m| m.is_dir()).unwrap_or(false)
+            {
+                debug!("Skipping symlinked directory {:?}", entry);
+                continue;
+            }
+            find_files(&entry, files)?;
+        }
+    } else {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+/// Chooses a power of two piece length for the given torrent size
+fn auto_piece_len(total_len: u64) -> u32 {
+    let mut len = MIN_PIECE_LEN;
+    while len < MAX_PIECE_LEN && total_len / len > TARGET_PIECES {
+        len *= 2;
+    }
+    len as u32
+}
+
+fn truncate_hash(hash: &[u8; 32]) -> [u8; 20] {
+    let mut h = [0u8; 20];
+    h.copy_from_slice(&hash[..20]);
+    h
+}
+
+fn to_hashes(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(32)
+        .map(|c| {
+            let mut h = [0u8; 32];
+            h.copy_from_slice(c);
+            h
+        })
+        .collect()
+}
+
+/// Computes the pieces root of a file from its piece layer
+fn layer_root(layer: &[u8], pl: u64) -> [u8; 32] {
+    let leaves = to_hashes(layer);
+    // Pieces past the end of the file are entirely zero leaves
+    let pad = util::merkle_root(&[], (pl / MERKLE_BLOCK) as usize, [0; 32]);
+    util::merkle_root(&leaves, leaves.len(), pad)
+}
+
+/// Parses a BEP 52 file tree into files in tree order. Single file
+/// torrents have the file named after the torrent at the root.
+fn parse_file_tree(tree: BEncode, name: &str, files: &mut Vec<File>) -> Result<(), &'static str> {
+    let single = tree.as_dict()
+        .map(|t| {
+            t.len() == 1
+                && t.get(name)
+                    .and_then(|n| n.as_dict())
+                    .map(|n| n.contains_key(""))
+                    .unwrap_or(false)
+        })
+        .unwrap_or(false);
+    let base = if single {
+        PathBuf::new()
+    } else {
+        PathBuf::from(name)
+    };
+    parse_tree_node(tree, base, files)
+}
+
+fn parse_tree_node(
+    node: BEncode,
+    path: PathBuf,
+    files: &mut Vec<File>,
+) -> Result<(), &'static str> {
+    for (part, child) in node.into_dict().ok_or("File tree nodes must be dictionaries")? {
+        if !part.is_empty() {
+            parse_tree_node(child, path.join(part), files)?;
+            continue;
+        }
+        let mut d = child.into_dict().ok_or("File tree files must be dictionaries")?;
+        let length = d.remove("length")
+            .and_then(|l| l.into_int())
+            .ok_or("File length must be a valid int")? as u64;
+        let mut f = File::new(path.clone(), length);
+        f.pieces_root = d.remove("pieces root")
+            .and_then(|r| r.into_bytes())
+            .and_then(|r| if r.len() == 32 { to_hashes(&r).pop() } else { None });
+        if length > 0 && f.pieces_root.is_none() {
+            return Err("Files must have a valid pieces root");
+        }
+        files.push(f);
+    }
+    Ok(())
+}
+
+/// Adds the pieces roots of a hybrid torrent's v2 files to its v1 files,
+/// which must be the same apart from padding files
+fn merge_file_tree(files: &mut [File], tree: Vec<File>) -> Result<(), &'static str> {
+    let mut tree = tree.into_iter();
+    for f in files.iter_mut().filter(|f| !f.padding) {
+        match tree.next() {
+            Some(ref t) if t.path == f.path && t.length == f.length => {
+                f.pieces_root = t.pieces_root;
+            }
+            _ => return Err("Hybrid torrent v1 and v2 files must match"),
+        }
+    }
+    if tree.next().is_some() {
+        return Err("Hybrid torrent v1 and v2 files must match");
+    }
+    Ok(())
+}
+
+/// Aligns the files of a v2 only torrent to piece boundaries with
+/// padding files, as files never share pieces in v2.
+fn pad_files(files: Vec<File>, pl: u64, name: &str) -> Vec<File> {
+    let count = files.len();
+    let mut padded = Vec::with_capacity(count);
+    for (i, f) in files.into_iter().enumerate() {
+        let rem = f.length % pl;
+        padded.push(f);
+        if rem != 0 && i + 1 != count {
+            let path = Path::new(name).join(".pad").join((pl - rem).to_string());
+            let mut pad = File::new(path, pl - rem);
+            pad.padding = true;
+            padded.push(pad);
+        }
+    }
+    padded
+}
+
+/// Ensures all piece layers have the right length and match their file
+fn check_piece_layers(
+    files: &[File],
+    pl: u64,
+    layers: &BTreeMap<[u8; 32], Vec<u8>>,
+) -> Result<(), &'static str> {
+    for f in files.iter().filter(|f| f.length > pl) {
+        if let Some(layer) = f.pieces_root.as_ref().and_then(|r| layers.get(r)) {
+            let pieces = (f.length + pl - 1) / pl;
+            if layer.len() as u64 != pieces * 32
+                || Some(layer_root(layer, pl)) != f.pieces_root
+            {
+                return Err("Piece layers must match their pieces root");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Determines the piece hashes of a v2 only torrent, which are the
+/// merkle roots of each piece. Pieces of files whose piece layer
+/// is missing are left empty until it's received from a peer.
+fn v2_hashes(files: &[File], pl: u64, layers: &BTreeMap<[u8; 32], Vec<u8>>) -> Vec<Vec<u8>> {
+    let mut hashes = Vec::new();
+    for f in files.iter().filter(|f| !f.padding && f.length > 0) {
+        let root = f.pieces_root.unwrap();
+        if f.length <= pl {
+            hashes.push(root.to_vec());
+            continue;
+        }
+        let pieces = ((f.length + pl - 1) / pl) as usize;
+        match layers.get(&root) {
+            Some(layer) => hashes.extend(layer.chunks(32).map(|h| h.to_vec())),
+            None => hashes.extend(vec![vec![]; pieces]),
+        }
+    }
+    hashes
+}
+
 fn parse_bencode_files(mut data: BTreeMap<String, BEncode>) -> Result<Vec<File>, &'static str> {
     match data.remove("files").and_then(|l| l.into_list()) {
         Some(fs) => {
@@ -539,18 +1127,197 @@ mod tests {
         assert_eq!(info.block_len(pieces, 16_384), (end % 16_384) as u32);
     }
 
+    #[test]
+    fn parse_web_seeds() {
+        let mut i = BTreeMap::new();
+        i.insert("name".to_owned(), BEncode::from_str("foo"));
+        i.insert("piece length".to_owned(), BEncode::Int(16_384));
+        i.insert("pieces".to_owned(), BEncode::String(vec![0u8; 20]));
+        i.insert("length".to_owned(), BEncode::Int(16_384));
+        let mut d = BTreeMap::new();
+        d.insert("info".to_owned(), BEncode::Dict(i));
+
+        d.insert("url-list".to_owned(), BEncode::from_str("http://a.com/foo"));
+        let info = Info::from_bencode(BEncode::Dict(d.clone())).unwrap();
+        assert_eq!(info.web_seeds, vec![Url::parse("http://a.com/foo").unwrap()]);
+
+        let urls = vec![
+            BEncode::from_str("http://a.com/"),
+            BEncode::from_str("udp://b.com/"),
+            BEncode::from_str("https://c.com/"),
+        ];
+        d.insert("url-list".to_owned(), BEncode::List(urls));
+        let info = Info::from_bencode(BEncode::Dict(d)).unwrap();
+        assert_eq!(
+            info.web_seeds,
+            vec![
+                Url::parse("http://a.com/").unwrap(),
+                Url::parse("https://c.com/").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn create_roundtrip() {
+        let mut dir = ::std::env::temp_dir();
+        dir.push(format!("synapse-create-{}", ::std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a"), vec![1u8; 40_000]).unwrap();
+        fs::write(dir.join("sub").join("b"), vec![2u8; 10_000]).unwrap();
+        // Symlinked directories are skipped, so loops can't be followed
+        ::std::os::unix::fs::symlink(&dir, dir.join("sub").join("loop")).unwrap();
+
+        let trackers = vec![
+            Url::parse("http://a.com/announce").unwrap(),
+            Url::parse("udp://b.com:80").unwrap(),
+        ];
+        let mut info = Info::create(&dir, 0, trackers, vec![], true, None).unwrap();
+        info.add_files(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(info.piece_len, 16_384);
+        assert_eq!(info.total_len, 50_000);
+        assert_eq!(info.pieces(), 4);
+        assert_eq!(info.files[1].path, Path::new(&info.name).join("sub").join("b"));
+        info.set_hashes(vec![vec![0u8; 20]; 4]);
+
+        let parsed = Info::from_bencode(info.to_torrent_bencode()).unwrap();
+        assert_eq!(parsed.hash, info.hash);
+        assert_eq!(parsed.name, info.name);
+        assert!(parsed.private);
+        assert_eq!(parsed.url_list.len(), 2);
+        let paths: Vec<_> = parsed.files.iter().map(|f| f.path.clone()).collect();
+        let expected: Vec<_> = info.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(paths, expected);
+    }
+
+    /// Builds a v2 torrent of two files, the first with two 32 KiB pieces
+    fn v2_torrent(a: &[u8], b: &[u8], layers: bool) -> BEncode {
+        let pad = util::merkle_root(&[], 2, [0; 32]);
+        let piece_roots: Vec<_> = a.chunks(32_768)
+            .map(|p| {
+                let leaves: Vec<_> = p.chunks(16_384).map(sha256_hash).collect();
+                util::merkle_root(&leaves, 2, [0; 32])
+            })
+            .collect();
+        let root_a = util::merkle_root(&piece_roots, 2, pad);
+        let root_b = util::merkle_root(&[sha256_hash(b)], 1, [0; 32]);
+
+        let file = |len: usize, root: [u8; 32]| {
+            let mut f = BTreeMap::new();
+            f.insert("length".to_owned(), BEncode::Int(len as i64));
+            f.insert("pieces root".to_owned(), BEncode::String(root.to_vec()));
+            let mut node = BTreeMap::new();
+            node.insert("".to_owned(), BEncode::Dict(f));
+            BEncode::Dict(node)
+        };
+        let mut tree = BTreeMap::new();
+        tree.insert("a".to_owned(), file(a.len(), root_a));
+        tree.insert("b".to_owned(), file(b.len(), root_b));
+        let mut i = BTreeMap::new();
+        i.insert("name".to_owned(), BEncode::from_str("foo"));
+        i.insert("piece length".to_owned(), BEncode::Int(32_768));
+        i.insert("meta version".to_owned(), BEncode::Int(2));
+        i.insert("file tree".to_owned(), BEncode::Dict(tree));
+        let mut d = BTreeMap::new();
+        d.insert("info".to_owned(), BEncode::Dict(i));
+        if layers {
+            let mut l = BTreeMap::new();
+            let layer = piece_roots.iter().flat_map(|r| r.to_vec()).collect();
+            l.insert(root_a.to_vec(), BEncode::String(layer));
+            d.insert("piece layers".to_owned(), BEncode::RawDict(l));
+        }
+        BEncode::Dict(d)
+    }
+
+    #[test]
+    fn parse_v2() {
+        let (a, b) = (vec![1u8; 40_000], vec![2u8; 10_000]);
+        let info = Info::from_bencode(v2_torrent(&a, &b, true)).unwrap();
+        let hash_v2 = info.hash_v2.unwrap();
+        assert_eq!(info.hash[..], hash_v2[..20]);
+        assert_eq!(info.id(), hash_to_id(&hash_v2));
+        assert!(!info.has_v1());
+        // a is padded so that b starts at a piece boundary
+        assert_eq!(info.files.len(), 3);
+        assert!(info.files[1].padding);
+        assert_eq!(info.files[1].length, 65_536 - 40_000);
+        assert_eq!(info.files[2].path, Path::new("foo").join("b"));
+        assert_eq!(info.pieces(), 3);
+
+        let mut p1 = a[32_768..].to_vec();
+        p1.resize(32_768, 0);
+        assert!(info.piece_valid(0, &a[..32_768]));
+        assert!(info.piece_valid(1, &p1));
+        assert!(info.piece_valid(2, &b));
+        assert!(!info.piece_valid(2, &a[..10_000]));
+
+        let info = Arc::new(info);
+        assert_eq!(Info::piece_data_len(&info, 1), 40_000 - 32_768);
+        assert_eq!(Info::piece_data_len(&info, 2), 10_000);
+
+        // Re-encoding gives the same info hash and piece layers
+        let parsed = Info::from_bencode(info.to_torrent_bencode()).unwrap();
+        assert_eq!(parsed.hash_v2, info.hash_v2);
+        assert_eq!(parsed.piece_layers, info.piece_layers);
+    }
+
+    #[test]
+    fn v2_piece_layers() {
+        let (a, b) = (vec![1u8; 40_000], vec![2u8; 10_000]);
+        let full = Info::from_bencode(v2_torrent(&a, &b, true)).unwrap();
+        let mut info = Info::from_bencode(v2_torrent(&a, &b, false)).unwrap();
+        let root = info.files[0].pieces_root.unwrap();
+        assert_eq!(info.missing_layers(), vec![(root, 2)]);
+        assert!(info.hashes[0].is_empty());
+        assert!(!info.piece_verifiable(0));
+        assert!(info.piece_verifiable(2));
+        assert!(info.layer_hashes(&root, 1, 0, 2, 0).is_none());
+
+        let layer = full.layer_hashes(&root, 1, 0, 2, 0).unwrap();
+        assert_eq!(layer.len(), 64);
+        assert!(full.layer_hashes(&root, 0, 0, 2, 0).is_none());
+        assert!(!info.set_piece_layer(&root, &layer[..32]));
+        assert!(info.set_piece_layer(&root, &layer));
+        assert!(info.missing_layers().is_empty());
+        assert!(info.piece_verifiable(0));
+        assert_eq!(info.hashes, full.hashes);
+
+        // Uncle hashes stop at the root
+        let proof = full.layer_hashes(&root, 1, 1, 1, 4).unwrap();
+        assert_eq!(&proof[..32], &layer[32..]);
+        assert_eq!(&proof[32..], &layer[..32]);
+    }
+
+    #[test]
+    fn parse_magnet_v2() {
+        let v2 = "1220".to_owned() + &"ab".repeat(32);
+        let info = Info::from_magnet(&format!("magnet:?xt=urn:btmh:{}", v2)).unwrap();
+        assert_eq!(info.hash, [0xab; 20]);
+        assert_eq!(info.hash_v2, Some([0xab; 32]));
+        assert_eq!(info.id(), "AB".repeat(32));
+
+        let v1 = "cd".repeat(20);
+        let uri = format!("magnet:?xt=urn:btih:{}&xt=urn:btmh:{}", v1, v2);
+        let info = Info::from_magnet(&uri).unwrap();
+        assert_eq!(info.hash, [0xcd; 20]);
+        assert!(info.matches(&[0xab; 20]));
+        assert_eq!(info.id(), "CD".repeat(20));
+    }
+
+    #[test]
+    fn auto_piece_len_bounds() {
+        assert_eq!(auto_piece_len(1), 16_384);
+        assert_eq!(auto_piece_len(1500 * 16_384), 16_384);
+        assert_eq!(auto_piece_len(1501 * 16_384), 32_768);
+        assert_eq!(auto_piece_len(::std::u64::MAX), 16 * 1024 * 1024);
+    }
+
     #[test]
     fn loc_iter_bounds() {
         let mut info = Info::with_pieces(4);
         info.files.clear();
-        info.files.push(File {
-            path: PathBuf::from(""),
-            length: 40000,
-        });
-        info.files.push(File {
-            path: PathBuf::from(""),
-            length: 10000,
-        });
+        info.files.push(File::new(PathBuf::from(""), 40000));
+        info.files.push(File::new(PathBuf::from(""), 10000));
         info.total_len = 50000;
         info.piece_idx =
             Info::generate_piece_idx(info.hashes.len(), info.piece_len as u64, &info.files);
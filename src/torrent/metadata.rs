@@ -0,0 +1,308 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use bencode::{self, BEncode};
+use util::UHashMap;
+
+/// Size of a metadata piece
+pub const PIECE_SIZE: usize = 16_384;
+/// Largest metadata accepted from peers
+const MAX_SIZE: usize = 16 * 1024 * 1024;
+/// Maximum number of outstanding piece requests to a single peer
+const MAX_REQS: usize = 2;
+/// Time after which an unanswered piece request is retried
+const REQ_TIMEOUT_SECS: u64 = 15;
+
+/// A ut_metadata (BEP 9) message
+#[derive(Debug, PartialEq)]
+pub enum Msg<'a> {
+    Request(usize),
+    Data(usize, &'a [u8]),
+    Reject(usize),
+}
+
+/// Picks the metadata pieces to request from peers offering the metadata
+/// and assembles them, retrying requests which time out or are rejected.
+pub struct Metadata {
+    /// Metadata size offered by each peer
+    peers: UHashMap<usize>,
+    size: usize,
+    data: Vec<u8>,
+    pieces: Vec<Piece>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Piece {
+    Missing,
+    Requested { pid: usize, at: Instant },
+    Received { pid: usize },
+}
+
+impl Metadata {
+    pub fn new() -> Metadata {
+        Metadata {
+            peers: UHashMap::default(),
+            size: 0,
+            data: vec![],
+            pieces: vec![],
+        }
+    }
+
+    /// Adds a peer which offers metadata of the given size, returning false
+    /// if the size is invalid. The size of the first peer is fetched until
+    /// the metadata fails to verify.
+    pub fn add_peer(&mut self, pid: usize, size: usize) -> bool {
+        if size == 0 || size > MAX_SIZE {
+            return false;
+        }
+        self.peers.insert(pid, size);
+        if self.pieces.is_empty() {
+            self.reset(size);
+        }
+        true
+    }
+
+    /// Removes a peer, making its outstanding requests available to others.
+    pub fn remove_peer(&mut self, pid: usize) {
+        self.peers.remove(&pid);
+        for piece in &mut self.pieces {
+            match *piece {
+                Piece::Requested { pid: p, .. } if p == pid => *piece = Piece::Missing,
+                _ => {}
+            }
+        }
+    }
+
+    /// Picks a piece to request from the peer, if it offers metadata
+    /// of the size being fetched and has few enough outstanding requests.
+    pub fn pick(&mut self, pid: usize) -> Option<usize> {
+        if self.peers.get(&pid) != Some(&self.size) {
+            return None;
+        }
+        let reqs = self.pieces
+            .iter()
+            .filter(|piece| match **piece {
+                Piece::Requested { pid: p, .. } => p == pid,
+                _ => false,
+            })
+            .count();
+        if reqs >= MAX_REQS {
+            return None;
+        }
+        let idx = match self.pieces.iter().position(|p| *p == Piece::Missing) {
+            Some(idx) => idx,
+            None => return None,
+        };
+        self.pieces[idx] = Piece::Requested {
+            pid,
+            at: Instant::now(),
+        };
+        Some(idx)
+    }
+
+    /// Stores a piece received from the peer. An error is returned if
+    /// the peer never offered the metadata or the piece has the wrong size.
+    pub fn received(&mut self, pid: usize, idx: usize, data: &[u8]) -> Result<(), ()> {
+        if self.peers.get(&pid) != Some(&self.size) || idx >= self.pieces.len() {
+            return Err(());
+        }
+        let start = idx * PIECE_SIZE;
+        let len = cmp::min(PIECE_SIZE, self.size - start);
+        if data.len() != len {
+            return Err(());
+        }
+        if let Piece::Received { .. } = self.pieces[idx] {
+            return Ok(());
+        }
+        self.data[start..start + len].copy_from_slice(data);
+        self.pieces[idx] = Piece::Received { pid };
+        Ok(())
+    }
+
+    /// Handles a rejected request, the peer isn't asked for pieces again.
+    pub fn rejected(&mut self, pid: usize) {
+        self.remove_peer(pid);
+    }
+
+    /// Discards the assembled metadata after it failed to verify. The peers
+    /// which sent any of it are no longer used, and the size offered by
+    /// most of the remaining peers is fetched instead.
+    pub fn failed(&mut self) {
+        for piece in &self.pieces {
+            if let Piece::Received { pid } = *piece {
+                self.peers.remove(&pid);
+            }
+        }
+        let mut sizes = UHashMap::default();
+        for size in self.peers.values() {
+            *sizes.entry(*size).or_insert(0) += 1;
+        }
+        let size = sizes
+            .into_iter()
+            .max_by_key(|&(_, count)| count)
+            .map(|(size, _)| size)
+            .unwrap_or(0);
+        self.reset(size);
+    }
+
+    /// Makes timed out requests available again, returning true if any were.
+    pub fn tick(&mut self) -> bool {
+        let timeout = Duration::from_secs(REQ_TIMEOUT_SECS);
+        let mut expired = false;
+        for piece in &mut self.pieces {
+            match *piece {
+                Piece::Requested { at, .. } if at.elapsed() > timeout => {
+                    *piece = Piece::Missing;
+                    expired = true;
+                }
+                _ => {}
+            }
+        }
+        expired
+    }
+
+    pub fn complete(&self) -> bool {
+        !self.pieces.is_empty() && self.pieces.iter().all(|p| match *p {
+            Piece::Received { .. } => true,
+            _ => false,
+        })
+    }
+
+    pub fn progress(&self) -> f32 {
+        if self.pieces.is_empty() {
+            return 0.;
+        }
+        let received = self.pieces
+            .iter()
+            .filter(|p| match **p {
+                Piece::Received { .. } => true,
+                _ => false,
+            })
+            .count();
+        received as f32 / self.pieces.len() as f32
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn reset(&mut self, size: usize) {
+        self.size = size;
+        self.data = vec![0u8; size];
+        self.pieces = vec![Piece::Missing; (size + PIECE_SIZE - 1) / PIECE_SIZE];
+    }
+}
+
+/// Returns a piece of the metadata, or None if the index is out of bounds
+pub fn piece(data: &[u8], idx: usize) -> Option<&[u8]> {
+    let start = idx.checked_mul(PIECE_SIZE)?;
+    if start >= data.len() {
+        return None;
+    }
+    let end = cmp::min(start + PIECE_SIZE, data.len());
+    Some(&data[start..end])
+}
+
+/// Encodes a ut_metadata message
+pub fn encode(msg: &Msg, total_size: usize) -> Vec<u8> {
+    let mut d = BTreeMap::new();
+    let (kind, piece) = match *msg {
+        Msg::Request(p) => (0, p),
+        Msg::Data(p, _) => (1, p),
+        Msg::Reject(p) => (2, p),
+    };
+    d.insert("msg_type".to_owned(), BEncode::Int(kind));
+    d.insert("piece".to_owned(), BEncode::Int(piece as i64));
+    if let Msg::Data(..) = *msg {
+        d.insert("total_size".to_owned(), BEncode::Int(total_size as i64));
+    }
+    let mut payload = BEncode::Dict(d).encode_to_buf();
+    if let Msg::Data(_, data) = *msg {
+        payload.extend_from_slice(data);
+    }
+    payload
+}
+
+/// Decodes a ut_metadata message, with the data of a piece
+/// being everything following the bencoded dictionary.
+pub fn decode(payload: &[u8]) -> Result<Msg, ()> {
+    let mut c = Cursor::new(payload);
+    let mut d = bencode::decode(&mut c)
+        .ok()
+        .and_then(BEncode::into_dict)
+        .ok_or(())?;
+    let kind = d.remove("msg_type").and_then(BEncode::into_int).ok_or(())?;
+    let piece = d.remove("piece").and_then(BEncode::into_int).ok_or(())?;
+    if piece < 0 {
+        return Err(());
+    }
+    match kind {
+        0 => Ok(Msg::Request(piece as usize)),
+        1 => Ok(Msg::Data(piece as usize, &payload[c.position() as usize..])),
+        2 => Ok(Msg::Reject(piece as usize)),
+        _ => Err(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let data = [1u8; 10];
+        for msg in vec![Msg::Request(1), Msg::Data(2, &data[..]), Msg::Reject(3)] {
+            assert_eq!(decode(&encode(&msg, 16_394)).unwrap(), msg);
+        }
+        assert!(decode(b"d8:msg_typei5e5:piecei0ee").is_err());
+        assert!(decode(b"d8:msg_typei0e5:piecei-1ee").is_err());
+        assert!(decode(b"d8:msg_typei0ee").is_err());
+    }
+
+    #[test]
+    fn test_piece() {
+        let data = vec![1u8; PIECE_SIZE + 10];
+        assert_eq!(piece(&data, 0).unwrap().len(), PIECE_SIZE);
+        assert_eq!(piece(&data, 1).unwrap().len(), 10);
+        assert!(piece(&data, 2).is_none());
+        assert!(piece(&data, usize::max_value() / 2).is_none());
+        assert!(piece(&data, usize::max_value()).is_none());
+    }
+
+    #[test]
+    fn test_pick() {
+        let mut md = Metadata::new();
+        assert!(!md.add_peer(0, MAX_SIZE + 1));
+        assert!(md.add_peer(0, PIECE_SIZE * 2 + 10));
+        assert!(md.add_peer(1, PIECE_SIZE * 2 + 10));
+        assert!(md.add_peer(2, 100));
+        assert_eq!(md.pick(0), Some(0));
+        assert_eq!(md.pick(0), Some(1));
+        assert_eq!(md.pick(0), None);
+        assert_eq!(md.pick(1), Some(2));
+        // Peers offering another size aren't used
+        assert_eq!(md.pick(2), None);
+
+        assert!(md.received(1, 2, &[0u8; 11]).is_err());
+        assert!(md.received(2, 2, &[0u8; 10]).is_err());
+        md.received(1, 2, &[0u8; 10]).unwrap();
+        md.rejected(0);
+        assert_eq!(md.pick(1), Some(0));
+        assert_eq!(md.pick(1), Some(1));
+        md.received(1, 0, &[0u8; PIECE_SIZE]).unwrap();
+        assert!(!md.complete());
+        md.received(1, 1, &[0u8; PIECE_SIZE]).unwrap();
+        assert!(md.complete());
+        assert_eq!(md.data().len(), PIECE_SIZE * 2 + 10);
+
+        // The remaining peer's size is fetched after the metadata fails
+        md.failed();
+        assert_eq!(md.progress(), 0.);
+        assert_eq!(md.pick(1), None);
+        assert_eq!(md.pick(2), Some(0));
+        md.received(2, 0, &[0u8; 100]).unwrap();
+        assert!(md.complete());
+    }
+}
@@ -3,13 +3,17 @@ pub mod peer;
 pub mod bitfield;
 mod picker;
 mod choker;
+mod pex;
+mod webseed;
+mod metadata;
 
-use std::fmt;
+use std::{cmp, fmt, mem};
 use std::collections::{BTreeMap, VecDeque};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::borrow::Cow;
 use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
 
 use bincode;
 use chrono::{DateTime, Utc};
@@ -18,19 +22,32 @@ use url::Url;
 pub use self::bitfield::Bitfield;
 pub use self::info::{Info, LocIter};
 pub use self::peer::{Peer, PeerConn};
-pub use self::peer::Message;
+pub use self::peer::{HashRequest, Message};
 pub use self::picker::Block;
 
+use self::metadata::Metadata;
 use self::picker::Picker;
-use {bencode, disk, rpc, util, CONFIG, EXT_PROTO, UT_META_ID};
+use self::webseed::WebSeed;
+use {bencode, disk, rpc, util, CONFIG, EXT_PROTO, UT_META_ID, UT_PEX_ID};
 use control::cio;
 use rpc::resource::{self, Resource, SResourceUpdate};
 use throttle::Throttle;
-use tracker::{self, TrackerResponse};
+use tracker::{self, ScrapeResponse, TrackerResponse};
 use util::{AView, FHashSet, MHashMap, UHashMap};
 use session::torrent::current::Session;
 use {session, stat};
 
+/// Maximum number of hashes requested in a BEP 52 hash request
+const MAX_HASHES: u32 = 512;
+/// Delay before retrying a failed tracker, doubled for each consecutive failure
+const TRK_BACKOFF_SECS: u64 = 60;
+/// Maximum delay before retrying a failed tracker
+const TRK_MAX_BACKOFF_SECS: u64 = 60 * 60 * 2;
+/// Maximum number of byte ranges streamed at once
+const MAX_STREAMS: usize = 2;
+/// Number of a stream's next missing pieces given deadlines
+const STREAM_PIECES: usize = 8;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum TrackerStatus {
     Updating,
@@ -62,13 +79,51 @@ pub struct Torrent<T: cio::CIO> {
     dirty: bool,
     path: Option<String>,
     info_bytes: Vec<u8>,
-    info_idx: Option<usize>,
+    /// Metadata being fetched from peers while in magnet state
+    metadata: Option<Metadata>,
     created: DateTime<Utc>,
+    /// Peer addresses advertised via PEX so far
+    pex: FHashSet<SocketAddr>,
+    /// Peer addresses received via PEX which should be connected to
+    pex_peers: Vec<SocketAddr>,
+    web_seeds: Vec<WebSeed>,
+    /// BEP 52 piece layers being received from peers, keyed
+    /// by pieces root and then the index of the first hash
+    layers: BTreeMap<[u8; 32], BTreeMap<u32, Vec<u8>>>,
+    queue_position: u32,
+    /// Seeding goals, overriding the configured ones if set.
+    /// Negative values disable a goal.
+    seed_ratio: Option<f32>,
+    seed_time: Option<i64>,
+    seed_idle: Option<i64>,
+    /// Seconds spent seeding, and seeding without uploading
+    seeding_time: u64,
+    idle_time: u64,
+    /// Upload total when seeding time was last accounted
+    seed_uploaded: u64,
+    /// Whether a seeding goal was reached as of the last update.
+    /// Goals are acted on only once reached, so torrents resumed
+    /// afterwards keep seeding.
+    seed_goal_reached: bool,
+    /// Byte ranges of the torrent being downloaded over HTTP,
+    /// whose pieces are picked by deadline
+    streams: VecDeque<(u64, u64)>,
+    /// Whether the picker was last reported to be in endgame
+    endgame: bool,
+    /// Offsets and sender IPs of the blocks of pieces awaiting validation
+    block_peers: UHashMap<Vec<(u32, IpAddr)>>,
+    /// Offsets, sender IPs and hashes of the blocks of pieces which failed
+    /// validation, compared against the valid blocks to find bad senders
+    failed_blocks: UHashMap<Vec<(u32, IpAddr, [u8; 20])>>,
+    /// IPs found to have sent corrupt data which should be banned
+    bans: Vec<IpAddr>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Status {
     pub paused: bool,
+    /// Set while the torrent is held back by the queue
+    pub queued: bool,
     pub validating: Option<f32>,
     pub error: Option<String>,
     pub state: StatusState,
@@ -85,9 +140,68 @@ pub enum StatusState {
 
 pub struct Tracker {
     pub url: AView<Url>,
+    /// Announce-list tier (BEP 12) of the tracker
+    pub tier: usize,
     pub status: TrackerStatus,
     pub last_announce: DateTime<Utc>,
+    /// Time of the next announce
     pub update: Option<Instant>,
+    /// Earliest time the tracker allows another announce
+    pub min_update: Option<Instant>,
+    /// Number of consecutive failed announces
+    pub failures: u32,
+    pub tracker_id: Option<String>,
+    /// Warning message of the last announce
+    pub warning: Option<String>,
+    /// Swarm statistics from the last scrape
+    pub scrape: Option<ScrapeResponse>,
+    /// Whether the tracker was sent a started event since the torrent
+    /// last started, or since it last failed
+    pub started: bool,
+    /// Whether the tracker failed since its tier was last reset,
+    /// in which case the next tracker is announced to instead.
+    failed: bool,
+}
+
+impl Tracker {
+    fn new(url: AView<Url>, tier: usize) -> Tracker {
+        Tracker {
+            url,
+            tier,
+            status: TrackerStatus::Updating,
+            last_announce: Utc::now(),
+            update: None,
+            min_update: None,
+            failures: 0,
+            tracker_id: None,
+            warning: None,
+            scrape: None,
+            started: false,
+            failed: false,
+        }
+    }
+
+    /// Wall clock time of the next announce
+    fn next_announce(&self) -> Option<DateTime<Utc>> {
+        self.update.map(|update| {
+            let now = Instant::now();
+            let left = if update > now {
+                update - now
+            } else {
+                Duration::from_secs(0)
+            };
+            Utc::now() + chrono::Duration::from_std(left).unwrap_or(chrono::Duration::zero())
+        })
+    }
+
+    fn set_failure(&mut self, reason: String) {
+        self.failures = self.failures.saturating_add(1);
+        let backoff = (TRK_BACKOFF_SECS << (self.failures - 1).min(16)).min(TRK_MAX_BACKOFF_SECS);
+        self.update = Some(Instant::now() + Duration::from_secs(backoff));
+        self.status = TrackerStatus::Failure(reason);
+        self.last_announce = Utc::now();
+        self.started = false;
+    }
 }
 
 impl Status {
@@ -99,7 +213,7 @@ impl Status {
     }
 
     pub fn stopped(&self) -> bool {
-        self.paused || self.error.is_some()
+        self.paused || self.queued || self.error.is_some()
     }
 
     pub fn completed(&self) -> bool {
@@ -123,6 +237,9 @@ impl Status {
         if self.error.is_some() {
             return rpc::resource::Status::Error;
         }
+        if self.queued {
+            return rpc::resource::Status::Queued;
+        }
 
         match self.state {
             StatusState::Incomplete => {
@@ -152,53 +269,81 @@ impl<T: cio::CIO> Torrent<T> {
         throttle: Throttle,
         cio: T,
         start: bool,
+    ) -> Torrent<T> {
+        Torrent::create(id, path, info, throttle, cio, start, false)
+    }
+
+    /// Creates a torrent whose files are known to be complete, e.g. one
+    /// just created from them, which seeds without being validated.
+    pub fn new_complete(
+        id: usize,
+        path: Option<String>,
+        info: Info,
+        throttle: Throttle,
+        cio: T,
+        start: bool,
+    ) -> Torrent<T> {
+        Torrent::create(id, path, info, throttle, cio, start, true)
+    }
+
+    fn create(
+        id: usize,
+        path: Option<String>,
+        info: Info,
+        throttle: Throttle,
+        cio: T,
+        start: bool,
+        complete: bool,
     ) -> Torrent<T> {
         debug!("Creating {:?}", info);
         let peers = UHashMap::default();
-        let pieces = Bitfield::new(u64::from(info.pieces()));
+        let mut pieces = Bitfield::new(u64::from(info.pieces()));
         let leechers = FHashSet::default();
         let mut status = Status {
             paused: !start,
+            queued: true,
             validating: None,
             error: None,
             state: StatusState::Incomplete,
         };
         let priorities = vec![3; info.files.len()];
-        let info_idx = if info.complete() {
+        let metadata = if info.complete() {
             None
         } else {
             status.state = StatusState::Magnet;
-            Some(::std::usize::MAX)
+            Some(Metadata::new())
         };
-        let info_bytes = if info_idx.is_none() {
+        let complete = complete && metadata.is_none();
+        if complete {
+            for i in 0..pieces.len() {
+                pieces.set_bit(i);
+            }
+            status.state = StatusState::Complete;
+        }
+        let info_bytes = if metadata.is_none() {
             info.to_bencode().encode_to_buf()
         } else {
             vec![]
         };
         let info = Arc::new(info);
         let picker = Picker::new(&info, &pieces, &priorities);
+        let web_seeds = if metadata.is_none() {
+            Torrent::<T>::init_webseeds(&info)
+        } else {
+            vec![]
+        };
 
         let mut trackers = VecDeque::with_capacity(1);
         if !info.url_list.is_empty() {
             for (i, list) in info.url_list.iter().enumerate() {
                 for (j, _) in list.iter().enumerate() {
-                    let tracker = Tracker {
-                        status: TrackerStatus::Updating,
-                        update: None,
-                        last_announce: Utc::now(),
-                        url: AView::new(&info, |inf| &inf.url_list[i][j]),
-                    };
-                    trackers.push_back(tracker);
+                    let url = AView::new(&info, |inf| &inf.url_list[i][j]);
+                    trackers.push_back(Tracker::new(url, i));
                 }
             }
         } else if info.announce.is_some() {
-            let tracker = Tracker {
-                status: TrackerStatus::Updating,
-                update: None,
-                last_announce: Utc::now(),
-                url: AView::new(&info, |i| i.announce.as_ref().unwrap()),
-            };
-            trackers.push_back(tracker);
+            let url = AView::new(&info, |i| i.announce.as_ref().unwrap());
+            trackers.push_back(Tracker::new(url, 0));
         }
 
         let mut t = Torrent {
@@ -221,11 +366,28 @@ impl<T: cio::CIO> Torrent<T> {
             dirty: true,
             status: status.clone(),
             info_bytes,
-            info_idx,
+            metadata,
             created: Utc::now(),
+            pex: FHashSet::default(),
+            pex_peers: Vec::new(),
+            layers: BTreeMap::new(),
+            web_seeds,
+            queue_position: 0,
+            seed_ratio: None,
+            seed_time: None,
+            seed_idle: None,
+            seeding_time: 0,
+            idle_time: 0,
+            seed_uploaded: 0,
+            seed_goal_reached: false,
+            streams: VecDeque::new(),
+            endgame: false,
+            block_peers: UHashMap::default(),
+            failed_blocks: UHashMap::default(),
+            bans: Vec::new(),
         };
         t.start();
-        if CONFIG.disk.validate && t.info_idx.is_none() {
+        if CONFIG.disk.validate && t.metadata.is_none() && !complete {
             t.validate();
         } else {
             t.announce_start();
@@ -258,53 +420,67 @@ impl<T: cio::CIO> Torrent<T> {
             total_len: d.info.total_len,
             hashes: d.info.hashes,
             hash: d.info.hash,
+            hash_v2: d.info.hash_v2,
+            piece_layers: d.info.piece_layers,
             files: d.info
                 .files
                 .into_iter()
                 .map(|f| info::File {
                     path: f.path,
                     length: f.length,
+                    padding: f.padding,
+                    pieces_root: f.pieces_root,
                 })
                 .collect(),
             private: d.info.private,
             be_name: d.info.be_name,
             piece_idx: d.info.piece_idx,
             url_list: vec![],
+            web_seeds: d.info
+                .web_seeds
+                .iter()
+                .filter_map(|url| Url::parse(url).ok())
+                .collect(),
         });
 
-        let info_idx = if info.complete() {
+        let metadata = if info.complete() {
             None
         } else {
-            Some(::std::usize::MAX)
+            Some(Metadata::new())
         };
-        let info_bytes = if info_idx.is_none() {
+        let info_bytes = if metadata.is_none() {
             info.to_bencode().encode_to_buf()
         } else {
             vec![]
         };
         let picker = picker::Picker::new(&info, &d.pieces, &d.priorities);
+        let web_seeds = if metadata.is_none() {
+            Torrent::<T>::init_webseeds(&info)
+        } else {
+            vec![]
+        };
         throttle.set_ul_rate(d.throttle_ul);
         throttle.set_dl_rate(d.throttle_dl);
 
-        let mut trackers: VecDeque<_> = d.trackers
+        // Trackers are saved in announce order, their tiers are those of the
+        // announce-list, with trackers added over RPC in the first tier.
+        let mut trackers: Vec<_> = d.trackers
             .into_iter()
             .filter_map(|url| Url::parse(&url).ok())
-            .map(|url| Tracker {
-                status: TrackerStatus::Updating,
-                update: None,
-                last_announce: Utc::now(),
-                url: AView::value(url),
+            .map(|url| {
+                let tier = info.url_list
+                    .iter()
+                    .position(|tier| tier.contains(&url))
+                    .unwrap_or(0);
+                Tracker::new(AView::value(url), tier)
             })
             .collect();
+        trackers.sort_by_key(|t| t.tier);
+        let mut trackers: VecDeque<_> = trackers.into_iter().collect();
 
         if trackers.is_empty() && info.announce.is_some() {
-            let tracker = Tracker {
-                status: TrackerStatus::Updating,
-                update: None,
-                last_announce: Utc::now(),
-                url: AView::new(&info, |i| i.announce.as_ref().unwrap()),
-            };
-            trackers.push_back(tracker);
+            let url = AView::new(&info, |i| i.announce.as_ref().unwrap());
+            trackers.push_back(Tracker::new(url, 0));
         }
 
         let mut t = Torrent {
@@ -326,6 +502,7 @@ impl<T: cio::CIO> Torrent<T> {
             dirty: false,
             status: Status {
                 paused: d.status.paused,
+                queued: true,
                 validating: None,
                 error: d.status.error,
                 state: match d.status.state {
@@ -336,10 +513,29 @@ impl<T: cio::CIO> Torrent<T> {
             },
             path: d.path,
             info_bytes,
-            info_idx,
+            metadata,
             created: d.created,
+            pex: FHashSet::default(),
+            pex_peers: Vec::new(),
+            layers: BTreeMap::new(),
+            web_seeds,
+            queue_position: d.queue_position,
+            seed_ratio: d.seed_ratio,
+            seed_time: d.seed_time,
+            seed_idle: d.seed_idle,
+            seeding_time: d.seeding_time,
+            idle_time: d.idle_time,
+            seed_uploaded: d.uploaded,
+            seed_goal_reached: false,
+            streams: VecDeque::new(),
+            endgame: false,
+            block_peers: UHashMap::default(),
+            failed_blocks: UHashMap::default(),
+            bans: Vec::new(),
         };
         t.status.error = None;
+        // Goals reached before the restart were already acted on
+        t.seed_goal_reached = t.complete() && t.seed_goal();
         t.start();
         t.announce_start();
         if d.status.validating {
@@ -359,6 +555,8 @@ impl<T: cio::CIO> Torrent<T> {
                 total_len: self.info.total_len,
                 hashes: self.info.hashes.clone(),
                 hash: self.info.hash,
+                hash_v2: self.info.hash_v2,
+                piece_layers: self.info.piece_layers.clone(),
                 files: self.info
                     .files
                     .iter()
@@ -366,11 +564,18 @@ impl<T: cio::CIO> Torrent<T> {
                     .map(|f| session::torrent::current::File {
                         path: f.path,
                         length: f.length,
+                        padding: f.padding,
+                        pieces_root: f.pieces_root,
                     })
                     .collect(),
                 private: self.info.private,
                 be_name: self.info.be_name.clone(),
                 piece_idx: self.info.piece_idx.clone(),
+                web_seeds: self.info
+                    .web_seeds
+                    .iter()
+                    .map(|url| url.as_str().to_owned())
+                    .collect(),
             },
             pieces: self.pieces.clone(),
             uploaded: self.uploaded,
@@ -395,6 +600,12 @@ impl<T: cio::CIO> Torrent<T> {
                 .iter()
                 .map(|trk| trk.url.as_str().to_owned())
                 .collect(),
+            queue_position: self.queue_position,
+            seed_ratio: self.seed_ratio,
+            seed_time: self.seed_time,
+            seed_idle: self.seed_idle,
+            seeding_time: self.seeding_time,
+            idle_time: self.idle_time,
         };
         let data = bincode::serialize(&d, bincode::Infinite).expect("Serialization failed!");
         debug!("Sending serialization request!");
@@ -404,13 +615,13 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn rpc_id(&self) -> String {
-        util::hash_to_id(&self.info.hash[..])
+        self.info.id()
     }
 
     pub fn delete(&mut self, artifacts: bool) {
         debug!("Sending file deletion request!");
         let mut files = Vec::new();
-        for file in &self.info.files {
+        for file in self.info.files.iter().filter(|f| !f.padding) {
             files.push(file.path.clone());
         }
         self.cio.msg_disk(disk::Request::delete(
@@ -434,76 +645,199 @@ impl<T: cio::CIO> Torrent<T> {
         self.priority
     }
 
-    pub fn set_tracker_response(&mut self, url: &Url, resp: &tracker::Result<TrackerResponse>) {
-        debug!("Processing tracker response");
-        let mut time = Instant::now();
-        match *resp {
-            Ok(ref r) => {
-                self.trackers
-                    .iter_mut()
-                    .find(|t| &*t.url == url)
-                    .map(|tracker| {
-                        time += Duration::from_secs(u64::from(r.interval));
-                        tracker.status = TrackerStatus::Ok {
-                            seeders: r.seeders,
-                            leechers: r.leechers,
-                            interval: r.interval,
-                        };
-                        tracker.update = Some(time);
-                        tracker.last_announce = Utc::now();
-                    });
+    pub fn queue_position(&self) -> u32 {
+        self.queue_position
+    }
+
+    pub fn set_queue_position(&mut self, queue_position: u32) {
+        if self.queue_position == queue_position {
+            return;
+        }
+        self.queue_position = queue_position;
+        self.dirty = true;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentQueue {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                queue_position,
+            },
+        ]));
+    }
+
+    /// Starts or stops the torrent on behalf of the queue. Queued
+    /// torrents leave the swarm, but keep their paused state.
+    pub fn set_queued(&mut self, queued: bool) {
+        if self.status.queued == queued {
+            return;
+        }
+        if queued {
+            debug!("Queueing torrent!");
+            if !self.status.paused {
+                self.announce_stop();
             }
-            Err(tracker::Error(tracker::ErrorKind::TrackerError(ref s), _)) => {
-                self.trackers
-                    .iter_mut()
-                    .find(|t| &*t.url == url)
-                    .map(|tracker| {
-                        time += Duration::from_secs(300);
-                        tracker.update = Some(time);
-                        tracker.status = TrackerStatus::Failure(s.clone());
-                        tracker.last_announce = Utc::now();
-                    });
+            self.cio.msg_trk(tracker::Request::LSDRemove(self.info.hash));
+            for pid in self.peers.keys() {
+                self.cio.remove_peer(*pid);
             }
-            Err(ref e) => {
-                self.trackers
-                    .iter_mut()
-                    .find(|t| &*t.url == url)
-                    .map(|tracker| {
-                        error!("Failed to query tracker {}: {}", url, e);
-                        // Wait 5 minutes before trying again
-                        time += Duration::from_secs(300);
-                        tracker.update = Some(time);
-                        let reason = format!("Couldn't contact tracker: {}", e);
-                        tracker.status = TrackerStatus::Failure(reason);
-                        tracker.last_announce = Utc::now();
-                    });
+            self.status.queued = true;
+        } else {
+            debug!("Dequeueing torrent!");
+            self.status.queued = false;
+            // Validation will start the torrent once done
+            if self.status.validating.is_none() {
+                self.announce_start();
+            }
+            self.request_all();
+        }
+        self.announce_status();
+    }
+
+    pub fn set_tracker_response(&mut self, url: &Url, resp: &tracker::Result<TrackerResponse>) {
+        debug!("Processing tracker response");
+        {
+            let tracker = match self.trackers.iter_mut().find(|t| &*t.url == url) {
+                Some(t) => t,
+                None => return,
+            };
+            match *resp {
+                Ok(ref r) => {
+                    let now = Instant::now();
+                    tracker.status = TrackerStatus::Ok {
+                        seeders: r.seeders,
+                        leechers: r.leechers,
+                        interval: r.interval,
+                    };
+                    // Trackers may give a min interval above the interval
+                    let interval = cmp::max(r.interval, r.min_interval.unwrap_or(0));
+                    tracker.update = Some(now + Duration::from_secs(u64::from(interval)));
+                    tracker.min_update = r.min_interval
+                        .map(|i| now + Duration::from_secs(u64::from(i)));
+                    tracker.failures = 0;
+                    if r.tracker_id.is_some() {
+                        tracker.tracker_id = r.tracker_id.clone();
+                    }
+                    tracker.warning = r.warning.clone();
+                    tracker.last_announce = Utc::now();
+                }
+                Err(tracker::Error(tracker::ErrorKind::TrackerError(ref s), _)) => {
+                    tracker.set_failure(s.clone());
+                }
+                Err(ref e) => {
+                    error!("Failed to query tracker {}: {}", url, e);
+                    tracker.set_failure(format!("Couldn't contact tracker: {}", e));
+                }
             }
         }
 
-        if resp.is_err() && self.trackers.iter().find(|t| &*t.url == url).is_some() {
-            if let Some(front) = self.trackers.pop_front() {
-                self.trackers.push_back(front);
+        if let Some(idx) = self.trackers.iter().position(|t| &*t.url == url) {
+            if resp.is_ok() {
+                self.tracker_succeeded(idx);
+            } else {
+                self.tracker_failed(idx);
                 self.try_update_tracker();
             }
         }
         self.update_rpc_tracker();
     }
 
-    pub fn try_update_tracker(&mut self) {
-        if let Some(end) = self.trackers.front().and_then(|t| t.update) {
-            debug!("Updating tracker at interval!");
-            let cur = Instant::now();
-            if cur >= end {
-                self.update_tracker();
-            }
+    /// Promotes the tracker to the front of its tier, which is
+    /// announced to from its first tracker again.
+    fn tracker_succeeded(&mut self, idx: usize) {
+        let tier = self.trackers[idx].tier;
+        for trk in self.trackers.iter_mut().filter(|t| t.tier == tier) {
+            trk.failed = false;
+        }
+        let pos = self.trackers
+            .iter()
+            .position(|t| t.tier == tier)
+            .unwrap_or(idx);
+        if let Some(trk) = self.trackers.remove(idx) {
+            self.trackers.insert(pos, trk);
+        }
+    }
+
+    /// Marks the tracker as failed, so the next one in its tier, or the
+    /// first of the next tier, is used. Once every tracker failed the
+    /// tiers are retried from the start.
+    fn tracker_failed(&mut self, idx: usize) {
+        self.trackers[idx].failed = true;
+        let tier = self.trackers[idx].tier;
+        let exhausted = if CONFIG.trk.announce_all_tiers {
+            self.trackers
+                .iter()
+                .filter(|t| t.tier == tier)
+                .all(|t| t.failed)
         } else {
-            self.update_tracker();
+            self.trackers.iter().all(|t| t.failed)
+        };
+        if exhausted {
+            for trk in self.trackers
+                .iter_mut()
+                .filter(|t| t.tier == tier || !CONFIG.trk.announce_all_tiers)
+            {
+                trk.failed = false;
+            }
+        }
+    }
+
+    /// Returns the trackers which should be announced to: the current
+    /// tracker of the first tier that has not failed, or that of every
+    /// tier if configured to announce to all tiers.
+    pub fn announce_trackers(&self) -> Vec<&Tracker> {
+        let mut trackers: Vec<&Tracker> = Vec::new();
+        for trk in self.trackers.iter().filter(|t| !t.failed) {
+            if trackers.last().map(|t| t.tier != trk.tier).unwrap_or(true) {
+                trackers.push(trk);
+                if !CONFIG.trk.announce_all_tiers {
+                    break;
+                }
+            }
         }
+        trackers
+    }
+
+    pub fn set_scrape_response(&mut self, url: &Url, resp: ScrapeResponse) {
+        let id = match self.trackers.iter_mut().find(|t| &*t.url == url) {
+            Some(tracker) => {
+                tracker.scrape = Some(resp);
+                util::trk_rpc_id(&self.info.hash, url.as_str())
+            }
+            None => return,
+        };
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TrackerScrape {
+                id,
+                kind: resource::ResourceKind::Tracker,
+                complete: resp.complete,
+                incomplete: resp.incomplete,
+                downloaded: resp.downloaded,
+            },
+        ]));
     }
 
-    pub fn update_tracker(&mut self) {
-        if let Some(req) = tracker::Request::interval(self) {
+    pub fn try_update_tracker(&mut self) {
+        if self.status.stopped() {
+            return;
+        }
+        let cur = Instant::now();
+        let reqs: Vec<_> = self.announce_trackers()
+            .into_iter()
+            .filter(|t| t.update.map(|end| cur >= end).unwrap_or(true))
+            .filter(|t| t.min_update.map(|min| cur >= min).unwrap_or(true))
+            .map(|t| (t.url.as_ref().clone(), tracker::Request::custom(self, t)))
+            .collect();
+        for (url, req) in reqs {
+            debug!("Updating tracker at interval!");
             self.cio.msg_trk(req);
+            self.tracker_started(&url);
+        }
+    }
+
+    /// Records that the tracker was sent a started event
+    fn tracker_started(&mut self, url: &Url) {
+        if let Some(trk) = self.trackers.iter_mut().find(|t| &*t.url == url) {
+            trk.started = true;
         }
     }
 
@@ -518,12 +852,7 @@ impl<T: cio::CIO> Torrent<T> {
 
     pub fn add_tracker(&mut self, url: Url) -> String {
         let id = util::trk_rpc_id(&self.info.hash, url.as_str());
-        self.trackers.push_front(Tracker {
-            status: TrackerStatus::Updating,
-            update: None,
-            last_announce: Utc::now(),
-            url: AView::value(url),
-        });
+        self.trackers.push_front(Tracker::new(AView::value(url), 0));
         {
             let trk = &self.trackers[0];
             let res = vec![
@@ -559,12 +888,40 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
+    /// Announces to the tracker, or if its min interval hasn't
+    /// passed yet, schedules the announce for when it has.
     pub fn update_tracker_req(&mut self, rpc_id: &str) {
-        self.trackers
+        let idx = match self.trackers
+            .iter()
+            .position(|trk| util::trk_rpc_id(&self.info.hash, trk.url.as_str()) == rpc_id)
+        {
+            Some(idx) => idx,
+            None => return,
+        };
+        match self.trackers[idx].min_update {
+            Some(min) if min > Instant::now() => {
+                self.trackers[idx].update = Some(min);
+                self.update_rpc_tracker();
+            }
+            _ => {
+                let req = tracker::Request::custom(self, &self.trackers[idx]);
+                self.cio.msg_trk(req);
+                self.trackers[idx].started = true;
+            }
+        }
+    }
+
+    pub fn scrape_tracker_req(&mut self, rpc_id: &str) {
+        let url = match self.trackers
             .iter()
             .find(|trk| util::trk_rpc_id(&self.info.hash, trk.url.as_str()) == rpc_id)
-            .and_then(|trk| tracker::Request::custom(self, trk.url.clone()))
-            .map(|req| self.cio.msg_trk(req));
+        {
+            Some(trk) => trk.url.as_ref().clone(),
+            None => return,
+        };
+        for req in tracker::Request::scrape(url, vec![(self.id, self.info.hash)]) {
+            self.cio.msg_trk(req);
+        }
     }
 
     pub fn get_throttle(&self, id: usize) -> Throttle {
@@ -620,9 +977,16 @@ impl<T: cio::CIO> Torrent<T> {
                     },
                 ]));
             }
-            disk::Response::PieceValidated { piece, valid, .. } => {
+            disk::Response::PieceValidated {
+                piece,
+                valid,
+                blocks,
+                ..
+            } => {
+                let senders = self.block_peers.remove(&(piece as usize)).unwrap_or_default();
                 // We use a transient, on the fly validation approach for simplicity.
                 if valid {
+                    self.piece_valid(piece, &blocks);
                     // Tell all relevant peers we got the piece
                     let m = Message::Have(piece);
                     for pid in &self.leechers {
@@ -638,6 +1002,7 @@ impl<T: cio::CIO> Torrent<T> {
                     }
                 } else {
                     info!("Invalid piece downloaded!");
+                    self.piece_invalid(piece, senders, &blocks);
                 }
             }
             disk::Response::ValidationUpdate { percent, .. } => {
@@ -708,7 +1073,9 @@ impl<T: cio::CIO> Torrent<T> {
                 error!("Disk error: {:?}", err);
                 self.status.error = Some(format!("{:?}", err));
             }
-            disk::Response::FreeSpace(_) => unreachable!(),
+            disk::Response::FreeSpace(_)
+            | disk::Response::IpFilter(_)
+            | disk::Response::Created { .. } => unreachable!(),
         }
         self.announce_status();
     }
@@ -751,7 +1118,7 @@ impl<T: cio::CIO> Torrent<T> {
     fn set_finished(&mut self) {
         // It's ok to say we've completed even if we haven't downloaded everything since
         // the `left` field should indicate how much there still is to download.
-        if let Some(req) = tracker::Request::completed(self) {
+        for req in tracker::Request::completed(self) {
             self.cio.msg_trk(req);
         }
         // Order here is important, if we're in an idle status,
@@ -784,9 +1151,16 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
-    pub fn peer_ev(&mut self, pid: cio::PID, evt: cio::Result<Message>) -> Result<(), ()> {
+    /// Handles an event for a peer, returning an error if the peer
+    /// was removed. If the peer should be reconnected to over TCP
+    /// or without encryption, a new connection is returned in the error.
+    pub fn peer_ev(
+        &mut self,
+        pid: cio::PID,
+        evt: cio::Result<Message>,
+    ) -> Result<(), Option<PeerConn>> {
         // TODO: Consider Boxing peers so it's just pointer insert/removal
-        let mut peer = self.peers.remove(&pid).ok_or(())?;
+        let mut peer = self.peers.remove(&pid).ok_or(None)?;
         match evt {
             Ok(mut msg) => {
                 if peer.handle_msg(&mut msg).is_ok() && self.handle_msg(msg, &mut peer).is_ok() {
@@ -810,9 +1184,9 @@ impl<T: cio::CIO> Torrent<T> {
                     e
                 );
                 self.cleanup_peer(&mut peer);
+                Err(peer.retry())
             }
         }
-        Err(())
     }
 
     pub fn handle_msg(&mut self, msg: Message, peer: &mut Peer<T>) -> Result<(), ()> {
@@ -826,14 +1200,42 @@ impl<T: cio::CIO> Torrent<T> {
                         "ut_metadata".to_owned(),
                         bencode::BEncode::Int(i64::from(UT_META_ID)),
                     );
+                    if !self.info.private {
+                        m.insert(
+                            "ut_pex".to_owned(),
+                            bencode::BEncode::Int(i64::from(UT_PEX_ID)),
+                        );
+                    }
                     ed.insert("m".to_owned(), bencode::BEncode::Dict(m));
-                    ed.insert(
-                        "metadata_size".to_owned(),
-                        bencode::BEncode::Int(self.info_bytes.len() as i64),
-                    );
+                    ed.insert("p".to_owned(), bencode::BEncode::Int(i64::from(CONFIG.port)));
+                    if self.metadata.is_none() {
+                        ed.insert(
+                            "metadata_size".to_owned(),
+                            bencode::BEncode::Int(self.info_bytes.len() as i64),
+                        );
+                    }
                     let payload = bencode::BEncode::Dict(ed).encode_to_buf();
                     peer.send_message(Message::Extension { id: 0, payload });
                 }
+                if self.info.complete() {
+                    self.request_layers(peer);
+                }
+            }
+            Message::HashRequest(req) => {
+                let hashes = self.info.layer_hashes(
+                    &req.pieces_root,
+                    req.base_layer,
+                    req.index,
+                    req.length,
+                    req.proof_layers,
+                );
+                match hashes {
+                    Some(hashes) => peer.send_message(Message::Hashes { req, hashes }),
+                    None => peer.send_message(Message::HashReject(req)),
+                }
+            }
+            Message::Hashes { req, hashes } => {
+                self.layer_received(&req, &hashes)?;
             }
             Message::Extension { id, payload } => {
                 self.handle_ext(id, payload, peer)?;
@@ -878,80 +1280,10 @@ impl<T: cio::CIO> Torrent<T> {
                 data,
                 length,
             } => {
-                // Ignore a piece we already have, this could happen from endgame
-                if self.pieces.has_bit(u64::from(index)) {
-                    return Ok(());
-                }
-
-                // Even though we have the data, if we are stopped we shouldn't use the disk
-                // regardless.
-                if self.status.stopped() || self.status.completed() {
-                    return Ok(());
-                }
-
-                // The length doesn't match what it should be
-                if self.info.block_len(index, begin) != length {
-                    return Err(());
-                }
-
-                // We already have this block, don't do anything with it, could happen
-                // from endgame
-                if self.picker.have_block(Block::new(index, begin)) {
-                    return Ok(());
-                }
-
-                let pr = self.picker.completed(Block::new(index, begin));
-                let (piece_done, peers) = if let Ok(r) = pr {
-                    r
-                } else {
-                    return Ok(());
-                };
-
-                self.dirty = true;
-                self.write_piece(index, begin, data);
-
-                self.downloaded += u64::from(length);
-                self.stat.add_dl(u64::from(length));
-
-                if piece_done {
-                    self.pieces.set_bit(u64::from(index));
-                    // Begin validation, and save state if the torrent is done
-                    self.check_complete();
-                    // Do on the fly validation of the piece
-                    if !self.complete() {
-                        if !self.leechers.is_empty() {
-                            self.cio.msg_disk(disk::Request::validate_piece(
-                                self.id,
-                                self.info.clone(),
-                                self.path.clone(),
-                                index,
-                            ));
-                        }
-                    }
-
-                    // Mark uninteresting peers
-                    for peer in self.peers.values_mut() {
-                        if !self.pieces.usable(peer.pieces()) {
-                            peer.uninterested();
-                        }
-                    }
-                }
-
-                // If there are any peers we've asked duplicate pieces for,
-                // cancel them, though we should still assume they'll probably send it anyways
-                let m = Message::Cancel {
-                    index,
-                    begin,
-                    length,
-                };
-
-                for pid in peers.into_iter().filter(|p| *p != peer.id()) {
-                    if let Some(peer) = self.peers.get_mut(&pid) {
-                        peer.send_message(m.clone());
-                    }
-                }
-
-                if self.status.should_dl() {
+                let ip = peer.addr().ip();
+                if self.block_received(index, begin, length, data, peer.id(), Some(ip))?
+                    && self.status.should_dl()
+                {
                     Torrent::make_requests(peer, &mut self.picker, &self.info);
                 }
             }
@@ -982,6 +1314,9 @@ impl<T: cio::CIO> Torrent<T> {
             // so just ignore here
             Message::KeepAlive | Message::Choke | Message::Cancel { .. } | Message::Port(_) => {}
 
+            // Other peers will be asked for the layer
+            Message::HashReject(_) => {}
+
             Message::SharedPiece { .. } => unreachable!(),
         }
         Ok(())
@@ -993,36 +1328,16 @@ impl<T: cio::CIO> Torrent<T> {
             let mut d = b.into_dict().ok_or(())?;
             let m = d.remove("m").and_then(|v| v.into_dict()).ok_or(())?;
             if m.contains_key("ut_metadata") {
-                let size = d.remove("metadata_size")
-                    .and_then(|v| v.into_int())
-                    .ok_or(())?;
-                if let Some(::std::usize::MAX) = self.info_idx {
-                    if size % 16_384 == 0 {
-                        self.info_idx = Some(size as usize / 16_384 - 1);
+                if let Some(ref mut md) = self.metadata {
+                    let size = d.remove("metadata_size")
+                        .and_then(|v| v.into_int())
+                        .unwrap_or(0);
+                    if size > 0 && md.add_peer(peer.id(), size as usize) {
+                        Torrent::<T>::request_metadata(md, peer);
                     } else {
-                        self.info_idx = Some(size as usize / 16_384);
-                    }
-                    self.info_bytes.reserve(size as usize);
-                    unsafe {
-                        self.info_bytes.set_len(size as usize);
+                        debug!("Ignoring peer offering metadata of size {}", size);
                     }
                 }
-                if !self.info.complete() {
-                    // Request the first index chunk to see if they have it
-                    let mut respb = BTreeMap::new();
-                    respb.insert("msg_type".to_owned(), bencode::BEncode::Int(0));
-                    respb.insert("piece".to_owned(), bencode::BEncode::Int(0));
-                    let payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                    let utm_id = if let Some(i) = peer.exts().ut_meta {
-                        i
-                    } else {
-                        return Err(());
-                    };
-                    peer.send_message(Message::Extension {
-                        id: utm_id,
-                        payload,
-                    });
-                }
             }
         } else if id == UT_META_ID {
             let utm_id = if let Some(i) = peer.exts().ut_meta {
@@ -1030,107 +1345,56 @@ impl<T: cio::CIO> Torrent<T> {
             } else {
                 return Err(());
             };
-            let b = bencode::decode_buf(&payload).map_err(|_| ())?;
-            let mut d = b.into_dict().ok_or(())?;
-            let t = d.remove("msg_type").and_then(|v| v.into_int()).ok_or(())?;
-            let p = d.remove("piece").and_then(|v| v.into_int()).ok_or(())? as usize;
-            if p * 16_384 >= self.info_bytes.len() {
-                return Err(());
-            }
-            // Our metadata request strategy is as follows: after requesting the first
-            // index chunk, we attempt to request every single subsequent chunk from
-            // a peer which responds succesfully. This is slightly wasteful, but
-            // simplifies logic (since we don't have to do "index piece picking").
-            match t {
-                0 => {
-                    let mut respb = BTreeMap::new();
-                    if self.info_idx.is_none() {
-                        respb.insert("msg_type".to_owned(), bencode::BEncode::Int(1));
-                        respb.insert("piece".to_owned(), bencode::BEncode::Int(p as i64));
-                        let size = if self.info_bytes.len() / 16_384 == p {
-                            self.info_bytes.len() % 16_384
-                        } else {
-                            16_384
-                        };
-                        let total_size = self.info_bytes.len() as i64;
-                        respb.insert("total_size".to_owned(), bencode::BEncode::Int(total_size));
-                        let mut payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                        let s = p * 16_384;
-                        payload.extend_from_slice(&self.info_bytes[s..s + size]);
-                        peer.send_message(Message::Extension {
-                            id: utm_id,
-                            payload,
-                        });
-                    } else {
-                        respb.insert("msg_type".to_owned(), bencode::BEncode::Int(2));
-                        respb.insert("piece".to_owned(), bencode::BEncode::Int(p as i64));
-                        let payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                        peer.send_message(Message::Extension {
-                            id: utm_id,
-                            payload,
-                        });
-                    }
+            match metadata::decode(&payload)? {
+                metadata::Msg::Request(p) => {
+                    let msg = match metadata::piece(&self.info_bytes, p) {
+                        Some(data) if self.metadata.is_none() => metadata::Msg::Data(p, data),
+                        _ => metadata::Msg::Reject(p),
+                    };
+                    peer.send_message(Message::Extension {
+                        id: utm_id,
+                        payload: metadata::encode(&msg, self.info_bytes.len()),
+                    });
                 }
-                1 => {
-                    if let Some(idx) = self.info_idx {
-                        let data_idx = util::find_subseq(&payload[..], b"ee").unwrap() + 2;
-                        if payload.len() - data_idx > self.info_bytes.len() - p * 16_384 {
-                            debug!(
-                                "Metadata bounds invalid, goes to: {}, ibl: {}",
-                                payload.len() - data_idx,
-                                self.info_bytes.len() - p * 16_384,
-                            );
-                            return Err(());
-                        }
-                        let size = if p == idx {
-                            self.info_bytes.len() - p * 16_384
-                        } else {
-                            16_384
-                        };
-                        (&mut self.info_bytes[p * 16_384..p * 16_384 + size])
-                            .copy_from_slice(&payload[data_idx..]);
-                        if p == idx {
-                            let mut b = BTreeMap::new();
-                            let bni = bencode::decode_buf(&self.info_bytes).map_err(|_| ())?;
-                            b.insert(
-                                "announce".to_owned(),
-                                bencode::BEncode::String(
-                                    self.info
-                                        .announce
-                                        .as_ref()
-                                        .map(|u| u.as_str())
-                                        .unwrap_or("")
-                                        .as_bytes()
-                                        .to_vec(),
-                                ),
-                            );
-                            b.insert("info".to_owned(), bni);
-                            let ni = Info::from_bencode(bencode::BEncode::Dict(b)).map_err(|_| ())?;
-                            if ni.hash == self.info.hash {
-                                debug!("Magnet file acquired succesfully!");
-                                self.info_idx = None;
-                                self.info = Arc::new(ni);
-                                self.magnet_complete();
-                            } else {
+                metadata::Msg::Data(p, data) => {
+                    let complete = match self.metadata {
+                        Some(ref mut md) => {
+                            if md.received(peer.id(), p, data).is_err() {
+                                debug!("Peer sent invalid metadata piece {}", p);
                                 return Err(());
                             }
-                        } else if p == 0 {
-                            for i in 1..(idx + 1) {
-                                let mut respb = BTreeMap::new();
-                                respb.insert("msg_type".to_owned(), bencode::BEncode::Int(0));
-                                respb.insert("piece".to_owned(), bencode::BEncode::Int(i as i64));
-                                let payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                                peer.send_message(Message::Extension {
-                                    id: utm_id,
-                                    payload,
-                                });
-                            }
+                            md.complete()
+                        }
+                        None => return Ok(()),
+                    };
+                    if complete {
+                        self.metadata_complete(peer);
+                    } else {
+                        self.update_metadata_progress();
+                        if let Some(ref mut md) = self.metadata {
+                            Torrent::<T>::request_metadata(md, peer);
                         }
                     }
                 }
-                2 => {}
-                i => {
-                    debug!("Got unknown ut_meta id: {}", i);
+                metadata::Msg::Reject(p) => {
+                    debug!("Peer rejected metadata piece {}", p);
+                    if let Some(ref mut md) = self.metadata {
+                        md.rejected(peer.id());
+                    }
+                    self.request_all_metadata();
+                }
+            }
+        } else if id == UT_PEX_ID {
+            if self.info.private {
+                return Ok(());
+            }
+            let added = pex::decode(&payload)?;
+            let connected: FHashSet<_> = self.peers.values().map(|p| p.addr()).collect();
+            for addr in added {
+                if addr != peer.addr() && !connected.contains(&addr)
+                    && !self.pex_peers.contains(&addr)
+                {
+                    self.pex_peers.push(addr);
                 }
             }
         } else {
@@ -1139,6 +1403,280 @@ impl<T: cio::CIO> Torrent<T> {
         Ok(())
     }
 
+    /// Builds the info from the fetched metadata, starting the download
+    /// if it matches the torrent's hash and refetching it otherwise.
+    fn metadata_complete(&mut self, peer: &mut Peer<T>) {
+        let ni = match self.metadata {
+            Some(ref md) => self.metadata_info(md.data()),
+            None => return,
+        };
+        match ni {
+            Ok(ni) => {
+                debug!("Magnet file acquired succesfully!");
+                self.info_bytes = self.metadata.take().unwrap().data().to_vec();
+                self.info = Arc::new(ni);
+                self.update_metadata_progress();
+                self.magnet_complete();
+                self.request_layers(peer);
+            }
+            Err(()) => {
+                debug!("Fetched metadata is invalid, refetching");
+                if let Some(ref mut md) = self.metadata {
+                    md.failed();
+                }
+                self.update_metadata_progress();
+                self.request_all_metadata();
+            }
+        }
+    }
+
+    fn metadata_info(&self, data: &[u8]) -> Result<Info, ()> {
+        let bni = bencode::decode_buf(data).map_err(|_| ())?;
+        let mut b = BTreeMap::new();
+        b.insert(
+            "announce".to_owned(),
+            bencode::BEncode::String(
+                self.info
+                    .announce
+                    .as_ref()
+                    .map(|u| u.as_str())
+                    .unwrap_or("")
+                    .as_bytes()
+                    .to_vec(),
+            ),
+        );
+        b.insert("info".to_owned(), bni);
+        let mut ni = Info::from_bencode(bencode::BEncode::Dict(b)).map_err(|_| ())?;
+        // Web seeds from the magnet link aren't part of the info dict
+        ni.web_seeds = self.info.web_seeds.clone();
+        // v2 magnets may identify hybrid torrents by their v2 hash
+        if ni.matches(&self.info.hash) {
+            ni.hash = self.info.hash;
+            Ok(ni)
+        } else {
+            Err(())
+        }
+    }
+
+    /// Requests metadata pieces from every peer which offered the metadata
+    fn request_all_metadata(&mut self) {
+        if let Some(ref mut md) = self.metadata {
+            for peer in self.peers.values_mut() {
+                Torrent::<T>::request_metadata(md, peer);
+            }
+        }
+    }
+
+    fn request_metadata(md: &mut Metadata, peer: &mut Peer<T>) {
+        let utm_id = match peer.exts().ut_meta {
+            Some(id) => id,
+            None => return,
+        };
+        while let Some(idx) = md.pick(peer.id()) {
+            peer.send_message(Message::Extension {
+                id: utm_id,
+                payload: metadata::encode(&metadata::Msg::Request(idx), 0),
+            });
+        }
+    }
+
+    /// Requests the piece layers missing from v2 only torrents,
+    /// which aren't part of the metadata exchanged with peers.
+    fn request_layers(&self, peer: &mut Peer<T>) {
+        if self.info.has_v1() || !peer.supports_v2() {
+            return;
+        }
+        let base_layer = self.info.piece_layer_idx();
+        for (pieces_root, pieces) in self.info.missing_layers() {
+            let length = cmp::min(pieces.next_power_of_two(), MAX_HASHES);
+            let mut index = 0;
+            while index < pieces {
+                peer.send_message(Message::HashRequest(Box::new(HashRequest {
+                    pieces_root,
+                    base_layer,
+                    index,
+                    length,
+                    proof_layers: 0,
+                })));
+                index += length;
+            }
+        }
+    }
+
+    /// Stores hashes received for a missing piece layer, setting
+    /// the layer once all of them have been received.
+    fn layer_received(&mut self, req: &HashRequest, hashes: &[u8]) -> Result<(), ()> {
+        let root = req.pieces_root;
+        let pieces = match self.info
+            .missing_layers()
+            .into_iter()
+            .find(|&(r, _)| r == root)
+        {
+            Some((_, pieces)) => pieces as usize,
+            None => return Ok(()),
+        };
+        let len = req.length as usize * 32;
+        if req.base_layer != self.info.piece_layer_idx() || hashes.len() < len {
+            return Err(());
+        }
+        let received = {
+            let chunks = self.layers.entry(root).or_insert_with(BTreeMap::new);
+            chunks.insert(req.index, hashes[..len].to_vec());
+            chunks.values().map(|c| c.len() / 32).sum::<usize>()
+        };
+        if received < pieces {
+            return Ok(());
+        }
+        let layer: Vec<u8> = self.layers
+            .remove(&root)
+            .unwrap()
+            .into_iter()
+            .flat_map(|(_, c)| c)
+            .collect();
+        if !Arc::make_mut(&mut self.info).set_piece_layer(&root, &layer) {
+            debug!("Received invalid piece layer for {}", self);
+            return Err(());
+        }
+        // The layer's pieces can now be validated, so download them
+        self.picker.set_priorities(&self.priorities, &self.info);
+        self.request_all();
+        if self.info.missing_layers().is_empty() {
+            self.serialize();
+            self.dump_torrent_file();
+        }
+        Ok(())
+    }
+
+    /// Sends PEX messages to all peers which support it. Peers which
+    /// have not yet been sent a PEX message get every connected peer
+    /// advertised so far, others get the peers added and dropped since
+    /// the last update.
+    pub fn update_pex(&mut self) {
+        if self.info.private {
+            return;
+        }
+        let current: Vec<(SocketAddr, u8)> = self.peers
+            .values()
+            .filter_map(|p| {
+                let flags = if p.pieces().complete() {
+                    pex::FLAG_SEED | pex::FLAG_REACHABLE
+                } else {
+                    pex::FLAG_REACHABLE
+                };
+                p.listen_addr().map(|a| (a, flags))
+            })
+            .collect();
+        let cset: FHashSet<_> = current.iter().map(|&(a, _)| a).collect();
+        let mut added: Vec<_> = current
+            .iter()
+            .filter(|&&(a, _)| !self.pex.contains(&a))
+            .cloned()
+            .collect();
+        let mut dropped: Vec<_> = self.pex.difference(&cset).cloned().collect();
+        // Only what fits in a message is sent, the rest being left
+        // for later updates
+        pex::truncate(&mut added, |&(a, _)| a);
+        pex::truncate(&mut dropped, |&a| a);
+        for a in &dropped {
+            self.pex.remove(a);
+        }
+        self.pex.extend(added.iter().map(|&(a, _)| a));
+        let known: Vec<_> = current
+            .iter()
+            .filter(|&&(a, _)| self.pex.contains(&a))
+            .cloned()
+            .collect();
+
+        for peer in self.peers.values_mut() {
+            let id = match peer.exts().ut_pex {
+                Some(id) => id,
+                None => continue,
+            };
+            let own = peer.listen_addr();
+            let others = |peers: &[(SocketAddr, u8)]| -> Vec<(SocketAddr, u8)> {
+                peers.iter().filter(|&&(a, _)| Some(a) != own).cloned().collect()
+            };
+            let payload = if peer.sync_pex() {
+                if added.is_empty() && dropped.is_empty() {
+                    continue;
+                }
+                pex::encode(&others(&added), &dropped)
+            } else {
+                pex::encode(&others(&known), &[])
+            };
+            peer.send_message(Message::Extension { id, payload });
+        }
+    }
+
+    /// Returns the peers received via PEX since the last call.
+    pub fn take_pex_peers(&mut self) -> Vec<SocketAddr> {
+        mem::replace(&mut self.pex_peers, Vec::new())
+    }
+
+    /// Creates a web seed for each URL in the torrent's url-list
+    fn init_webseeds(info: &Info) -> Vec<WebSeed> {
+        info.web_seeds
+            .iter()
+            .enumerate()
+            .map(|(i, url)| WebSeed::new(url.clone(), i, info))
+            .collect()
+    }
+
+    /// Starts fetching blocks from any idle web seeds
+    pub fn update_webseeds(&mut self) {
+        if !self.status.should_dl() || self.metadata.is_some() {
+            return;
+        }
+        for ws in &mut self.web_seeds {
+            if !ws.idle() {
+                continue;
+            }
+            let mut blocks = Vec::new();
+            while blocks.len() < webseed::REQ_BLOCKS {
+                match self.picker.pick_from(ws.pieces(), ws.id()) {
+                    Some(b) => blocks.push(b),
+                    None => break,
+                }
+            }
+            if !blocks.is_empty() {
+                let req = ws.request(self.id, blocks, &self.info);
+                self.cio.msg_trk(tracker::Request::WebSeed(req));
+            }
+        }
+    }
+
+    /// Handles the result of a fetch from the web seed with id
+    pub fn webseed_response(&mut self, id: usize, resp: tracker::Result<Vec<u8>>) {
+        let idx = match self.web_seeds.iter().position(|ws| ws.id() == id) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let len = self.web_seeds[idx].fetch_len(&self.info);
+        match resp {
+            Ok(ref data) if data.len() as u64 == len => {
+                for (b, buf) in self.web_seeds[idx].completed(&self.info, data) {
+                    let length = self.info.block_len(b.index, b.offset);
+                    if self.block_received(b.index, b.offset, length, buf, id, None)
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+            Ok(_) => {
+                debug!("Web seed {} returned invalid data", self.web_seeds[idx].url);
+                self.web_seeds[idx].failed();
+            }
+            Err(e) => {
+                debug!("Web seed {} failed: {}", self.web_seeds[idx].url, e);
+                self.web_seeds[idx].failed();
+            }
+        }
+        // Any blocks which weren't received can be picked again
+        self.picker.remove_requests(id);
+        self.update_webseeds();
+    }
+
     /// Periodically called to update peers, choking the slowest one and
     /// optimistically unchoking a new peer
     pub fn update_unchoked(&mut self) {
@@ -1167,6 +1705,75 @@ impl<T: cio::CIO> Torrent<T> {
         if let Some(s) = u.sequential {
             self.change_picker(s);
         }
+
+        if u.seed_ratio.is_some() || u.seed_time.is_some() || u.seed_idle.is_some() {
+            let ratio = u.seed_ratio.unwrap_or(self.seed_ratio);
+            let time = u.seed_time.unwrap_or(self.seed_time);
+            let idle = u.seed_idle.unwrap_or(self.seed_idle);
+            self.set_seed_goals(ratio, time, idle);
+        }
+    }
+
+    fn set_seed_goals(&mut self, ratio: Option<f32>, time: Option<i64>, idle: Option<i64>) {
+        self.seed_ratio = ratio;
+        self.seed_time = time;
+        self.seed_idle = idle;
+        self.dirty = true;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentSeedGoals {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                seed_ratio: ratio,
+                seed_time: time,
+                seed_idle: idle,
+            },
+        ]));
+    }
+
+    /// Accounts for elapsed seconds spent seeding, returning
+    /// true if a seeding goal has just been reached.
+    pub fn update_seeding(&mut self, elapsed: u64) -> bool {
+        if !self.complete() || self.status.stopped() {
+            self.seed_uploaded = self.uploaded;
+            return false;
+        }
+        self.seeding_time += elapsed;
+        if self.uploaded > self.seed_uploaded {
+            self.idle_time = 0;
+            self.seed_uploaded = self.uploaded;
+        } else {
+            self.idle_time += elapsed;
+        }
+
+        let reached = self.seed_goal();
+        let newly_reached = reached && !self.seed_goal_reached;
+        self.seed_goal_reached = reached;
+        newly_reached
+    }
+
+    /// Whether any seeding goal has been reached
+    fn seed_goal(&self) -> bool {
+        let ratio = self.seed_ratio.or(CONFIG.seed.ratio);
+        let time = self.seed_time.or(CONFIG.seed.time.map(|t| t as i64));
+        let idle = self.seed_idle.or(CONFIG.seed.idle.map(|t| t as i64));
+        ratio.map(|r| r >= 0. && self.ratio() >= r).unwrap_or(false)
+            || time.map(|t| t >= 0 && self.seeding_time >= t as u64).unwrap_or(false)
+            || idle.map(|t| t >= 0 && self.idle_time >= t as u64).unwrap_or(false)
+    }
+
+    /// Share ratio, based on the torrent size if
+    /// it wasn't downloaded by us.
+    fn ratio(&self) -> f32 {
+        let down = if self.downloaded == 0 {
+            self.info.total_len
+        } else {
+            self.downloaded
+        };
+        if down == 0 {
+            return 0.;
+        }
+        self.uploaded as f32 / down as f32
     }
 
     pub fn rpc_update_file(&mut self, id: String, priority: u8) {
@@ -1194,6 +1801,66 @@ impl<T: cio::CIO> Torrent<T> {
         ]));
     }
 
+    /// Prioritizes the pieces covering the byte ranges, given as offset and
+    /// length, of a file being downloaded over HTTP. The oldest stream is
+    /// replaced once too many are active.
+    pub fn stream(&mut self, id: &str, ranges: &[(u64, u64)]) {
+        let ranges = self.stream_ranges(id, ranges);
+        self.streams.extend(ranges);
+        while self.streams.len() > MAX_STREAMS {
+            self.streams.pop_front();
+        }
+        self.update_deadlines();
+    }
+
+    /// Stops prioritizing the byte ranges of a file once its HTTP
+    /// download has ended, even if they weren't downloaded.
+    pub fn end_stream(&mut self, id: &str, ranges: &[(u64, u64)]) {
+        for r in self.stream_ranges(id, ranges) {
+            if let Some(i) = self.streams.iter().position(|s| *s == r) {
+                self.streams.remove(i);
+            }
+        }
+        self.update_deadlines();
+    }
+
+    /// Converts byte ranges of a file, given as offset and length,
+    /// into start and end offsets in the torrent.
+    fn stream_ranges(&self, id: &str, ranges: &[(u64, u64)]) -> Vec<(u64, u64)> {
+        let mut offset = 0;
+        for f in &self.info.files {
+            let fid =
+                util::file_rpc_id(&self.info.hash, f.path.as_path().to_string_lossy().as_ref());
+            if fid == id {
+                return ranges
+                    .iter()
+                    .filter(|&&(_, len)| len > 0)
+                    .map(|&(start, len)| (offset + start, offset + start + len))
+                    .collect();
+            }
+            offset += f.length;
+        }
+        Vec::new()
+    }
+
+    /// Gives deadlines to the next missing pieces of each stream,
+    /// dropping streams which have been completely downloaded.
+    fn update_deadlines(&mut self) {
+        let pl = u64::from(self.info.piece_len);
+        let pieces = &self.pieces;
+        let mut deadlines = Vec::new();
+        self.streams.retain(|&(start, end)| {
+            let missing: Vec<_> = (start / pl..(end - 1) / pl + 1)
+                .map(|p| p as u32)
+                .filter(|&p| !pieces.has_bit(u64::from(p)))
+                .take(STREAM_PIECES)
+                .collect();
+            deadlines.extend_from_slice(&missing);
+            !missing.is_empty()
+        });
+        self.picker.set_deadlines(&deadlines);
+    }
+
     pub fn rpc_update_pieces(&mut self) {
         let id = self.rpc_id();
         let piece_field = self.pieces.b64();
@@ -1212,11 +1879,11 @@ impl<T: cio::CIO> Torrent<T> {
         let mut resources = Vec::new();
         resources.push(self.rpc_info());
         resources.extend(self.rpc_trk_info());
-        if self.info_idx.is_none() {
+        if self.metadata.is_none() {
             resources.extend(self.rpc_rel_info());
         }
         self.cio.msg_rpc(rpc::CtlMessage::Extant(resources));
-        if self.info_idx.is_none() {
+        if self.metadata.is_none() {
             self.update_rpc_transfer();
         }
         self.serialize();
@@ -1226,8 +1893,7 @@ impl<T: cio::CIO> Torrent<T> {
         if self.status.stopped() {
             return;
         }
-        if let Some(req) = tracker::Request::started(self) {
-            self.cio.msg_trk(req);
+        if self.announce_started() {
             self.dump_torrent_file();
         }
         // TODO: Consider repeatedly sending out these during annoucne intervals
@@ -1240,6 +1906,18 @@ impl<T: cio::CIO> Torrent<T> {
             });
             self.cio.msg_trk(req);
         }
+        self.announce_lsd();
+    }
+
+    /// Starts announcing the torrent on the local network.
+    /// Private torrents must only use their trackers, so aren't announced.
+    fn announce_lsd(&mut self) {
+        if !self.info.private {
+            self.cio.msg_trk(tracker::Request::LSDAnnounce(tracker::GetPeers {
+                id: self.id,
+                hash: self.info.hash,
+            }));
+        }
     }
 
     pub fn complete(&self) -> bool {
@@ -1276,6 +1954,7 @@ impl<T: cio::CIO> Torrent<T> {
         for peer in self.peers.values_mut() {
             peer.magnet_complete(&self.info);
         }
+        self.web_seeds = Torrent::<T>::init_webseeds(&self.info);
 
         let resources = self.rpc_rel_info();
         self.cio.msg_rpc(rpc::CtlMessage::Extant(resources));
@@ -1319,14 +1998,26 @@ impl<T: cio::CIO> Torrent<T> {
         ]));
     }
 
+    fn update_metadata_progress(&mut self) {
+        let metadata_progress = self.metadata.as_ref().map(|md| md.progress());
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentMetadata {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                metadata_progress,
+            },
+        ]));
+    }
+
     fn rpc_info(&self) -> resource::Resource {
-        let (name, size, pieces, piece_size, files) = if self.info_idx.is_none() {
+        let (name, size, pieces, piece_size, files) = if self.metadata.is_none() {
             (
                 Some(self.info.name.clone()),
                 Some(self.info.total_len),
                 Some(u64::from(self.info.pieces())),
                 Some(self.info.piece_len),
-                Some(self.info.files.len() as u32),
+                Some(self.info.files.iter().filter(|f| !f.padding).count() as u32),
             )
         } else {
             let name = if self.info.name == "" {
@@ -1365,6 +2056,12 @@ impl<T: cio::CIO> Torrent<T> {
             creator: self.info.creator.clone(),
             comment: self.info.comment.clone(),
             files,
+            queue_position: self.queue_position,
+            seed_ratio: self.seed_ratio,
+            seed_time: self.seed_time,
+            seed_idle: self.seed_idle,
+            metadata_progress: self.metadata.as_ref().map(|md| md.progress()),
+            endgame: self.endgame,
             ..Default::default()
         })
     }
@@ -1383,6 +2080,9 @@ impl<T: cio::CIO> Torrent<T> {
         }
 
         for (i, (done, total)) in files.into_iter().enumerate() {
+            if self.info.files[i].padding {
+                continue;
+            }
             let id = util::file_rpc_id(
                 &self.info.hash,
                 self.info.files[i].path.to_string_lossy().as_ref(),
@@ -1404,6 +2104,17 @@ impl<T: cio::CIO> Torrent<T> {
             }))
         }
 
+        for ws in &self.web_seeds {
+            r.push(resource::Resource::Peer(resource::Peer {
+                id: util::peer_rpc_id(&self.info.hash, ws.id() as u64),
+                torrent_id: self.rpc_id(),
+                ip: ws.url.as_str().to_owned(),
+                availability: 1.,
+                web_seed: true,
+                ..Default::default()
+            }))
+        }
+
         r
     }
 
@@ -1417,6 +2128,13 @@ impl<T: cio::CIO> Torrent<T> {
                     url: Some(trk.url.as_ref().clone()),
                     last_report: trk.last_announce.clone(),
                     error: None,
+                    warning: trk.warning.clone(),
+                    next_announce: trk.next_announce(),
+                    failures: trk.failures,
+                    tier: trk.tier as u32,
+                    complete: trk.scrape.map(|s| s.complete),
+                    incomplete: trk.scrape.map(|s| s.incomplete),
+                    downloaded: trk.scrape.map(|s| s.downloaded),
                     ..Default::default()
                 })
             })
@@ -1426,7 +2144,7 @@ impl<T: cio::CIO> Torrent<T> {
     pub fn send_rpc_removal(&mut self) {
         let mut r = Vec::new();
         r.push(self.rpc_id());
-        for f in &self.info.files {
+        for f in self.info.files.iter().filter(|f| !f.padding) {
             let id =
                 util::file_rpc_id(&self.info.hash, f.path.as_path().to_string_lossy().as_ref());
             r.push(id)
@@ -1439,6 +2157,9 @@ impl<T: cio::CIO> Torrent<T> {
                 .map(|u| u.as_str())
                 .unwrap_or(""),
         ));
+        for ws in &self.web_seeds {
+            r.push(util::peer_rpc_id(&self.info.hash, ws.id() as u64));
+        }
         // TODO: Tracker removal too
         self.cio.msg_rpc(rpc::CtlMessage::Removed(r));
     }
@@ -1478,6 +2199,9 @@ impl<T: cio::CIO> Torrent<T> {
         self.stat.tick();
         let mut active = self.stat.active();
         self.picker.tick();
+        if self.metadata.as_mut().map(|md| md.tick()).unwrap_or(false) {
+            self.request_all_metadata();
+        }
 
         for (_, peer) in self.peers.iter_mut() {
             active |= peer.tick();
@@ -1489,6 +2213,172 @@ impl<T: cio::CIO> Torrent<T> {
         (self.stat.avg_ul(), self.stat.avg_dl())
     }
 
+    /// Handles a received block, writing it to disk and notifying peers
+    /// of any completed piece. Returns true if the block was used, or
+    /// an error if the block is malformed.
+    fn block_received(
+        &mut self,
+        index: u32,
+        begin: u32,
+        length: u32,
+        data: Box<[u8; 16_384]>,
+        pid: usize,
+        ip: Option<IpAddr>,
+    ) -> Result<bool, ()> {
+        // Ignore a piece we already have, this could happen from endgame
+        if self.pieces.has_bit(u64::from(index)) {
+            return Ok(false);
+        }
+
+        // Even though we have the data, if we are stopped we shouldn't use the disk
+        // regardless.
+        if self.status.stopped() || self.status.completed() {
+            return Ok(false);
+        }
+
+        // The length doesn't match what it should be
+        if self.info.block_len(index, begin) != length {
+            return Err(());
+        }
+
+        // We already have this block, don't do anything with it, could happen
+        // from endgame
+        if self.picker.have_block(Block::new(index, begin)) {
+            return Ok(false);
+        }
+
+        let pr = self.picker.completed(Block::new(index, begin));
+        let (piece_done, peers) = if let Ok(r) = pr {
+            r
+        } else {
+            return Ok(false);
+        };
+
+        self.dirty = true;
+        self.write_piece(index, begin, data);
+        if let Some(ip) = ip {
+            self.block_peers
+                .entry(index as usize)
+                .or_insert_with(Vec::new)
+                .push((begin, ip));
+        }
+
+        self.downloaded += u64::from(length);
+        self.stat.add_dl(u64::from(length));
+
+        if piece_done && !self.info.piece_verifiable(index) {
+            // Without a hash the data can't be trusted, but its
+            // senders can't be blamed either
+            info!("Piece {} is unverifiable until its piece layer is received", index);
+            self.block_peers.remove(&(index as usize));
+            self.requeue_piece(index);
+        } else if piece_done {
+            self.pieces.set_bit(u64::from(index));
+            if !self.streams.is_empty() {
+                self.update_deadlines();
+            }
+            // Begin validation, and save state if the torrent is done
+            self.check_complete();
+            if self.status.validating.is_none() {
+                // Do on the fly validation of the piece, hashing its blocks
+                // if it previously failed so the bad sender can be found
+                self.cio.msg_disk(disk::Request::validate_piece(
+                    self.id,
+                    self.info.clone(),
+                    self.path.clone(),
+                    index,
+                    self.failed_blocks.contains_key(&(index as usize)),
+                ));
+            } else {
+                // The whole torrent is being validated, which covers this piece
+                self.block_peers.remove(&(index as usize));
+            }
+
+            // Mark uninteresting peers
+            for peer in self.peers.values_mut() {
+                if !self.pieces.usable(peer.pieces()) {
+                    peer.uninterested();
+                }
+            }
+        }
+
+        // If there are any peers we've asked duplicate pieces for,
+        // cancel them, though we should still assume they'll probably send it anyways
+        for id in peers.into_iter().filter(|p| *p != pid) {
+            if let Some(peer) = self.peers.get_mut(&id) {
+                peer.cancel_piece(index, begin, length);
+            }
+        }
+        self.update_endgame();
+
+        Ok(true)
+    }
+
+    /// Bans the senders of any blocks of a previously failed piece
+    /// which differ from the now valid blocks.
+    fn piece_valid(&mut self, piece: u32, blocks: &[[u8; 20]]) {
+        if let Some(failed) = self.failed_blocks.remove(&(piece as usize)) {
+            for (begin, ip, hash) in failed {
+                if blocks.get((begin / 16_384) as usize).map(|h| *h != hash).unwrap_or(false) {
+                    self.ban(ip);
+                }
+            }
+        }
+    }
+
+    /// Requeues an invalid piece. If every block came from one IP it's
+    /// banned, otherwise the block hashes are kept so that the senders of
+    /// bad blocks can be identified once the piece is valid.
+    fn piece_invalid(&mut self, piece: u32, senders: Vec<(u32, IpAddr)>, blocks: &[[u8; 20]]) {
+        let single = !blocks.is_empty() && senders.len() == blocks.len()
+            && senders.iter().all(|&(_, ip)| ip == senders[0].1);
+        if single {
+            self.ban(senders[0].1);
+        } else {
+            let failed = self.failed_blocks
+                .entry(piece as usize)
+                .or_insert_with(Vec::new);
+            for (begin, ip) in senders {
+                if let Some(hash) = blocks.get((begin / 16_384) as usize) {
+                    failed.push((begin, ip, *hash));
+                }
+            }
+        }
+        self.requeue_piece(piece);
+    }
+
+    /// Marks a piece as missing so that it's downloaded again
+    fn requeue_piece(&mut self, piece: u32) {
+        self.picker.invalidate_piece(piece);
+        self.pieces.unset_bit(u64::from(piece));
+        if self.status.state == StatusState::Complete && self.status.validating.is_none() {
+            self.status.state = StatusState::Incomplete;
+        }
+        self.rpc_update_pieces();
+        self.request_all();
+    }
+
+    fn ban(&mut self, ip: IpAddr) {
+        if !self.bans.contains(&ip) {
+            info!("Banning {} for sending corrupt data", ip);
+            self.bans.push(ip);
+        }
+    }
+
+    /// Returns IPs which sent corrupt data and should be banned
+    pub fn take_bans(&mut self) -> Vec<IpAddr> {
+        mem::replace(&mut self.bans, Vec::new())
+    }
+
+    /// Disconnects all peers whose IP matches f
+    pub fn disconnect_ips<F: Fn(&IpAddr) -> bool>(&mut self, f: F) {
+        for (pid, peer) in &self.peers {
+            if f(&peer.addr().ip()) {
+                self.cio.remove_peer(*pid);
+            }
+        }
+    }
+
     /// Writes a piece of torrent info, with piece index idx,
     /// piece offset begin, piece length of len, and data bytes.
     /// The disk send handle is also provided.
@@ -1513,6 +2403,26 @@ impl<T: cio::CIO> Torrent<T> {
                 .get_mut(&pid)
                 .expect("Expected peer id not present");
             Torrent::make_requests(peer, &mut self.picker, &self.info);
+            self.update_endgame();
+        }
+    }
+
+    /// Notifies RPC of the picker entering or leaving endgame
+    fn update_endgame(&mut self) {
+        let endgame = self.picker.endgame();
+        if endgame != self.endgame {
+            self.endgame = endgame;
+            if endgame {
+                debug!("Torrent {} entering endgame", self.id);
+            }
+            let id = self.rpc_id();
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                SResourceUpdate::TorrentEndgame {
+                    id,
+                    kind: resource::ResourceKind::Torrent,
+                    endgame,
+                },
+            ]));
         }
     }
 
@@ -1538,7 +2448,7 @@ impl<T: cio::CIO> Torrent<T> {
         if let Ok(p) = Peer::new(conn, self, None, None) {
             let pid = p.id();
             trace!("Adding peer {:?}!", pid);
-            if self.info_idx.is_none() {
+            if self.metadata.is_none() {
                 self.picker.add_peer(&p);
             }
             self.peers.insert(pid, p);
@@ -1552,7 +2462,7 @@ impl<T: cio::CIO> Torrent<T> {
         if let Ok(p) = Peer::new(conn, self, Some(id), Some(rsv)) {
             let pid = p.id();
             debug!("Adding peer {:?}!", pid);
-            if self.info_idx.is_none() {
+            if self.metadata.is_none() {
                 self.picker.add_peer(&p);
             }
             self.peers.insert(pid, p);
@@ -1601,6 +2511,9 @@ impl<T: cio::CIO> Torrent<T> {
                     kind: resource::ResourceKind::Tracker,
                     last_report: tracker.last_announce,
                     error,
+                    warning: tracker.warning.clone(),
+                    next_announce: tracker.next_announce(),
+                    failures: tracker.failures,
                 }
             })
             .collect();
@@ -1627,18 +2540,25 @@ impl<T: cio::CIO> Torrent<T> {
                 continue;
             }
             let (rate_up, rate_down) = p.get_tx_rates();
+            let id = util::peer_rpc_id(&self.info.hash, *pid as u64);
             updates.push(SResourceUpdate::Rate {
-                id: util::peer_rpc_id(&self.info.hash, *pid as u64),
+                id: id.clone(),
                 kind: resource::ResourceKind::Peer,
                 rate_up,
                 rate_down,
             });
+            updates.push(SResourceUpdate::PeerQueue {
+                id,
+                kind: resource::ResourceKind::Peer,
+                queue_depth: p.queue_depth(),
+                rtt: p.rtt(),
+            });
         }
 
         if self.stat.active() {
             let mut files = MHashMap::default();
             for (i, f) in self.info.files.iter().enumerate() {
-                if self.priorities[i] != 0 {
+                if self.priorities[i] != 0 && !f.padding {
                     files.insert(f.path.clone(), (0, f.length));
                 }
             }
@@ -1671,14 +2591,46 @@ impl<T: cio::CIO> Torrent<T> {
         if self.info.complete() {
             self.picker.remove_peer(peer);
         }
+        if let Some(ref mut md) = self.metadata {
+            md.remove_peer(peer.id());
+        }
+        self.request_all_metadata();
+    }
+
+    /// Sends a started event to the trackers in use,
+    /// returning whether there were any.
+    fn announce_started(&mut self) -> bool {
+        let urls: Vec<Url> = self.announce_trackers()
+            .into_iter()
+            .map(|t| t.url.as_ref().clone())
+            .collect();
+        for req in tracker::Request::started(self) {
+            self.cio.msg_trk(req);
+        }
+        for url in &urls {
+            self.tracker_started(url);
+        }
+        !urls.is_empty()
+    }
+
+    /// Sends a stopped event to the trackers in use, all trackers
+    /// being sent a started event once the torrent starts again.
+    fn announce_stop(&mut self) {
+        for req in tracker::Request::stopped(self) {
+            self.cio.msg_trk(req);
+        }
+        for trk in &mut self.trackers {
+            trk.started = false;
+        }
     }
 
     pub fn pause(&mut self) {
         debug!("Pausing torrent!");
         if !self.status.paused {
-            debug!("Sending stopped request to trk");
-            if let Some(req) = tracker::Request::stopped(self) {
-                self.cio.msg_trk(req);
+            if !self.status.queued {
+                debug!("Sending stopped request to trk");
+                self.announce_stop();
+                self.cio.msg_trk(tracker::Request::LSDRemove(self.info.hash));
             }
             self.status.paused = true;
             self.announce_status();
@@ -1691,13 +2643,12 @@ impl<T: cio::CIO> Torrent<T> {
             if self.status.error.is_some() {
                 self.status.error = None;
             }
-            if self.status.paused {
+            if self.status.paused && !self.status.queued {
                 debug!("Sending started request to trk");
-                if let Some(req) = tracker::Request::started(self) {
-                    self.cio.msg_trk(req);
-                }
-                self.status.paused = false;
+                self.announce_started();
+                self.announce_lsd();
             }
+            self.status.paused = false;
             self.request_all();
             self.announce_status();
         }
@@ -1714,12 +2665,13 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn request_all(&mut self) {
-        if self.status.stopped() || self.info_idx.is_some() {
+        if self.status.stopped() || self.metadata.is_some() {
             return;
         }
         for pid in self.pids() {
             self.make_requests_pid(pid);
         }
+        self.update_webseeds();
     }
 
     fn pids(&self) -> Vec<usize> {
@@ -1742,6 +2694,7 @@ impl<T: cio::CIO> Torrent<T> {
                 sequential,
             },
         ]));
+        self.update_endgame();
     }
 }
 
@@ -1764,11 +2717,12 @@ impl<T: cio::CIO> Drop for Torrent<T> {
             trace!("Removing peer {:?}", peer);
             self.leechers.remove(&id);
         }
-        if !self.status.paused {
-            if let Some(msg) = tracker::Request::stopped(self) {
+        if !self.status.paused && !self.status.queued {
+            for msg in tracker::Request::stopped(self) {
                 self.cio.msg_trk(msg);
             }
         }
+        self.cio.msg_trk(tracker::Request::LSDRemove(self.info.hash));
         self.send_rpc_removal();
     }
 }
@@ -1778,4 +2732,180 @@ mod tests {
     use super::*;
     use control::cio::{test, CIO};
     use throttle::*;
+
+    fn torrent(pieces: usize) -> Torrent<test::TCIO> {
+        let throttler = Throttler::test(None, None, 1_000_000);
+        let info = Info::with_pieces(pieces);
+        Torrent::new(0, None, info, throttler.get_throttle(0), test::TCIO::new(), true)
+    }
+
+    fn add_tracker(t: &mut Torrent<test::TCIO>, url: &str, tier: usize) -> Url {
+        let url = Url::parse(url).unwrap();
+        t.trackers.push_back(Tracker::new(AView::value(url.clone()), tier));
+        url
+    }
+
+    fn secs_until(i: Instant) -> u64 {
+        let d = i - Instant::now();
+        (d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9).round() as u64
+    }
+
+    #[test]
+    fn test_new_complete() {
+        let throttler = Throttler::test(None, None, 1_000_000);
+        let info = Info::with_pieces(4);
+        let throttle = throttler.get_throttle(0);
+        let t = Torrent::new_complete(0, None, info, throttle, test::TCIO::new(), true);
+        assert!(t.pieces.complete());
+        assert!(t.complete());
+        assert!(!torrent(4).pieces.complete());
+    }
+
+    #[test]
+    fn test_seed_goal() {
+        let throttler = Throttler::test(None, None, 1_000_000);
+        let info = Info::with_pieces(4);
+        let throttle = throttler.get_throttle(0);
+        let mut t = Torrent::new_complete(0, None, info, throttle, test::TCIO::new(), true);
+        t.status.queued = false;
+        t.set_seed_goals(None, Some(60), None);
+        assert!(!t.update_seeding(30));
+        assert!(t.update_seeding(30));
+        t.pause();
+        assert!(!t.update_seeding(30));
+
+        // Once resumed the torrent keeps seeding past the goal
+        t.resume();
+        assert!(!t.update_seeding(30));
+        assert!(!t.update_seeding(30));
+
+        // Until a new goal is reached
+        t.set_seed_goals(None, Some(200), None);
+        assert!(!t.update_seeding(30));
+        assert!(t.update_seeding(50));
+    }
+
+    #[test]
+    fn test_end_stream() {
+        let mut t = torrent(4);
+        let id = util::file_rpc_id(&t.info.hash, "");
+        t.stream(&id, &[(0, 16_384), (32_768, 100)]);
+        t.stream(&id, &[(0, 16_384)]);
+        assert_eq!(t.streams.len(), 3);
+
+        // Only the ended download's ranges are dropped
+        t.end_stream(&id, &[(0, 16_384), (32_768, 100)]);
+        assert_eq!(t.streams.iter().cloned().collect::<Vec<_>>(), vec![(0, 16_384)]);
+        t.end_stream(&id, &[(0, 16_384)]);
+        assert!(t.streams.is_empty());
+    }
+
+    #[test]
+    fn test_ban_single_sender() {
+        let mut t = torrent(4);
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let blocks = [[1u8; 20], [2u8; 20]];
+        t.piece_invalid(0, vec![(0, ip), (16_384, ip)], &blocks);
+        assert_eq!(t.take_bans(), vec![ip]);
+        assert!(t.failed_blocks.is_empty());
+    }
+
+    #[test]
+    fn test_ban_multiple_senders() {
+        let mut t = torrent(4);
+        let a: IpAddr = "1.2.3.4".parse().unwrap();
+        let b: IpAddr = "5.6.7.8".parse().unwrap();
+        t.piece_invalid(0, vec![(0, a), (16_384, b)], &[[1u8; 20], [2u8; 20]]);
+        assert!(t.take_bans().is_empty());
+
+        // Once valid, only the sender of the differing block is banned
+        t.piece_valid(0, &[[1u8; 20], [3u8; 20]]);
+        assert_eq!(t.take_bans(), vec![b]);
+        assert!(t.failed_blocks.is_empty());
+
+        // Pieces which never failed blame no one
+        t.piece_valid(1, &[[1u8; 20]]);
+        assert!(t.take_bans().is_empty());
+    }
+
+    fn announce_urls(t: &Torrent<test::TCIO>) -> Vec<Url> {
+        t.announce_trackers()
+            .into_iter()
+            .map(|trk| trk.url.as_ref().clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_tracker_failover() {
+        let mut t = torrent(1);
+        let a = add_tracker(&mut t, "http://a/announce", 0);
+        let b = add_tracker(&mut t, "http://b/announce", 0);
+        let c = add_tracker(&mut t, "http://c/announce", 1);
+        assert_eq!(announce_urls(&t), vec![a.clone()]);
+
+        // The next tracker in the tier is used, and moved to its front
+        // once it works
+        t.tracker_failed(0);
+        assert_eq!(announce_urls(&t), vec![b.clone()]);
+        t.tracker_succeeded(1);
+        assert_eq!(announce_urls(&t), vec![b.clone()]);
+        assert_eq!(&*t.trackers[1].url, &a);
+        assert!(!t.trackers[1].failed);
+
+        // Once a tier fails the next one is used
+        t.tracker_failed(0);
+        assert_eq!(announce_urls(&t), vec![a.clone()]);
+        t.tracker_failed(1);
+        assert_eq!(announce_urls(&t), vec![c.clone()]);
+
+        // And once every tracker failed they're all retried
+        t.tracker_failed(2);
+        assert_eq!(announce_urls(&t), vec![b.clone()]);
+        assert!(t.trackers.iter().all(|trk| !trk.failed));
+    }
+
+    #[test]
+    fn test_tracker_failover_started() {
+        let mut t = torrent(1);
+        t.status.queued = false;
+        let a = add_tracker(&mut t, "http://a/announce", 0);
+        add_tracker(&mut t, "http://b/announce", 0);
+        t.announce_started();
+        assert!(t.trackers[0].started);
+        assert!(!t.trackers[1].started);
+
+        // The tracker failed over to is sent a started event
+        let err: tracker::Error = tracker::ErrorKind::TrackerError("fail".to_owned()).into();
+        t.set_tracker_response(&a, &Err(err));
+        assert!(!t.trackers[0].started);
+        assert!(t.trackers[1].started);
+
+        t.announce_stop();
+        assert!(t.trackers.iter().all(|trk| !trk.started));
+    }
+
+    #[test]
+    fn test_tracker_backoff() {
+        let mut trk = Tracker::new(AView::value(Url::parse("http://a/announce").unwrap()), 0);
+        for &backoff in &[60, 120, 240, 480] {
+            trk.set_failure("fail".to_owned());
+            assert_eq!(secs_until(trk.update.unwrap()), backoff);
+        }
+        for _ in 0..40 {
+            trk.set_failure("fail".to_owned());
+        }
+        assert_eq!(secs_until(trk.update.unwrap()), TRK_MAX_BACKOFF_SECS);
+    }
+
+    #[test]
+    fn test_tracker_min_interval() {
+        let mut t = torrent(1);
+        let url = add_tracker(&mut t, "http://a/announce", 0);
+        let mut resp = TrackerResponse::empty();
+        resp.interval = 60;
+        resp.min_interval = Some(600);
+        t.set_tracker_response(&url, &Ok(resp));
+        assert_eq!(secs_until(t.trackers[0].update.unwrap()), 600);
+        assert_eq!(secs_until(t.trackers[0].min_update.unwrap()), 600);
+    }
 }
@@ -51,6 +51,23 @@ pub enum Message {
         id: u8,
         payload: Vec<u8>,
     },
+    HashRequest(Box<HashRequest>),
+    Hashes {
+        req: Box<HashRequest>,
+        hashes: Vec<u8>,
+    },
+    HashReject(Box<HashRequest>),
+}
+
+/// BEP 52 merkle hash request, also echoed
+/// back by the hashes and reject messages.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
 }
 
 impl fmt::Debug for Message {
@@ -92,6 +109,17 @@ impl fmt::Debug for Message {
             ),
             Message::Port(port) => write!(f, "Message::Port({:?})", port),
             Message::Extension { id, .. } => write!(f, "Message::Extension {{ id: {} }}", id),
+            Message::HashRequest(ref r) => write!(f, "Message::HashRequest({:?})", r),
+            Message::Hashes {
+                ref req,
+                ref hashes,
+            } => write!(
+                f,
+                "Message::Hashes {{ req: {:?}, count: {} }}",
+                req,
+                hashes.len() / 32
+            ),
+            Message::HashReject(ref r) => write!(f, "Message::HashReject({:?})", r),
         }
     }
 }
@@ -158,6 +186,15 @@ impl Clone for Message {
                 id,
                 payload: payload.clone(),
             },
+            Message::HashRequest(ref r) => Message::HashRequest(r.clone()),
+            Message::Hashes {
+                ref req,
+                ref hashes,
+            } => Message::Hashes {
+                req: req.clone(),
+                hashes: hashes.clone(),
+            },
+            Message::HashReject(ref r) => Message::HashReject(r.clone()),
         }
     }
 }
@@ -225,6 +262,18 @@ impl PartialEq for Message {
                     payload: ref p,
                 },
             ) => id == i && payload == p,
+            (&Message::HashRequest(ref r), &Message::HashRequest(ref r_))
+            | (&Message::HashReject(ref r), &Message::HashReject(ref r_)) => r == r_,
+            (
+                &Message::Hashes {
+                    ref req,
+                    ref hashes,
+                },
+                &Message::Hashes {
+                    req: ref r,
+                    hashes: ref h,
+                },
+            ) => req == r && hashes == h,
             _ => false,
         }
     }
@@ -232,10 +281,13 @@ impl PartialEq for Message {
 
 impl Message {
     pub fn handshake(torrent: &TorrentInfo) -> Message {
-        use {DHT_EXT, EXT_PROTO, PEER_ID};
+        use {DHT_EXT, EXT_PROTO, PEER_ID, V2_EXT};
         let mut rsv = [0u8; 8];
         rsv[DHT_EXT.0] |= DHT_EXT.1;
         rsv[EXT_PROTO.0] |= EXT_PROTO.1;
+        if torrent.hash_v2.is_some() {
+            rsv[V2_EXT.0] |= V2_EXT.1;
+        }
         Message::Handshake {
             rsv,
             hash: torrent.hash,
@@ -269,7 +321,12 @@ impl Message {
 
     pub fn is_special(&self) -> bool {
         match *self {
-            Message::Handshake { .. } | Message::Bitfield(_) | Message::Extension { .. } => true,
+            Message::Handshake { .. }
+            | Message::Bitfield(_)
+            | Message::Extension { .. }
+            | Message::HashRequest(_)
+            | Message::Hashes { .. }
+            | Message::HashReject(_) => true,
             _ => false,
         }
     }
@@ -286,6 +343,8 @@ impl Message {
             Message::Piece { ref data, .. } => 13 + data.len(),
             Message::SharedPiece { ref data, .. } => 13 + data.len(),
             Message::Extension { ref payload, .. } => 6 + payload.len(),
+            Message::HashRequest(_) | Message::HashReject(_) => 53,
+            Message::Hashes { ref hashes, .. } => 53 + hashes.len(),
         }
     }
 
@@ -385,7 +444,37 @@ impl Message {
                 buf.write_u8(id)?;
                 buf.write_all(payload)?;
             }
+            Message::HashRequest(ref req) => {
+                buf.write_u32::<BigEndian>(49)?;
+                buf.write_u8(21)?;
+                req.encode(&mut buf)?;
+            }
+            Message::Hashes {
+                ref req,
+                ref hashes,
+            } => {
+                buf.write_u32::<BigEndian>(49 + hashes.len() as u32)?;
+                buf.write_u8(22)?;
+                req.encode(&mut buf)?;
+                buf.write_all(hashes)?;
+            }
+            Message::HashReject(ref req) => {
+                buf.write_u32::<BigEndian>(49)?;
+                buf.write_u8(23)?;
+                req.encode(&mut buf)?;
+            }
         };
         Ok(())
     }
 }
+
+impl HashRequest {
+    fn encode<W: Write>(&self, mut buf: W) -> io::Result<()> {
+        buf.write_all(&self.pieces_root)?;
+        buf.write_u32::<BigEndian>(self.base_layer)?;
+        buf.write_u32::<BigEndian>(self.index)?;
+        buf.write_u32::<BigEndian>(self.length)?;
+        buf.write_u32::<BigEndian>(self.proof_layers)?;
+        Ok(())
+    }
+}
@@ -4,9 +4,9 @@ mod message;
 
 use std::net::SocketAddr;
 use std::{cmp, fmt, io, mem, time};
-use std::net::TcpStream;
+use std::sync::Arc;
 
-pub use self::message::Message;
+pub use self::message::{HashRequest, Message};
 use self::reader::Reader;
 use self::writer::Writer;
 use socket::Socket;
@@ -16,9 +16,10 @@ use control::cio;
 use rpc::{self, resource};
 use bencode;
 use tracker;
-use util;
+use util::{self, MHashSet};
 use stat;
-use {CONFIG, DHT_EXT};
+use config::Encryption;
+use {CONFIG, DHT_EXT, V2_EXT};
 
 error_chain! {
     errors {
@@ -31,6 +32,8 @@ error_chain! {
 
 const INIT_MAX_QUEUE: u16 = 15;
 const MAX_QUEUE_CAP: u16 = 400;
+/// Time after which an unanswered RTT probe request is discarded
+const RTT_PROBE_TIMEOUT: u64 = 30;
 
 /// Peer connection and associated metadata.
 pub struct Peer<T: cio::CIO> {
@@ -40,24 +43,43 @@ pub struct Peer<T: cio::CIO> {
     piece_count: usize,
     remote_status: Status,
     local_status: Status,
-    /// Current number of queued requests
-    queued: u16,
+    /// Queued requests, by piece index and offset
+    requests: MHashSet<(u32, u32)>,
     /// Maximum number of requests that can be queued
     /// at a time.
     max_queue: u16,
+    /// Maximum number of outstanding requests the peer accepts,
+    /// as given by reqq in its extension handshake
+    reqq: Option<u16>,
+    /// Smoothed round trip time of requests
+    rtt: Option<time::Duration>,
+    /// Request sent while none were outstanding and the time it was sent.
+    /// Only these are timed, so the RTT doesn't include queueing delay.
+    rtt_probe: Option<(u32, u32, time::Instant)>,
     tid: usize,
     downloaded: u32,
     uploaded: u32,
     stat: stat::EMA,
     addr: SocketAddr,
     t_hash: [u8; 20],
+    /// RPC id of the torrent, which differs from its wire hash for v2 torrents
+    t_id: String,
     cid: Option<[u8; 20]>,
     rsv: Option<[u8; 8]>,
     ext_ids: ExtIDs,
+    /// Whether or not we initiated an MSE handshake with the peer
+    mse: bool,
+    /// Whether or not the connection uses uTP
+    utp: bool,
+    /// Address the peer accepts connections on, if known
+    listen: Option<SocketAddr>,
+    /// Whether or not the peer has been sent a full PEX peer list
+    pex_synced: bool,
 }
 
 pub struct ExtIDs {
     pub ut_meta: Option<u8>,
+    pub ut_pex: Option<u8>,
 }
 
 #[derive(Debug)]
@@ -71,6 +93,8 @@ pub struct PeerConn {
     sock: Socket,
     reader: Reader,
     writer: Writer,
+    /// Whether or not an MSE handshake should be initiated
+    encrypt: bool,
 }
 
 impl PeerConn {
@@ -82,6 +106,7 @@ impl PeerConn {
             writer,
             reader,
             last_action: time::Instant::now(),
+            encrypt: false,
         }
     }
 
@@ -94,6 +119,7 @@ impl PeerConn {
             sock: Socket::empty(),
             writer,
             reader,
+            encrypt: false,
         }
     }
 
@@ -111,23 +137,70 @@ impl PeerConn {
 
     /// Creates a new "outgoing" peer, which acts as a client.
     /// Once created, set_torrent should be called.
+    /// An MSE handshake will be attempted unless encryption is disabled.
+    /// uTP is used if enabled, otherwise TCP.
     pub fn new_outgoing(ip: &SocketAddr) -> io::Result<PeerConn> {
+        if !CONFIG.net.utp {
+            return PeerConn::new_outgoing_tcp(ip);
+        }
+        let mut conn = PeerConn::new(Socket::new_utp(ip));
+        conn.encrypt = CONFIG.peer.encryption != Encryption::Disable;
+        Ok(conn)
+    }
+
+    /// Creates a new "outgoing" TCP peer, used to retry peers
+    /// which couldn't be reached over uTP.
+    pub fn new_outgoing_tcp(ip: &SocketAddr) -> io::Result<PeerConn> {
+        let mut conn = PeerConn::new(Socket::new(ip)?);
+        conn.encrypt = CONFIG.peer.encryption != Encryption::Disable;
+        Ok(conn)
+    }
+
+    /// Creates a new "outgoing" peer which never attempts an MSE
+    /// handshake, used to retry peers which rejected encryption.
+    pub fn new_outgoing_plain(ip: &SocketAddr) -> io::Result<PeerConn> {
         Ok(PeerConn::new(Socket::new(ip)?))
     }
 
     /// Creates a peer where we are acting as the server.
     /// Once the handshake is received, set_torrent should be called.
-    pub fn new_incoming(sock: TcpStream) -> io::Result<PeerConn> {
-        Ok(PeerConn::new(Socket::from_stream(sock)?))
+    /// hashes is the set of torrent info hashes which an MSE handshake
+    /// may be completed for.
+    pub fn new_incoming(sock: Socket, hashes: &Arc<MHashSet<[u8; 20]>>) -> io::Result<PeerConn> {
+        let mut conn = PeerConn::new(sock);
+        if CONFIG.peer.encryption != Encryption::Disable {
+            conn.sock.accept_mse(hashes.clone(), CONFIG.peer.encryption)?;
+        }
+        Ok(conn)
+    }
+
+    /// Begins an MSE handshake for the given torrent if the connection
+    /// was configured for one, returning whether or not it was started.
+    pub fn start_mse(&mut self, hash: [u8; 20]) -> io::Result<bool> {
+        if !self.encrypt {
+            return Ok(false);
+        }
+        self.sock.initiate_mse(hash, CONFIG.peer.encryption)?;
+        Ok(true)
     }
 
     pub fn writable(&mut self) -> io::Result<()> {
         self.last_action = time::Instant::now();
+        if !self.sock.handshake()? {
+            return Ok(());
+        }
         self.writer.writable(&mut self.sock)
     }
 
     pub fn readable(&mut self) -> io::Result<Option<Message>> {
         self.last_action = time::Instant::now();
+        if self.sock.handshaking() {
+            if !self.sock.handshake()? {
+                return Ok(None);
+            }
+            // Flush any messages which were queued during the handshake
+            self.writer.writable(&mut self.sock)?;
+        }
         self.reader.readable(&mut self.sock)
     }
 
@@ -168,15 +241,23 @@ impl Peer<cio::test::TCIO> {
             stat: stat::EMA::new(),
             addr: "127.0.0.1:0".parse().unwrap(),
             cio: cio::test::TCIO::new(),
-            queued,
+            requests: MHashSet::default(),
             max_queue: queued,
+            reqq: None,
+            rtt: None,
+            rtt_probe: None,
             pieces,
             piece_count,
             tid: 0,
             t_hash: [0u8; 20],
+            t_id: String::new(),
             rsv: None,
             cid: None,
             ext_ids: ExtIDs::new(),
+            mse: false,
+            utp: false,
+            listen: None,
+            pex_synced: false,
         }
     }
 
@@ -207,7 +288,14 @@ impl<T: cio::CIO> Peer<T> {
         rsv: Option<[u8; 8]>,
     ) -> cio::Result<Peer<T>> {
         let addr = conn.sock().addr();
+        let utp = conn.sock().utp().is_some();
         conn.set_throttle(t.get_throttle(0));
+        let mse = if cid.is_none() {
+            conn.start_mse(t.info.hash)
+                .map_err(|e| cio::Error::with_chain(e, cio::ErrorKind::IO))?
+        } else {
+            false
+        };
         let id = t.cio.add_peer(conn)?;
         let mut p = Peer {
             id,
@@ -218,15 +306,23 @@ impl<T: cio::CIO> Peer<T> {
             downloaded: 0,
             stat: stat::EMA::new(),
             cio: t.cio.new_handle(),
-            queued: 0,
+            requests: MHashSet::default(),
             max_queue: INIT_MAX_QUEUE,
+            reqq: None,
+            rtt: None,
+            rtt_probe: None,
             pieces: Bitfield::new(t.info.hashes.len() as u64),
             piece_count: 0,
             tid: t.id,
             t_hash: t.info.hash,
+            t_id: t.info.id(),
             rsv,
             cid,
             ext_ids: ExtIDs::new(),
+            mse,
+            utp,
+            listen: if cid.is_none() { Some(addr) } else { None },
+            pex_synced: false,
         };
         p.send_message(Message::handshake(&t.info));
         if t.info.complete() {
@@ -253,10 +349,31 @@ impl<T: cio::CIO> Peer<T> {
         self.cid.is_some()
     }
 
+    /// Returns a new connection to retry the peer with if the
+    /// connection failed before the BT handshake completed. uTP
+    /// connections are retried over TCP, and connections which
+    /// attempted an MSE handshake are retried in plaintext.
+    pub fn retry(&self) -> Option<PeerConn> {
+        if self.ready() || !CONFIG.net.tcp {
+            None
+        } else if self.utp {
+            PeerConn::new_outgoing_tcp(&self.addr).ok()
+        } else if self.mse && CONFIG.peer.encryption == Encryption::Prefer {
+            PeerConn::new_outgoing_plain(&self.addr).ok()
+        } else {
+            None
+        }
+    }
+
     pub fn exts(&self) -> &ExtIDs {
         &self.ext_ids
     }
 
+    /// Whether the peer supports the BEP 52 hash messages
+    pub fn supports_v2(&self) -> bool {
+        self.rsv.map(|rsv| (rsv[V2_EXT.0] & V2_EXT.1) != 0).unwrap_or(false)
+    }
+
     pub fn addr(&self) -> SocketAddr {
         self.addr
     }
@@ -265,6 +382,19 @@ impl<T: cio::CIO> Peer<T> {
         &self.pieces
     }
 
+    /// Returns the address the peer accepts connections on, which is
+    /// only known for outgoing peers or those which sent their port
+    /// in the extension handshake.
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        self.listen
+    }
+
+    /// Marks the peer as having been sent a full PEX peer list,
+    /// returning whether or not it already had been.
+    pub fn sync_pex(&mut self) -> bool {
+        mem::replace(&mut self.pex_synced, true)
+    }
+
     #[cfg(test)]
     pub fn pieces_mut(&mut self) -> &mut Bitfield {
         &mut self.pieces
@@ -287,32 +417,70 @@ impl<T: cio::CIO> Peer<T> {
 
     pub fn tick(&mut self) -> bool {
         self.stat.tick();
+        if self.rtt_probe
+            .map(|(_, _, t)| t.elapsed().as_secs() >= RTT_PROBE_TIMEOUT)
+            .unwrap_or(false)
+        {
+            self.rtt_probe = None;
+        }
         if !self.stat.active() {
             return false;
         }
         let (_, dl) = (self.stat.avg_ul(), self.stat.avg_dl());
-        let rate = (dl / 1024) as u16;
-        // Taken from rtorrent's pipeline calculation
-        let nmq = if rate < 20 { rate + 2 } else { rate / 5 + 18 };
+        let nmq = match self.rtt {
+            // Enough requests to cover the bandwidth delay product,
+            // with some slack to allow the rate to grow
+            Some(rtt) => {
+                let bdp = dl * dur_to_ms(rtt) / 1000 / 16_384;
+                cmp::min(bdp + bdp / 2 + 2, u64::from(MAX_QUEUE_CAP)) as u16
+            }
+            None => {
+                let rate = (dl / 1024) as u16;
+                // Taken from rtorrent's pipeline calculation
+                if rate < 20 {
+                    rate + 2
+                } else {
+                    rate / 5 + 18
+                }
+            }
+        };
         // Clamp between -15 / +50 for queue len changes
         self.max_queue = cmp::min(
             cmp::max(nmq, self.max_queue.saturating_sub(15)),
             self.max_queue + 50,
         );
-        // Keep it under the max cap
-        self.max_queue = cmp::min(self.max_queue, MAX_QUEUE_CAP);
+        // Keep it under the max cap and what the peer accepts
+        self.max_queue = cmp::min(self.max_queue, self.queue_cap());
         true
     }
 
+    /// Maximum number of requests which may be queued
+    fn queue_cap(&self) -> u16 {
+        self.reqq
+            .map(|q| cmp::max(cmp::min(q, MAX_QUEUE_CAP), 1))
+            .unwrap_or(MAX_QUEUE_CAP)
+    }
+
+    /// Current request queue depth
+    pub fn queue_depth(&self) -> u16 {
+        self.max_queue
+    }
+
+    /// Measured request round trip time in milliseconds
+    pub fn rtt(&self) -> Option<u32> {
+        self.rtt.map(|rtt| dur_to_ms(rtt) as u32)
+    }
+
     pub fn get_tx_rates(&self) -> (u64, u64) {
         (self.stat.avg_ul(), self.stat.avg_dl())
     }
 
     pub fn queue_reqs(&mut self) -> Option<u16> {
-        if self.remote_status.choked || self.queued > self.max_queue / 2 {
+        let queued = self.requests.len() as u16;
+        if self.remote_status.choked || queued > self.max_queue / 2 {
             None
         } else {
-            Some(cmp::max(self.max_queue.saturating_sub(self.queued), 1))
+            Some(cmp::max(self.max_queue.saturating_sub(queued), 1))
         }
     }
 
@@ -326,19 +494,40 @@ impl<T: cio::CIO> Peer<T> {
                 self.cid = Some(id);
                 self.send_rpc_info();
             }
-            Message::Piece { length, .. } | Message::SharedPiece { length, .. } => {
+            Message::Piece {
+                index,
+                begin,
+                length,
+                ..
+            }
+            | Message::SharedPiece {
+                index,
+                begin,
+                length,
+                ..
+            } => {
                 self.stat.add_dl(u64::from(length));
                 self.downloaded += 1;
-                self.queued -= 1;
+                // A cancelled request may still be fulfilled, but
+                // was already removed
+                self.requests.remove(&(index, begin));
+                if let Some((i, b, t)) = self.rtt_probe {
+                    if i == index && b == begin {
+                        self.rtt_probe = None;
+                        self.update_rtt(t.elapsed());
+                    }
+                }
+            }
+            Message::Choke => {
+                self.remote_status.choked = true;
+                // Requests are dropped when choked, so don't wait on the probe
+                self.rtt_probe = None;
             }
             Message::Request { .. } => {
                 if self.local_status.choked {
                     return Err(ErrorKind::ProtocolError("Peer requested while choked!").into());
                 }
             }
-            Message::Choke => {
-                self.remote_status.choked = true;
-            }
             Message::Unchoke => {
                 self.remote_status.choked = false;
             }
@@ -382,6 +571,11 @@ impl<T: cio::CIO> Peer<T> {
                     });
                 });
             }
+            Message::HashRequest(_) | Message::Hashes { .. } | Message::HashReject(_) => {
+                if !self.supports_v2() {
+                    return Err(ErrorKind::ProtocolError("Unsupported hash message!").into());
+                }
+            }
             Message::Port(p) => {
                 let mut s = self.addr();
                 s.set_port(p);
@@ -400,6 +594,22 @@ impl<T: cio::CIO> Peer<T> {
                     if let Some(uti) = m.remove("ut_metadata").and_then(|v| v.into_int()) {
                         self.ext_ids.ut_meta = Some(uti as u8);
                     }
+                    if let Some(upi) = m.remove("ut_pex").and_then(|v| v.into_int()) {
+                        self.ext_ids.ut_pex = Some(upi as u8);
+                    }
+                    if let Some(q) = d.remove("reqq").and_then(|v| v.into_int()) {
+                        if q > 0 {
+                            self.reqq = Some(cmp::min(q, i64::from(MAX_QUEUE_CAP)) as u16);
+                            self.max_queue = cmp::min(self.max_queue, self.queue_cap());
+                        }
+                    }
+                    if let Some(port) = d.remove("p").and_then(|v| v.into_int()) {
+                        if port > 0 && port <= i64::from(::std::u16::MAX) {
+                            let mut s = self.addr;
+                            s.set_port(port as u16);
+                            self.listen = Some(s);
+                        }
+                    }
                 }
             }
         }
@@ -408,10 +618,38 @@ impl<T: cio::CIO> Peer<T> {
 
     pub fn request_piece(&mut self, idx: u32, offset: u32, len: u32) {
         let m = Message::request(idx, offset, len);
-        self.queued += 1;
+        if self.requests.is_empty() && self.rtt_probe.is_none() {
+            self.rtt_probe = Some((idx, offset, time::Instant::now()));
+        }
+        self.requests.insert((idx, offset));
         self.send_message(m);
     }
 
+    /// Cancels a request, no longer counting it as queued.
+    pub fn cancel_piece(&mut self, index: u32, begin: u32, length: u32) {
+        self.requests.remove(&(index, begin));
+        if self.rtt_probe
+            .map(|(i, b, _)| i == index && b == begin)
+            .unwrap_or(false)
+        {
+            self.rtt_probe = None;
+        }
+        self.send_message(Message::Cancel {
+            index,
+            begin,
+            length,
+        });
+    }
+
+    /// Folds an RTT sample into the smoothed RTT, weighting
+    /// new samples by 1/8 as TCP does.
+    fn update_rtt(&mut self, sample: time::Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
     pub fn choke(&mut self) {
         if !self.local_status.choked {
             self.local_status.choked = true;
@@ -457,12 +695,13 @@ impl<T: cio::CIO> Peer<T> {
             self.cio.msg_rpc(rpc::CtlMessage::Extant(vec![
                 resource::Resource::Peer(resource::Peer {
                     id,
-                    torrent_id: util::hash_to_id(&self.t_hash[..]),
+                    torrent_id: self.t_id.clone(),
                     client_id: util::hash_to_id(&cid[..]),
                     ip: self.addr.to_string(),
                     rate_up: 0,
                     rate_down: 0,
                     availability: self.piece_count as f32 / self.pieces.len() as f32,
+                    queue_depth: self.max_queue,
                     ..Default::default()
                 }),
             ]));
@@ -507,9 +746,16 @@ impl<T: cio::CIO> fmt::Debug for Peer<T> {
     }
 }
 
+fn dur_to_ms(d: time::Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_nanos()) / 1_000_000
+}
+
 impl ExtIDs {
     fn new() -> ExtIDs {
-        ExtIDs { ut_meta: None }
+        ExtIDs {
+            ut_meta: None,
+            ut_pex: None,
+        }
     }
 }
 
@@ -558,4 +804,63 @@ mod tests {
         assert_eq!(wq[0], p1);
         assert_eq!(wq[1], p3);
     }
+
+    #[test]
+    fn test_queue_depth() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.max_queue = 15;
+
+        // Only requests sent with none outstanding are timed
+        peer.request_piece(0, 0, 16_384);
+        peer.request_piece(0, 16_384, 16_384);
+        let mut p2 = Message::Piece {
+            index: 0,
+            begin: 16_384,
+            data: Box::new([0u8; 16_384]),
+            length: 16_384,
+        };
+        peer.handle_msg(&mut p2).unwrap();
+        assert!(peer.rtt().is_none());
+        let mut p1 = Message::Piece {
+            index: 0,
+            begin: 0,
+            data: Box::new([0u8; 16_384]),
+            length: 16_384,
+        };
+        peer.handle_msg(&mut p1).unwrap();
+        assert!(peer.rtt().is_some());
+
+        // The queue is capped to the peer's reqq
+        let mut ext = Message::Extension {
+            id: 0,
+            payload: b"d1:md11:ut_metadatai1ee4:reqqi5ee".to_vec(),
+        };
+        peer.handle_msg(&mut ext).unwrap();
+        assert_eq!(peer.queue_depth(), 5);
+    }
+
+    #[test]
+    fn test_cancel_queued() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.max_queue = 4;
+        peer.handle_msg(&mut Message::Unchoke).unwrap();
+        for i in 0..4 {
+            peer.request_piece(i, 0, 16_384);
+        }
+        assert_eq!(peer.queue_reqs(), None);
+
+        // A cancelled request which still arrives is only dequeued once
+        peer.cancel_piece(0, 0, 16_384);
+        let mut p = Message::Piece {
+            index: 0,
+            begin: 0,
+            data: Box::new([0u8; 16_384]),
+            length: 16_384,
+        };
+        peer.handle_msg(&mut p).unwrap();
+        assert_eq!(peer.requests.len(), 3);
+        assert_eq!(peer.queue_reqs(), None);
+    }
 }
@@ -1,6 +1,6 @@
 use std::io::{self, Read};
 use std::mem;
-use torrent::peer::Message;
+use torrent::peer::{HashRequest, Message};
 use torrent::Bitfield;
 use byteorder::{BigEndian, ReadBytesExt};
 use util::{aread, io_err, IOR};
@@ -36,6 +36,10 @@ enum State {
         id: u8,
         payload: Vec<u8>,
     },
+    Hash {
+        id: u8,
+        data: Vec<u8>,
+    },
 }
 
 impl Reader {
@@ -125,6 +129,19 @@ impl Reader {
                             8 => self.state = State::Cancel,
                             9 => self.state = State::Port,
                             20 => self.state = State::ExtensionID,
+                            21...23 => {
+                                let id = self.prefix[4];
+                                let mlen = (&self.prefix[0..4]).read_u32::<BigEndian>().unwrap();
+                                let dlen = mlen as usize - 1;
+                                if dlen < 48 || (id != 22 && dlen != 48) || (dlen - 48) % 32 != 0 {
+                                    return io_err("Invalid hash message length!");
+                                }
+                                self.idx = 0;
+                                self.state = State::Hash {
+                                    id,
+                                    data: vec![0u8; dlen],
+                                };
+                            }
                             _ => return io_err("Invalid ID used!"),
                         }
                     }
@@ -259,6 +276,32 @@ impl Reader {
                     IOR::EOF => return io_err("EOF"),
                     IOR::Err(e) => return Err(e),
                 },
+                State::Hash { id, ref mut data } => match aread(&mut data[self.idx..len], conn) {
+                    IOR::Complete => {
+                        let mut pieces_root = [0u8; 32];
+                        pieces_root.copy_from_slice(&data[..32]);
+                        let req = Box::new(HashRequest {
+                            pieces_root,
+                            base_layer: (&data[32..36]).read_u32::<BigEndian>().unwrap(),
+                            index: (&data[36..40]).read_u32::<BigEndian>().unwrap(),
+                            length: (&data[40..44]).read_u32::<BigEndian>().unwrap(),
+                            proof_layers: (&data[44..48]).read_u32::<BigEndian>().unwrap(),
+                        });
+                        let msg = match id {
+                            21 => Message::HashRequest(req),
+                            22 => Message::Hashes {
+                                req,
+                                hashes: data[48..].to_vec(),
+                            },
+                            _ => Message::HashReject(req),
+                        };
+                        return Ok(Some(msg));
+                    }
+                    IOR::Incomplete(a) => self.idx += a,
+                    IOR::Blocked => return Ok(None),
+                    IOR::EOF => return io_err("EOF"),
+                    IOR::Err(e) => return Err(e),
+                },
             }
         }
     }
@@ -278,6 +321,7 @@ impl State {
             State::Bitfield { ref data, .. } => data.len(),
             State::ExtensionID => 6,
             State::Extension { ref payload, .. } => payload.len(),
+            State::Hash { ref data, .. } => data.len(),
         }
     }
 }
@@ -496,6 +540,31 @@ mod tests {
         test_message(data, Message::Port(6881));
     }
 
+    #[test]
+    fn test_read_hashes() {
+        let req = Box::new(HashRequest {
+            pieces_root: [1; 32],
+            base_layer: 2,
+            index: 0,
+            length: 4,
+            proof_layers: 1,
+        });
+        let m = Message::Hashes {
+            req,
+            hashes: vec![3; 5 * 32],
+        };
+        let mut data = vec![0; m.len()];
+        m.encode(&mut data[..]).unwrap();
+        test_message(data, m);
+
+        // Requests carry no hashes
+        let mut data = vec![0u8, 0, 0, 81, 21];
+        data.extend(vec![0u8; 80]);
+        let mut r = Reader::new();
+        r.state = State::Len;
+        assert!(r.readable(&mut Cursor::new(&data)).is_err());
+    }
+
     #[test]
     fn test_read_handshake() {
         use PEER_ID;
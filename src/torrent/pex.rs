@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use bencode::{self, BEncode};
+use util::{addr_to_bytes, bytes_to_addr};
+
+/// Maximum number of added or dropped peers sent in a single message
+pub const MAX_PEERS: usize = 50;
+
+/// Flag indicating the peer is a seed
+pub const FLAG_SEED: u8 = 0x02;
+/// Flag indicating the peer is connectable
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+/// Encodes a ut_pex message containing the given added peers and their
+/// flags, and the dropped peers. At most MAX_PEERS of each address
+/// family are included, IPv6 peers being sent in the added6/dropped6 keys.
+pub fn encode(added: &[(SocketAddr, u8)], dropped: &[SocketAddr]) -> Vec<u8> {
+    let mut msg = BTreeMap::new();
+    for &(v6, suffix) in &[(false, ""), (true, "6")] {
+        let mut a = Vec::new();
+        let mut af = Vec::new();
+        for &(addr, flags) in added
+            .iter()
+            .filter(|&&(addr, _)| addr.is_ipv6() == v6)
+            .take(MAX_PEERS)
+        {
+            a.extend_from_slice(&addr_to_bytes(&addr)[..]);
+            af.push(flags);
+        }
+        let mut d = Vec::new();
+        for addr in dropped.iter().filter(|addr| addr.is_ipv6() == v6).take(MAX_PEERS) {
+            d.extend_from_slice(&addr_to_bytes(addr)[..]);
+        }
+        // The IPv4 keys are always sent for compatibility
+        if v6 && a.is_empty() && d.is_empty() {
+            continue;
+        }
+        msg.insert(format!("added{}", suffix), BEncode::String(a));
+        msg.insert(format!("added{}.f", suffix), BEncode::String(af));
+        msg.insert(format!("dropped{}", suffix), BEncode::String(d));
+    }
+    BEncode::Dict(msg).encode_to_buf()
+}
+
+/// Keeps only the peers encode would include, the first MAX_PEERS
+/// of each address family.
+pub fn truncate<T, F: Fn(&T) -> SocketAddr>(peers: &mut Vec<T>, addr: F) {
+    let (mut v4, mut v6) = (0, 0);
+    peers.retain(|p| {
+        let n = if addr(p).is_ipv6() { &mut v6 } else { &mut v4 };
+        *n += 1;
+        *n <= MAX_PEERS
+    });
+}
+
+/// Decodes the added IPv4 and IPv6 peers of a ut_pex message,
+/// returning an error if the message is malformed.
+pub fn decode(payload: &[u8]) -> Result<Vec<SocketAddr>, ()> {
+    let b = bencode::decode_buf(payload).map_err(|_| ())?;
+    let mut d = b.into_dict().ok_or(())?;
+    let mut peers = Vec::new();
+    for &(key, len) in &[("added", 6), ("added6", 18)] {
+        let added = match d.remove(key) {
+            Some(a) => a.into_bytes().ok_or(())?,
+            None => continue,
+        };
+        if added.len() % len != 0 {
+            return Err(());
+        }
+        peers.extend(
+            added
+                .chunks(len)
+                .take(MAX_PEERS)
+                .map(bytes_to_addr)
+                .filter(|addr| addr.port() != 0),
+        );
+    }
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let a: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:51413".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:6882".parse().unwrap();
+        let payload = encode(&[(a, FLAG_SEED), (b, 0)], &[c]);
+        assert_eq!(decode(&payload).unwrap(), vec![a, b]);
+        assert_eq!(decode(b"d7:dropped6:aaaaaae").unwrap(), vec![]);
+        assert!(decode(b"d5:added5:aaaaae").is_err());
+
+        let e: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let payload = encode(&[(a, 0), (e, FLAG_REACHABLE)], &[]);
+        assert_eq!(decode(&payload).unwrap(), vec![a, e]);
+        assert!(decode(b"d6:added65:aaaaae").is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut peers: Vec<SocketAddr> = (0..MAX_PEERS as u16 + 10)
+            .map(|p| SocketAddr::from(([10, 0, 0, 1], p)))
+            .collect();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        peers.push(v6);
+        truncate(&mut peers, |a| *a);
+        assert_eq!(peers.len(), MAX_PEERS + 1);
+        assert_eq!(peers[MAX_PEERS - 1].port(), MAX_PEERS as u16 - 1);
+        assert_eq!(peers[MAX_PEERS], v6);
+    }
+}
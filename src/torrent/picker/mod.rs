@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::{mem, time};
+use std::{cmp, mem, time};
 use std::sync::Arc;
 use torrent::{Bitfield, Info, Peer};
 use control::cio;
@@ -14,8 +14,9 @@ mod tests;
 pub struct Picker {
     /// Number of blocks per piece
     scale: u32,
-    last_piece_scale: u32,
-    last_piece: u32,
+    /// Number of blocks in pieces with fewer, those being the last
+    /// piece and pieces ending in padding which isn't downloaded
+    short_pieces: HashMap<u32, u32>,
     /// Number of detected seeders
     seeders: u16,
     /// Set of pieces which have blocks waiting. These should be prioritized.
@@ -27,6 +28,13 @@ pub struct Picker {
     picker: PickerKind,
     /// Piece priorities
     priorities: Vec<u8>,
+    /// Pieces being streamed and the time they're needed by,
+    /// ordered by deadline. These are picked before all others.
+    deadlines: Vec<(u32, time::Instant)>,
+    /// Number of unpicked pieces which are wanted(nonzero priority).
+    /// Once this hits zero every remaining block has been requested
+    /// and the picker enters endgame.
+    wanted: u32,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -63,6 +71,11 @@ struct Request {
 const MAX_DUP_REQS: usize = 3;
 const MAX_DL_Q: usize = 150;
 const REQ_TIMEOUT: u64 = 15;
+/// Time given to download each successive streamed piece
+const DEADLINE_MS: u64 = 1000;
+/// Download rate(bytes/s) above which a peer is sent duplicate
+/// requests for streamed pieces past their deadline
+const FAST_PEER_RATE: u64 = 100 * 1024;
 
 impl Picker {
     /// Creates a new picker, which will select over
@@ -72,13 +85,19 @@ impl Picker {
     pub fn new(info: &Arc<Info>, pieces: &Bitfield, priorities: &[u8]) -> Picker {
         let scale = info.piece_len / 16_384;
         let picker = rarest::Picker::new(pieces);
-        let last_piece = info.pieces().saturating_sub(1);
-        let lpl = info.piece_len(last_piece);
-        let last_piece_scale = if lpl % 16_384 == 0 {
-            lpl / 16_384
-        } else {
-            lpl / 16_384 + 1
-        };
+        let mut short_pieces = HashMap::new();
+        if info.complete() {
+            let last_piece = info.pieces() - 1;
+            short_pieces.insert(last_piece, blocks(info.piece_len(last_piece)));
+        }
+        if info.files.iter().any(|f| f.padding) {
+            for piece in 0..info.pieces() {
+                let len = Info::piece_data_len(info, piece);
+                if len < info.piece_len(piece) {
+                    short_pieces.insert(piece, cmp::max(blocks(len), 1));
+                }
+            }
+        }
         let downloading = if pieces.complete() {
             HashMap::with_capacity(0)
         } else {
@@ -87,12 +106,13 @@ impl Picker {
         let mut picker = Picker {
             picker: PickerKind::Rarest(picker),
             scale,
-            last_piece,
-            last_piece_scale,
+            short_pieces,
             seeders: 0,
             unpicked: pieces.clone(),
             downloading,
             priorities: vec![3; info.pieces() as usize],
+            deadlines: Vec::new(),
+            wanted: 0,
         };
         picker.set_priorities(priorities, info);
         picker
@@ -106,6 +126,13 @@ impl Picker {
         }
     }
 
+    /// Returns true if all wanted blocks have been requested
+    /// but some are still outstanding. In endgame, outstanding
+    /// blocks are requested from every peer which has them.
+    pub fn endgame(&self) -> bool {
+        self.wanted == 0 && !self.downloading.is_empty()
+    }
+
     pub fn tick(&mut self) {
         let mut expired = 0;
         for chunks in self.downloading.values_mut() {
@@ -128,23 +155,56 @@ impl Picker {
 
     /// Attempts to select a block for a peer.
     pub fn pick<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
-        if let Some(b) = self.pick_expired(peer) {
+        let fast = peer.get_tx_rates().1 >= FAST_PEER_RATE;
+        self.pick_block(peer.pieces(), peer.id(), fast)
+    }
+
+    /// Attempts to select a block for a source with the given pieces
+    /// and id, which need not be a connected peer(e.g. a web seed).
+    pub fn pick_from(&mut self, pieces: &Bitfield, id: usize) -> Option<Block> {
+        self.pick_block(pieces, id, false)
+    }
+
+    /// Sets the pieces needed by streams, in the order they're needed.
+    /// Pieces which already had a deadline keep it, while others are
+    /// given one after those before them.
+    pub fn set_deadlines(&mut self, pieces: &[u32]) {
+        let now = time::Instant::now();
+        let prev = mem::replace(&mut self.deadlines, Vec::with_capacity(pieces.len()));
+        for (i, &piece) in pieces.iter().enumerate() {
+            if self.deadlines.iter().any(|&(p, _)| p == piece) {
+                continue;
+            }
+            let deadline = prev.iter()
+                .find(|&&(p, _)| p == piece)
+                .map(|&(_, d)| d)
+                .unwrap_or_else(|| now + time::Duration::from_millis(DEADLINE_MS * (i as u64 + 1)));
+            self.deadlines.push((piece, deadline));
+        }
+        self.deadlines.sort_by_key(|&(_, d)| d);
+    }
+
+    fn pick_block(&mut self, pieces: &Bitfield, id: usize, fast: bool) -> Option<Block> {
+        if let Some(b) = self.pick_deadline(pieces, id, fast) {
+            return Some(b);
+        }
+        if let Some(b) = self.pick_expired(id) {
             return Some(b);
         }
         if self.downloading.len() > MAX_DL_Q {
-            if let Some(b) = self.pick_pri(peer) {
+            if let Some(b) = self.pick_pri(id) {
                 return Some(b);
             }
         }
 
         let piece = match self.picker {
-            PickerKind::Sequential(ref mut p) => p.pick(peer),
-            PickerKind::Rarest(ref mut p) => p.pick(peer),
+            PickerKind::Sequential(ref mut p) => p.pick(pieces),
+            PickerKind::Rarest(ref mut p) => p.pick(pieces),
         };
         let res = piece
-            .and_then(|p| self.pick_piece(p, peer.id()))
-            .or_else(|| self.pick_downloading(peer));
-        if res.is_none() && peer.pieces().complete() && !self.unpicked.complete() {
+            .and_then(|p| self.pick_piece(p, id))
+            .or_else(|| self.pick_downloading(pieces, id));
+        if res.is_none() && pieces.complete() && self.wanted != 0 {
             debug_assert!(
                 false,
                 "Couldnt pick {:?} from a seeder for some reason!",
@@ -154,16 +214,55 @@ impl Picker {
         res
     }
 
+    /// Attempts to pick a block of the streamed piece with the earliest
+    /// deadline, fast peers also being sent duplicate requests for
+    /// pieces past their deadline.
+    fn pick_deadline(&mut self, pieces: &Bitfield, id: usize, fast: bool) -> Option<Block> {
+        let now = time::Instant::now();
+        for i in 0..self.deadlines.len() {
+            let (piece, deadline) = self.deadlines[i];
+            if !pieces.has_bit(u64::from(piece)) {
+                continue;
+            }
+            if !self.unpicked.has_bit(u64::from(piece)) {
+                return self.pick_piece(piece, id);
+            }
+            if fast && deadline <= now {
+                let b = self.downloading.get_mut(&piece).and_then(|dl| {
+                    dl.iter_mut()
+                        .find(|r| {
+                            !r.completed && r.requested.len() < MAX_DUP_REQS
+                                && r.requested.iter().all(|req| req.peer != id)
+                        })
+                        .map(|r| {
+                            r.requested.push(Request::new(id));
+                            Block::new(piece, r.offset)
+                        })
+                });
+                if b.is_some() {
+                    return b;
+                }
+            }
+        }
+        None
+    }
+
     /// Attempts to pick an expired block
-    fn pick_expired<T: cio::CIO>(&mut self, _: &Peer<T>) -> Option<Block> {
+    fn pick_expired(&mut self, _: usize) -> Option<Block> {
         // TODO: Use some form of heuristic here to say "we expect to have
         // downloaded some pieces by X, hit the picker with a tick which checks
         // that, flags shit as invalid, and then does a double request
         None
     }
 
+    /// Number of blocks to be downloaded for a piece
+    fn piece_scale(&self, piece: u32) -> u32 {
+        self.short_pieces.get(&piece).cloned().unwrap_or(self.scale)
+    }
+
     /// Picks a block from a given piece for a peer
     fn pick_piece(&mut self, piece: u32, id: usize) -> Option<Block> {
+        let scale = self.piece_scale(piece);
         self.downloading.entry(piece).or_insert_with(|| vec![]);
         let dl = self.downloading.get_mut(&piece).unwrap();
         let offset = dl.len() as u32 * 16_384;
@@ -173,13 +272,14 @@ impl Picker {
             requested: vec![Request::new(id)],
         });
 
-        if dl.len() == self.scale as usize
-            || (piece == self.last_piece && dl.len() == self.last_piece_scale as usize)
-        {
+        if dl.len() as u32 == scale {
             match self.picker {
                 PickerKind::Sequential(ref mut p) => p.completed(piece),
                 PickerKind::Rarest(ref mut p) => p.completed(piece),
             }
+            if !self.unpicked.has_bit(u64::from(piece)) && self.priorities[piece as usize] != 0 {
+                self.wanted -= 1;
+            }
             self.unpicked.set_bit(u64::from(piece));
         }
         Some(Block {
@@ -189,7 +289,7 @@ impl Picker {
     }
 
     /// Attempts to pick the highest priority piece in the dl q
-    fn pick_pri<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+    fn pick_pri(&mut self, id: usize) -> Option<Block> {
         let pri = &mut self.priorities;
         self.downloading
             .iter_mut()
@@ -198,26 +298,32 @@ impl Picker {
                 dl.iter_mut()
                     .find(|r| {
                         !r.completed && r.requested.len() < MAX_DUP_REQS
-                            && r.requested.iter().all(|req| req.peer != peer.id())
+                            && r.requested.iter().all(|req| req.peer != id)
                     })
                     .map(|r| {
-                        r.requested.push(Request::new(peer.id()));
+                        r.requested.push(Request::new(id));
                         Block::new(*idx, r.offset)
                     })
             })
     }
 
-    /// Attempts to pick an already requested block
-    fn pick_downloading<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+    /// Attempts to pick an already requested block. In endgame
+    /// the number of duplicate requests is unlimited.
+    fn pick_downloading(&mut self, pieces: &Bitfield, id: usize) -> Option<Block> {
+        let max_reqs = if self.endgame() {
+            usize::max_value()
+        } else {
+            MAX_DUP_REQS
+        };
         for (idx, dl) in &mut self.downloading {
-            if peer.pieces().has_bit(u64::from(*idx)) {
+            if pieces.has_bit(u64::from(*idx)) {
                 let r = dl.iter_mut()
                     .find(|r| {
-                        !r.completed && r.requested.len() < MAX_DUP_REQS
-                            && r.requested.iter().all(|req| req.peer != peer.id())
+                        !r.completed && r.requested.len() < max_reqs
+                            && r.requested.iter().all(|req| req.peer != id)
                     })
                     .map(|r| {
-                        r.requested.push(Request::new(peer.id()));
+                        r.requested.push(Request::new(id));
                         Block::new(*idx, r.offset)
                     });
                 if r.is_some() {
@@ -246,19 +352,15 @@ impl Picker {
 
         // If we've requested every single block for this piece and they're all complete, remove it
         // and report completion
-        let scale = self.scale;
-        let lp = self.last_piece;
-        let lps = self.last_piece_scale;
+        let scale = self.piece_scale(b.index);
         let complete = self.downloading
             .get_mut(&b.index)
-            .map(|r| {
-                (r.len() as u32 == scale || (b.index == lp && r.len() as u32 == lps))
-                    && r.iter().all(|d| d.completed)
-            })
+            .map(|r| r.len() as u32 == scale && r.iter().all(|d| d.completed))
             .unwrap_or(false);
 
         if complete {
             self.downloading.remove(&b.index);
+            self.deadlines.retain(|&(p, _)| p != b.index);
         }
 
         res.map(|r| (complete, r)).ok_or(())
@@ -281,6 +383,9 @@ impl Picker {
             PickerKind::Sequential(ref mut p) => p.incomplete(idx),
             PickerKind::Rarest(ref mut p) => p.incomplete(idx),
         }
+        if self.unpicked.has_bit(u64::from(idx)) && self.priorities[idx as usize] != 0 {
+            self.wanted += 1;
+        }
         self.unpicked.unset_bit(u64::from(idx));
         self.downloading.remove(&idx);
     }
@@ -311,9 +416,14 @@ impl Picker {
             }
         }
 
+        self.remove_requests(peer.id());
+    }
+
+    /// Cancels all outstanding block requests made by id
+    pub fn remove_requests(&mut self, id: usize) {
         for piece in self.downloading.values_mut() {
             for block in piece {
-                block.requested.retain(|req| req.peer != peer.id())
+                block.requested.retain(|req| req.peer != id)
             }
         }
     }
@@ -329,12 +439,23 @@ impl Picker {
             PickerKind::Rarest(rarest::Picker::new(&self.unpicked))
         };
         self.downloading.clear();
+        self.count_wanted();
     }
 
     pub fn set_priorities(&mut self, pri: &[u8], info: &Arc<Info>) {
         self.unapply_priorities();
         self.priorities = generate_piece_pri(pri, info);
         self.apply_priorities();
+        self.count_wanted();
+    }
+
+    fn count_wanted(&mut self) {
+        let unpicked = &self.unpicked;
+        self.wanted = self.priorities
+            .iter()
+            .enumerate()
+            .filter(|&(piece, pri)| *pri != 0 && !unpicked.has_bit(piece as u64))
+            .count() as u32;
     }
 
     pub fn apply_priorities(&mut self) {
@@ -382,12 +503,23 @@ impl Picker {
     }
 }
 
+/// Number of blocks needed to cover len bytes
+fn blocks(len: u32) -> u32 {
+    (len + 16_383) / 16_384
+}
+
 fn generate_piece_pri(pri: &[u8], info: &Arc<Info>) -> Vec<u8> {
     // Map piece -> priority
     let mut priorities = Vec::with_capacity(info.pieces() as usize);
     // If a piece is completely in a file, just assign that pri.
     // Otherwise mark it as the higher pri piece
     for p in 0..info.pieces() {
+        // Pieces without a known hash can't be validated, so they
+        // aren't downloaded until their piece layer is received
+        if !info.piece_verifiable(p) {
+            priorities.push(0);
+            continue;
+        }
         let max = Info::piece_disk_locs(&info, p)
             .map(|loc| pri[loc.file])
             .max()
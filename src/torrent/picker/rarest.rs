@@ -109,14 +109,14 @@ impl Picker {
         self.swap_piece(idx, swap_idx);
     }
 
-    pub fn pick<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<u32> {
+    pub fn pick(&mut self, pieces: &Bitfield) -> Option<u32> {
         // Find the first matching piece which is not complete,
         // and that the peer also has
         self.pieces
             .iter()
             .cloned()
             .filter(|p| self.piece_idx[*p as usize].status == PieceStatus::Incomplete)
-            .find(|p| peer.pieces().has_bit(u64::from(*p)))
+            .find(|p| pieces.has_bit(u64::from(*p)))
             .map(|p| {
                 if (self.piece_idx[p as usize].availability % 2) == 0 {
                     self.dec_pri(p);
@@ -166,7 +166,7 @@ mod tests {
             Peer::test_from_pieces(0, b.clone()),
             Peer::test_from_pieces(0, b.clone()),
         ];
-        assert_eq!(picker.pick(&peers[0]), None);
+        assert_eq!(picker.pick(peers[0].pieces()), None);
 
         peers[0].pieces_mut().set_bit(0);
         peers[1].pieces_mut().set_bit(0);
@@ -176,13 +176,13 @@ mod tests {
         for peer in peers.iter() {
             picker.add_peer(peer);
         }
-        assert_eq!(picker.pick(&peers[1]), Some(2));
+        assert_eq!(picker.pick(peers[1].pieces()), Some(2));
         picker.completed(2);
-        assert_eq!(picker.pick(&peers[1]), Some(0));
+        assert_eq!(picker.pick(peers[1].pieces()), Some(0));
         picker.completed(0);
-        assert_eq!(picker.pick(&peers[1]), None);
-        assert_eq!(picker.pick(&peers[0]), None);
-        assert_eq!(picker.pick(&peers[2]), Some(1));
+        assert_eq!(picker.pick(peers[1].pieces()), None);
+        assert_eq!(picker.pick(peers[0].pieces()), None);
+        assert_eq!(picker.pick(peers[2].pieces()), Some(1));
         picker.completed(1);
     }
 
@@ -196,7 +196,7 @@ mod tests {
             Peer::test_from_pieces(0, b.clone()),
             Peer::test_from_pieces(0, b.clone()),
         ];
-        assert_eq!(picker.pick(&peers[0]), None);
+        assert_eq!(picker.pick(peers[0].pieces()), None);
 
         peers[0].pieces_mut().set_bit(0);
         peers[0].pieces_mut().set_bit(1);
@@ -210,15 +210,15 @@ mod tests {
         }
         picker.remove_peer(&peers[0]);
 
-        assert_eq!(picker.pick(&peers[1]), Some(2));
+        assert_eq!(picker.pick(peers[1].pieces()), Some(2));
         picker.completed(2);
-        assert_eq!(picker.pick(&peers[2]), Some(0));
+        assert_eq!(picker.pick(peers[2].pieces()), Some(0));
         picker.completed(0);
-        assert_eq!(picker.pick(&peers[2]), Some(1));
+        assert_eq!(picker.pick(peers[2].pieces()), Some(1));
         picker.completed(1);
 
-        assert_eq!(picker.pick(&peers[1]), None);
+        assert_eq!(picker.pick(peers[1].pieces()), None);
         picker.incomplete(1);
-        assert_eq!(picker.pick(&peers[1]), Some(1));
+        assert_eq!(picker.pick(peers[1].pieces()), Some(1));
     }
 }
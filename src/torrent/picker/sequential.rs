@@ -1,5 +1,4 @@
-use torrent::{Bitfield, Peer};
-use control::cio;
+use torrent::Bitfield;
 
 #[derive(Clone, Debug)]
 pub struct Picker {
@@ -57,10 +56,10 @@ impl Picker {
         }
     }
 
-    pub fn pick<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<u32> {
+    pub fn pick(&mut self, pieces: &Bitfield) -> Option<u32> {
         self.pieces[self.piece_idx..]
             .iter()
-            .find(|p| peer.pieces().has_bit(u64::from(p.pos)))
+            .find(|p| pieces.has_bit(u64::from(p.pos)))
             .map(|p| p.pos)
     }
 
@@ -106,19 +105,19 @@ mod tests {
         let b = Bitfield::new(3);
         let mut picker = Picker::new(&b);
         let mut peer = Peer::test_from_pieces(0, b);
-        assert_eq!(picker.pick(&peer), None);
+        assert_eq!(picker.pick(peer.pieces()), None);
         peer.pieces_mut().set_bit(1);
-        assert_eq!(picker.pick(&peer), Some(1));
+        assert_eq!(picker.pick(peer.pieces()), Some(1));
         peer.pieces_mut().set_bit(0);
-        assert_eq!(picker.pick(&peer), Some(0));
+        assert_eq!(picker.pick(peer.pieces()), Some(0));
         picker.completed(0);
         picker.completed(1);
         peer.pieces_mut().set_bit(2);
-        assert_eq!(picker.pick(&peer), Some(2));
+        assert_eq!(picker.pick(peer.pieces()), Some(2));
 
         picker.completed(2);
-        assert_eq!(picker.pick(&peer), None);
+        assert_eq!(picker.pick(peer.pieces()), None);
         picker.incomplete(1);
-        assert_eq!(picker.pick(&peer), Some(1));
+        assert_eq!(picker.pick(peer.pieces()), Some(1));
     }
 }
@@ -1,7 +1,11 @@
-use super::{Block, Picker};
+use super::{Block, Picker, MAX_DUP_REQS};
 use std::collections::HashMap;
 use std::cell::RefCell;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time;
 use torrent::{Bitfield, Info, Peer as TGPeer};
+use torrent::info::File;
 use rand::distributions::{IndependentSample, Range};
 use {control, rand};
 
@@ -263,3 +267,119 @@ fn test_seq_picker() {
 
     assert_eq!(p.pick(&peer), Some(Block::new(5, 0)));
 }
+
+#[test]
+fn test_unverifiable_picker() {
+    let mut i = Info::with_pieces(3);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    i.hashes[1] = vec![];
+    let b = Bitfield::new(3);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut pb = Bitfield::new(3);
+    for i in 0..3 {
+        pb.set_bit(i);
+    }
+
+    // Pieces missing their hash are skipped until it's known
+    let mut picked = vec![
+        p.pick_block(&pb, 0, false).unwrap().index,
+        p.pick_block(&pb, 0, false).unwrap().index,
+    ];
+    picked.sort();
+    assert_eq!(picked, vec![0, 2]);
+    assert_eq!(p.pick_block(&pb, 0, false), None);
+
+    i.hashes[1] = vec![0u8];
+    p.set_priorities(&[3], &Arc::new(i));
+    assert_eq!(p.pick_block(&pb, 0, false), Some(Block::new(1, 0)));
+}
+
+#[test]
+fn test_padding_picker() {
+    let mut i = Info::with_pieces_scale(2, 2);
+    let mut pad = File::new(PathBuf::from(".pad/22768"), 22_768);
+    pad.padding = true;
+    i.files = vec![
+        File::new(PathBuf::from("a"), 10_000),
+        pad,
+        File::new(PathBuf::from("b"), 32_768),
+    ];
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(2);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(2);
+    pb.set_bit(0);
+    pb.set_bit(1);
+    let peer = TPeer::test_from_pieces(0, pb);
+
+    // Blocks entirely in padding aren't downloaded
+    assert_eq!(p.pick(&peer), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&peer), Some(Block::new(1, 0)));
+    assert_eq!(p.pick(&peer), Some(Block::new(1, 16_384)));
+    assert_eq!(p.completed(Block::new(0, 0)), Ok((true, vec![0])));
+    assert_eq!(p.completed(Block::new(1, 0)), Ok((false, vec![0])));
+}
+
+#[test]
+fn test_deadline_picker() {
+    let mut i = Info::with_pieces_scale(4, 2);
+    i.files = vec![File::new(PathBuf::from("a"), 4 * 32_768)];
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(4);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut pb = Bitfield::new(4);
+    for i in 0..4 {
+        pb.set_bit(i);
+    }
+
+    // Streamed pieces are picked first, in order of deadline
+    p.set_deadlines(&[2, 1]);
+    assert_eq!(p.pick_block(&pb, 0, false), Some(Block::new(2, 0)));
+    assert_eq!(p.pick_block(&pb, 0, false), Some(Block::new(2, 16_384)));
+    assert_eq!(p.pick_block(&pb, 0, false), Some(Block::new(1, 0)));
+    assert_eq!(p.pick_block(&pb, 0, false), Some(Block::new(1, 16_384)));
+
+    // Only fast peers are sent duplicate requests for late pieces
+    p.deadlines = vec![(2, time::Instant::now())];
+    assert!(p.pick_block(&pb, 1, false) != Some(Block::new(2, 0)));
+    assert_eq!(p.pick_block(&pb, 2, true), Some(Block::new(2, 0)));
+
+    // Deadlines are removed once their piece completes
+    assert_eq!(p.completed(Block::new(2, 0)), Ok((false, vec![0, 2])));
+    assert_eq!(p.completed(Block::new(2, 16_384)), Ok((true, vec![0])));
+    assert!(p.deadlines.is_empty());
+}
+
+#[test]
+fn test_endgame_picker() {
+    let mut i = Info::with_pieces_scale(2, 2);
+    i.files = vec![File::new(PathBuf::from("a"), 2 * 32_768)];
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(2);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut pb = Bitfield::new(2);
+    for i in 0..2 {
+        pb.set_bit(i);
+    }
+
+    for _ in 0..3 {
+        p.pick_block(&pb, 0, false).unwrap();
+        assert!(!p.endgame());
+    }
+    assert_eq!(p.pick_block(&pb, 0, false), Some(Block::new(1, 16_384)));
+    assert!(p.endgame());
+
+    for &(idx, off) in &[(0, 0), (0, 16_384), (1, 0)] {
+        p.completed(Block::new(idx, off)).unwrap();
+    }
+    assert!(p.endgame());
+
+    // The last block is requested from every peer, past MAX_DUP_REQS,
+    // all of which are returned for cancellation once it arrives
+    for id in 1..MAX_DUP_REQS + 2 {
+        assert_eq!(p.pick_block(&pb, id, false), Some(Block::new(1, 16_384)));
+    }
+    let peers: Vec<_> = (0..MAX_DUP_REQS + 2).collect();
+    assert_eq!(p.completed(Block::new(1, 16_384)), Ok((true, peers)));
+    assert!(!p.endgame());
+}
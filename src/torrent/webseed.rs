@@ -0,0 +1,249 @@
+use std::cmp;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use torrent::{Bitfield, Block, Info};
+use tracker;
+
+/// Maximum number of blocks fetched from a web seed in a single request
+pub const REQ_BLOCKS: usize = 64;
+/// Base delay before retrying a failed web seed, doubled for each
+/// consecutive failure
+const RETRY_SECS: u64 = 30;
+const MAX_RETRY_SECS: u64 = 60 * 30;
+
+/// A BEP 19 HTTP web seed. Web seeds are assumed to have every piece,
+/// and are given picker ids counting down from usize::MAX so they
+/// cannot collide with those of connected peers.
+pub struct WebSeed {
+    pub url: Url,
+    id: usize,
+    pieces: Bitfield,
+    /// Blocks being fetched, ordered by their position in the torrent
+    blocks: Vec<Block>,
+    failures: u32,
+    retry: Option<Instant>,
+}
+
+impl WebSeed {
+    pub fn new(url: Url, idx: usize, info: &Info) -> WebSeed {
+        let mut pieces = Bitfield::new(u64::from(info.pieces()));
+        for i in 0..pieces.len() {
+            pieces.set_bit(i);
+        }
+        WebSeed {
+            url,
+            id: ::std::usize::MAX - idx,
+            pieces,
+            blocks: Vec::new(),
+            failures: 0,
+            retry: None,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn pieces(&self) -> &Bitfield {
+        &self.pieces
+    }
+
+    /// Returns true if no fetch is in progress and the seed
+    /// isn't backing off after a failure.
+    pub fn idle(&self) -> bool {
+        self.blocks.is_empty() && self.retry.map(|r| Instant::now() >= r).unwrap_or(true)
+    }
+
+    /// Creates a request for the given blocks, mapping them to
+    /// contiguous byte ranges of the files served by the seed.
+    pub fn request(
+        &mut self,
+        tid: usize,
+        mut blocks: Vec<Block>,
+        info: &Arc<Info>,
+    ) -> tracker::WebSeed {
+        blocks.sort_by_key(|b| (b.index, b.offset));
+        let mut ranges: Vec<(usize, u64, u64)> = Vec::new();
+        for b in &blocks {
+            for loc in Info::block_disk_locs(info, b.index, b.offset) {
+                let len = (loc.end - loc.start) as u64;
+                // Empty files and blocks starting at the end of a
+                // file give empty locations, which can't be fetched.
+                // Padding files aren't served by seeds at all.
+                if len == 0 || loc.padding() {
+                    continue;
+                }
+                if let Some(last) = ranges.last_mut() {
+                    if last.0 == loc.file && last.1 + last.2 == loc.offset {
+                        last.2 += len;
+                        continue;
+                    }
+                }
+                ranges.push((loc.file, loc.offset, len));
+            }
+        }
+        self.blocks = blocks;
+        tracker::WebSeed {
+            tid,
+            id: self.id,
+            segments: ranges
+                .into_iter()
+                .map(|(file, offset, len)| (self.file_url(info, file), offset, len))
+                .collect(),
+        }
+    }
+
+    /// Total length of the data being fetched, which excludes padding
+    pub fn fetch_len(&self, info: &Arc<Info>) -> u64 {
+        self.blocks
+            .iter()
+            .flat_map(|b| Info::block_disk_locs(info, b.index, b.offset))
+            .filter(|loc| !loc.padding())
+            .map(|loc| (loc.end - loc.start) as u64)
+            .sum()
+    }
+
+    /// Marks the current fetch as successful, splitting its data,
+    /// which must be fetch_len bytes, into blocks. Padding is zero
+    /// filled.
+    pub fn completed(
+        &mut self,
+        info: &Arc<Info>,
+        data: &[u8],
+    ) -> Vec<(Block, Box<[u8; 16_384]>)> {
+        self.failures = 0;
+        self.retry = None;
+        let mut pos = 0;
+        self.blocks
+            .drain(..)
+            .map(|b| {
+                let mut buf = Box::new([0u8; 16_384]);
+                for loc in Info::block_disk_locs(info, b.index, b.offset) {
+                    if !loc.padding() {
+                        let end = pos + loc.end - loc.start;
+                        buf[loc.start..loc.end].copy_from_slice(&data[pos..end]);
+                        pos = end;
+                    }
+                }
+                (b, buf)
+            })
+            .collect()
+    }
+
+    /// Marks the current fetch as failed, backing off before the
+    /// seed is used again.
+    pub fn failed(&mut self) {
+        self.failures += 1;
+        let delay = cmp::min(
+            RETRY_SECS << cmp::min(self.failures - 1, 16),
+            MAX_RETRY_SECS,
+        );
+        self.retry = Some(Instant::now() + Duration::from_secs(delay));
+        self.blocks.clear();
+    }
+
+    /// Returns the URL of a file. Per BEP 19 a URL ending in '/' is a
+    /// directory which contains the torrent, otherwise it is the
+    /// file itself in single file torrents.
+    fn file_url(&self, info: &Info, file: usize) -> Url {
+        let mut url = self.url.clone();
+        if info.files.len() == 1 && !url.path().ends_with('/') {
+            return url;
+        }
+        if let Ok(mut segs) = url.path_segments_mut() {
+            segs.pop_if_empty();
+            for c in info.files[file].path.components() {
+                segs.push(&c.as_os_str().to_string_lossy());
+            }
+        }
+        url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use url::Url;
+
+    use super::WebSeed;
+    use torrent::{Block, Info};
+    use torrent::info::File;
+
+    #[test]
+    fn test_request() {
+        let mut info = Info::with_pieces(4);
+        info.files.clear();
+        info.files.push(File::new(PathBuf::from("foo/a b"), 40000));
+        info.files.push(File::new(PathBuf::from("foo/empty"), 0));
+        info.files.push(File::new(PathBuf::from("foo/c"), 25536));
+        info.piece_idx =
+            Info::generate_piece_idx(info.hashes.len(), info.piece_len as u64, &info.files);
+        let info = Arc::new(info);
+
+        let url = Url::parse("http://a.com/seed/").unwrap();
+        let mut ws = WebSeed::new(url, 0, &info);
+        assert_eq!(ws.id(), ::std::usize::MAX);
+        assert!(ws.pieces().complete());
+
+        let blocks = vec![Block::new(3, 0), Block::new(1, 0), Block::new(2, 0)];
+        let req = ws.request(0, blocks, &info);
+        assert!(!ws.idle());
+        assert_eq!(ws.fetch_len(&info), 16_384 * 3);
+        let a = Url::parse("http://a.com/seed/foo/a%20b").unwrap();
+        let c = Url::parse("http://a.com/seed/foo/c").unwrap();
+        assert_eq!(
+            req.segments,
+            vec![(a, 16_384, 40000 - 16_384), (c, 0, 16_384 * 4 - 40000)]
+        );
+
+        let data = vec![1u8; 16_384 * 3];
+        let blocks: Vec<_> = ws.completed(&info, &data)
+            .into_iter()
+            .map(|(b, _)| b)
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![Block::new(1, 0), Block::new(2, 0), Block::new(3, 0)]
+        );
+        assert!(ws.idle());
+        ws.request(0, vec![Block::new(0, 0)], &info);
+        ws.failed();
+        assert!(!ws.idle());
+
+        // Padding files are skipped, and zero filled in the blocks
+        let mut info = Info::with_pieces(4);
+        info.files.clear();
+        info.files.push(File::new(PathBuf::from("foo/a"), 40000));
+        let mut pad = File::new(PathBuf::from("foo/.pad/9152"), 16_384 * 3 - 40000);
+        pad.padding = true;
+        info.files.push(pad);
+        info.files.push(File::new(PathBuf::from("foo/c"), 16_384));
+        info.piece_idx =
+            Info::generate_piece_idx(info.hashes.len(), info.piece_len as u64, &info.files);
+        let info = Arc::new(info);
+
+        let mut ws = WebSeed::new(Url::parse("http://a.com/seed/").unwrap(), 0, &info);
+        let req = ws.request(0, vec![Block::new(2, 0), Block::new(3, 0)], &info);
+        let a = Url::parse("http://a.com/seed/foo/a").unwrap();
+        let c = Url::parse("http://a.com/seed/foo/c").unwrap();
+        assert_eq!(
+            req.segments,
+            vec![(a, 16_384 * 2, 40000 - 16_384 * 2), (c, 0, 16_384)]
+        );
+        let len = 40000 - 16_384 * 2 + 16_384;
+        assert_eq!(ws.fetch_len(&info), len as u64);
+
+        let data = vec![1u8; len];
+        let blocks = ws.completed(&info, &data);
+        assert_eq!(blocks.len(), 2);
+        let split = 40000 - 16_384 * 2;
+        assert!(blocks[0].1[..split].iter().all(|&b| b == 1));
+        assert!(blocks[0].1[split..].iter().all(|&b| b == 0));
+        assert!(blocks[1].1.iter().all(|&b| b == 1));
+    }
+}
@@ -0,0 +1,245 @@
+use openssl::pkey::{Id, PKey};
+use openssl::sign::{Signer, Verifier};
+
+use super::proto::ErrorKind;
+use util::sha1_hash;
+
+/// Maximum size of a bencoded item value
+pub const MAX_VALUE_SIZE: usize = 1000;
+/// Maximum size of a mutable item's salt
+pub const MAX_SALT_SIZE: usize = 64;
+
+/// A BEP 44 item, with its value kept bencoded
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    Immutable {
+        v: Vec<u8>,
+    },
+    Mutable {
+        /// ed25519 public key
+        k: Vec<u8>,
+        salt: Vec<u8>,
+        seq: i64,
+        /// ed25519 signature of the salt, seq and value
+        sig: Vec<u8>,
+        v: Vec<u8>,
+    },
+}
+
+/// Identifies an item being looked up
+#[derive(Clone, Debug, PartialEq)]
+pub enum Key {
+    /// SHA-1 hash of the value
+    Immutable([u8; 20]),
+    /// Public key and salt
+    Mutable { k: Vec<u8>, salt: Vec<u8> },
+}
+
+impl Item {
+    pub fn immutable(v: Vec<u8>) -> Item {
+        Item::Immutable { v }
+    }
+
+    /// Creates a mutable item signed with the ed25519 private key seed,
+    /// returning None if the seed is invalid.
+    pub fn mutable(seed: &[u8], salt: Vec<u8>, seq: i64, v: Vec<u8>) -> Option<Item> {
+        let key = PKey::private_key_from_raw_bytes(seed, Id::ED25519).ok()?;
+        let k = key.raw_public_key().ok()?;
+        let sig = Signer::new_without_digest(&key)
+            .and_then(|mut s| s.sign_oneshot_to_vec(&signed_data(&salt, seq, &v)))
+            .ok()?;
+        Some(Item::Mutable {
+            k,
+            salt,
+            seq,
+            sig,
+            v,
+        })
+    }
+
+    pub fn key(&self) -> Key {
+        match *self {
+            Item::Immutable { ref v } => Key::Immutable(sha1_hash(v)),
+            Item::Mutable {
+                ref k, ref salt, ..
+            } => Key::Mutable {
+                k: k.clone(),
+                salt: salt.clone(),
+            },
+        }
+    }
+
+    pub fn target(&self) -> [u8; 20] {
+        self.key().target()
+    }
+
+    pub fn value(&self) -> &[u8] {
+        match *self {
+            Item::Immutable { ref v } | Item::Mutable { ref v, .. } => v,
+        }
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        match *self {
+            Item::Immutable { .. } => None,
+            Item::Mutable { seq, .. } => Some(seq),
+        }
+    }
+
+    /// Checks the size limits of the item and the signature of mutable items
+    pub fn verify(&self) -> Result<(), ErrorKind> {
+        if self.value().len() > MAX_VALUE_SIZE {
+            return Err(ErrorKind::ValueTooBig("Message too big".to_owned()));
+        }
+        if let Item::Mutable {
+            ref k,
+            ref salt,
+            seq,
+            ref sig,
+            ref v,
+        } = *self
+        {
+            if salt.len() > MAX_SALT_SIZE {
+                return Err(ErrorKind::SaltTooBig("Salt too big".to_owned()));
+            }
+            let valid = PKey::public_key_from_raw_bytes(k, Id::ED25519)
+                .and_then(|key| {
+                    Verifier::new_without_digest(&key)?
+                        .verify_oneshot(sig, &signed_data(salt, seq, v))
+                })
+                .unwrap_or(false);
+            if !valid {
+                return Err(ErrorKind::InvalidSignature("Invalid signature".to_owned()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Key {
+    pub fn target(&self) -> [u8; 20] {
+        match *self {
+            Key::Immutable(target) => target,
+            Key::Mutable { ref k, ref salt } => {
+                let mut data = k.clone();
+                data.extend_from_slice(salt);
+                sha1_hash(&data)
+            }
+        }
+    }
+
+    /// Returns true if the item is the one identified by the key. Mutable
+    /// items in responses don't include their salt, which is set from the key.
+    pub fn matches(&self, item: &mut Item) -> bool {
+        match (self, item) {
+            (&Key::Immutable(target), &mut Item::Immutable { ref v }) => sha1_hash(v) == target,
+            (
+                &Key::Mutable {
+                    ref k,
+                    salt: ref ksalt,
+                },
+                &mut Item::Mutable {
+                    k: ref ik,
+                    ref mut salt,
+                    ..
+                },
+            ) if k == ik =>
+            {
+                *salt = ksalt.clone();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Builds the data signed for a mutable item
+fn signed_data(salt: &[u8], seq: i64, v: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    if !salt.is_empty() {
+        data.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        data.extend_from_slice(salt);
+    }
+    data.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    data.extend_from_slice(v);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Item, Key};
+    use util::{hash_to_id, id_to_hash_v2};
+
+    fn hex(s: &str) -> Vec<u8> {
+        s.as_bytes()
+            .chunks(2)
+            .map(|c| u8::from_str_radix(::std::str::from_utf8(c).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_immutable() {
+        let item = Item::immutable(b"12:Hello World!".to_vec());
+        assert_eq!(
+            hash_to_id(&item.target()),
+            "E5F96F6F38320F0F33959CB4D3D656452117AADB"
+        );
+        assert!(item.verify().is_ok());
+        assert!(Item::immutable(vec![b'0'; 1001]).verify().is_err());
+    }
+
+    // Test vectors from BEP 44
+    #[test]
+    fn test_mutable() {
+        let k = hex("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548");
+        let mut item = Item::Mutable {
+            k: k.clone(),
+            salt: vec![],
+            seq: 1,
+            sig: hex(
+                "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff\
+                 1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01",
+            ),
+            v: b"12:Hello World!".to_vec(),
+        };
+        assert!(item.verify().is_ok());
+        assert_eq!(
+            hash_to_id(&item.target()),
+            "4A533D47EC9C7D95B1AD75F576CFFC641853B750"
+        );
+        if let Item::Mutable { ref mut seq, .. } = item {
+            *seq = 2;
+        }
+        assert!(item.verify().is_err());
+
+        let mut item = Item::Mutable {
+            k: k.clone(),
+            salt: vec![],
+            seq: 1,
+            sig: hex(
+                "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17d\
+                 df9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08",
+            ),
+            v: b"12:Hello World!".to_vec(),
+        };
+        let key = Key::Mutable {
+            k,
+            salt: b"foobar".to_vec(),
+        };
+        assert!(key.matches(&mut item));
+        assert!(item.verify().is_ok());
+        assert_eq!(
+            hash_to_id(&key.target()),
+            "411EBA73B6F087CA51A3795D9C8C938D365E32C1"
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        let seed = id_to_hash_v2(&"01".repeat(32)).unwrap();
+        let item = Item::mutable(&seed, b"salt".to_vec(), 5, b"3:abc".to_vec()).unwrap();
+        assert!(item.verify().is_ok());
+        assert_eq!(item.seq(), Some(5));
+        assert!(Item::mutable(&[0u8; 5], vec![], 0, vec![]).is_none());
+    }
+}
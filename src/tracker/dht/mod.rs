@@ -1,18 +1,27 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::io::{self, Read};
 use std::time;
 use std::fs::OpenOptions;
 use std::path::Path;
+use std::sync::Arc;
 
 use num::bigint::BigUint;
 use amy;
 
-use tracker;
+use tracker::{self, dns};
 use disk;
+use rpc::resource::DhtNode;
+use ipfilter::IpFilter;
+use util::udp_bind_v6;
 use CONFIG;
 
 mod rt;
 mod proto;
+mod item;
+mod secure;
+
+pub use self::item::{Item, Key};
 
 type ID = BigUint;
 
@@ -20,77 +29,164 @@ const BUCKET_MAX: usize = 8;
 const MAX_BUCKETS: usize = 64;
 const VERSION: &'static str = "SY";
 const SESSION_FILE: &'static str = "dht_data";
+const SESSION_FILE6: &'static str = "dht_data6";
 const MIN_BOOTSTRAP_BKTS: usize = 2;
 const TX_TIMEOUT_SECS: i64 = 20;
+/// Maximum number of BEP 44 items stored for other nodes
+const MAX_ITEMS: usize = 1000;
+/// Time after which a stored item expires unless it is put again
+const ITEM_EXPIRY_SECS: i64 = 2 * 60 * 60;
+/// Maximum number of queries answered per second from a single IP
+const MAX_IP_QUERIES: usize = 20;
+/// Maximum number of queries answered per second
+const MAX_QUERIES: usize = 500;
+/// Interval to retry the bootstrap nodes while the table isn't bootstrapped
+const BOOTSTRAP_RETRY_SECS: u64 = 60;
+
+/// Statistics of the DHT routing tables and sockets.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Nodes in each bucket of the IPv4 and IPv6 tables
+    pub buckets: Vec<usize>,
+    pub buckets6: Vec<usize>,
+    pub good: usize,
+    pub questionable: usize,
+    pub bad: usize,
+    pub transactions: usize,
+    /// Peers announced to us for each info hash
+    pub peers: HashMap<[u8; 20], usize>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
 
 pub struct Manager {
-    id: usize,
-    table: rt::RoutingTable,
+    v4: Endpoint,
+    /// IPv6 DHT(BEP 32), if IPv6 is available
+    v6: Option<Endpoint>,
     dht_flush: time::Instant,
-    sock: UdpSocket,
     buf: Vec<u8>,
     db: amy::Sender<disk::Request>,
+    /// Item lookups in progress, keyed by RPC client and serial, with the
+    /// number of tables still searching and the result of those finished
+    lookups: HashMap<(usize, u64), (usize, Option<tracker::Response>)>,
+    /// Queries received from each IP during the current second
+    queries: HashMap<IpAddr, usize>,
+    total_queries: usize,
+    query_window: time::Instant,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Port of each bootstrap node being resolved, keyed by DNS query id
+    resolving: HashMap<usize, u16>,
+    last_bootstrap: time::Instant,
+}
+
+/// A DHT socket and the routing table of its address family.
+struct Endpoint {
+    id: usize,
+    sock: UdpSocket,
+    table: rt::RoutingTable,
+    session: &'static str,
 }
 
 impl Manager {
     pub fn new(reg: &amy::Registrar, db: amy::Sender<disk::Request>) -> io::Result<Manager> {
         let sock = UdpSocket::bind(("0.0.0.0", CONFIG.dht.port))?;
-        sock.set_nonblocking(true)?;
-        let id = reg.register(&sock, amy::Event::Read)?;
-
-        let p = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
-        let mut data = Vec::new();
-        if let Ok(mut f) = OpenOptions::new().read(true).open(&p) {
-            f.read_to_end(&mut data)?;
-        }
-        let table = if let Some(t) = rt::RoutingTable::deserialize(&data[..]) {
-            t
-        } else {
-            info!("DHT table could not be read from disk, creating new table!");
-            let mut t = rt::RoutingTable::new();
-            if let Some(addr) = CONFIG.dht.bootstrap_node {
-                info!("Using bootstrap node!");
-                let (msg, _) = t.add_addr(addr);
-                sock.send_to(&msg.encode(), addr)?;
+        let v4 = Endpoint::new(reg, sock, SESSION_FILE)?;
+        let v6 = match udp_bind_v6(CONFIG.dht.port)
+            .and_then(|sock| Endpoint::new(reg, sock, SESSION_FILE6))
+        {
+            Ok(ep) => Some(ep),
+            Err(e) => {
+                info!("IPv6 DHT unavailable: {}", e);
+                None
             }
-            t
         };
 
         Ok(Manager {
-            table,
-            sock,
-            id,
+            v4,
+            v6,
             db,
-            buf: vec![0u8; 500],
+            buf: vec![0u8; 1500],
             dht_flush: time::Instant::now(),
+            lookups: HashMap::new(),
+            queries: HashMap::new(),
+            total_queries: 0,
+            query_window: time::Instant::now(),
+            bytes_in: 0,
+            bytes_out: 0,
+            resolving: HashMap::new(),
+            last_bootstrap: time::Instant::now(),
         })
     }
 
-    pub fn init(&mut self) {
+    /// Pings the nodes persisted by the last session, falling back
+    /// to the bootstrap nodes if there are none.
+    pub fn init(&mut self, dns: &mut dns::Resolver) {
         debug!("Initializing DHT nodes!");
-        for (q, a) in self.table.init() {
-            self.send_msg(&q.encode(), a);
+        let mut reqs = self.v4.table.init();
+        if let Some(ref mut ep) = self.v6 {
+            reqs.extend(ep.table.init());
+        }
+        if reqs.is_empty() {
+            self.bootstrap(dns);
         }
+        for (q, a) in reqs {
+            self.send_req(q, a);
+        }
+    }
+
+    /// Returns true if id belongs to a bootstrap node DNS query
+    pub fn contains(&self, id: usize) -> bool {
+        self.resolving.contains_key(&id)
     }
 
-    pub fn id(&self) -> usize {
-        self.id
+    pub fn dns_resolved(&mut self, resp: dns::QueryResponse) {
+        if let Some(port) = self.resolving.remove(&resp.id) {
+            match resp.res {
+                Ok(ip) => self.add_addr(SocketAddr::new(ip, port)),
+                Err(e) => debug!("Failed to resolve DHT bootstrap node: {}", e),
+            }
+        }
     }
 
-    pub fn readable(&mut self) -> Vec<tracker::Response> {
+    /// Returns true if id belongs to one of the DHT sockets
+    pub fn has_sock(&self, id: usize) -> bool {
+        self.v4.id == id || self.v6.as_ref().map(|ep| ep.id == id).unwrap_or(false)
+    }
+
+    pub fn readable(&mut self, id: usize) -> Vec<tracker::Response> {
+        let v6 = id != self.v4.id;
         let mut resps = Vec::new();
         loop {
-            match self.sock.recv_from(&mut self.buf[..]) {
+            let res = match (v6, self.v6.as_ref()) {
+                (true, Some(ep)) => ep.sock.recv_from(&mut self.buf[..]),
+                (true, None) => break,
+                (false, _) => self.v4.sock.recv_from(&mut self.buf[..]),
+            };
+            match res {
                 Ok((v, addr)) => {
                     trace!("Processing msg from {}", addr);
+                    self.bytes_in += v as u64;
                     if let Ok(req) = proto::Request::decode(&self.buf[..v]) {
-                        let resp = self.table.handle_req(req, addr).encode();
+                        if !self.allow_query(addr.ip()) {
+                            trace!("Dropping query from {}, rate limit exceeded", addr);
+                            continue;
+                        }
+                        let resp = self.handle_req(req, addr, v6).encode();
                         self.send_msg(&resp, addr);
-                    } else if let Ok(resp) = proto::Response::decode(&self.buf[..v]) {
-                        match self.table.handle_resp(resp, addr) {
-                            Ok(r) => resps.push(r),
+                    } else if let Ok(mut resp) = proto::Response::decode(&self.buf[..v]) {
+                        // Nodes of the other address family can only be
+                        // used by its own table
+                        let other = resp.take_nodes(v6);
+                        self.bootstrap_nodes(other, !v6);
+                        let res = match self.endpoint_mut(v6) {
+                            Some(ep) => ep.table.handle_resp(resp, addr),
+                            None => continue,
+                        };
+                        match res {
+                            Ok(r) => resps.extend(self.lookup_done(r)),
                             Err(q) => for (req, a) in q {
-                                self.send_msg(&req.encode(), a);
+                                self.send_req(req, a);
                             },
                         }
                     } else {
@@ -114,42 +210,349 @@ impl Manager {
     }
 
     pub fn get_peers(&mut self, tid: usize, hash: [u8; 20]) {
-        for (req, a) in self.table.get_peers(tid, hash) {
-            self.send_msg(&req.encode(), a);
+        let mut reqs = self.v4.table.get_peers(tid, hash);
+        if let Some(ref mut ep) = self.v6 {
+            reqs.extend(ep.table.get_peers(tid, hash));
+        }
+        for (req, a) in reqs {
+            self.send_req(req, a);
         }
     }
 
+    /// Pings a node, adding it to the routing table if it responds
     pub fn add_addr(&mut self, addr: SocketAddr) {
-        self.table.add_addr(addr);
+        let req = match self.endpoint_mut(addr.is_ipv6()) {
+            Some(ep) => ep.table.add_addr(addr),
+            None => return,
+        };
+        self.send_req(req.0, req.1);
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            ..Default::default()
+        };
+        stats.buckets = self.v4.table.add_stats(&mut stats);
+        if let Some(ref ep) = self.v6 {
+            stats.buckets6 = ep.table.add_stats(&mut stats);
+        }
+        stats
+    }
+
+    /// Filters the nodes of both routing tables and those found by lookups
+    pub fn set_ip_filter(&mut self, filter: Arc<IpFilter>) {
+        if let Some(ref mut ep) = self.v6 {
+            ep.table.set_ip_filter(filter.clone());
+        }
+        self.v4.table.set_ip_filter(filter);
+    }
+
+    /// Returns the nodes of both routing tables
+    pub fn nodes(&self) -> Vec<DhtNode> {
+        let mut nodes = self.v4.table.nodes();
+        if let Some(ref ep) = self.v6 {
+            nodes.extend(ep.table.nodes());
+        }
+        nodes
     }
 
     pub fn announce(&mut self, hash: [u8; 20]) {
-        for (req, a) in self.table.announce(hash) {
-            self.send_msg(&req.encode(), a);
+        let mut reqs = self.v4.table.announce(hash);
+        if let Some(ref mut ep) = self.v6 {
+            reqs.extend(ep.table.announce(hash));
+        }
+        for (req, a) in reqs {
+            self.send_req(req, a);
+        }
+    }
+
+    /// Looks up a BEP 44 item, the result being returned
+    /// once the lookups of every table have finished.
+    pub fn get(&mut self, client: usize, serial: u64, key: Key) {
+        let mut reqs = self.v4.table.get(client, serial, key.clone());
+        let mut tables = 1;
+        if let Some(ref mut ep) = self.v6 {
+            reqs.extend(ep.table.get(client, serial, key));
+            tables += 1;
+        }
+        self.lookups.insert((client, serial), (tables, None));
+        for (req, a) in reqs {
+            self.send_req(req, a);
+        }
+    }
+
+    /// Stores a BEP 44 item on the closest nodes found
+    pub fn put(&mut self, client: usize, serial: u64, item: Item, cas: Option<i64>) {
+        let mut reqs = self.v4.table.put(client, serial, item.clone(), cas);
+        let mut tables = 1;
+        if let Some(ref mut ep) = self.v6 {
+            reqs.extend(ep.table.put(client, serial, item, cas));
+            tables += 1;
+        }
+        self.lookups.insert((client, serial), (tables, None));
+        for (req, a) in reqs {
+            self.send_req(req, a);
         }
     }
 
-    pub fn tick(&mut self) {
+    pub fn tick(&mut self, dns: &mut dns::Resolver) -> Vec<tracker::Response> {
+        let retry = time::Duration::from_secs(BOOTSTRAP_RETRY_SECS);
+        if !self.v4.table.is_bootstrapped() && self.last_bootstrap.elapsed() > retry {
+            self.bootstrap(dns);
+        }
         if self.dht_flush.elapsed() > time::Duration::from_secs(60) {
-            let data = self.table.serialize();
-            let path = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
-            self.db.send(disk::Request::WriteFile { data, path }).ok();
+            self.v4.flush(&self.db);
+            if let Some(ref ep) = self.v6 {
+                ep.flush(&self.db);
+            }
             self.dht_flush = time::Instant::now();
         }
-        for (req, a) in self.table.tick() {
-            self.send_msg(&req.encode(), a);
+        let mut reqs = self.v4.table.tick();
+        let mut done = self.v4.table.finished_lookups();
+        if let Some(ref mut ep) = self.v6 {
+            reqs.extend(ep.table.tick());
+            done.extend(ep.table.finished_lookups());
+        }
+        for (req, a) in reqs {
+            self.send_req(req, a);
+        }
+        done.into_iter().filter_map(|r| self.lookup_done(r)).collect()
+    }
+
+    /// Merges the result of a table's item lookup with those of
+    /// the other table, returning it once both have finished.
+    fn lookup_done(&mut self, resp: tracker::Response) -> Option<tracker::Response> {
+        let id = match resp {
+            tracker::Response::DHTGet { client, serial, .. }
+            | tracker::Response::DHTPut { client, serial, .. } => (client, serial),
+            r => return Some(r),
+        };
+        let (tables, prev) = match self.lookups.remove(&id) {
+            Some(l) => l,
+            None => return Some(resp),
+        };
+        let merged = match (prev, resp) {
+            (
+                Some(tracker::Response::DHTGet {
+                    client,
+                    serial,
+                    item: a,
+                }),
+                tracker::Response::DHTGet { item: b, .. },
+            ) => {
+                // Keep the most recent item found
+                let item = if b.as_ref().map(|i| i.seq()) > a.as_ref().map(|i| i.seq()) {
+                    b
+                } else {
+                    a
+                };
+                tracker::Response::DHTGet {
+                    client,
+                    serial,
+                    item,
+                }
+            }
+            (
+                Some(tracker::Response::DHTPut {
+                    client,
+                    serial,
+                    target,
+                    nodes: a,
+                }),
+                tracker::Response::DHTPut { nodes: b, .. },
+            ) => tracker::Response::DHTPut {
+                client,
+                serial,
+                target,
+                nodes: a + b,
+            },
+            (_, resp) => resp,
+        };
+        if tables > 1 {
+            self.lookups.insert(id, (tables - 1, Some(merged)));
+            None
+        } else {
+            Some(merged)
+        }
+    }
+
+    /// Handles a request received over the given address family,
+    /// adding or removing nodes according to its want field(BEP 32).
+    fn handle_req(&mut self, req: proto::Request, addr: SocketAddr, v6: bool) -> proto::Response {
+        let query = req.node_query();
+        let mut resp = match self.endpoint_mut(v6) {
+            Some(ep) => ep.table.handle_req(req, addr),
+            None => unreachable!(),
+        };
+        if let Some((want, target)) = query {
+            let (own, other) = if v6 {
+                (want.v6, want.v4)
+            } else {
+                (want.v4, want.v6)
+            };
+            if other && !own {
+                resp.take_nodes(!v6);
+            }
+            if other {
+                if let Some(ep) = self.endpoint(!v6) {
+                    resp.add_nodes(ep.table.closest_nodes(&target));
+                }
+            }
+        }
+        // Let the node learn its external IP(BEP 42)
+        resp.ip = Some(addr);
+        resp
+    }
+
+    /// Counts a query from the IP, returning false if too many queries
+    /// were received from it or from all nodes during the current second.
+    fn allow_query(&mut self, ip: IpAddr) -> bool {
+        if self.query_window.elapsed() >= time::Duration::from_secs(1) {
+            self.queries.clear();
+            self.total_queries = 0;
+            self.query_window = time::Instant::now();
         }
+        if self.total_queries >= MAX_QUERIES {
+            return false;
+        }
+        let count = self.queries.entry(ip).or_insert(0);
+        if *count >= MAX_IP_QUERIES {
+            return false;
+        }
+        *count += 1;
+        self.total_queries += 1;
+        true
+    }
+
+    /// Pings the configured bootstrap nodes, resolving their hostnames.
+    fn bootstrap(&mut self, dns: &mut dns::Resolver) {
+        info!("Bootstrapping DHT");
+        self.last_bootstrap = time::Instant::now();
+        self.resolving.clear();
+        for (i, node) in CONFIG.dht.bootstrap_nodes.iter().enumerate() {
+            if let Ok(addr) = node.parse() {
+                self.add_addr(addr);
+                continue;
+            }
+            let host = node.rfind(':').and_then(|p| {
+                node[p + 1..].parse::<u16>().ok().map(|port| (&node[..p], port))
+            });
+            match host {
+                Some((host, port)) => {
+                    // Query ids are taken from the top of the range to
+                    // avoid those of tracker connections
+                    let id = usize::max_value() - i;
+                    self.resolving.insert(id, port);
+                    dns.new_query(id, host);
+                }
+                None => warn!("Invalid DHT bootstrap node {}", node),
+            }
+        }
+    }
+
+    /// Pings nodes of the given address family if its table has yet
+    /// to be bootstrapped.
+    fn bootstrap_nodes(&mut self, nodes: Vec<proto::Node>, v6: bool) {
+        let mut reqs = Vec::new();
+        if let Some(ep) = self.endpoint_mut(v6) {
+            if !ep.table.is_bootstrapped() {
+                for node in nodes {
+                    reqs.push(ep.table.add_addr(node.addr));
+                }
+            }
+        }
+        for (req, a) in reqs {
+            self.send_req(req, a);
+        }
+    }
+
+    fn endpoint(&self, v6: bool) -> Option<&Endpoint> {
+        if v6 {
+            self.v6.as_ref()
+        } else {
+            Some(&self.v4)
+        }
+    }
+
+    fn endpoint_mut(&mut self, v6: bool) -> Option<&mut Endpoint> {
+        if v6 {
+            self.v6.as_mut()
+        } else {
+            Some(&mut self.v4)
+        }
+    }
+
+    /// Sends a request, asking for nodes of both address families
+    /// while the table of the other family is being bootstrapped.
+    fn send_req(&mut self, mut req: proto::Request, addr: SocketAddr) {
+        let bootstrapping = self.endpoint(!addr.is_ipv6())
+            .map(|ep| !ep.table.is_bootstrapped())
+            .unwrap_or(false);
+        if bootstrapping {
+            req.set_want(proto::Want { v4: true, v6: true });
+        }
+        self.send_msg(&req.encode(), addr);
     }
 
     fn send_msg(&mut self, msg: &[u8], addr: SocketAddr) {
-        // Cap tries to avoid burning CPU
-        for _ in 0..25 {
-            if let Err(e) = self.sock.send_to(msg, addr) {
-                if e.raw_os_error().map(|c| c != 11).unwrap_or(true) {
-                    error!("Failed to send message on UDP socket: {:?}", e);
-                    break;
+        let sent = {
+            let sock = match self.endpoint(addr.is_ipv6()) {
+                Some(ep) => &ep.sock,
+                None => return,
+            };
+            // Cap tries to avoid burning CPU
+            let mut sent = false;
+            for _ in 0..25 {
+                match sock.send_to(msg, addr) {
+                    Ok(_) => {
+                        sent = true;
+                        break;
+                    }
+                    Err(ref e) if e.raw_os_error().map(|c| c != 11).unwrap_or(true) => {
+                        error!("Failed to send message on UDP socket: {:?}", e);
+                        break;
+                    }
+                    Err(_) => {}
                 }
             }
+            sent
+        };
+        if sent {
+            self.bytes_out += msg.len() as u64;
+        }
+    }
+}
+
+impl Endpoint {
+    fn new(reg: &amy::Registrar, sock: UdpSocket, session: &'static str) -> io::Result<Endpoint> {
+        sock.set_nonblocking(true)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+
+        let p = Path::new(&CONFIG.disk.session[..]).join(session);
+        let mut data = Vec::new();
+        if let Ok(mut f) = OpenOptions::new().read(true).open(&p) {
+            f.read_to_end(&mut data)?;
         }
+        let table = if let Some(t) = rt::RoutingTable::deserialize(&data[..]) {
+            t
+        } else {
+            info!("DHT table could not be read from disk, creating new table!");
+            rt::RoutingTable::new()
+        };
+
+        Ok(Endpoint {
+            id,
+            sock,
+            table,
+            session,
+        })
+    }
+
+    fn flush(&self, db: &amy::Sender<disk::Request>) {
+        let data = self.table.serialize();
+        let path = Path::new(&CONFIG.disk.session[..]).join(self.session);
+        db.send(disk::Request::WriteFile { data, path }).ok();
     }
 }
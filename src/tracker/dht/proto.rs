@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use super::{ID, VERSION};
+use super::item::Item;
 use bencode::{self, BEncode};
 use num::bigint::BigUint;
 use util::{addr_to_bytes, bytes_to_addr};
@@ -29,6 +30,31 @@ error_chain! {
                 display("method unknown: {}", r)
         }
 
+        ValueTooBig(r: String) {
+            description("value too big")
+                display("value too big: {}", r)
+        }
+
+        InvalidSignature(r: String) {
+            description("invalid signature")
+                display("invalid signature: {}", r)
+        }
+
+        SaltTooBig(r: String) {
+            description("salt too big")
+                display("salt too big: {}", r)
+        }
+
+        CasMismatch(r: String) {
+            description("cas mismatch")
+                display("cas mismatch: {}", r)
+        }
+
+        SeqTooLow(r: String) {
+            description("sequence number too low")
+                display("sequence number too low: {}", r)
+        }
+
         InvalidResponse(r: &'static str) {
             description("invalid response")
                 display("invalid response: {}", r)
@@ -54,10 +80,12 @@ pub enum RequestKind {
     FindNode {
         id: ID,
         target: ID,
+        want: Want,
     },
     GetPeers {
         id: ID,
         hash: [u8; 20],
+        want: Want,
     },
     AnnouncePeer {
         id: ID,
@@ -66,11 +94,38 @@ pub enum RequestKind {
         port: u16,
         implied_port: bool,
     },
+    /// BEP 44 item lookup, seq being the sequence number of
+    /// a mutable item which the querying node already has
+    Get {
+        id: ID,
+        target: [u8; 20],
+        seq: Option<i64>,
+        want: Want,
+    },
+    /// BEP 44 item storage, cas being the expected sequence
+    /// number of the mutable item currently stored
+    Put {
+        id: ID,
+        token: Vec<u8>,
+        item: Item,
+        cas: Option<i64>,
+    },
+}
+
+/// Address families of the nodes requested by a find_node or
+/// get_peers query(BEP 32). If neither is set, nodes of the
+/// querying node's address family are returned.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Want {
+    pub v4: bool,
+    pub v6: bool,
 }
 
 #[derive(Debug)]
 pub struct Response {
     pub transaction: Vec<u8>,
+    /// Address of the querying node as seen by the responding node(BEP 42)
+    pub ip: Option<SocketAddr>,
     pub kind: ResponseKind,
 }
 
@@ -87,6 +142,12 @@ pub enum ResponseKind {
         values: Vec<SocketAddr>,
         nodes: Vec<Node>,
     },
+    Get {
+        id: ID,
+        token: Vec<u8>,
+        nodes: Vec<Node>,
+        item: Option<Item>,
+    },
     Error(ErrorKind),
 }
 
@@ -109,7 +170,11 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
-            kind: RequestKind::FindNode { id, target },
+            kind: RequestKind::FindNode {
+                id,
+                target,
+                want: Want::default(),
+            },
         }
     }
 
@@ -117,7 +182,11 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
-            kind: RequestKind::GetPeers { id, hash },
+            kind: RequestKind::GetPeers {
+                id,
+                hash,
+                want: Want::default(),
+            },
         }
     }
 
@@ -135,6 +204,59 @@ impl Request {
         }
     }
 
+    pub fn get(transaction: Vec<u8>, id: ID, target: [u8; 20], seq: Option<i64>) -> Self {
+        Request {
+            transaction,
+            version: Some(VERSION.to_owned()),
+            kind: RequestKind::Get {
+                id,
+                target,
+                seq,
+                want: Want::default(),
+            },
+        }
+    }
+
+    pub fn put(transaction: Vec<u8>, id: ID, token: Vec<u8>, item: Item, cas: Option<i64>) -> Self {
+        Request {
+            transaction,
+            version: Some(VERSION.to_owned()),
+            kind: RequestKind::Put {
+                id,
+                token,
+                item,
+                cas,
+            },
+        }
+    }
+
+    /// Returns the requested address families and target id
+    /// of queries which return nodes.
+    pub fn node_query(&self) -> Option<(Want, ID)> {
+        match self.kind {
+            RequestKind::FindNode {
+                ref target, want, ..
+            } => Some((want, target.clone())),
+            RequestKind::GetPeers { ref hash, want, .. }
+            | RequestKind::Get {
+                target: ref hash,
+                want,
+                ..
+            } => Some((want, BigUint::from_bytes_be(&hash[..]))),
+            _ => None,
+        }
+    }
+
+    /// Sets the address families of nodes requested by the query
+    pub fn set_want(&mut self, w: Want) {
+        match self.kind {
+            RequestKind::FindNode { ref mut want, .. }
+            | RequestKind::GetPeers { ref mut want, .. }
+            | RequestKind::Get { ref mut want, .. } => *want = w,
+            _ => {}
+        }
+    }
+
     pub fn encode(self) -> Vec<u8> {
         let mut b = BTreeMap::new();
         b.insert(String::from("t"), BEncode::String(self.transaction));
@@ -151,7 +273,7 @@ impl Request {
 
                 b.insert(String::from("a"), BEncode::Dict(args));
             }
-            RequestKind::FindNode { id, target } => {
+            RequestKind::FindNode { id, target, want } => {
                 b.insert(String::from("q"), BEncode::from_str("find_node"));
 
                 let mut args = BTreeMap::new();
@@ -160,16 +282,18 @@ impl Request {
                     String::from("target"),
                     BEncode::String(target.to_bytes_be()),
                 );
+                want.encode(&mut args);
 
                 b.insert(String::from("a"), BEncode::Dict(args));
             }
-            RequestKind::GetPeers { id, hash } => {
+            RequestKind::GetPeers { id, hash, want } => {
                 b.insert(String::from("q"), BEncode::from_str("get_peers"));
 
                 let mut args = BTreeMap::new();
                 args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
                 let ib = Vec::from(&hash[..]);
                 args.insert(String::from("info_hash"), BEncode::String(ib));
+                want.encode(&mut args);
 
                 b.insert(String::from("a"), BEncode::Dict(args));
             }
@@ -193,6 +317,42 @@ impl Request {
                 args.insert(String::from("port"), BEncode::Int(port as i64));
                 args.insert(String::from("token"), BEncode::String(token));
 
+                b.insert(String::from("a"), BEncode::Dict(args));
+            }
+            RequestKind::Get {
+                id,
+                target,
+                seq,
+                want,
+            } => {
+                b.insert(String::from("q"), BEncode::from_str("get"));
+
+                let mut args = BTreeMap::new();
+                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                args.insert(String::from("target"), BEncode::String(target.to_vec()));
+                if let Some(seq) = seq {
+                    args.insert(String::from("seq"), BEncode::Int(seq));
+                }
+                want.encode(&mut args);
+
+                b.insert(String::from("a"), BEncode::Dict(args));
+            }
+            RequestKind::Put {
+                id,
+                token,
+                item,
+                cas,
+            } => {
+                b.insert(String::from("q"), BEncode::from_str("put"));
+
+                let mut args = BTreeMap::new();
+                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                args.insert(String::from("token"), BEncode::String(token));
+                if let Some(cas) = cas {
+                    args.insert(String::from("cas"), BEncode::Int(cas));
+                }
+                encode_item(&mut args, item, true);
+
                 b.insert(String::from("a"), BEncode::Dict(args));
             }
         }
@@ -236,6 +396,7 @@ impl Request {
                     "Invalid BEncoded data(ping must have id field)",
                 ))
             })?;
+        let want = Want::decode(&mut a);
         let kind = match &q[..] {
             "ping" => RequestKind::Ping(id),
             "find_node" => {
@@ -247,7 +408,7 @@ impl Request {
                             "Invalid BEncoded data(find_node must have target field)",
                         ))
                     })?;
-                RequestKind::FindNode { id, target }
+                RequestKind::FindNode { id, target, want }
             }
             "get_peers" => {
                 let mut hash = [0u8; 20];
@@ -265,7 +426,7 @@ impl Request {
                             "Invalid BEncoded data(get_peers must have hash field)",
                         ))
                     })?;
-                RequestKind::GetPeers { id, hash }
+                RequestKind::GetPeers { id, hash, want }
             }
             "announce_peer" => {
                 let mut hash = [0u8; 20];
@@ -316,6 +477,51 @@ impl Request {
                     token,
                 }
             }
+            "get" => {
+                let mut target = [0u8; 20];
+                a.remove("target")
+                    .and_then(|b| b.into_bytes())
+                    .and_then(|b| {
+                        if b.len() != 20 {
+                            return None;
+                        }
+                        target.copy_from_slice(&b[..]);
+                        Some(())
+                    })
+                    .ok_or_else(|| {
+                        Error::from(ErrorKind::InvalidRequest(
+                            "Invalid BEncoded data(get must have target field)",
+                        ))
+                    })?;
+                let seq = a.remove("seq").and_then(|b| b.into_int());
+                RequestKind::Get {
+                    id,
+                    target,
+                    seq,
+                    want,
+                }
+            }
+            "put" => {
+                let token = a.remove("token")
+                    .and_then(|b| b.into_bytes())
+                    .ok_or_else(|| {
+                        Error::from(ErrorKind::InvalidRequest(
+                            "Invalid BEncoded data(put must have token field)",
+                        ))
+                    })?;
+                let cas = a.remove("cas").and_then(|b| b.into_int());
+                let item = decode_item(&mut a).ok_or_else(|| {
+                    Error::from(ErrorKind::InvalidRequest(
+                        "Invalid BEncoded data(put must have a valid item)",
+                    ))
+                })?;
+                RequestKind::Put {
+                    id,
+                    token,
+                    item,
+                    cas,
+                }
+            }
             _ => {
                 return Err(ErrorKind::InvalidRequest(
                     "Invalid BEncoded data(request must be a valid query type)",
@@ -334,6 +540,7 @@ impl Response {
     pub fn id(transaction: Vec<u8>, id: ID) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::ID(id),
         }
     }
@@ -341,6 +548,7 @@ impl Response {
     pub fn find_node(transaction: Vec<u8>, id: ID, nodes: Vec<Node>) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::FindNode { id, nodes },
         }
     }
@@ -348,6 +556,7 @@ impl Response {
     pub fn peers(transaction: Vec<u8>, id: ID, token: Vec<u8>, nodes: Vec<SocketAddr>) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::GetPeers {
                 id,
                 token,
@@ -360,6 +569,7 @@ impl Response {
     pub fn nodes(transaction: Vec<u8>, id: ID, token: Vec<u8>, nodes: Vec<Node>) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::GetPeers {
                 id,
                 token,
@@ -369,9 +579,29 @@ impl Response {
         }
     }
 
+    pub fn item(
+        transaction: Vec<u8>,
+        id: ID,
+        token: Vec<u8>,
+        nodes: Vec<Node>,
+        item: Option<Item>,
+    ) -> Self {
+        Response {
+            transaction,
+            ip: None,
+            kind: ResponseKind::Get {
+                id,
+                token,
+                nodes,
+                item,
+            },
+        }
+    }
+
     pub fn error(transaction: Vec<u8>, error: ErrorKind) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::Error(error),
         }
     }
@@ -380,17 +610,16 @@ impl Response {
         let mut b = BTreeMap::new();
         let is_err = self.is_err();
         b.insert(String::from("t"), BEncode::String(self.transaction));
+        if let Some(ip) = self.ip {
+            b.insert(String::from("ip"), BEncode::String(addr_to_bytes(&ip)));
+        }
         let mut args = BTreeMap::new();
         match self.kind {
             ResponseKind::ID(id) => {
                 args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
             }
             ResponseKind::FindNode { id, nodes } => {
-                let mut data = Vec::new();
-                for node in nodes {
-                    data.extend(node.to_bytes())
-                }
-                args.insert(String::from("nodes"), BEncode::String(data));
+                encode_nodes(&mut args, nodes);
                 args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
             }
             ResponseKind::GetPeers {
@@ -406,12 +635,20 @@ impl Response {
                     values_b.push(BEncode::String(addr_to_bytes(&addr).to_vec()));
                 }
                 args.insert(String::from("values"), BEncode::List(values_b));
-
-                let mut nodes_b = Vec::new();
-                for node in nodes {
-                    nodes_b.extend(node.to_bytes())
+                encode_nodes(&mut args, nodes);
+            }
+            ResponseKind::Get {
+                id,
+                token,
+                nodes,
+                item,
+            } => {
+                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                args.insert(String::from("token"), BEncode::String(token));
+                encode_nodes(&mut args, nodes);
+                if let Some(item) = item {
+                    encode_item(&mut args, item, false);
                 }
-                args.insert(String::from("nodes"), BEncode::String(nodes_b));
             }
             ResponseKind::Error(e) => {
                 let mut err = Vec::new();
@@ -432,6 +669,26 @@ impl Response {
                         err.push(BEncode::from_int(204));
                         err.push(BEncode::from_str(&msg));
                     }
+                    ErrorKind::ValueTooBig(msg) => {
+                        err.push(BEncode::from_int(205));
+                        err.push(BEncode::from_str(&msg));
+                    }
+                    ErrorKind::InvalidSignature(msg) => {
+                        err.push(BEncode::from_int(206));
+                        err.push(BEncode::from_str(&msg));
+                    }
+                    ErrorKind::SaltTooBig(msg) => {
+                        err.push(BEncode::from_int(207));
+                        err.push(BEncode::from_str(&msg));
+                    }
+                    ErrorKind::CasMismatch(msg) => {
+                        err.push(BEncode::from_int(301));
+                        err.push(BEncode::from_str(&msg));
+                    }
+                    ErrorKind::SeqTooLow(msg) => {
+                        err.push(BEncode::from_int(302));
+                        err.push(BEncode::from_str(&msg));
+                    }
                     _ => unreachable!(),
                 }
                 b.insert(String::from("e"), BEncode::List(err));
@@ -464,6 +721,10 @@ impl Response {
                 "Invalid BEncoded data(dict must have y field)",
             ))
         })?;
+        let ip = match d.remove("ip").and_then(|b| b.into_bytes()) {
+            Some(ref data) if data.len() == 6 || data.len() == 18 => Some(bytes_to_addr(data)),
+            _ => None,
+        };
         match &y[..] {
             "e" => {
                 let mut e = d.remove("e").and_then(|b| b.into_list()).ok_or_else(|| {
@@ -491,6 +752,11 @@ impl Response {
                     202 => ErrorKind::Server(msg),
                     203 => ErrorKind::Protocol(msg),
                     204 => ErrorKind::MethodUnknown(msg),
+                    205 => ErrorKind::ValueTooBig(msg),
+                    206 => ErrorKind::InvalidSignature(msg),
+                    207 => ErrorKind::SaltTooBig(msg),
+                    301 => ErrorKind::CasMismatch(msg),
+                    302 => ErrorKind::SeqTooLow(msg),
                     _ => {
                         return Err(ErrorKind::InvalidResponse(
                             "Invalid BEncoded data(invalid error code)",
@@ -499,6 +765,7 @@ impl Response {
                 };
                 Ok(Response {
                     transaction,
+                    ip,
                     kind: ResponseKind::Error(err),
                 })
             }
@@ -518,39 +785,47 @@ impl Response {
                         ))
                     })?;
 
-                let kind = if let Some(token) = r.remove("token").and_then(|b| b.into_bytes()) {
+                let kind = if r.contains_key("v") {
+                    let token = r.remove("token")
+                        .and_then(|b| b.into_bytes())
+                        .unwrap_or_else(Vec::new);
+                    let item = decode_item(&mut r);
+                    let nodes = decode_nodes(&mut r);
+                    ResponseKind::Get {
+                        id,
+                        token,
+                        nodes,
+                        item,
+                    }
+                } else if let Some(token) = r.remove("token").and_then(|b| b.into_bytes()) {
                     let mut values = Vec::new();
                     if let Some(addrs) = r.remove("values").and_then(|b| b.into_list()) {
                         for addr in addrs {
                             if let Some(data) = addr.into_bytes() {
-                                if data.len() == 6 {
+                                if data.len() == 6 || data.len() == 18 {
                                     values.push(bytes_to_addr(&data));
                                 }
                             }
                         }
                     }
-                    let mut nodes = Vec::new();
-                    if let Some(ns) = r.remove("nodes").and_then(|b| b.into_bytes()) {
-                        for n in ns.chunks(26) {
-                            nodes.push(Node::new(n));
-                        }
-                    }
+                    let nodes = decode_nodes(&mut r);
                     ResponseKind::GetPeers {
                         id,
                         token,
                         nodes,
                         values,
                     }
-                } else if let Some(ns) = r.remove("nodes").and_then(|b| b.into_bytes()) {
-                    let mut nodes = Vec::new();
-                    for n in ns.chunks(26) {
-                        nodes.push(Node::new(n));
-                    }
+                } else if r.contains_key("nodes") || r.contains_key("nodes6") {
+                    let nodes = decode_nodes(&mut r);
                     ResponseKind::FindNode { id, nodes }
                 } else {
                     ResponseKind::ID(id)
                 };
-                Ok(Response { transaction, kind })
+                Ok(Response {
+                    transaction,
+                    ip,
+                    kind,
+                })
             }
             _ => {
                 Err(ErrorKind::InvalidResponse("Invalid BEncoded data(y field must be e/r)").into())
@@ -558,6 +833,31 @@ impl Response {
         }
     }
 
+    /// Adds nodes to a response which returns nodes
+    pub fn add_nodes(&mut self, mut n: Vec<Node>) {
+        match self.kind {
+            ResponseKind::FindNode { ref mut nodes, .. }
+            | ResponseKind::GetPeers { ref mut nodes, .. }
+            | ResponseKind::Get { ref mut nodes, .. } => nodes.append(&mut n),
+            _ => {}
+        }
+    }
+
+    /// Removes and returns the nodes of a response which
+    /// don't match the given address family.
+    pub fn take_nodes(&mut self, v6: bool) -> Vec<Node> {
+        match self.kind {
+            ResponseKind::FindNode { ref mut nodes, .. }
+            | ResponseKind::GetPeers { ref mut nodes, .. }
+            | ResponseKind::Get { ref mut nodes, .. } => {
+                let (keep, other) = nodes.drain(..).partition(|n| n.addr.is_ipv6() == v6);
+                *nodes = keep;
+                other
+            }
+            _ => vec![],
+        }
+    }
+
     fn is_err(&self) -> bool {
         match self.kind {
             ResponseKind::Error(_) => true,
@@ -566,7 +866,124 @@ impl Response {
     }
 }
 
+impl Want {
+    fn encode(&self, args: &mut BTreeMap<String, BEncode>) {
+        let mut want = Vec::new();
+        if self.v4 {
+            want.push(BEncode::from_str("n4"));
+        }
+        if self.v6 {
+            want.push(BEncode::from_str("n6"));
+        }
+        if !want.is_empty() {
+            args.insert(String::from("want"), BEncode::List(want));
+        }
+    }
+
+    fn decode(args: &mut BTreeMap<String, BEncode>) -> Want {
+        let mut want = Want::default();
+        for w in args.remove("want")
+            .and_then(|b| b.into_list())
+            .unwrap_or_else(Vec::new)
+        {
+            match w.into_string().as_ref().map(|s| s.as_str()) {
+                Some("n4") => want.v4 = true,
+                Some("n6") => want.v6 = true,
+                _ => {}
+            }
+        }
+        want
+    }
+}
+
+/// Encodes IPv4 nodes into the nodes key, and IPv6 nodes into the nodes6 key
+fn encode_nodes(args: &mut BTreeMap<String, BEncode>, nodes: Vec<Node>) {
+    let mut nodes_b = Vec::new();
+    let mut nodes6_b = Vec::new();
+    for node in nodes {
+        if node.addr.is_ipv4() {
+            nodes_b.extend(node.to_bytes())
+        } else {
+            nodes6_b.extend(node.to_bytes())
+        }
+    }
+    args.insert(String::from("nodes"), BEncode::String(nodes_b));
+    if !nodes6_b.is_empty() {
+        args.insert(String::from("nodes6"), BEncode::String(nodes6_b));
+    }
+}
+
+fn decode_nodes(args: &mut BTreeMap<String, BEncode>) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    if let Some(ns) = args.remove("nodes").and_then(|b| b.into_bytes()) {
+        for n in ns.chunks(26).filter(|n| n.len() == 26) {
+            nodes.push(Node::new(n));
+        }
+    }
+    if let Some(ns) = args.remove("nodes6").and_then(|b| b.into_bytes()) {
+        for n in ns.chunks(38).filter(|n| n.len() == 38) {
+            nodes.push(Node::new(n));
+        }
+    }
+    nodes
+}
+
+/// Encodes an item's fields, the salt of mutable items
+/// only being sent in put queries.
+fn encode_item(args: &mut BTreeMap<String, BEncode>, item: Item, with_salt: bool) {
+    match item {
+        Item::Immutable { v } => {
+            if let Ok(v) = bencode::decode_buf(&v) {
+                args.insert(String::from("v"), v);
+            }
+        }
+        Item::Mutable {
+            k,
+            salt,
+            seq,
+            sig,
+            v,
+        } => {
+            if let Ok(v) = bencode::decode_buf(&v) {
+                args.insert(String::from("v"), v);
+            }
+            args.insert(String::from("k"), BEncode::String(k));
+            args.insert(String::from("seq"), BEncode::Int(seq));
+            args.insert(String::from("sig"), BEncode::String(sig));
+            if with_salt && !salt.is_empty() {
+                args.insert(String::from("salt"), BEncode::String(salt));
+            }
+        }
+    }
+}
+
+/// Decodes an item, which is mutable if it has a public key
+fn decode_item(args: &mut BTreeMap<String, BEncode>) -> Option<Item> {
+    let v = args.remove("v")?.encode_to_buf();
+    let k = match args.remove("k").and_then(|b| b.into_bytes()) {
+        Some(k) => k,
+        None => return Some(Item::Immutable { v }),
+    };
+    let seq = args.remove("seq").and_then(|b| b.into_int())?;
+    let sig = args.remove("sig").and_then(|b| b.into_bytes())?;
+    let salt = args.remove("salt")
+        .and_then(|b| b.into_bytes())
+        .unwrap_or_else(Vec::new);
+    if k.len() != 32 || sig.len() != 64 {
+        return None;
+    }
+    Some(Item::Mutable {
+        k,
+        salt,
+        seq,
+        sig,
+        v,
+    })
+}
+
 impl Node {
+    /// Parses a compact node, which is 26 bytes for IPv4
+    /// or 38 bytes for IPv6.
     pub fn new(data: &[u8]) -> Node {
         let id = BigUint::from_bytes_be(&data[0..20]);
         Node {
@@ -584,7 +1001,8 @@ impl Node {
 
 #[cfg(test)]
 mod tests {
-    use super::{Request, Response, ResponseKind};
+    use super::{Node, Request, RequestKind, Response, ResponseKind, Want};
+    use super::super::item::Item;
     use num::bigint::BigUint;
 
     #[test]
@@ -637,4 +1055,81 @@ mod tests {
             String::from_utf8(r).unwrap()
         );
     }
+
+    #[test]
+    fn test_encode_decode_bep32() {
+        let id = BigUint::from_bytes_be(b"abcdefghij0123456789");
+        let mut req = Request::find_node(b"aa".to_vec(), id.clone(), id.clone());
+        req.set_want(Want { v4: true, v6: true });
+        let d = Request::decode(&req.encode()).unwrap();
+        assert_eq!(d.node_query().unwrap(), (Want { v4: true, v6: true }, id.clone()));
+
+        let n4 = Node {
+            id: id.clone(),
+            addr: "1.2.3.4:6881".parse().unwrap(),
+        };
+        let n6 = Node {
+            id: id.clone(),
+            addr: "[2001:db8::1]:6881".parse().unwrap(),
+        };
+        let resp = Response::find_node(b"aa".to_vec(), id.clone(), vec![n4, n6]);
+        let mut d = Response::decode(&resp.encode()).unwrap();
+        let other = d.take_nodes(false);
+        assert_eq!(other.len(), 1);
+        assert!(other[0].addr.is_ipv6());
+        match d.kind {
+            ResponseKind::FindNode { nodes, .. } => {
+                assert_eq!(nodes.len(), 1);
+                assert!(nodes[0].addr.is_ipv4());
+            }
+            _ => panic!("Should decode to FindNode!"),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_bep44() {
+        let id = BigUint::from_bytes_be(b"abcdefghij0123456789");
+        let item = Item::Mutable {
+            k: vec![1u8; 32],
+            salt: b"foobar".to_vec(),
+            seq: 4,
+            sig: vec![2u8; 64],
+            v: b"12:Hello World!".to_vec(),
+        };
+        let req = Request::put(b"aa".to_vec(), id.clone(), b"tok".to_vec(), item.clone(), None);
+        match Request::decode(&req.encode()).unwrap().kind {
+            RequestKind::Put { item: ref i, .. } => assert_eq!(i, &item),
+            _ => panic!("Should decode to Put!"),
+        }
+
+        let req = Request::get(b"aa".to_vec(), id.clone(), [3u8; 20], Some(2));
+        let d = Request::decode(&req.encode()).unwrap();
+        assert_eq!(d.node_query().unwrap().1, BigUint::from_bytes_be(&[3u8; 20]));
+
+        let resp = Response::item(b"aa".to_vec(), id.clone(), b"tok".to_vec(), vec![], Some(item));
+        match Response::decode(&resp.encode()).unwrap().kind {
+            ResponseKind::Get {
+                item: Some(Item::Mutable { salt, seq, .. }),
+                ..
+            } => {
+                // The salt is only sent in put queries
+                assert!(salt.is_empty());
+                assert_eq!(seq, 4);
+            }
+            _ => panic!("Should decode to Get!"),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_ip() {
+        let id = BigUint::from_bytes_be(b"abcdefghij0123456789");
+        let mut resp = Response::id(b"aa".to_vec(), id.clone());
+        assert!(Response::decode(&resp.encode()).unwrap().ip.is_none());
+        for addr in &["124.31.75.21:6881", "[2001:db8::1]:6881"] {
+            resp = Response::id(b"aa".to_vec(), id.clone());
+            resp.ip = Some(addr.parse().unwrap());
+            let d = Response::decode(&resp.encode()).unwrap();
+            assert_eq!(d.ip, Some(addr.parse().unwrap()));
+        }
+    }
 }
@@ -1,14 +1,24 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::{cmp, mem};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use chrono::{DateTime, Utc};
 use num::bigint::BigUint;
 use rand::{self, Rng};
 use super::{proto, BUCKET_MAX, ID, MAX_BUCKETS, MIN_BOOTSTRAP_BKTS, TX_TIMEOUT_SECS};
+use super::{Stats, ITEM_EXPIRY_SECS, MAX_ITEMS};
+use super::item::{Item, Key};
+use super::secure;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rpc::resource::{DhtNode, DhtNodeState};
+use ipfilter::IpFilter;
 use {bincode, tracker};
 
 const MAX_SEARCH_DEPTH: u8 = 5;
+/// Number of nodes whose reports of our external IP are compared
+const IP_VOTES: usize = 10;
+/// Maximum number of nodes in a bucket sharing an IP
+const MAX_BUCKET_IP_NODES: usize = 1;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RoutingTable {
@@ -21,6 +31,18 @@ pub struct RoutingTable {
     transactions: HashMap<u32, Transaction>,
     torrents: HashMap<[u8; 20], Torrent>,
     bootstrapping: bool,
+    /// BEP 44 items stored for other nodes
+    #[serde(skip)]
+    items: HashMap<[u8; 20], StoredItem>,
+    /// BEP 44 item lookups in progress
+    #[serde(skip)]
+    lookups: HashMap<u32, Lookup>,
+    /// External IP reported by each responding node
+    #[serde(skip)]
+    ip_votes: HashMap<IpAddr, IpAddr>,
+    /// Blocked addresses, which are never added or queried
+    #[serde(skip)]
+    filter: Arc<IpFilter>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -39,6 +61,15 @@ enum TransactionKind {
         hash: [u8; 20],
         depth: u8,
     },
+    ItemGet {
+        id: ID,
+        lookup: u32,
+        depth: u8,
+    },
+    ItemPut {
+        id: ID,
+        lookup: u32,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -46,6 +77,33 @@ struct Torrent {
     peers: Vec<SocketAddr>,
 }
 
+#[derive(Clone, Debug)]
+struct StoredItem {
+    item: Item,
+    stored: DateTime<Utc>,
+}
+
+/// A BEP 44 item lookup, which stores an item on the nodes found if it's a put
+#[derive(Clone, Debug)]
+struct Lookup {
+    client: usize,
+    serial: u64,
+    key: Key,
+    /// Item being put and the sequence number expected to be replaced
+    put: Option<(Item, Option<i64>)>,
+    /// Most recent item found
+    item: Option<Item>,
+    /// Number of nodes the item was stored on
+    stored: usize,
+    /// Nodes which answered a put's lookup, with their write tokens
+    responders: Vec<(ID, SocketAddr, Vec<u8>)>,
+    /// Whether the lookup is done and the item is being put
+    putting: bool,
+    queried: HashSet<SocketAddr>,
+    pending: usize,
+    updated: DateTime<Utc>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Bucket {
     start: ID,
@@ -90,11 +148,35 @@ impl RoutingTable {
             transactions: HashMap::new(),
             torrents: HashMap::new(),
             bootstrapping: true,
+            items: HashMap::new(),
+            lookups: HashMap::new(),
+            ip_votes: HashMap::new(),
+            filter: Arc::new(IpFilter::new()),
         }
     }
 
+    /// Uses a new IP filter, removing the nodes it blocks
+    pub fn set_ip_filter(&mut self, filter: Arc<IpFilter>) {
+        for bucket in &mut self.buckets {
+            bucket.nodes.retain(|n| !filter.blocked(&n.addr.ip()));
+        }
+        self.filter = filter;
+    }
+
+    /// Pings the nodes which were good when the table was persisted, which
+    /// then bootstrap the emptied table as they respond.
     pub fn init(&mut self) -> Vec<(proto::Request, SocketAddr)> {
-        self.refresh_tokens()
+        let buckets = mem::replace(
+            &mut self.buckets,
+            vec![Bucket::new(BigUint::from(0u8), id_from_pow(160))],
+        );
+        self.bootstrapping = true;
+        buckets
+            .into_iter()
+            .flat_map(|b| b.nodes)
+            .filter(|n| n.good())
+            .map(|n| self.add_addr(n.addr))
+            .collect()
     }
 
     pub fn deserialize(data: &[u8]) -> Option<RoutingTable> {
@@ -154,6 +236,42 @@ impl RoutingTable {
         reqs
     }
 
+    /// Looks up a BEP 44 item on behalf of an RPC client
+    pub fn get(
+        &mut self,
+        client: usize,
+        serial: u64,
+        key: Key,
+    ) -> Vec<(proto::Request, SocketAddr)> {
+        self.start_lookup(Lookup::new(client, serial, key, None))
+    }
+
+    /// Stores a BEP 44 item on the nodes closest to its target
+    pub fn put(
+        &mut self,
+        client: usize,
+        serial: u64,
+        item: Item,
+        cas: Option<i64>,
+    ) -> Vec<(proto::Request, SocketAddr)> {
+        let key = item.key();
+        self.start_lookup(Lookup::new(client, serial, key, Some((item, cas))))
+    }
+
+    /// Removes the lookups which have no outstanding queries
+    /// or have timed out, returning their results.
+    pub fn finished_lookups(&mut self) -> Vec<tracker::Response> {
+        let done: Vec<u32> = self.lookups
+            .iter()
+            .filter(|&(_, l)| l.finished())
+            .map(|(id, _)| *id)
+            .collect();
+        done.into_iter()
+            .filter_map(|id| self.lookups.remove(&id))
+            .map(|l| l.result())
+            .collect()
+    }
+
     pub fn handle_req(&mut self, req: proto::Request, mut addr: SocketAddr) -> proto::Response {
         self.last_req_recvd = Utc::now();
         match req.kind {
@@ -164,7 +282,7 @@ impl RoutingTable {
                 }
                 proto::Response::id(req.transaction, self.id.clone())
             }
-            proto::RequestKind::FindNode { id, target } => {
+            proto::RequestKind::FindNode { id, target, .. } => {
                 if self.contains_id(&id) {
                     self.get_node_mut(&id).update();
                 }
@@ -208,10 +326,13 @@ impl RoutingTable {
                 if !implied_port {
                     addr.set_port(port);
                 }
-                self.torrents.get_mut(&hash).unwrap().peers.push(addr);
+                let peers = &mut self.torrents.get_mut(&hash).unwrap().peers;
+                if !peers.contains(&addr) {
+                    peers.push(addr);
+                }
                 proto::Response::id(req.transaction, self.id.clone())
             }
-            proto::RequestKind::GetPeers { id, hash } => {
+            proto::RequestKind::GetPeers { id, hash, .. } => {
                 let token = if !self.contains_id(&id) {
                     return proto::Response::error(
                         req.transaction,
@@ -231,6 +352,55 @@ impl RoutingTable {
                     proto::Response::nodes(req.transaction, self.id.clone(), token, nodes)
                 }
             }
+            proto::RequestKind::Get {
+                id, target, seq, ..
+            } => {
+                let token = if !self.contains_id(&id) {
+                    return proto::Response::error(
+                        req.transaction,
+                        proto::ErrorKind::Protocol("Unregistered peer!".to_owned()),
+                    );
+                } else {
+                    self.get_node(&id).token.clone()
+                };
+                let nodes = self.closest_nodes(&BigUint::from_bytes_be(&target[..]));
+                // Mutable items are only sent if newer than the querying node's
+                let item = self.items
+                    .get(&target)
+                    .map(|s| s.item.clone())
+                    .and_then(|i| match (i.seq(), seq) {
+                        (Some(s), Some(min)) if s <= min => None,
+                        _ => Some(i),
+                    });
+                proto::Response::item(req.transaction, self.id.clone(), token, nodes, item)
+            }
+            proto::RequestKind::Put {
+                id,
+                token,
+                item,
+                cas,
+            } => {
+                if !self.contains_id(&id) {
+                    return proto::Response::error(
+                        req.transaction,
+                        proto::ErrorKind::Protocol("Unregistered peer!".to_owned()),
+                    );
+                }
+                {
+                    let node = self.get_node_mut(&id);
+                    if !node.token_valid(&token) {
+                        return proto::Response::error(
+                            req.transaction,
+                            proto::ErrorKind::Protocol("Bad token!".to_owned()),
+                        );
+                    }
+                    node.update();
+                }
+                if let Err(e) = self.store_item(item, cas) {
+                    return proto::Response::error(req.transaction, e);
+                }
+                proto::Response::id(req.transaction, self.id.clone())
+            }
         }
     }
 
@@ -250,12 +420,18 @@ impl RoutingTable {
         } else {
             return Err(reqs);
         };
+        if let Some(ip) = resp.ip {
+            self.vote_ip(addr.ip(), ip.ip());
+        }
 
         match (tx.kind, resp.kind) {
             (TransactionKind::Initialization, proto::ResponseKind::ID(id)) => {
                 let mut n = Node::new(id.clone(), addr);
                 n.update();
-                if self.add_node(n).is_ok() && self.bootstrapping {
+                // Nodes rejected from the table, e.g. for not following
+                // BEP 42, may still be used to find others
+                self.add_node(n).ok();
+                if self.bootstrapping {
                     let tx = self.new_query_tx(id);
                     reqs.push((
                         proto::Request::find_node(tx, self.id.clone(), self.id.clone()),
@@ -286,10 +462,9 @@ impl RoutingTable {
                 },
             ) if id1 == id2 =>
             {
-                if !self.contains_id(id1) {
-                    return Err(reqs);
+                if self.contains_id(id1) {
+                    self.get_node_mut(id1).update();
                 }
-                self.get_node_mut(id1).update();
                 for node in nodes.drain(..) {
                     if !self.contains_id(&node.id) {
                         let id = node.id.clone();
@@ -360,7 +535,7 @@ impl RoutingTable {
                     for node in nodes.drain(..) {
                         let id = node.id.clone();
                         let addr = node.addr;
-                        if !self.contains_id(&node.id) {
+                        if !self.contains_id(&node.id) && !self.filter.blocked(&addr.ip()) {
                             let tx = self.new_tsearch_tx(id.clone(), torrent, hash, depth + 1);
                             reqs.push((proto::Request::get_peers(tx, self.id.clone(), hash), addr));
                         }
@@ -368,6 +543,59 @@ impl RoutingTable {
                 }
             }
 
+            (
+                TransactionKind::ItemGet {
+                    id: ref id1,
+                    lookup,
+                    depth,
+                },
+                proto::ResponseKind::Get {
+                    id: ref id2,
+                    ref mut token,
+                    ref mut nodes,
+                    ref mut item,
+                },
+            ) if id1 == id2 =>
+            {
+                let token = mem::replace(token, vec![]);
+                let nodes = mem::replace(nodes, vec![]);
+                return self.handle_lookup_resp(lookup, depth, id1, addr, token, nodes, item.take());
+            }
+
+            // Nodes without the item may respond as to get_peers
+            (
+                TransactionKind::ItemGet {
+                    id: ref id1,
+                    lookup,
+                    depth,
+                },
+                proto::ResponseKind::GetPeers {
+                    id: ref id2,
+                    ref mut token,
+                    ref mut nodes,
+                    ..
+                },
+            ) if id1 == id2 =>
+            {
+                let token = mem::replace(token, vec![]);
+                let nodes = mem::replace(nodes, vec![]);
+                return self.handle_lookup_resp(lookup, depth, id1, addr, token, nodes, None);
+            }
+
+            (TransactionKind::ItemPut { ref id, lookup }, proto::ResponseKind::ID(ref id2))
+                if id == id2 =>
+            {
+                if let Some(l) = self.lookups.get_mut(&lookup) {
+                    l.stored += 1;
+                }
+                return self.lookup_query_done(lookup);
+            }
+
+            (TransactionKind::ItemGet { lookup, .. }, _)
+            | (TransactionKind::ItemPut { lookup, .. }, _) => {
+                return self.lookup_query_done(lookup);
+            }
+
             (TransactionKind::Query(id), proto::ResponseKind::Error(_)) => {
                 if !self.contains_id(&id) {
                     return Err(reqs);
@@ -379,20 +607,30 @@ impl RoutingTable {
             (TransactionKind::Query(id), proto::ResponseKind::ID(_))
             | (TransactionKind::Query(id), proto::ResponseKind::FindNode { .. })
             | (TransactionKind::Query(id), proto::ResponseKind::GetPeers { .. })
+            | (TransactionKind::Query(id), proto::ResponseKind::Get { .. })
             | (TransactionKind::TSearch { id, .. }, proto::ResponseKind::GetPeers { .. })
             | (TransactionKind::TSearch { id, .. }, _) => {
                 self.remove_node(&id);
             }
 
-            (TransactionKind::Initialization, _) => {
-                unreachable!();
-            }
+            // Invalid responses to a bootstrap ping are ignored
+            (TransactionKind::Initialization, _) => {}
         }
         Err(reqs)
     }
 
     pub fn tick(&mut self) -> Vec<(proto::Request, SocketAddr)> {
         let mut reqs = Vec::new();
+        // Puts whose lookups timed out still go to the nodes which answered
+        let stalled: Vec<u32> = self.lookups
+            .iter()
+            .filter(|&(_, l)| l.put.is_some() && !l.putting && l.finished())
+            .map(|(id, _)| *id)
+            .collect();
+        for lid in stalled {
+            reqs.extend(self.start_puts(lid));
+        }
+
         let dur = Utc::now().signed_duration_since(self.last_tick);
         if dur.num_seconds() < 10 {
             return reqs;
@@ -407,6 +645,9 @@ impl RoutingTable {
         self.transactions.retain(|_, tx| {
             Utc::now().signed_duration_since(tx.created).num_seconds() < TX_TIMEOUT_SECS
         });
+        self.items.retain(|_, i| {
+            Utc::now().signed_duration_since(i.stored).num_seconds() < ITEM_EXPIRY_SECS
+        });
 
         let dur = Utc::now().signed_duration_since(self.last_token_refresh);
         let tok_refresh = dur.num_minutes() > 5;
@@ -441,25 +682,214 @@ impl RoutingTable {
         bincode::serialize(self, bincode::Infinite).unwrap()
     }
 
-    fn is_bootstrapped(&self) -> bool {
+    /// Returns the nodes of the bucket which would contain id
+    pub fn closest_nodes(&self, id: &ID) -> Vec<proto::Node> {
+        let b = self.bucket_idx(id);
+        self.buckets[b].nodes.iter().map(|n| n.into()).collect()
+    }
+
+    pub fn is_bootstrapped(&self) -> bool {
         self.buckets.len() >= MIN_BOOTSTRAP_BKTS
     }
 
-    /// Send a bogus get_peers query and internally refresh our token.
-    fn refresh_tokens(&mut self) -> Vec<(proto::Request, SocketAddr)> {
-        let mut nodes: Vec<proto::Node> = Vec::new();
-        for bucket in &mut self.buckets {
-            for node in &mut bucket.nodes {
-                node.new_token();
-                nodes.push((&*node).into());
+    /// Adds the node, transaction and stored peer counts of the table
+    /// to stats, returning the number of nodes in each bucket.
+    pub fn add_stats(&self, stats: &mut Stats) -> Vec<usize> {
+        for node in self.buckets.iter().flat_map(|b| b.nodes.iter()) {
+            match node.state {
+                NodeState::Good => stats.good += 1,
+                NodeState::Questionable(_) => stats.questionable += 1,
+                NodeState::Bad => stats.bad += 1,
+            }
+        }
+        stats.transactions += self.transactions.len();
+        for (hash, torrent) in &self.torrents {
+            *stats.peers.entry(*hash).or_insert(0) += torrent.peers.len();
+        }
+        self.buckets.iter().map(|b| b.nodes.len()).collect()
+    }
+
+    pub fn nodes(&self) -> Vec<DhtNode> {
+        let mut nodes = Vec::new();
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            for node in &bucket.nodes {
+                nodes.push(DhtNode {
+                    id: format!("{:040X}", node.id),
+                    addr: node.addr.to_string(),
+                    bucket: i as u32,
+                    state: match node.state {
+                        NodeState::Good => DhtNodeState::Good,
+                        NodeState::Questionable(_) => DhtNodeState::Questionable,
+                        NodeState::Bad => DhtNodeState::Bad,
+                    },
+                    last_updated: node.last_updated,
+                });
+            }
+        }
+        nodes
+    }
+
+    /// Stores an item put by another node, replacing the item
+    /// stored longest ago once the storage limit is reached.
+    fn store_item(&mut self, item: Item, cas: Option<i64>) -> Result<(), proto::ErrorKind> {
+        item.verify()?;
+        let target = item.target();
+        if let Some(stored) = self.items.get(&target) {
+            if let (Some(seq), Some(cur)) = (item.seq(), stored.item.seq()) {
+                if cas.map(|c| c != cur).unwrap_or(false) {
+                    return Err(proto::ErrorKind::CasMismatch("CAS mismatch".to_owned()));
+                }
+                if seq < cur {
+                    return Err(proto::ErrorKind::SeqTooLow(
+                        "Sequence number less than current".to_owned(),
+                    ));
+                }
+            }
+        } else if self.items.len() >= MAX_ITEMS {
+            let oldest = self.items
+                .iter()
+                .min_by_key(|&(_, i)| i.stored)
+                .map(|(t, _)| *t);
+            if let Some(t) = oldest {
+                self.items.remove(&t);
             }
         }
+        self.items.insert(
+            target,
+            StoredItem {
+                item,
+                stored: Utc::now(),
+            },
+        );
+        Ok(())
+    }
 
+    fn start_lookup(&mut self, mut lookup: Lookup) -> Vec<(proto::Request, SocketAddr)> {
+        let lid = rand::random::<u32>();
+        let target = lookup.key.target();
+        let nodes = self.closest_nodes(&BigUint::from_bytes_be(&target[..]));
         let mut reqs = Vec::new();
         for node in nodes {
-            let tx = self.new_query_tx(node.id);
-            let req = proto::Request::get_peers(tx, self.id.clone(), [0xBEu8; 20]);
-            reqs.push((req, node.addr));
+            lookup.queried.insert(node.addr);
+            let tx = self.new_item_get_tx(node.id, lid, 0);
+            reqs.push((proto::Request::get(tx, self.id.clone(), target, None), node.addr));
+        }
+        lookup.pending = reqs.len();
+        self.lookups.insert(lid, lookup);
+        reqs
+    }
+
+    /// Handles a node's response to a lookup, keeping the most recent valid
+    /// item found and the node's token if the lookup is a put.
+    #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+    fn handle_lookup_resp(
+        &mut self,
+        lid: u32,
+        depth: u8,
+        id: &ID,
+        addr: SocketAddr,
+        token: Vec<u8>,
+        nodes: Vec<proto::Node>,
+        item: Option<Item>,
+    ) -> Result<tracker::Response, Vec<(proto::Request, SocketAddr)>> {
+        if self.contains_id(id) {
+            self.get_node_mut(id).update();
+        }
+        let filter = self.filter.clone();
+        let (target, next) = match self.lookups.get_mut(&lid) {
+            Some(l) => {
+                if l.put.is_some() && !token.is_empty() {
+                    l.responders.push((id.clone(), addr, token));
+                }
+                if let Some(mut item) = item {
+                    if l.key.matches(&mut item) && item.verify().is_ok()
+                        && l.item.as_ref().map(|i| i.seq()) < Some(item.seq())
+                    {
+                        l.item = Some(item);
+                    }
+                }
+                let mut next = Vec::new();
+                if depth < MAX_SEARCH_DEPTH {
+                    for node in nodes {
+                        if !filter.blocked(&node.addr.ip()) && l.queried.insert(node.addr) {
+                            next.push(node);
+                        }
+                    }
+                }
+                (l.key.target(), next)
+            }
+            None => return Err(vec![]),
+        };
+
+        let mut reqs = Vec::new();
+        for node in next {
+            let tx = self.new_item_get_tx(node.id, lid, depth + 1);
+            reqs.push((proto::Request::get(tx, self.id.clone(), target, None), node.addr));
+        }
+        if let Some(l) = self.lookups.get_mut(&lid) {
+            l.pending += reqs.len();
+        }
+        match self.lookup_query_done(lid) {
+            Err(puts) => {
+                reqs.extend(puts);
+                Err(reqs)
+            }
+            done => done,
+        }
+    }
+
+    /// Accounts for an answered lookup query, returning
+    /// the lookup's result if it has no outstanding queries.
+    fn lookup_query_done(
+        &mut self,
+        lid: u32,
+    ) -> Result<tracker::Response, Vec<(proto::Request, SocketAddr)>> {
+        let finished = match self.lookups.get_mut(&lid) {
+            Some(l) => {
+                l.pending = l.pending.saturating_sub(1);
+                l.updated = Utc::now();
+                l.pending == 0
+            }
+            None => return Err(vec![]),
+        };
+        if !finished {
+            return Err(vec![]);
+        }
+        let reqs = self.start_puts(lid);
+        if reqs.is_empty() {
+            Ok(self.lookups.remove(&lid).unwrap().result())
+        } else {
+            Err(reqs)
+        }
+    }
+
+    /// Puts a lookup's item on the closest nodes which answered it,
+    /// returning no requests if it isn't a put or already put.
+    fn start_puts(&mut self, lid: u32) -> Vec<(proto::Request, SocketAddr)> {
+        let (item, cas, mut nodes, target) = match self.lookups.get_mut(&lid) {
+            Some(l) => {
+                if l.putting || l.put.is_none() {
+                    return Vec::new();
+                }
+                l.putting = true;
+                let (item, cas) = l.put.clone().unwrap();
+                let nodes = mem::replace(&mut l.responders, Vec::new());
+                (item, cas, nodes, BigUint::from_bytes_be(&l.key.target()[..]))
+            }
+            None => return Vec::new(),
+        };
+        nodes.sort_by_key(|n| &n.0 ^ &target);
+        nodes.truncate(BUCKET_MAX);
+
+        let mut reqs = Vec::new();
+        for (id, addr, token) in nodes {
+            let tx = self.new_item_put_tx(id, lid);
+            let req = proto::Request::put(tx, self.id.clone(), token, item.clone(), cas);
+            reqs.push((req, addr));
+        }
+        if let Some(l) = self.lookups.get_mut(&lid) {
+            l.pending = reqs.len();
+            l.updated = Utc::now();
         }
         reqs
     }
@@ -528,8 +958,88 @@ impl RoutingTable {
         tb
     }
 
+    fn new_item_get_tx(&mut self, id: ID, lookup: u32, depth: u8) -> Vec<u8> {
+        let mut tb = Vec::new();
+        let tid = rand::random::<u32>();
+        tb.write_u32::<BigEndian>(tid).unwrap();
+        self.transactions.insert(
+            tid,
+            Transaction {
+                created: Utc::now(),
+                kind: TransactionKind::ItemGet { id, lookup, depth },
+            },
+        );
+        tb
+    }
+
+    fn new_item_put_tx(&mut self, id: ID, lookup: u32) -> Vec<u8> {
+        let mut tb = Vec::new();
+        let tid = rand::random::<u32>();
+        tb.write_u32::<BigEndian>(tid).unwrap();
+        self.transactions.insert(
+            tid,
+            Transaction {
+                created: Utc::now(),
+                kind: TransactionKind::ItemPut { id, lookup },
+            },
+        );
+        tb
+    }
+
+    /// Records the external IP reported by a node. Once enough nodes have
+    /// reported it and most agree, our ID is changed to one derived from
+    /// the IP(BEP 42) if it wasn't already.
+    fn vote_ip(&mut self, voter: IpAddr, ip: IpAddr) {
+        if voter.is_ipv6() != ip.is_ipv6() {
+            return;
+        }
+        self.ip_votes.insert(voter, ip);
+        if self.ip_votes.len() < IP_VOTES {
+            return;
+        }
+        let mut counts = HashMap::new();
+        for ip in self.ip_votes.values() {
+            *counts.entry(*ip).or_insert(0) += 1;
+        }
+        self.ip_votes.clear();
+        let (ip, votes) = match counts.into_iter().max_by_key(|&(_, c)| c) {
+            Some(v) => v,
+            None => return,
+        };
+        if votes * 2 <= IP_VOTES {
+            return;
+        }
+        if !secure::valid_id(&self.id, ip) {
+            info!("Changing DHT node ID for external IP {}", ip);
+            self.id = secure::generate_id(ip);
+            self.rebuild_buckets();
+        }
+    }
+
+    /// Reinserts every node into a new set of buckets after our ID changed
+    fn rebuild_buckets(&mut self) {
+        let buckets = mem::replace(
+            &mut self.buckets,
+            vec![Bucket::new(BigUint::from(0u8), id_from_pow(160))],
+        );
+        for node in buckets.into_iter().flat_map(|b| b.nodes) {
+            self.add_node(node).ok();
+        }
+        self.bootstrapping = true;
+    }
+
+    /// Adds a node, rejecting those whose ID doesn't match their IP(BEP 42)
+    /// or whose IP is already used by other nodes of the bucket. Nodes on
+    /// local networks are exempt from both. Filtered IPs are always rejected.
     fn add_node(&mut self, node: Node) -> Result<(), ()> {
+        let ip = node.addr.ip();
+        if self.filter.blocked(&ip) || !secure::valid_id(&node.id, ip) {
+            return Err(());
+        }
         let idx = self.bucket_idx(&node.id);
+        if !secure::is_local(ip) && self.buckets[idx].ip_nodes(ip) >= MAX_BUCKET_IP_NODES {
+            return Err(());
+        }
         if self.buckets[idx].full() {
             if self.buckets[idx].could_hold(&self.id) && self.buckets.len() < MAX_BUCKETS {
                 self.split_bucket(idx);
@@ -621,11 +1131,56 @@ impl Bucket {
         self.idx_of(id).is_some()
     }
 
+    /// Returns the number of nodes with the given IP
+    fn ip_nodes(&self, ip: IpAddr) -> usize {
+        self.nodes.iter().filter(|n| n.addr.ip() == ip).count()
+    }
+
     fn idx_of(&self, id: &ID) -> Option<usize> {
         self.nodes.iter().position(|node| &node.id == id)
     }
 }
 
+impl Lookup {
+    fn new(client: usize, serial: u64, key: Key, put: Option<(Item, Option<i64>)>) -> Lookup {
+        Lookup {
+            client,
+            serial,
+            key,
+            put,
+            item: None,
+            stored: 0,
+            responders: Vec::new(),
+            putting: false,
+            queried: HashSet::new(),
+            pending: 0,
+            updated: Utc::now(),
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.pending == 0
+            || Utc::now().signed_duration_since(self.updated).num_seconds() >= TX_TIMEOUT_SECS
+    }
+
+    fn result(self) -> tracker::Response {
+        if self.put.is_some() {
+            tracker::Response::DHTPut {
+                client: self.client,
+                serial: self.serial,
+                target: self.key.target(),
+                nodes: self.stored,
+            }
+        } else {
+            tracker::Response::DHTGet {
+                client: self.client,
+                serial: self.serial,
+                item: self.item,
+            }
+        }
+    }
+}
+
 impl Node {
     fn new(id: ID, addr: SocketAddr) -> Node {
         let token = Node::create_token();
@@ -704,7 +1259,14 @@ fn id_from_pow(pow: usize) -> ID {
 
 #[cfg(test)]
 mod tests {
-    use super::{id_from_pow, Bucket, Node, RoutingTable};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use chrono::{Duration, Utc};
+    use super::{id_from_pow, Bucket, Lookup, Node, NodeState, RoutingTable, IP_VOTES,
+                MAX_SEARCH_DEPTH};
+    use super::super::{proto, secure, Stats, MAX_ITEMS};
+    use super::super::item::Item;
+    use ipfilter::IpFilter;
     use num::bigint::BigUint;
 
     #[test]
@@ -739,4 +1301,144 @@ mod tests {
         assert_eq!(rt.buckets[0].nodes.len(), 0);
         assert_eq!(rt.buckets[1].nodes.len(), 8);
     }
+
+    #[test]
+    fn test_add_node_bep42() {
+        let mut rt = RoutingTable::new();
+        let ip: IpAddr = "124.31.75.21".parse().unwrap();
+        let addr = SocketAddr::new(ip, 6881);
+        assert!(rt.add_node(Node::new(BigUint::from(1u8), addr)).is_err());
+        assert!(rt.add_node(Node::new(secure::generate_id(ip), addr)).is_ok());
+        // Other nodes of the bucket can't share the IP
+        let addr = SocketAddr::new(ip, 6882);
+        assert!(rt.add_node(Node::new(secure::generate_id(ip), addr)).is_err());
+
+        // Local nodes may share an IP
+        let ip: IpAddr = "192.168.1.2".parse().unwrap();
+        for i in 1..4u8 {
+            let addr = SocketAddr::new(ip, 6880 + u16::from(i));
+            assert!(rt.add_node(Node::new(BigUint::from(i), addr)).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_add_node_filtered() {
+        let mut rt = RoutingTable::new();
+        for i in 1..4u8 {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)), 6881);
+            rt.add_node(Node::new(BigUint::from(i), addr)).unwrap();
+        }
+        // Blocked nodes are removed and can't be added back
+        rt.set_ip_filter(Arc::new(IpFilter::from_list("10.0.0.1-10.0.0.2")));
+        assert_eq!(rt.nodes().len(), 1);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 6881);
+        assert!(rt.add_node(Node::new(BigUint::from(1u8), addr)).is_err());
+    }
+
+    #[test]
+    fn test_ip_vote() {
+        let mut rt = RoutingTable::new();
+        let ip: IpAddr = "124.31.75.21".parse().unwrap();
+        for i in 1..IP_VOTES {
+            rt.vote_ip(IpAddr::V4(Ipv4Addr::new(1, 1, 1, i as u8)), ip);
+        }
+        // Votes are counted once per node
+        rt.vote_ip(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), ip);
+        assert!(!secure::valid_id(&rt.id, ip));
+        rt.vote_ip(IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)), ip);
+        assert!(secure::valid_id(&rt.id, ip));
+    }
+
+    #[test]
+    fn test_add_stats() {
+        let mut rt = RoutingTable::new();
+        for i in 1..4u8 {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)), 6881);
+            rt.add_node(Node::new(BigUint::from(i), addr)).unwrap();
+        }
+        rt.get_node_mut(&BigUint::from(1u8)).update();
+        rt.get_node_mut(&BigUint::from(2u8)).state = NodeState::Questionable(1);
+        let mut stats = Stats::default();
+        assert_eq!(rt.add_stats(&mut stats), vec![3]);
+        assert_eq!((stats.good, stats.questionable, stats.bad), (1, 1, 1));
+        assert_eq!(rt.nodes().len(), 3);
+    }
+
+    #[test]
+    fn test_init() {
+        let mut rt = RoutingTable::new();
+        for i in 1..4u8 {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)), 6881);
+            rt.add_node(Node::new(BigUint::from(i), addr)).unwrap();
+        }
+        rt.get_node_mut(&BigUint::from(1u8)).update();
+        rt.get_node_mut(&BigUint::from(2u8)).update();
+        // Only nodes which were good are pinged, and added back once they respond
+        let reqs = rt.init();
+        assert_eq!(reqs.len(), 2);
+        assert!(rt.nodes().is_empty());
+    }
+
+    #[test]
+    fn test_store_item() {
+        let mut rt = RoutingTable::new();
+        let seed = [1u8; 32];
+        let item = |seq| Item::mutable(&seed, vec![], seq, b"3:abc".to_vec()).unwrap();
+        assert!(rt.store_item(item(5), None).is_ok());
+        match rt.store_item(item(6), Some(4)) {
+            Err(proto::ErrorKind::CasMismatch(_)) => {}
+            r => panic!("Expected CAS mismatch, got {:?}", r),
+        }
+        match rt.store_item(item(4), None) {
+            Err(proto::ErrorKind::SeqTooLow(_)) => {}
+            r => panic!("Expected low sequence number, got {:?}", r),
+        }
+        assert!(rt.store_item(item(6), Some(5)).is_ok());
+        assert_eq!(rt.items[&item(6).target()].item.seq(), Some(6));
+    }
+
+    #[test]
+    fn test_store_item_evict() {
+        let mut rt = RoutingTable::new();
+        let item = |i: usize| Item::immutable(format!("i{}e", i).into_bytes());
+        for i in 0..MAX_ITEMS {
+            rt.store_item(item(i), None).unwrap();
+        }
+        rt.items.get_mut(&item(1).target()).unwrap().stored = Utc::now() - Duration::hours(1);
+        rt.store_item(item(MAX_ITEMS), None).unwrap();
+        assert_eq!(rt.items.len(), MAX_ITEMS);
+        assert!(!rt.items.contains_key(&item(1).target()));
+        assert!(rt.items.contains_key(&item(MAX_ITEMS).target()));
+    }
+
+    #[test]
+    fn test_put_closest() {
+        let mut rt = RoutingTable::new();
+        let item = Item::immutable(b"3:abc".to_vec());
+        let target = BigUint::from_bytes_be(&item.target()[..]);
+        let mut lookup = Lookup::new(0, 0, item.key(), Some((item, None)));
+        lookup.pending = 10;
+        rt.lookups.insert(1, lookup);
+
+        // Responders are given out of order, with their distance to the target being i
+        let mut puts = Vec::new();
+        for i in (1..11u8).rev() {
+            let id = &target ^ &BigUint::from(i);
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)), 6881);
+            let resp = rt.handle_lookup_resp(1, MAX_SEARCH_DEPTH, &id, addr, vec![i], vec![], None);
+            match resp {
+                Err(reqs) => puts.extend(reqs),
+                Ok(r) => panic!("Lookup finished early: {:?}", r),
+            }
+        }
+        assert_eq!(puts.len(), 8);
+        for (i, &(ref req, addr)) in puts.iter().enumerate() {
+            assert_eq!(addr.ip(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, i as u8 + 1)));
+            match req.kind {
+                proto::RequestKind::Put { ref token, .. } => assert_eq!(token, &vec![i as u8 + 1]),
+                ref k => panic!("Expected put, got {:?}", k),
+            }
+        }
+        assert!(rt.lookups[&1].putting);
+    }
 }
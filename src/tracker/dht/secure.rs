@@ -0,0 +1,128 @@
+use std::net::IpAddr;
+
+use num::bigint::BigUint;
+use rand::{self, Rng};
+
+use super::ID;
+
+const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// Generates a node ID derived from our external IP(BEP 42)
+pub fn generate_id(ip: IpAddr) -> ID {
+    let mut rng = rand::thread_rng();
+    let mut id = [0u8; 20];
+    for i in &mut id {
+        *i = rng.gen::<u8>();
+    }
+    let crc = ip_crc(ip, id[19] & 0x7);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x7);
+    BigUint::from_bytes_be(&id)
+}
+
+/// Checks that a node's ID was derived from its IP(BEP 42).
+/// Nodes on local networks are exempt.
+pub fn valid_id(id: &ID, ip: IpAddr) -> bool {
+    if is_local(ip) {
+        return true;
+    }
+    let b = id.to_bytes_be();
+    if b.len() > 20 {
+        return false;
+    }
+    let mut id = [0u8; 20];
+    id[20 - b.len()..].copy_from_slice(&b);
+    let crc = ip_crc(ip, id[19] & 0x7);
+    id[0] == (crc >> 24) as u8 && id[1] == (crc >> 16) as u8
+        && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+/// Whether an IP is on a local network, and so exempt from
+/// BEP 42 restrictions
+pub(crate) fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(ip) => {
+            let seg = ip.segments()[0];
+            // Unique local(fc00::/7) and link local(fe80::/10) addresses
+            ip.is_loopback() || seg & 0xfe00 == 0xfc00 || seg & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+fn ip_crc(ip: IpAddr, r: u8) -> u32 {
+    let mut data = match ip {
+        IpAddr::V4(ip) => {
+            let mut o = ip.octets().to_vec();
+            for (b, m) in o.iter_mut().zip(V4_MASK.iter()) {
+                *b &= m;
+            }
+            o
+        }
+        IpAddr::V6(ip) => {
+            let mut o = ip.octets()[..8].to_vec();
+            for (b, m) in o.iter_mut().zip(V6_MASK.iter()) {
+                *b &= m;
+            }
+            o
+        }
+    };
+    data[0] |= r << 5;
+    crc32c(&data)
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32c, generate_id, valid_id};
+    use num::bigint::BigUint;
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    // Test vectors from BEP 42
+    #[test]
+    fn test_valid_id() {
+        let vectors = [
+            ("124.31.75.21", "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401"),
+            ("21.75.31.124", "5a3ce9c14e7a08645677bbd1cfe7d8f956d53256"),
+            ("65.23.51.170", "a5d43220bc8f112a3d426c84764f8c2a1150e616"),
+            ("84.124.73.14", "1b0321dd1bb1fe518101ceef99462b947a01ff41"),
+            ("43.213.53.83", "e56f6cbf5b7c4be0237986d5243b87aa6d51305a"),
+        ];
+        for &(ip, id) in &vectors {
+            let id = BigUint::parse_bytes(id.as_bytes(), 16).unwrap();
+            assert!(valid_id(&id, ip.parse().unwrap()));
+            assert!(!valid_id(&id, "1.2.3.4".parse().unwrap()));
+        }
+        let id = BigUint::from(1u8);
+        assert!(valid_id(&id, "192.168.1.5".parse().unwrap()));
+        assert!(valid_id(&id, "::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_generate_id() {
+        for ip in &["124.31.75.21", "2001:db8::1"] {
+            let ip = ip.parse().unwrap();
+            assert!(valid_id(&generate_id(ip), ip));
+        }
+    }
+}
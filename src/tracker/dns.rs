@@ -1,3 +1,4 @@
+use std::mem;
 use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
 
@@ -14,6 +15,8 @@ pub struct QueryResponse {
 pub struct Resolver {
     chan: c_ares::Channel,
     sender: Arc<Mutex<amy::Sender<QueryResponse>>>,
+    /// Queries which found no IPv4 address, to be retried for IPv6
+    retry: Arc<Mutex<Vec<(usize, String)>>>,
 }
 
 impl Resolver {
@@ -23,6 +26,7 @@ impl Resolver {
         Resolver {
             chan: c_ares::Channel::with_options(opts).unwrap(),
             sender: Arc::new(Mutex::new(send)),
+            retry: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
@@ -46,13 +50,39 @@ impl Resolver {
         for fd in wfd {
             self.chan.process_fd(c_ares::SOCKET_BAD, fd);
         }
+
+        let retry = mem::replace(&mut *self.retry.lock().unwrap(), Vec::new());
+        for (id, host) in retry {
+            self.query_v6(id, &host);
+        }
     }
 
+    /// Resolves host, preferring IPv4 addresses and falling
+    /// back to IPv6 if none exist.
     pub fn new_query(&mut self, id: usize, host: &str) {
-        // TODO: handle ipv6 too
+        // IPv6 literals in URLs are enclosed in brackets
+        let host = host.trim_left_matches('[').trim_right_matches(']');
         let s = self.sender.clone();
+        let retry = self.retry.clone();
+        let h = host.to_owned();
         self.chan
             .get_host_by_name(host, c_ares::AddressFamily::INET, move |res| {
+                match res.ok().and_then(|ips| ips.addresses().next()) {
+                    Some(ip) => {
+                        let resp = QueryResponse { id, res: Ok(ip) };
+                        if s.lock().unwrap().send(resp).is_err() {
+                            // Other end was shutdown, ignore
+                        }
+                    }
+                    None => retry.lock().unwrap().push((id, h)),
+                }
+            });
+    }
+
+    fn query_v6(&mut self, id: usize, host: &str) {
+        let s = self.sender.clone();
+        self.chan
+            .get_host_by_name(host, c_ares::AddressFamily::INET6, move |res| {
                 let res = res.chain_err(|| ErrorKind::DNS)
                     .and_then(|ips| ips.addresses().next().ok_or_else(|| ErrorKind::DNS.into()));
                 let resp = QueryResponse { id, res };
@@ -9,10 +9,12 @@ use url::percent_encoding::percent_encode_byte;
 use url::Url;
 
 use {amy, bencode, PEER_ID};
-use self::writer::Writer;
-use self::reader::{ReadRes, Reader};
+pub use self::writer::Writer;
+pub use self::reader::{ReadRes, Reader};
 use socket::TSocket;
-use tracker::{self, dns, Announce, Error, ErrorKind, Response, Result, ResultExt, TrackerResponse};
+use bencode::BEncode;
+use tracker::{self, dns, Announce, Error, ErrorKind, Response, Result, ResultExt, Scrape,
+              ScrapeResponse, TrackerResponse};
 use util::{AView, UHashMap};
 
 const TIMEOUT_MS: u64 = 5_000;
@@ -29,13 +31,20 @@ enum Event {
 }
 
 struct Tracker {
-    torrent: usize,
+    kind: Kind,
     url: AView<Url>,
     last_updated: Instant,
     redirect: bool,
     state: TrackerState,
 }
 
+#[derive(Clone)]
+enum Kind {
+    /// Announce for the torrent with the given id
+    Announce(usize),
+    Scrape(Scrape),
+}
+
 enum TrackerState {
     Error,
     ResolvingDNS {
@@ -52,13 +61,13 @@ enum TrackerState {
         reader: Reader,
     },
     Redirect(String),
-    Complete(TrackerResponse),
+    Complete(BEncode),
 }
 
 enum HTTPRes {
     None,
     Redirect(String),
-    Complete(TrackerResponse),
+    Complete(BEncode),
 }
 
 impl TrackerState {
@@ -120,8 +129,7 @@ impl TrackerState {
                 ReadRes::Done(data) => {
                     let content = bencode::decode_buf(&data)
                         .chain_err(|| ErrorKind::InvalidResponse("Invalid BEncoded response!"))?;
-                    let resp = TrackerResponse::from_bencode(content)?;
-                    Ok(TrackerState::Complete(resp))
+                    Ok(TrackerState::Complete(content))
                 }
                 ReadRes::Redirect(l) => Ok(TrackerState::Redirect(l)),
                 ReadRes::None => Ok(TrackerState::Reading { sock, reader }),
@@ -132,6 +140,22 @@ impl TrackerState {
     }
 }
 
+impl Tracker {
+    fn response(&self, resp: Result<BEncode>) -> Response {
+        match self.kind {
+            Kind::Announce(tid) => Response::Tracker {
+                tid,
+                url: self.url.clone(),
+                resp: resp.and_then(TrackerResponse::from_bencode),
+            },
+            Kind::Scrape(ref s) => Response::Scrape {
+                url: s.url.clone(),
+                resp: resp.and_then(|r| ScrapeResponse::from_bencode(r, &s.torrents)),
+            },
+        }
+    }
+}
+
 impl Handler {
     pub fn new(reg: &amy::Registrar) -> io::Result<Handler> {
         Ok(Handler {
@@ -159,11 +183,7 @@ impl Handler {
             trk.last_updated = Instant::now();
             match trk.state.handle(Event::DNSResolved(resp)) {
                 Ok(_) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.response(Err(e))),
             }
         } else {
             None
@@ -179,11 +199,7 @@ impl Handler {
             trk.last_updated = Instant::now();
             match trk.state.handle(Event::Writable) {
                 Ok(_) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.response(Err(e))),
             }
         } else {
             None
@@ -200,23 +216,15 @@ impl Handler {
             trk.last_updated = Instant::now();
             match trk.state.handle(Event::Readable) {
                 Ok(HTTPRes::Complete(r)) => {
-                    debug!("Announce response received for {:?} succesfully", id);
-                    Some(Response::Tracker {
-                        tid: trk.torrent,
-                        url: trk.url.clone(),
-                        resp: Ok(r),
-                    })
+                    debug!("Tracker response received for {:?} succesfully", id);
+                    Some(trk.response(Ok(r)))
                 }
                 Ok(HTTPRes::Redirect(l)) => {
                     loc = Some(l);
                     None
                 }
                 Ok(HTTPRes::None) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.response(Err(e))),
             }
         } else {
             None
@@ -230,28 +238,19 @@ impl Handler {
             let trk = self.connections.remove(&id).unwrap();
             // Disallow 2 levels of redirection
             if trk.redirect {
-                resp = Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(ErrorKind::InvalidResponse("Too many redirects").into()),
-                });
+                resp = Some(trk.response(Err(
+                    ErrorKind::InvalidResponse("Too many redirects").into(),
+                )));
             }
-            if let Err(e) = self.try_redirect(&l, trk.torrent, dns) {
-                debug!(
-                    "Announce response received for {:?}, redirecting!",
-                    trk.torrent
-                );
-                resp = Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                });
+            if let Err(e) = self.try_redirect(&l, trk.kind.clone(), dns) {
+                debug!("Tracker response received for {}, redirecting!", *trk.url);
+                resp = Some(trk.response(Err(e)));
             }
         }
         resp
     }
 
-    fn try_redirect(&mut self, url: &str, torrent: usize, dns: &mut dns::Resolver) -> Result<()> {
+    fn try_redirect(&mut self, url: &str, kind: Kind, dns: &mut dns::Resolver) -> Result<()> {
         let url = Url::parse(url).chain_err(|| ErrorKind::InvalidResponse("Malformed redirect!"))?;
         let mut http_req = Vec::with_capacity(50);
         http_req.extend_from_slice(b"GET ");
@@ -277,7 +276,7 @@ impl Handler {
         };
 
         // Setup actual connection and start DNS query
-        let sock = TSocket::new_v4(ohost).chain_err(|| ErrorKind::IO)?;
+        let sock = TSocket::new(ohost).chain_err(|| ErrorKind::IO)?;
         let id = self.reg
             .register(&sock, amy::Event::Both)
             .chain_err(|| ErrorKind::IO)?;
@@ -286,7 +285,7 @@ impl Handler {
             Tracker {
                 last_updated: Instant::now(),
                 redirect: true,
-                torrent,
+                kind,
                 url: AView::value(url.clone()),
                 state: TrackerState::new(sock, http_req, port),
             },
@@ -301,12 +300,8 @@ impl Handler {
         let mut resps = Vec::new();
         self.connections.retain(|id, trk| {
             if trk.last_updated.elapsed() > Duration::from_millis(TIMEOUT_MS) {
-                debug!("Announce {:?} timed out", id);
-                resps.push(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(ErrorKind::Timeout.into()),
-                });
+                debug!("Tracker request {:?} timed out", id);
+                resps.push(trk.response(Err(ErrorKind::Timeout.into())));
                 false
             } else {
                 true
@@ -317,35 +312,61 @@ impl Handler {
 
     pub fn new_announce(&mut self, req: Announce, dns: &mut dns::Resolver) -> Result<()> {
         debug!("Received a new announce req for {:?}", req.url);
-        let mut http_req = Vec::with_capacity(50);
-        // Encode GET req
-        http_req.extend_from_slice(b"GET ");
-
-        // Encode the URL
-        http_req.extend_from_slice(req.url.path().as_bytes());
-        http_req.extend_from_slice(b"?");
-        append_query_pair(&mut http_req, "info_hash", &encode_param(&req.hash));
-        append_query_pair(&mut http_req, "peer_id", &encode_param(&PEER_ID[..]));
-        append_query_pair(&mut http_req, "uploaded", &req.uploaded.to_string());
-        append_query_pair(&mut http_req, "downloaded", &req.downloaded.to_string());
-        append_query_pair(&mut http_req, "left", &req.left.to_string());
-        append_query_pair(&mut http_req, "compact", "1");
-        append_query_pair(&mut http_req, "port", &req.port.to_string());
+        let mut query = Vec::with_capacity(50);
+        append_query_pair(&mut query, "info_hash", &encode_param(&req.hash));
+        append_query_pair(&mut query, "peer_id", &encode_param(&PEER_ID[..]));
+        append_query_pair(&mut query, "uploaded", &req.uploaded.to_string());
+        append_query_pair(&mut query, "downloaded", &req.downloaded.to_string());
+        append_query_pair(&mut query, "left", &req.left.to_string());
+        append_query_pair(&mut query, "compact", "1");
+        append_query_pair(&mut query, "port", &req.port.to_string());
         if let Some(nw) = req.num_want {
-            append_query_pair(&mut http_req, "numwant", &nw.to_string());
+            append_query_pair(&mut query, "numwant", &nw.to_string());
+        }
+        if let Some(ref id) = req.tracker_id {
+            append_query_pair(&mut query, "trackerid", &encode_param(id.as_bytes()));
         }
         match req.event {
             Some(tracker::Event::Started) => {
-                append_query_pair(&mut http_req, "event", "started");
+                append_query_pair(&mut query, "event", "started");
             }
             Some(tracker::Event::Stopped) => {
-                append_query_pair(&mut http_req, "event", "stopped");
+                append_query_pair(&mut query, "event", "stopped");
             }
             Some(tracker::Event::Completed) => {
-                append_query_pair(&mut http_req, "event", "completed");
+                append_query_pair(&mut query, "event", "completed");
             }
             None => {}
         }
+        self.new_request(req.url, &query, Kind::Announce(req.id), dns)
+    }
+
+    pub fn new_scrape(&mut self, req: Scrape, dns: &mut dns::Resolver) -> Result<()> {
+        debug!("Received a new scrape req for {:?}", req.url);
+        let url = scrape_url(&req.url)?;
+        let mut query = Vec::with_capacity(50);
+        for &(_, ref hash) in &req.torrents {
+            append_query_pair(&mut query, "info_hash", &encode_param(hash));
+        }
+        self.new_request(AView::value(url), &query, Kind::Scrape(req), dns)
+    }
+
+    /// Sends a GET request with the given query string to the tracker url.
+    fn new_request(
+        &mut self,
+        url: AView<Url>,
+        query: &[u8],
+        kind: Kind,
+        dns: &mut dns::Resolver,
+    ) -> Result<()> {
+        let mut http_req = Vec::with_capacity(50 + query.len());
+        // Encode GET req
+        http_req.extend_from_slice(b"GET ");
+
+        // Encode the URL
+        http_req.extend_from_slice(url.path().as_bytes());
+        http_req.extend_from_slice(b"?");
+        http_req.extend_from_slice(query);
 
         // Encode HTTP protocol
         http_req.extend_from_slice(b" HTTP/1.1\r\n");
@@ -353,37 +374,35 @@ impl Handler {
         http_req.extend_from_slice(b"Connection: close\r\n");
         // Encode host header
         http_req.extend_from_slice(b"Host: ");
-        let host = req.url.host_str().ok_or_else(|| {
+        let host = url.host_str().ok_or_else(|| {
             Error::from(ErrorKind::InvalidRequest(
-                "Tracker announce url has no host!".to_owned(),
+                "Tracker url has no host!".to_owned(),
             ))
         })?;
-        let port =
-            req.url
-                .port()
-                .unwrap_or_else(|| if req.url.scheme() == "https" { 443 } else { 80 });
+        let port = url.port()
+            .unwrap_or_else(|| if url.scheme() == "https" { 443 } else { 80 });
         http_req.extend_from_slice(host.as_bytes());
         http_req.extend_from_slice(b"\r\n");
         // Encode empty line to terminate request
         http_req.extend_from_slice(b"\r\n");
 
-        let ohost = if req.url.scheme() == "https" {
+        let ohost = if url.scheme() == "https" {
             Some(host.to_owned())
         } else {
             None
         };
 
         // Setup actual connection and start DNS query
-        let sock = TSocket::new_v4(ohost).chain_err(|| ErrorKind::IO)?;
+        let sock = TSocket::new(ohost).chain_err(|| ErrorKind::IO)?;
         let id = self.reg
             .register(&sock, amy::Event::Both)
             .chain_err(|| ErrorKind::IO)?;
         self.connections.insert(
             id,
             Tracker {
-                url: req.url.clone(),
+                url: url.clone(),
                 last_updated: Instant::now(),
-                torrent: req.id,
+                kind,
                 state: TrackerState::new(sock, http_req, port),
                 redirect: false,
             },
@@ -396,6 +415,21 @@ impl Handler {
     }
 }
 
+/// Derives the scrape URL from an announce URL, by convention replacing
+/// the "announce" at the start of the last path segment with "scrape".
+fn scrape_url(url: &Url) -> Result<Url> {
+    let path = url.path().to_owned();
+    let pos = path.rfind('/').map(|p| p + 1).unwrap_or(0);
+    if !path[pos..].starts_with("announce") {
+        bail!(ErrorKind::InvalidRequest(
+            "Tracker does not support scraping".to_owned()
+        ));
+    }
+    let mut scrape = url.clone();
+    scrape.set_path(&format!("{}scrape{}", &path[..pos], &path[pos + 8..]));
+    Ok(scrape)
+}
+
 fn append_query_pair(s: &mut Vec<u8>, k: &str, v: &str) {
     s.extend_from_slice(k.as_bytes());
     s.extend_from_slice(b"=");
@@ -415,3 +449,68 @@ fn encode_param(data: &[u8]) -> String {
     }
     resp
 }
+
+#[cfg(test)]
+mod tests {
+    use super::scrape_url;
+    use bencode;
+    use tracker::{ScrapeResponse, TrackerResponse};
+    use url::Url;
+
+    #[test]
+    fn test_scrape_url() {
+        let scrape = |u: &str| scrape_url(&Url::parse(u).unwrap()).map(|u| u.into_string());
+        assert_eq!(
+            scrape("http://example.com/announce").unwrap(),
+            "http://example.com/scrape"
+        );
+        assert_eq!(
+            scrape("http://example.com/x/announce.php").unwrap(),
+            "http://example.com/x/scrape.php"
+        );
+        assert!(scrape("http://example.com/a").is_err());
+        assert!(scrape("http://example.com/announce/x").is_err());
+    }
+
+    #[test]
+    fn test_announce_response() {
+        let data = b"d8:intervali1800e12:min intervali900e5:peers0:\
+                     10:tracker id3:abc15:warning message4:slowe";
+        let resp = TrackerResponse::from_bencode(bencode::decode_buf(data).unwrap()).unwrap();
+        assert_eq!(resp.interval, 1800);
+        assert_eq!(resp.min_interval, Some(900));
+        assert_eq!(resp.tracker_id, Some("abc".to_owned()));
+        assert_eq!(resp.warning, Some("slow".to_owned()));
+
+        let data = b"d8:intervali1800e5:peers0:e";
+        let resp = TrackerResponse::from_bencode(bencode::decode_buf(data).unwrap()).unwrap();
+        assert_eq!(resp.min_interval, None);
+        assert_eq!(resp.tracker_id, None);
+        assert_eq!(resp.warning, None);
+    }
+
+    #[test]
+    fn test_scrape_response() {
+        let data = b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e\
+                     10:incompletei10eeee";
+        let torrents = [(1, [b'a'; 20]), (2, [b'b'; 20])];
+        let resp =
+            ScrapeResponse::from_bencode(bencode::decode_buf(data).unwrap(), &torrents).unwrap();
+        assert_eq!(
+            resp,
+            vec![
+                (
+                    1,
+                    ScrapeResponse {
+                        complete: 5,
+                        incomplete: 10,
+                        downloaded: 50,
+                    },
+                ),
+            ]
+        );
+        let fail = b"d14:failure reason4:nopee";
+        let fail = bencode::decode_buf(fail).unwrap();
+        assert!(ScrapeResponse::from_bencode(fail, &torrents).is_err());
+    }
+}
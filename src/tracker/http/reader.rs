@@ -10,6 +10,9 @@ pub struct Reader {
     data: Vec<u8>,
     idx: usize,
     state: ReadState,
+    code: Option<u16>,
+    /// Body length past which the rest of the response is ignored
+    limit: Option<usize>,
 }
 
 pub enum ReadRes {
@@ -29,9 +32,26 @@ impl Reader {
             data: vec![0; 75],
             idx: 0,
             state: ReadState::Header,
+            code: None,
+            limit: None,
         }
     }
 
+    /// Creates a reader which finishes once limit bytes
+    /// of the body have been read.
+    pub fn with_limit(limit: usize) -> Reader {
+        Reader {
+            limit: Some(limit),
+            ..Reader::new()
+        }
+    }
+
+    /// Returns the HTTP status code of the response, if the
+    /// header has been read.
+    pub fn code(&self) -> Option<u16> {
+        self.code
+    }
+
     pub fn readable<R: io::Read>(&mut self, conn: &mut R) -> Result<ReadRes> {
         loop {
             match aread(&mut self.data[self.idx..], conn) {
@@ -52,6 +72,7 @@ impl Reader {
                             let mut resp = httparse::Response::new(&mut headers);
                             match resp.parse(&self.data) {
                                 Ok(httparse::Status::Complete(i)) => {
+                                    self.code = resp.code;
                                     // Redirect handling
                                     let redirect_codes = [301, 302, 303, 307, 308];
                                     if resp.code
@@ -81,6 +102,7 @@ impl Reader {
                         ReadState::Body => {}
                     }
                     if let Some(i) = header_done {
+                        self.data.truncate(self.idx);
                         self.data = self.data.split_off(i);
                         self.idx = self.data.len();
                         self.state = ReadState::Body;
@@ -97,6 +119,36 @@ impl Reader {
                 },
                 IOR::Err(_) => return Err(ErrorKind::IO.into()),
             }
+            if let (&ReadState::Body, Some(limit)) = (&self.state, self.limit) {
+                if self.idx >= limit {
+                    let mut data = mem::replace(&mut self.data, Vec::with_capacity(0));
+                    data.truncate(limit);
+                    return Ok(ReadRes::Done(data));
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::{ReadRes, Reader};
+
+    fn read(mut r: Reader, body: &[u8]) -> Vec<u8> {
+        let mut resp = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n".to_vec();
+        resp.extend_from_slice(body);
+        match r.readable(&mut Cursor::new(resp)) {
+            Ok(ReadRes::Done(data)) => data,
+            _ => panic!("Response wasn't read"),
+        }
+    }
+
+    #[test]
+    fn test_read_body() {
+        let body: Vec<u8> = (0..200).collect();
+        assert_eq!(read(Reader::new(), &body), body);
+        assert_eq!(read(Reader::with_limit(150), &body), &body[..150]);
+        assert_eq!(read(Reader::with_limit(500), &body), body);
+    }
+}
@@ -0,0 +1,300 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time;
+
+use amy;
+use httparse;
+use net2::UdpBuilder;
+
+use tracker::Response;
+use util::{hash_to_id, id_to_hash, random_string, FHashMap};
+use CONFIG;
+
+const LSD_PORT: u16 = 6771;
+const ANNOUNCE_INT_SECS: u64 = 300;
+/// Announces for a torrent are never sent more often than this
+const MIN_ANNOUNCE_INT_SECS: u64 = 60;
+
+/// Local Service Discovery(BEP 14), which finds peers on the
+/// local network by multicasting announces.
+pub struct Handler {
+    v4: Option<(usize, UdpSocket)>,
+    v6: Option<(usize, UdpSocket)>,
+    torrents: FHashMap<[u8; 20], Torrent>,
+    /// Included in our announces so they can be ignored when looped back
+    cookie: String,
+    buf: Vec<u8>,
+}
+
+struct Torrent {
+    tid: usize,
+    last_announce: Option<time::Instant>,
+}
+
+impl Handler {
+    pub fn new(reg: &amy::Registrar) -> Handler {
+        let (v4, v6) = if CONFIG.net.lsd {
+            (Handler::try_bind(reg, false), Handler::try_bind(reg, true))
+        } else {
+            (None, None)
+        };
+        Handler {
+            v4,
+            v6,
+            torrents: FHashMap::default(),
+            cookie: random_string(8),
+            buf: vec![0u8; 1500],
+        }
+    }
+
+    fn try_bind(reg: &amy::Registrar, v6: bool) -> Option<(usize, UdpSocket)> {
+        match Handler::bind(reg, v6) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                info!("LSD unavailable for IPv{}: {}", if v6 { 6 } else { 4 }, e);
+                None
+            }
+        }
+    }
+
+    fn bind(reg: &amy::Registrar, v6: bool) -> io::Result<(usize, UdpSocket)> {
+        let sock = if v6 {
+            let sock = UdpBuilder::new_v6()?
+                .only_v6(true)?
+                .reuse_address(true)?
+                .bind((Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), LSD_PORT))?;
+            sock.join_multicast_v6(&mcast_v6(), 0)?;
+            sock
+        } else {
+            let sock = UdpBuilder::new_v4()?
+                .reuse_address(true)?
+                .bind(("0.0.0.0", LSD_PORT))?;
+            sock.join_multicast_v4(&mcast_v4(), &Ipv4Addr::new(0, 0, 0, 0))?;
+            sock
+        };
+        sock.set_nonblocking(true)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+        Ok((id, sock))
+    }
+
+    /// Returns true if id belongs to one of the LSD sockets
+    pub fn has_sock(&self, id: usize) -> bool {
+        self.v4.as_ref().map(|&(i, _)| i == id).unwrap_or(false)
+            || self.v6.as_ref().map(|&(i, _)| i == id).unwrap_or(false)
+    }
+
+    /// Starts periodically announcing a torrent, immediately
+    /// announcing it if it hasn't been recently.
+    pub fn announce(&mut self, tid: usize, hash: [u8; 20]) {
+        let min = time::Duration::from_secs(MIN_ANNOUNCE_INT_SECS);
+        let t = self.torrents.entry(hash).or_insert(Torrent {
+            tid,
+            last_announce: None,
+        });
+        t.tid = tid;
+        if t.last_announce.map(|l| l.elapsed() >= min).unwrap_or(false) {
+            t.last_announce = None;
+        }
+        self.send_announces();
+    }
+
+    /// Stops announcing a torrent and accepting its peers
+    pub fn remove(&mut self, hash: &[u8; 20]) {
+        self.torrents.remove(hash);
+    }
+
+    pub fn readable(&mut self, id: usize) -> Vec<Response> {
+        let mut resps = Vec::new();
+        loop {
+            let res = match (&self.v4, &self.v6) {
+                (&Some((i, ref s)), _) if i == id => s.recv_from(&mut self.buf[..]),
+                (_, &Some((i, ref s))) if i == id => s.recv_from(&mut self.buf[..]),
+                _ => break,
+            };
+            let (len, addr) = match res {
+                Ok(r) => r,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("Encountered unexpected error reading LSD socket: {:?}!", e);
+                    break;
+                }
+            };
+            let ann = match Announce::decode(&self.buf[..len]) {
+                Some(a) => a,
+                None => {
+                    trace!("Received invalid LSD message from {:?}!", addr);
+                    continue;
+                }
+            };
+            if ann.cookie.as_ref() == Some(&self.cookie) {
+                continue;
+            }
+            let peer = SocketAddr::new(addr.ip(), ann.port);
+            for hash in ann.hashes {
+                if let Some(t) = self.torrents.get(&hash) {
+                    debug!("Found local peer {:?} via LSD", peer);
+                    resps.push(Response::LSD {
+                        tid: t.tid,
+                        peers: vec![peer],
+                    });
+                }
+            }
+        }
+        resps
+    }
+
+    pub fn tick(&mut self) {
+        self.send_announces();
+    }
+
+    /// Announces all torrents which are due to be announced,
+    /// grouping as many as possible into each message.
+    fn send_announces(&mut self) {
+        if self.v4.is_none() && self.v6.is_none() {
+            return;
+        }
+        let now = time::Instant::now();
+        let int = time::Duration::from_secs(ANNOUNCE_INT_SECS);
+        let mut due = Vec::new();
+        for (hash, t) in &mut self.torrents {
+            let ready = t
+                .last_announce
+                .map(|l| now.duration_since(l) >= int)
+                .unwrap_or(true);
+            if ready {
+                t.last_announce = Some(now);
+                due.push(*hash);
+            }
+        }
+        // Keep messages within a single unfragmented datagram
+        for hashes in due.chunks(10) {
+            if let Some((_, ref sock)) = self.v4 {
+                send(sock, IpAddr::V4(mcast_v4()), hashes, &self.cookie);
+            }
+            if let Some((_, ref sock)) = self.v6 {
+                send(sock, IpAddr::V6(mcast_v6()), hashes, &self.cookie);
+            }
+        }
+    }
+}
+
+fn send(sock: &UdpSocket, group: IpAddr, hashes: &[[u8; 20]], cookie: &str) {
+    let ann = Announce::new(group, hashes, cookie);
+    if let Err(e) = sock.send_to(&ann.encode(), SocketAddr::new(group, LSD_PORT)) {
+        debug!("Failed to send LSD announce: {:?}", e);
+    }
+}
+
+fn mcast_v4() -> Ipv4Addr {
+    Ipv4Addr::new(239, 192, 152, 143)
+}
+
+fn mcast_v6() -> Ipv6Addr {
+    Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f)
+}
+
+/// A BT-SEARCH message
+#[derive(Debug, PartialEq)]
+struct Announce {
+    host: String,
+    port: u16,
+    hashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+impl Announce {
+    fn new(group: IpAddr, hashes: &[[u8; 20]], cookie: &str) -> Announce {
+        let host = match group {
+            IpAddr::V4(ip) => format!("{}:{}", ip, LSD_PORT),
+            IpAddr::V6(ip) => format!("[{}]:{}", ip, LSD_PORT),
+        };
+        Announce {
+            host,
+            port: CONFIG.port,
+            hashes: hashes.to_vec(),
+            cookie: Some(cookie.to_owned()),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut msg = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            self.host, self.port
+        );
+        for hash in &self.hashes {
+            msg.push_str(&format!("Infohash: {}\r\n", hash_to_id(hash)));
+        }
+        if let Some(ref c) = self.cookie {
+            msg.push_str(&format!("cookie: {}\r\n", c));
+        }
+        msg.push_str("\r\n\r\n");
+        msg.into_bytes()
+    }
+
+    fn decode(data: &[u8]) -> Option<Announce> {
+        let mut headers = [httparse::EMPTY_HEADER; 24];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(data) {
+            Ok(httparse::Status::Complete(_)) => {}
+            _ => return None,
+        }
+        if req.method != Some("BT-SEARCH") {
+            return None;
+        }
+        let mut ann = Announce {
+            host: String::new(),
+            port: 0,
+            hashes: Vec::new(),
+            cookie: None,
+        };
+        for header in req.headers.iter() {
+            let value = match ::std::str::from_utf8(header.value) {
+                Ok(v) => v.trim(),
+                Err(_) => continue,
+            };
+            if header.name.eq_ignore_ascii_case("host") {
+                ann.host = value.to_owned();
+            } else if header.name.eq_ignore_ascii_case("port") {
+                ann.port = value.parse().ok()?;
+            } else if header.name.eq_ignore_ascii_case("infohash") {
+                ann.hashes.push(id_to_hash(value)?);
+            } else if header.name.eq_ignore_ascii_case("cookie") {
+                ann.cookie = Some(value.to_owned());
+            }
+        }
+        if ann.port == 0 || ann.hashes.is_empty() {
+            return None;
+        }
+        Some(ann)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Announce;
+
+    #[test]
+    fn test_encode_decode() {
+        let ann = Announce {
+            host: "239.192.152.143:6771".to_owned(),
+            port: 6881,
+            hashes: vec![[0xAB; 20], [0x01; 20]],
+            cookie: Some("abcd".to_owned()),
+        };
+        assert_eq!(Announce::decode(&ann.encode()), Some(ann));
+    }
+
+    #[test]
+    fn test_decode() {
+        let msg = b"BT-SEARCH * HTTP/1.1\r\n\
+                    Host: [ff15::efc0:988f]:6771\r\n\
+                    Port: 16493\r\n\
+                    Infohash: 0123456789abcdef0123456789abcdef01234567\r\n\
+                    \r\n\r\n";
+        let ann = Announce::decode(msg).unwrap();
+        assert_eq!(ann.port, 16493);
+        assert_eq!(ann.hashes[0][..2], [0x01, 0x23]);
+        assert_eq!(ann.cookie, None);
+        assert!(Announce::decode(b"NOTIFY * HTTP/1.1\r\nPort: 1\r\n\r\n").is_none());
+    }
+}
@@ -3,9 +3,12 @@ mod udp;
 mod errors;
 mod dns;
 mod dht;
+mod webseed;
+mod lsd;
 
 use std::collections::VecDeque;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
 use std::{io, result, thread};
 
 use byteorder::{BigEndian, ReadBytesExt};
@@ -13,12 +16,15 @@ use url::Url;
 use amy;
 
 pub use self::errors::{Error, ErrorKind, Result, ResultExt};
-use torrent::Torrent;
+pub use self::dht::{Item, Key, Stats as DHTStats};
+use torrent::{self, Torrent};
 use bencode::BEncode;
 use control::cio;
-use util::AView;
+use util::{bytes_to_addr, AView};
 use handle;
 use disk;
+use rpc::resource::DhtNode;
+use ipfilter::IpFilter;
 use CONFIG;
 
 pub struct Tracker {
@@ -26,9 +32,11 @@ pub struct Tracker {
     ch: handle::Handle<Request, Response>,
     dns_res: amy::Receiver<dns::QueryResponse>,
     http: http::Handler,
+    webseed: webseed::Handler,
     queue: VecDeque<Announce>,
     udp: udp::Handler,
     dht: dht::Manager,
+    lsd: lsd::Handler,
     dns: dns::Resolver,
     timer: usize,
     shutting_down: bool,
@@ -40,6 +48,33 @@ pub enum Request {
     GetPeers(GetPeers),
     AddNode(SocketAddr),
     DHTAnnounce([u8; 20]),
+    /// Looks up a BEP 44 item for an RPC client
+    DHTGet {
+        client: usize,
+        serial: u64,
+        key: Key,
+    },
+    /// Stores a BEP 44 item for an RPC client
+    DHTPut {
+        client: usize,
+        serial: u64,
+        item: Item,
+        cas: Option<i64>,
+    },
+    DHTStats,
+    /// Lists the DHT routing table nodes for an RPC client
+    DHTTable {
+        client: usize,
+        serial: u64,
+    },
+    /// Starts announcing a torrent over LSD(BEP 14)
+    LSDAnnounce(GetPeers),
+    /// Stops announcing a torrent over LSD
+    LSDRemove([u8; 20]),
+    /// Replaces the IP filter applied to DHT nodes
+    IpFilter(Arc<IpFilter>),
+    WebSeed(WebSeed),
+    Scrape(Scrape),
     Shutdown,
 }
 
@@ -54,6 +89,8 @@ pub struct Announce {
     left: u64,
     num_want: Option<u16>,
     event: Option<Event>,
+    /// Tracker id returned by a previous announce
+    tracker_id: Option<String>,
 }
 
 #[derive(Debug)]
@@ -62,7 +99,27 @@ pub struct GetPeers {
     pub hash: [u8; 20],
 }
 
+/// Request for a range of torrent data from a web seed.
 #[derive(Debug)]
+pub struct WebSeed {
+    pub tid: usize,
+    /// Torrent specific id of the web seed
+    pub id: usize,
+    /// File URL, offset, and length of each part of the range,
+    /// which may span several files.
+    pub segments: Vec<(Url, u64, u64)>,
+}
+
+/// Scrape request for a batch of torrents sharing a tracker.
+#[derive(Clone, Debug)]
+pub struct Scrape {
+    /// Announce URL of the tracker
+    pub url: Url,
+    /// Id and info hash of each torrent
+    pub torrents: Vec<(usize, [u8; 20])>,
+}
+
+#[derive(Clone, Copy, Debug)]
 pub enum Event {
     Started,
     Stopped,
@@ -80,17 +137,64 @@ pub enum Response {
         tid: usize,
         peers: Vec<SocketAddr>,
     },
+    /// Most recent BEP 44 item found, if any
+    DHTGet {
+        client: usize,
+        serial: u64,
+        item: Option<Item>,
+    },
+    /// Number of nodes a BEP 44 item was stored on
+    DHTPut {
+        client: usize,
+        serial: u64,
+        target: [u8; 20],
+        nodes: usize,
+    },
+    DHTStats(DHTStats),
+    DHTTable {
+        client: usize,
+        serial: u64,
+        nodes: Vec<DhtNode>,
+    },
+    LSD {
+        tid: usize,
+        peers: Vec<SocketAddr>,
+    },
+    WebSeed {
+        tid: usize,
+        id: usize,
+        resp: Result<Vec<u8>>,
+    },
+    Scrape {
+        url: Url,
+        resp: Result<Vec<(usize, ScrapeResponse)>>,
+    },
 }
 
 #[derive(Debug)]
 pub struct TrackerResponse {
     pub peers: Vec<SocketAddr>,
     pub interval: u32,
+    /// Minimum interval the tracker allows between announces
+    pub min_interval: Option<u32>,
+    pub tracker_id: Option<String>,
+    pub warning: Option<String>,
     pub leechers: u32,
     pub seeders: u32,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ScrapeResponse {
+    pub complete: u32,
+    pub incomplete: u32,
+    pub downloaded: u32,
+}
+
 const POLL_INT_MS: usize = 1000;
+/// Maximum number of info hashes scraped in a single HTTP request
+const MAX_HTTP_SCRAPE: usize = 50;
+/// Maximum number of info hashes which fit in a single UDP scrape
+const MAX_UDP_SCRAPE: usize = 74;
 
 impl Tracker {
     pub fn start(
@@ -104,7 +208,9 @@ impl Tracker {
         let (dtx, drx) = reg.channel()?;
         let udp = udp::Handler::new(&reg)?;
         let dht = dht::Manager::new(&reg, db)?;
+        let lsd = lsd::Handler::new(&reg);
         let http = http::Handler::new(&reg)?;
+        let webseed = webseed::Handler::new(&reg)?;
         let dns = dns::Resolver::new(dtx);
         let th = dh.run("trk", move |h| {
             Tracker {
@@ -112,7 +218,9 @@ impl Tracker {
                 ch: h,
                 udp,
                 dht,
+                lsd,
                 http,
+                webseed,
                 dns,
                 dns_res: drx,
                 timer,
@@ -124,7 +232,7 @@ impl Tracker {
     }
 
     pub fn run(&mut self) {
-        self.dht.init();
+        self.dht.init(&mut self.dns);
 
         debug!("Initialized!");
         'outer: loop {
@@ -183,6 +291,60 @@ impl Tracker {
                     trace!("Handling dht announce req!");
                     self.dht.announce(hash);
                 }
+                Request::DHTGet {
+                    client,
+                    serial,
+                    key,
+                } => {
+                    trace!("Handling dht get req!");
+                    self.dht.get(client, serial, key);
+                }
+                Request::DHTPut {
+                    client,
+                    serial,
+                    item,
+                    cas,
+                } => {
+                    trace!("Handling dht put req!");
+                    self.dht.put(client, serial, item, cas);
+                }
+                Request::DHTStats => {
+                    let stats = self.dht.stats();
+                    self.send_response(Response::DHTStats(stats));
+                }
+                Request::DHTTable { client, serial } => {
+                    let nodes = self.dht.nodes();
+                    self.send_response(Response::DHTTable {
+                        client,
+                        serial,
+                        nodes,
+                    });
+                }
+                Request::LSDAnnounce(gp) => {
+                    trace!("Handling lsd announce req!");
+                    self.lsd.announce(gp.id, gp.hash);
+                }
+                Request::LSDRemove(hash) => {
+                    self.lsd.remove(&hash);
+                }
+                Request::IpFilter(filter) => {
+                    self.dht.set_ip_filter(filter);
+                }
+                Request::WebSeed(req) => {
+                    trace!("Handling web seed req!");
+                    let (tid, id) = (req.tid, req.id);
+                    if let Err(e) = self.webseed.new_fetch(req, &mut self.dns) {
+                        self.send_response(Response::WebSeed {
+                            tid,
+                            id,
+                            resp: Err(e),
+                        });
+                    }
+                }
+                Request::Scrape(req) => {
+                    trace!("Handling scrape req!");
+                    self.handle_scrape(req);
+                }
                 Request::Shutdown => {
                     return Err(());
                 }
@@ -216,6 +378,21 @@ impl Tracker {
         }
     }
 
+    fn handle_scrape(&mut self, req: Scrape) {
+        debug!("Handling scrape request!");
+        let url = req.url.clone();
+        let response = match url.scheme() {
+            "http" | "https" => self.http.new_scrape(req, &mut self.dns),
+            "udp" => self.udp.new_scrape(req, &mut self.dns),
+            s => Err(
+                ErrorKind::InvalidRequest(format!("Unknown tracker url scheme: {}", s)).into(),
+            ),
+        };
+        if let Err(e) = response {
+            self.send_response(Response::Scrape { url, resp: Err(e) });
+        }
+    }
+
     fn dequeue_req(&mut self) {
         // Attempt to dequeue next request if we can
         if let Some(a) = self.queue.pop_front() {
@@ -229,6 +406,11 @@ impl Tracker {
                 self.http.dns_resolved(r)
             } else if self.udp.contains(r.id) {
                 self.udp.dns_resolved(r)
+            } else if self.webseed.contains(r.id) {
+                self.webseed.dns_resolved(r, &mut self.dns)
+            } else if self.dht.contains(r.id) {
+                self.dht.dns_resolved(r);
+                None
             } else {
                 None
             };
@@ -243,12 +425,16 @@ impl Tracker {
             .tick()
             .into_iter()
             .chain(self.udp.tick().into_iter())
+            .chain(self.webseed.tick().into_iter())
         {
             self.send_response(r);
         }
 
         self.dns.tick();
-        self.dht.tick();
+        for r in self.dht.tick(&mut self.dns) {
+            self.send_response(r);
+        }
+        self.lsd.tick();
     }
 
     fn handle_socket(&mut self, event: amy::Notification) {
@@ -261,12 +447,25 @@ impl Tracker {
             if let Some(r) = resp {
                 self.send_response(r);
             }
-        } else if self.udp.id() == event.id {
-            for resp in self.udp.readable() {
+        } else if self.webseed.contains(event.id) {
+            let resp = if event.event.readable() {
+                self.webseed.readable(event.id, &mut self.dns)
+            } else {
+                self.webseed.writable(event.id, &mut self.dns)
+            };
+            if let Some(r) = resp {
+                self.send_response(r);
+            }
+        } else if self.udp.has_sock(event.id) {
+            for resp in self.udp.readable(event.id) {
+                self.send_response(resp);
+            }
+        } else if self.dht.has_sock(event.id) {
+            for resp in self.dht.readable(event.id) {
                 self.send_response(resp);
             }
-        } else if self.dht.id() == event.id {
-            for resp in self.dht.readable() {
+        } else if self.lsd.has_sock(event.id) {
+            for resp in self.lsd.readable(event.id) {
                 self.send_response(resp);
             }
         } else {
@@ -289,16 +488,13 @@ impl Tracker {
 impl Request {
     pub fn new_announce<T: cio::CIO>(
         torrent: &Torrent<T>,
+        tracker: &torrent::Tracker,
         event: Option<Event>,
-    ) -> Option<Request> {
-        let url = if let Some(trk) = torrent.trackers().front() {
-            trk.url.clone()
-        } else {
-            return None;
-        };
-        Some(Request::Announce(Announce {
+    ) -> Request {
+        Request::Announce(Announce {
             id: torrent.id(),
-            url,
+            url: tracker.url.clone(),
+            tracker_id: tracker.tracker_id.clone(),
             hash: torrent.info().hash,
             port: CONFIG.port,
             uploaded: torrent.uploaded(),
@@ -314,33 +510,58 @@ impl Request {
             // let existing peers connect otherwise
             num_want: if torrent.complete() { None } else { Some(50) },
             event,
-        }))
+        })
     }
 
-    pub fn started<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
-        Request::new_announce(torrent, Some(Event::Started))
+    /// Creates announces to each of the torrent's current trackers.
+    fn announces<T: cio::CIO>(torrent: &Torrent<T>, event: Option<Event>) -> Vec<Request> {
+        torrent
+            .announce_trackers()
+            .into_iter()
+            .map(|trk| Request::new_announce(torrent, trk, event))
+            .collect()
     }
 
-    pub fn stopped<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
-        Request::new_announce(torrent, Some(Event::Stopped))
+    pub fn started<T: cio::CIO>(torrent: &Torrent<T>) -> Vec<Request> {
+        Request::announces(torrent, Some(Event::Started))
     }
 
-    pub fn completed<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
-        Request::new_announce(torrent, Some(Event::Completed))
+    pub fn stopped<T: cio::CIO>(torrent: &Torrent<T>) -> Vec<Request> {
+        Request::announces(torrent, Some(Event::Stopped))
     }
 
-    pub fn interval<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
-        Request::new_announce(torrent, None)
+    pub fn completed<T: cio::CIO>(torrent: &Torrent<T>) -> Vec<Request> {
+        Request::announces(torrent, Some(Event::Completed))
     }
 
-    pub fn custom<T: cio::CIO>(torrent: &Torrent<T>, url: AView<Url>) -> Option<Request> {
-        Request::new_announce(torrent, None).map(|mut r| {
-            match r {
-                Request::Announce(ref mut a) => a.url = url,
-                _ => {}
-            }
-            r
-        })
+    /// Creates scrape requests for the given torrents, split into
+    /// batches which the tracker's protocol can handle.
+    pub fn scrape(url: Url, torrents: Vec<(usize, [u8; 20])>) -> Vec<Request> {
+        let max = if url.scheme() == "udp" {
+            MAX_UDP_SCRAPE
+        } else {
+            MAX_HTTP_SCRAPE
+        };
+        torrents
+            .chunks(max)
+            .map(|batch| {
+                Request::Scrape(Scrape {
+                    url: url.clone(),
+                    torrents: batch.to_vec(),
+                })
+            })
+            .collect()
+    }
+
+    /// Creates a regular announce, or a started one if the tracker
+    /// hasn't yet been sent one.
+    pub fn custom<T: cio::CIO>(torrent: &Torrent<T>, tracker: &torrent::Tracker) -> Request {
+        let event = if tracker.started {
+            None
+        } else {
+            Some(Event::Started)
+        };
+        Request::new_announce(torrent, tracker, event)
     }
 }
 
@@ -349,6 +570,9 @@ impl TrackerResponse {
         TrackerResponse {
             peers: vec![],
             interval: 900,
+            min_interval: None,
+            tracker_id: None,
+            warning: None,
             leechers: 0,
             seeders: 0,
         }
@@ -372,6 +596,22 @@ impl TrackerResponse {
             },
             _ => {}
         };
+        // BEP 7 compact IPv6 peers
+        if let Some(BEncode::String(ref data)) = d.remove("peers6") {
+            resp.peers
+                .extend(data.chunks(18).filter(|p| p.len() == 18).map(bytes_to_addr));
+        }
+        if let Some(BEncode::Int(i)) = d.remove("complete") {
+            resp.seeders = i as u32;
+        }
+        if let Some(BEncode::Int(i)) = d.remove("incomplete") {
+            resp.leechers = i as u32;
+        }
+        if let Some(BEncode::Int(i)) = d.remove("min interval") {
+            resp.min_interval = Some(i as u32);
+        }
+        resp.tracker_id = d.remove("tracker id").and_then(BEncode::into_string);
+        resp.warning = d.remove("warning message").and_then(BEncode::into_string);
         match d.remove("interval") {
             Some(BEncode::Int(ref i)) => {
                 resp.interval = *i as u32;
@@ -383,3 +623,44 @@ impl TrackerResponse {
         Ok(resp)
     }
 }
+
+impl ScrapeResponse {
+    /// Parses a scrape response, matching the returned
+    /// files to the scraped torrents by info hash.
+    pub fn from_bencode(
+        data: BEncode,
+        torrents: &[(usize, [u8; 20])],
+    ) -> Result<Vec<(usize, ScrapeResponse)>> {
+        let mut d = data.into_dict().ok_or(ErrorKind::InvalidResponse(
+            "Scrape response must be a dictionary type!",
+        ))?;
+        if let Some(BEncode::String(data)) = d.remove("failure reason") {
+            let reason = String::from_utf8(data)
+                .chain_err(|| ErrorKind::InvalidResponse("Failure reason must be UTF8!"))?;
+            return Err(ErrorKind::TrackerError(reason).into());
+        }
+        let mut files = d.remove("files")
+            .and_then(|f| f.into_raw_dict())
+            .ok_or(ErrorKind::InvalidResponse("Scrape response must have files!"))?;
+        let mut resp = Vec::new();
+        for &(id, ref hash) in torrents {
+            let mut f = match files.remove(&hash[..]).and_then(|f| f.into_dict()) {
+                Some(f) => f,
+                None => continue,
+            };
+            let mut field = |k: &str| match f.remove(k) {
+                Some(BEncode::Int(i)) => i as u32,
+                _ => 0,
+            };
+            resp.push((
+                id,
+                ScrapeResponse {
+                    complete: field("complete"),
+                    incomplete: field("incomplete"),
+                    downloaded: field("downloaded"),
+                },
+            ));
+        }
+        Ok(resp)
+    }
+}
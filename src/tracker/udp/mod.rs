@@ -5,10 +5,12 @@ use std::time;
 use amy;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use rand::random;
+use url::Url;
 
 use {CONFIG, PEER_ID};
-use tracker::{dns, Announce, Error, ErrorKind, Event, Response, Result, ResultExt, TrackerResponse};
-use util::{bytes_to_addr, FHashMap, UHashMap};
+use tracker::{dns, Announce, Error, ErrorKind, Event, Response, Result, ResultExt, Scrape,
+              ScrapeResponse, TrackerResponse};
+use util::{bytes_to_addr, udp_bind_v6, FHashMap, UHashMap};
 
 // We're not going to bother with backoff, if the tracker/network aren't working now
 // the torrent can just resend a request later.
@@ -19,6 +21,8 @@ const MAGIC_NUM: u64 = 0x417_2710_1980;
 pub struct Handler {
     id: usize,
     sock: UdpSocket,
+    /// Id and socket used for IPv6 trackers, if IPv6 is available
+    v6: Option<(usize, UdpSocket)>,
     connections: UHashMap<Connection>,
     transactions: FHashMap<u32, usize>,
     conn_count: usize,
@@ -26,17 +30,38 @@ pub struct Handler {
 }
 
 struct Connection {
-    torrent: usize,
     last_updated: time::Instant,
     last_retrans: time::Instant,
     state: State,
-    announce: Announce,
+    req: Req,
+}
+
+enum Req {
+    Announce(Announce),
+    Scrape(Scrape),
 }
 
 enum State {
     ResolvingDNS { port: u16 },
     Connecting { addr: SocketAddr, data: [u8; 16] },
     Announcing { addr: SocketAddr, data: [u8; 98] },
+    Scraping { addr: SocketAddr, data: Vec<u8> },
+}
+
+impl Connection {
+    fn error(&self, e: Error) -> Response {
+        match self.req {
+            Req::Announce(ref a) => Response::Tracker {
+                tid: a.id,
+                url: a.url.clone(),
+                resp: Err(e),
+            },
+            Req::Scrape(ref s) => Response::Scrape {
+                url: s.url.clone(),
+                resp: Err(e),
+            },
+        }
+    }
 }
 
 impl Handler {
@@ -45,18 +70,30 @@ impl Handler {
         let sock = UdpSocket::bind(("0.0.0.0", port))?;
         sock.set_nonblocking(true)?;
         let id = reg.register(&sock, amy::Event::Read)?;
+        let v6 = match udp_bind_v6(port).and_then(|s| {
+            s.set_nonblocking(true)?;
+            Ok((reg.register(&s, amy::Event::Read)?, s))
+        }) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                info!("IPv6 UDP trackers unavailable: {}", e);
+                None
+            }
+        };
         Ok(Handler {
             id,
             sock,
+            v6,
             connections: UHashMap::default(),
             transactions: FHashMap::default(),
             conn_count: 0,
-            buf: vec![0u8; 350],
+            buf: vec![0u8; 1500],
         })
     }
 
-    pub fn id(&self) -> usize {
-        self.id
+    /// Returns true if id belongs to one of the handler's sockets
+    pub fn has_sock(&self, id: usize) -> bool {
+        self.id == id || self.v6.as_ref().map(|&(id6, _)| id6 == id).unwrap_or(false)
     }
 
     pub fn complete(&self) -> bool {
@@ -72,9 +109,19 @@ impl Handler {
     }
 
     pub fn new_announce(&mut self, req: Announce, dns: &mut dns::Resolver) -> Result<()> {
-        // TODO: Attempt to parse into an IP address first, then perform dns res
         let url = req.url.clone();
         debug!("Received a new announce req for {:?}", url);
+        self.new_request(&url, Req::Announce(req), dns)
+    }
+
+    pub fn new_scrape(&mut self, req: Scrape, dns: &mut dns::Resolver) -> Result<()> {
+        let url = req.url.clone();
+        debug!("Received a new scrape req for {:?}", url);
+        self.new_request(&url, Req::Scrape(req), dns)
+    }
+
+    fn new_request(&mut self, url: &Url, req: Req, dns: &mut dns::Resolver) -> Result<()> {
+        // TODO: Attempt to parse into an IP address first, then perform dns res
         let host = url.host_str().ok_or_else(|| {
             Error::from(ErrorKind::InvalidRequest(
                 "Tracker announce url has no host!".to_owned(),
@@ -90,11 +137,10 @@ impl Handler {
         self.connections.insert(
             id,
             Connection {
-                torrent: req.id,
                 last_updated: time::Instant::now(),
                 last_retrans: time::Instant::now(),
                 state: State::ResolvingDNS { port },
-                announce: req,
+                req,
             },
         );
         debug!("Dispatching DNS req for {:?}, url: {:?}", id, host);
@@ -128,11 +174,7 @@ impl Handler {
                             self.transactions.insert(tid, id);
                             None
                         }
-                        Err(e) => Some(Response::Tracker {
-                            tid: conn.torrent,
-                            url: conn.announce.url.clone(),
-                            resp: Err(e),
-                        }),
+                        Err(e) => Some(conn.error(e)),
                     }
                 }
                 _ => None,
@@ -150,9 +192,18 @@ impl Handler {
         }
     }
 
-    pub fn readable(&mut self) -> Vec<Response> {
+    pub fn readable(&mut self, id: usize) -> Vec<Response> {
         let mut resps = Vec::new();
-        while let Ok((v, _)) = self.sock.recv_from(&mut self.buf[..]) {
+        let v6 = id != self.id;
+        loop {
+            let res = match self.v6 {
+                Some((_, ref s)) if v6 => s.recv_from(&mut self.buf[..]),
+                _ => self.sock.recv_from(&mut self.buf[..]),
+            };
+            let v = match res {
+                Ok((v, _)) => v,
+                Err(_) => break,
+            };
             let action = (&self.buf[0..4]).read_u32::<BigEndian>().unwrap();
             match action {
                 0 if v == 16 => {
@@ -161,7 +212,12 @@ impl Handler {
                     }
                 }
                 1 if v >= 20 => {
-                    if let Some(r) = self.process_announce(v) {
+                    if let Some(r) = self.process_announce(v, v6) {
+                        resps.push(r);
+                    }
+                }
+                2 if v >= 8 => {
+                    if let Some(r) = self.process_scrape(v) {
                         resps.push(r);
                     }
                 }
@@ -184,12 +240,8 @@ impl Handler {
         {
             self.connections.retain(|id, conn| {
                 if conn.last_updated.elapsed() > time::Duration::from_millis(TIMEOUT_MS) {
-                    resps.push(Response::Tracker {
-                        tid: conn.torrent,
-                        url: conn.announce.url.clone(),
-                        resp: Err(ErrorKind::Timeout.into()),
-                    });
-                    debug!("Announce {:?} timed out", id);
+                    resps.push(conn.error(ErrorKind::Timeout.into()));
+                    debug!("Tracker request {:?} timed out", id);
                     false
                 } else {
                     if conn.last_retrans.elapsed() > time::Duration::from_millis(RETRANS_MS) {
@@ -225,7 +277,6 @@ impl Handler {
             None => return None,
         };
 
-        let mut data = [0u8; 98];
         {
             let conn = match self.connections.get_mut(&id) {
                 Some(conn) => conn,
@@ -236,61 +287,26 @@ impl Handler {
                 _ => return None,
             };
 
-            {
-                let mut announce_req = Cursor::new(&mut data[..]);
-                announce_req.write_u64::<BigEndian>(connection_id).unwrap();
-                // announce action
-                announce_req.write_u32::<BigEndian>(1).unwrap();
-
-                let tid = random::<u32>();
-                announce_req.write_u32::<BigEndian>(tid).unwrap();
-                self.transactions.insert(tid, id);
-
-                announce_req.write_all(&conn.announce.hash).unwrap();
-                announce_req.write_all(&PEER_ID[..]).unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.downloaded as u64)
-                    .unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.left as u64)
-                    .unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.uploaded as u64)
-                    .unwrap();
-                match conn.announce.event {
-                    Some(Event::Started) => {
-                        announce_req.write_u32::<BigEndian>(2).unwrap();
-                    }
-                    Some(Event::Stopped) => {
-                        announce_req.write_u32::<BigEndian>(3).unwrap();
-                    }
-                    Some(Event::Completed) => {
-                        announce_req.write_u32::<BigEndian>(1).unwrap();
-                    }
-                    None => {
-                        announce_req.write_u32::<BigEndian>(0).unwrap();
-                    }
-                }
-
-                // IP
-                announce_req.write_u32::<BigEndian>(0).unwrap();
-                // Key - TODO: randomly generate this
-                announce_req.write_u32::<BigEndian>(0xFFFF_00BA).unwrap();
-                // Num want
-                let nw = conn.announce.num_want.map(|nw| nw as i32).unwrap_or(-1);
-                announce_req.write_i32::<BigEndian>(nw).unwrap();
-                // port
-                announce_req
-                    .write_u16::<BigEndian>(conn.announce.port)
-                    .unwrap();
-            }
-            conn.state = State::Announcing { addr, data };
+            let tid = random::<u32>();
+            self.transactions.insert(tid, id);
+            conn.state = match conn.req {
+                Req::Announce(ref announce) => State::Announcing {
+                    addr,
+                    data: announce_data(connection_id, tid, announce),
+                },
+                Req::Scrape(ref scrape) => State::Scraping {
+                    addr,
+                    data: scrape_data(connection_id, tid, scrape),
+                },
+            };
             conn.last_updated = time::Instant::now();
         }
         self.send_data(id)
     }
 
-    fn process_announce(&mut self, len: usize) -> Option<Response> {
+    /// Processes an announce response, whose peers are 18 byte
+    /// compact IPv6 addresses if it was received over IPv6.
+    fn process_announce(&mut self, len: usize, v6: bool) -> Option<Response> {
         let mut announce_resp = Cursor::new(&self.buf[4..len]);
         let mut resp = TrackerResponse::empty();
         let transaction_id = announce_resp.read_u32::<BigEndian>().unwrap();
@@ -300,8 +316,14 @@ impl Handler {
             None => return None,
         };
 
-        let conn = match self.connections.remove(&id) {
-            Some(c) => c,
+        let announce = match self.connections.remove(&id) {
+            Some(Connection {
+                req: Req::Announce(a),
+                ..
+            }) => a,
+            Some(c) => {
+                return Some(c.error(ErrorKind::InvalidResponse("Unexpected announce").into()))
+            }
             None => return None,
         };
 
@@ -310,13 +332,61 @@ impl Handler {
         resp.seeders = announce_resp.read_u32::<BigEndian>().unwrap();
         if len > 20 {
             let pos = announce_resp.position() as usize;
-            for p in announce_resp.get_ref()[pos..].chunks(6) {
-                resp.peers.push(bytes_to_addr(p));
+            let size = if v6 { 18 } else { 6 };
+            for p in announce_resp.get_ref()[pos..].chunks(size) {
+                if p.len() == size {
+                    resp.peers.push(bytes_to_addr(p));
+                }
             }
         }
         Some(Response::Tracker {
-            tid: conn.torrent,
-            url: conn.announce.url,
+            tid: announce.id,
+            url: announce.url,
+            resp: Ok(resp),
+        })
+    }
+
+    /// Processes a scrape response, which holds the seeders, completed
+    /// and leechers counts of each scraped torrent in request order.
+    fn process_scrape(&mut self, len: usize) -> Option<Response> {
+        let transaction_id = (&self.buf[4..8]).read_u32::<BigEndian>().unwrap();
+
+        let id = match self.transactions.remove(&transaction_id) {
+            Some(id) => id,
+            None => return None,
+        };
+
+        let scrape = match self.connections.remove(&id) {
+            Some(Connection {
+                req: Req::Scrape(s),
+                ..
+            }) => s,
+            Some(c) => {
+                return Some(c.error(ErrorKind::InvalidResponse("Unexpected scrape").into()))
+            }
+            None => return None,
+        };
+
+        let resp = scrape
+            .torrents
+            .iter()
+            .zip(self.buf[8..len].chunks(12).filter(|c| c.len() == 12))
+            .map(|(&(tid, _), mut c)| {
+                let complete = c.read_u32::<BigEndian>().unwrap();
+                let downloaded = c.read_u32::<BigEndian>().unwrap();
+                let incomplete = c.read_u32::<BigEndian>().unwrap();
+                (
+                    tid,
+                    ScrapeResponse {
+                        complete,
+                        incomplete,
+                        downloaded,
+                    },
+                )
+            })
+            .collect();
+        Some(Response::Scrape {
+            url: scrape.url,
             resp: Ok(resp),
         })
     }
@@ -337,19 +407,11 @@ impl Handler {
         };
 
         if connect_resp.read_to_string(&mut s).is_err() {
-            let resp =
-                Err(ErrorKind::InvalidResponse("Tracker error response was invalid UTF8").into());
-            Some(Response::Tracker {
-                tid: conn.torrent,
-                url: conn.announce.url,
-                resp,
-            })
+            Some(conn.error(
+                ErrorKind::InvalidResponse("Tracker error response was invalid UTF8").into(),
+            ))
         } else {
-            Some(Response::Tracker {
-                tid: conn.torrent,
-                url: conn.announce.url,
-                resp: Err(ErrorKind::TrackerError(s).into()),
-            })
+            Some(conn.error(ErrorKind::TrackerError(s).into()))
         }
     }
 
@@ -360,35 +422,111 @@ impl Handler {
     }
 
     fn send_data(&mut self, id: usize) -> Option<Response> {
-        let tid;
+        let sock = &self.sock;
+        let sock6 = self.v6.as_ref().map(|&(_, ref s)| s);
         let res = {
             let conn = self.connections.get_mut(&id).unwrap();
-            tid = conn.torrent;
             // If this actually blocks, something is really fucked(prob with the NIC)
             // and i dont think we need to care
             match conn.state {
                 State::Connecting { ref addr, ref data } => {
                     conn.last_retrans = time::Instant::now();
-                    self.sock.send_to(data, addr).chain_err(|| ErrorKind::IO)
+                    send_to(sock, sock6, data, addr)
                 }
                 State::Announcing { ref addr, ref data } => {
                     conn.last_retrans = time::Instant::now();
-                    self.sock.send_to(data, addr).chain_err(|| ErrorKind::IO)
+                    send_to(sock, sock6, data, addr)
+                }
+                State::Scraping { ref addr, ref data } => {
+                    conn.last_retrans = time::Instant::now();
+                    send_to(sock, sock6, data, addr)
                 }
                 _ => Ok(0),
             }
         };
 
         match res {
-            Err(e) => {
-                let url = self.connections.remove(&id).unwrap().announce.url;
-                Some(Response::Tracker {
-                    tid,
-                    url,
-                    resp: Err(e),
-                })
-            }
+            Err(e) => Some(self.connections.remove(&id).unwrap().error(e)),
             Ok(_) => None,
         }
     }
 }
+
+fn announce_data(connection_id: u64, tid: u32, announce: &Announce) -> [u8; 98] {
+    let mut data = [0u8; 98];
+    {
+        let mut announce_req = Cursor::new(&mut data[..]);
+        announce_req.write_u64::<BigEndian>(connection_id).unwrap();
+        // announce action
+        announce_req.write_u32::<BigEndian>(1).unwrap();
+        announce_req.write_u32::<BigEndian>(tid).unwrap();
+
+        announce_req.write_all(&announce.hash).unwrap();
+        announce_req.write_all(&PEER_ID[..]).unwrap();
+        announce_req
+            .write_u64::<BigEndian>(announce.downloaded as u64)
+            .unwrap();
+        announce_req
+            .write_u64::<BigEndian>(announce.left as u64)
+            .unwrap();
+        announce_req
+            .write_u64::<BigEndian>(announce.uploaded as u64)
+            .unwrap();
+        match announce.event {
+            Some(Event::Started) => {
+                announce_req.write_u32::<BigEndian>(2).unwrap();
+            }
+            Some(Event::Stopped) => {
+                announce_req.write_u32::<BigEndian>(3).unwrap();
+            }
+            Some(Event::Completed) => {
+                announce_req.write_u32::<BigEndian>(1).unwrap();
+            }
+            None => {
+                announce_req.write_u32::<BigEndian>(0).unwrap();
+            }
+        }
+
+        // IP
+        announce_req.write_u32::<BigEndian>(0).unwrap();
+        // Key - TODO: randomly generate this
+        announce_req.write_u32::<BigEndian>(0xFFFF_00BA).unwrap();
+        // Num want
+        let nw = announce.num_want.map(|nw| nw as i32).unwrap_or(-1);
+        announce_req.write_i32::<BigEndian>(nw).unwrap();
+        // port
+        announce_req.write_u16::<BigEndian>(announce.port).unwrap();
+    }
+    data
+}
+
+fn scrape_data(connection_id: u64, tid: u32, scrape: &Scrape) -> Vec<u8> {
+    let mut scrape_req = Vec::with_capacity(16 + 20 * scrape.torrents.len());
+    scrape_req.write_u64::<BigEndian>(connection_id).unwrap();
+    // scrape action
+    scrape_req.write_u32::<BigEndian>(2).unwrap();
+    scrape_req.write_u32::<BigEndian>(tid).unwrap();
+    for &(_, ref hash) in &scrape.torrents {
+        scrape_req.extend_from_slice(hash);
+    }
+    scrape_req
+}
+
+/// Sends data over the socket matching the address family of addr
+fn send_to(
+    sock: &UdpSocket,
+    sock6: Option<&UdpSocket>,
+    data: &[u8],
+    addr: &SocketAddr,
+) -> Result<usize> {
+    let s = if addr.is_ipv6() {
+        sock6.ok_or_else(|| {
+            Error::from(ErrorKind::InvalidRequest(
+                "IPv6 trackers are unavailable".to_owned(),
+            ))
+        })?
+    } else {
+        sock
+    };
+    s.send_to(data, addr).chain_err(|| ErrorKind::IO)
+}
@@ -0,0 +1,360 @@
+use std::time::{Duration, Instant};
+use std::{io, mem};
+use std::net::SocketAddr;
+
+use url::Url;
+
+use amy;
+use socket::TSocket;
+use tracker::http::{ReadRes, Reader, Writer};
+use tracker::{dns, Error, ErrorKind, Response, Result, ResultExt, WebSeed};
+use util::UHashMap;
+
+const TIMEOUT_MS: u64 = 20_000;
+const MAX_REDIRECTS: u8 = 2;
+
+/// Fetches byte ranges from BEP 19 web seeds over HTTP(S).
+pub struct Handler {
+    reg: amy::Registrar,
+    connections: UHashMap<Fetch>,
+}
+
+enum Event {
+    DNSResolved(dns::QueryResponse),
+    Readable,
+    Writable,
+}
+
+/// An in progress fetch of all segments of a web seed request.
+/// Each segment is fetched using its own connection.
+struct Fetch {
+    req: WebSeed,
+    /// Index of the segment currently being fetched
+    seg: usize,
+    /// URL of the current segment, which may differ from the
+    /// requested one due to redirects
+    url: Url,
+    redirects: u8,
+    data: Vec<u8>,
+    last_updated: Instant,
+    state: FetchState,
+}
+
+enum FetchState {
+    Error,
+    ResolvingDNS {
+        sock: TSocket,
+        req: Vec<u8>,
+        port: u16,
+    },
+    Writing {
+        sock: TSocket,
+        writer: Writer,
+    },
+    Reading {
+        sock: TSocket,
+        reader: Reader,
+    },
+    Redirect(String),
+    Complete(Vec<u8>),
+}
+
+enum FetchRes {
+    None,
+    Redirect(String),
+    Complete(Vec<u8>),
+}
+
+impl FetchState {
+    fn handle(&mut self, event: Event, range: (u64, u64)) -> Result<FetchRes> {
+        let s = mem::replace(self, FetchState::Error);
+        match s.next(event, range)? {
+            FetchState::Complete(d) => Ok(FetchRes::Complete(d)),
+            FetchState::Redirect(l) => Ok(FetchRes::Redirect(l)),
+            n => {
+                mem::replace(self, n);
+                Ok(FetchRes::None)
+            }
+        }
+    }
+
+    fn next(self, event: Event, range: (u64, u64)) -> Result<FetchState> {
+        match (self, event) {
+            (
+                FetchState::ResolvingDNS {
+                    mut sock,
+                    req,
+                    port,
+                },
+                Event::DNSResolved(r),
+            ) => {
+                let addr = SocketAddr::new(r.res?, port);
+                sock.connect(addr).chain_err(|| ErrorKind::IO)?;
+                Ok(FetchState::Writing {
+                    sock,
+                    writer: Writer::new(req),
+                }.next(Event::Writable, range)?
+                    .next(Event::Readable, range)?)
+            }
+            (
+                FetchState::Writing {
+                    mut sock,
+                    mut writer,
+                },
+                _,
+            ) => match writer.writable(&mut sock)? {
+                Some(()) => {
+                    // Servers ignoring the range send everything up to it
+                    let r = Reader::with_limit((range.0 + range.1) as usize);
+                    Ok(FetchState::Reading { sock, reader: r }.next(Event::Readable, range)?)
+                }
+                None => Ok(FetchState::Writing { sock, writer }),
+            },
+            (
+                FetchState::Reading {
+                    mut sock,
+                    mut reader,
+                },
+                _,
+            ) => match reader.readable(&mut sock)? {
+                ReadRes::Done(data) => Ok(FetchState::Complete(extract_range(
+                    reader.code(),
+                    data,
+                    range,
+                )?)),
+                ReadRes::Redirect(l) => Ok(FetchState::Redirect(l)),
+                ReadRes::None => Ok(FetchState::Reading { sock, reader }),
+            },
+            (s @ FetchState::ResolvingDNS { .. }, _) => Ok(s),
+            _ => bail!("Unknown state transition encountered!"),
+        }
+    }
+}
+
+/// Validates a response body against the requested range, returning
+/// only the requested bytes. Servers which ignore the Range header
+/// and send the whole file are accepted, the body being read only
+/// up to the end of the range.
+fn extract_range(code: Option<u16>, mut data: Vec<u8>, range: (u64, u64)) -> Result<Vec<u8>> {
+    let (offset, len) = (range.0 as usize, range.1 as usize);
+    match code {
+        Some(206) if data.len() == len => Ok(data),
+        Some(200) if data.len() >= offset + len => {
+            data.truncate(offset + len);
+            Ok(data.split_off(offset))
+        }
+        Some(c) if c >= 400 => Err(ErrorKind::TrackerError(format!("HTTP error {}", c)).into()),
+        _ => Err(ErrorKind::InvalidResponse("Web seed response has invalid range").into()),
+    }
+}
+
+impl Handler {
+    pub fn new(reg: &amy::Registrar) -> io::Result<Handler> {
+        Ok(Handler {
+            reg: reg.try_clone()?,
+            connections: UHashMap::default(),
+        })
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.connections.contains_key(&id)
+    }
+
+    pub fn new_fetch(&mut self, req: WebSeed, dns: &mut dns::Resolver) -> Result<()> {
+        debug!("Received a new web seed req for torrent {:?}", req.tid);
+        let url = req.segments
+            .first()
+            .map(|s| s.0.clone())
+            .ok_or_else(|| {
+                Error::from(ErrorKind::InvalidRequest(
+                    "Web seed request has no segments".to_owned(),
+                ))
+            })?;
+        let fetch = Fetch {
+            req,
+            seg: 0,
+            url,
+            redirects: 0,
+            data: Vec::new(),
+            last_updated: Instant::now(),
+            state: FetchState::Error,
+        };
+        self.start_segment(fetch, dns)
+    }
+
+    pub fn dns_resolved(
+        &mut self,
+        resp: dns::QueryResponse,
+        dns: &mut dns::Resolver,
+    ) -> Option<Response> {
+        let id = resp.id;
+        self.handle(id, Event::DNSResolved(resp), dns)
+    }
+
+    pub fn writable(&mut self, id: usize, dns: &mut dns::Resolver) -> Option<Response> {
+        self.handle(id, Event::Writable, dns)
+    }
+
+    pub fn readable(&mut self, id: usize, dns: &mut dns::Resolver) -> Option<Response> {
+        self.handle(id, Event::Readable, dns)
+    }
+
+    pub fn tick(&mut self) -> Vec<Response> {
+        let mut resps = Vec::new();
+        self.connections.retain(|id, f| {
+            if f.last_updated.elapsed() > Duration::from_millis(TIMEOUT_MS) {
+                debug!("Web seed fetch {:?} timed out", id);
+                resps.push(Response::WebSeed {
+                    tid: f.req.tid,
+                    id: f.req.id,
+                    resp: Err(ErrorKind::Timeout.into()),
+                });
+                false
+            } else {
+                true
+            }
+        });
+        resps
+    }
+
+    fn handle(&mut self, id: usize, event: Event, dns: &mut dns::Resolver) -> Option<Response> {
+        let res = {
+            let f = self.connections.get_mut(&id)?;
+            f.last_updated = Instant::now();
+            let range = f.range();
+            f.state.handle(event, range)
+        };
+        match res {
+            Ok(FetchRes::None) => None,
+            Ok(FetchRes::Complete(data)) => {
+                let mut f = self.connections.remove(&id).unwrap();
+                f.data.extend_from_slice(&data);
+                f.seg += 1;
+                f.redirects = 0;
+                if f.seg == f.req.segments.len() {
+                    return Some(f.response(Ok(())));
+                }
+                f.url = f.req.segments[f.seg].0.clone();
+                self.restart(f, dns)
+            }
+            Ok(FetchRes::Redirect(l)) => {
+                let mut f = self.connections.remove(&id).unwrap();
+                if f.redirects == MAX_REDIRECTS {
+                    return Some(f.response(Err(
+                        ErrorKind::InvalidResponse("Too many redirects").into(),
+                    )));
+                }
+                match Url::parse(&l) {
+                    Ok(url) => {
+                        f.url = url;
+                        f.redirects += 1;
+                        self.restart(f, dns)
+                    }
+                    Err(_) => Some(f.response(Err(
+                        ErrorKind::InvalidResponse("Malformed redirect!").into(),
+                    ))),
+                }
+            }
+            Err(e) => {
+                let f = self.connections.remove(&id).unwrap();
+                Some(f.response(Err(e)))
+            }
+        }
+    }
+
+    /// Starts the next connection of the fetch, returning an error
+    /// response if this fails.
+    fn restart(&mut self, f: Fetch, dns: &mut dns::Resolver) -> Option<Response> {
+        let (tid, wid) = (f.req.tid, f.req.id);
+        self.start_segment(f, dns)
+            .err()
+            .map(|e| Response::WebSeed {
+                tid,
+                id: wid,
+                resp: Err(e),
+            })
+    }
+
+    /// Opens a connection for the current segment of the fetch
+    fn start_segment(&mut self, mut f: Fetch, dns: &mut dns::Resolver) -> Result<()> {
+        let (offset, len) = f.range();
+        if len == 0 {
+            return Err(ErrorKind::InvalidRequest("Web seed segment is empty".to_owned()).into());
+        }
+        let mut http_req = Vec::with_capacity(100);
+        http_req.extend_from_slice(b"GET ");
+        http_req.extend_from_slice(f.url.path().as_bytes());
+        if let Some(q) = f.url.query() {
+            http_req.extend_from_slice(b"?");
+            http_req.extend_from_slice(q.as_bytes());
+        }
+        http_req.extend_from_slice(b" HTTP/1.1\r\n");
+        http_req.extend_from_slice(b"Connection: close\r\n");
+        http_req.extend_from_slice(
+            format!("Range: bytes={}-{}\r\n", offset, offset + len - 1).as_bytes(),
+        );
+        http_req.extend_from_slice(b"Host: ");
+        let host = f.url
+            .host_str()
+            .ok_or_else(|| {
+                Error::from(ErrorKind::InvalidRequest(
+                    "Web seed url has no host!".to_owned(),
+                ))
+            })?
+            .to_owned();
+        let https = f.url.scheme() == "https";
+        let port = f.url.port().unwrap_or_else(|| if https { 443 } else { 80 });
+        http_req.extend_from_slice(host.as_bytes());
+        http_req.extend_from_slice(b"\r\n\r\n");
+
+        let ohost = if https { Some(host.clone()) } else { None };
+        let sock = TSocket::new(ohost).chain_err(|| ErrorKind::IO)?;
+        let id = self.reg
+            .register(&sock, amy::Event::Both)
+            .chain_err(|| ErrorKind::IO)?;
+        f.state = FetchState::ResolvingDNS {
+            sock,
+            req: http_req,
+            port,
+        };
+        f.last_updated = Instant::now();
+        self.connections.insert(id, f);
+        dns.new_query(id, &host);
+        Ok(())
+    }
+}
+
+impl Fetch {
+    fn range(&self) -> (u64, u64) {
+        let seg = &self.req.segments[self.seg];
+        (seg.1, seg.2)
+    }
+
+    fn response(self, res: Result<()>) -> Response {
+        Response::WebSeed {
+            tid: self.req.tid,
+            id: self.req.id,
+            resp: res.map(|_| self.data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_range;
+
+    #[test]
+    fn test_extract_range() {
+        let data: Vec<u8> = (0..10).collect();
+        assert_eq!(
+            extract_range(Some(206), data[2..6].to_vec(), (2, 4)).unwrap(),
+            vec![2, 3, 4, 5]
+        );
+        assert_eq!(
+            extract_range(Some(200), data.clone(), (2, 4)).unwrap(),
+            vec![2, 3, 4, 5]
+        );
+        assert!(extract_range(Some(206), data.clone(), (2, 4)).is_err());
+        assert!(extract_range(Some(404), vec![], (2, 4)).is_err());
+    }
+}
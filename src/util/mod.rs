@@ -3,13 +3,17 @@ mod aview;
 mod io;
 
 use std::fmt::Write as FWrite;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6,
+               TcpListener, UdpSocket};
 use std::hash::BuildHasherDefault;
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 
 use rand::{self, Rng};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use metrohash::MetroHash;
+use net2::{TcpBuilder, UdpBuilder};
+use openssl::sha;
 use sha1;
 use fnv;
 
@@ -54,6 +58,39 @@ pub fn sha1_hash(data: &[u8]) -> [u8; 20] {
     ctx.digest().bytes()
 }
 
+pub fn sha256_hash(data: &[u8]) -> [u8; 32] {
+    sha::sha256(data)
+}
+
+/// Computes the root of a BEP 52 merkle tree which is `width` leaves
+/// wide, leaves past the end of `hashes` being `pad`.
+pub fn merkle_root(hashes: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    let layers = merkle_layers(hashes, width, pad);
+    layers[layers.len() - 1][0]
+}
+
+/// Computes every layer of a BEP 52 merkle tree including padding,
+/// from the leaves up to the root.
+pub fn merkle_layers(hashes: &[[u8; 32]], width: usize, pad: [u8; 32]) -> Vec<Vec<[u8; 32]>> {
+    let width = cmp::max(cmp::max(width, hashes.len()), 1).next_power_of_two();
+    let mut layer = hashes.to_vec();
+    layer.resize(width, pad);
+    let mut layers = vec![layer];
+    while layers[layers.len() - 1].len() > 1 {
+        let next = layers[layers.len() - 1]
+            .chunks(2)
+            .map(|pair| {
+                let mut ctx = sha::Sha256::new();
+                ctx.update(&pair[0]);
+                ctx.update(&pair[1]);
+                ctx.finish()
+            })
+            .collect();
+        layers.push(next);
+    }
+    layers
+}
+
 pub fn peer_rpc_id(torrent: &[u8; 20], peer: u64) -> String {
     const PEER_ID: &'static [u8] = b"PEER";
     let mut idx = [0u8; 8];
@@ -84,6 +121,22 @@ pub fn trk_rpc_id(torrent: &[u8; 20], url: &str) -> String {
     hash_to_id(&ctx.digest().bytes())
 }
 
+pub fn dht_rpc_id(server: &str) -> String {
+    const DHT_ID: &'static [u8] = b"DHT";
+    let mut ctx = sha1::Sha1::new();
+    ctx.update(server.as_bytes());
+    ctx.update(DHT_ID);
+    hash_to_id(&ctx.digest().bytes())
+}
+
+pub fn ban_rpc_id(ip: &IpAddr) -> String {
+    const BAN_ID: &'static [u8] = b"BAN";
+    let mut ctx = sha1::Sha1::new();
+    ctx.update(BAN_ID);
+    ctx.update(ip.to_string().as_bytes());
+    hash_to_id(&ctx.digest().bytes())
+}
+
 pub fn hash_to_id(hash: &[u8]) -> String {
     let mut hash_str = String::new();
     for i in hash {
@@ -92,20 +145,42 @@ pub fn hash_to_id(hash: &[u8]) -> String {
     hash_str
 }
 
+/// Converts a v1 or v2 id into the hash used on the wire,
+/// v2 hashes being truncated to 20 bytes.
 pub fn id_to_hash(s: &str) -> Option<[u8; 20]> {
     let mut data = [0u8; 20];
-    if s.len() != 40 {
+    if s.len() == 64 {
+        let full = id_to_hash_v2(s)?;
+        data.copy_from_slice(&full[..20]);
+        return Some(data);
+    }
+    if s.len() != 40 || !decode_hex(s, &mut data) {
         return None;
     }
+    Some(data)
+}
+
+pub fn id_to_hash_v2(s: &str) -> Option<[u8; 32]> {
+    let mut data = [0u8; 32];
+    if s.len() != 64 || !decode_hex(s, &mut data) {
+        return None;
+    }
+    Some(data)
+}
+
+fn decode_hex(s: &str, data: &mut [u8]) -> bool {
     let mut c = s.chars();
-    for i in &mut data {
-        if let (Some(a), Some(b)) = (hex_to_bit(c.next().unwrap()), hex_to_bit(c.next().unwrap())) {
+    for i in data {
+        if let (Some(a), Some(b)) = (
+            c.next().and_then(hex_to_bit),
+            c.next().and_then(hex_to_bit),
+        ) {
             *i = a << 4 | b
         } else {
-            return None;
+            return false;
         }
     }
-    Some(data)
+    true
 }
 
 fn hex_to_bit(c: char) -> Option<u8> {
@@ -131,7 +206,19 @@ fn hex_to_bit(c: char) -> Option<u8> {
     Some(r)
 }
 
+/// Parses a compact address, which is 6 bytes for IPv4
+/// or 18 bytes for IPv6.
 pub fn bytes_to_addr(p: &[u8]) -> SocketAddr {
+    if p.len() == 18 {
+        let mut ip = [0u8; 16];
+        ip.copy_from_slice(&p[..16]);
+        return SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::from(ip),
+            (&p[16..]).read_u16::<BigEndian>().unwrap(),
+            0,
+            0,
+        ));
+    }
     let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
     SocketAddr::V4(SocketAddrV4::new(
         ip,
@@ -139,22 +226,33 @@ pub fn bytes_to_addr(p: &[u8]) -> SocketAddr {
     ))
 }
 
-pub fn addr_to_bytes(addr: &SocketAddr) -> [u8; 6] {
-    let mut data = [0u8; 6];
-    match *addr {
-        SocketAddr::V4(s) => {
-            let oct = s.ip().octets();
-            data[0] = oct[0];
-            data[1] = oct[1];
-            data[2] = oct[2];
-            data[3] = oct[3];
-            (&mut data[4..]).write_u16::<BigEndian>(s.port()).unwrap();
-        }
-        _ => unimplemented!(),
-    }
+/// Encodes an address in compact form, 6 bytes for IPv4
+/// or 18 bytes for IPv6.
+pub fn addr_to_bytes(addr: &SocketAddr) -> Vec<u8> {
+    let mut data = match *addr {
+        SocketAddr::V4(s) => s.ip().octets().to_vec(),
+        SocketAddr::V6(s) => s.ip().octets().to_vec(),
+    };
+    data.write_u16::<BigEndian>(addr.port()).unwrap();
     data
 }
 
+/// Binds an IPv6 only UDP socket, so that it may share
+/// a port with an IPv4 socket.
+pub fn udp_bind_v6(port: u16) -> ::std::io::Result<UdpSocket> {
+    UdpBuilder::new_v6()?
+        .only_v6(true)?
+        .bind((Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), port))
+}
+
+/// Binds an IPv6 only TCP listener, so that it may share
+/// a port with an IPv4 listener.
+pub fn tcp_listen_v6(port: u16) -> ::std::io::Result<TcpListener> {
+    let b = TcpBuilder::new_v6()?;
+    b.only_v6(true)?.reuse_address(true)?;
+    b.bind((Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), port))?.listen(128)
+}
+
 pub fn find_subseq(haystack: &[u8], needle: &[u8]) -> Option<usize> {
     haystack
         .windows(needle.len())
@@ -167,3 +265,37 @@ fn test_hash_enc() {
     let s = hash_to_id(&hash);
     assert_eq!(id_to_hash(&s).unwrap(), hash);
 }
+
+#[test]
+fn test_hash_enc_v2() {
+    let hash = [8u8; 32];
+    let s = hash_to_id(&hash);
+    assert_eq!(id_to_hash_v2(&s).unwrap(), hash);
+    assert_eq!(id_to_hash(&s).unwrap(), [8u8; 20]);
+    assert!(id_to_hash(&s[..63]).is_none());
+}
+
+#[test]
+fn test_merkle_root() {
+    let (a, b) = (sha256_hash(b"a"), sha256_hash(b"b"));
+    let mut ab = a.to_vec();
+    ab.extend_from_slice(&b);
+    assert_eq!(merkle_root(&[a, b], 2, [0; 32]), sha256_hash(&ab));
+    assert_eq!(merkle_root(&[a], 1, [0; 32]), a);
+    // Missing leaves are padded up to a power of two
+    let mut a0 = a.to_vec();
+    a0.extend_from_slice(&[0; 32]);
+    let mut top = sha256_hash(&a0).to_vec();
+    top.extend_from_slice(&sha256_hash(&[0; 64]));
+    assert_eq!(merkle_root(&[a], 3, [0; 32]), sha256_hash(&top));
+}
+
+#[test]
+fn test_compact_addr() {
+    let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let v6: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+    assert_eq!(addr_to_bytes(&v4).len(), 6);
+    assert_eq!(addr_to_bytes(&v6).len(), 18);
+    assert_eq!(bytes_to_addr(&addr_to_bytes(&v4)), v4);
+    assert_eq!(bytes_to_addr(&addr_to_bytes(&v6)), v6);
+}
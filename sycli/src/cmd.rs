@@ -62,6 +62,45 @@ fn add_file(c: &mut Client, url: &str, file: &str, dir: Option<&str>, start: boo
     Ok(())
 }
 
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+pub fn create(
+    mut c: Client,
+    path: &str,
+    piece_size: Option<&str>,
+    trackers: Vec<&str>,
+    web_seeds: Vec<&str>,
+    private: bool,
+    comment: Option<&str>,
+    start: bool,
+) -> Result<()> {
+    let piece_size = match piece_size {
+        Some(s) => Some(s.parse().chain_err(|| ErrorKind::Parse)?),
+        None => None,
+    };
+    let msg = CMessage::CreateTorrent {
+        serial: c.next_serial(),
+        path: path.to_owned(),
+        piece_size,
+        trackers: trackers.into_iter().map(str::to_owned).collect(),
+        web_seeds: web_seeds.into_iter().map(str::to_owned).collect(),
+        private,
+        comment: comment.map(str::to_owned),
+        start,
+    };
+    match c.rr(msg)? {
+        SMessage::ResourcesExtant { ids, .. } => {
+            get_(&mut c, ids[0].as_ref(), "text")?;
+        }
+        SMessage::InvalidRequest(e) => {
+            bail!("{}", e.reason);
+        }
+        _ => {
+            bail!("Failed to receieve creation acknowledgement from synapse!");
+        }
+    }
+    Ok(())
+}
+
 pub fn del(mut c: Client, torrents: Vec<&str>, artifacts: bool) -> Result<()> {
     for torrent in torrents {
         del_torrent(&mut c, torrent, artifacts)?;
@@ -189,6 +228,8 @@ pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Re
         "piece" => ResourceKind::Piece,
         "file" => ResourceKind::File,
         "server" => ResourceKind::Server,
+        "dht" => ResourceKind::Dht,
+        "ban" => ResourceKind::Ban,
         _ => bail!("Unexpected resource kind {}", kind),
     };
     let results = search(&mut c, k, crit)?;
@@ -213,6 +254,12 @@ pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Re
             ResourceKind::Server => {
                 table.add_row(row!["DL RT", "UL RT"]);
             }
+            ResourceKind::Dht => {
+                table.add_row(row!["Good", "Questionable", "Bad", "Transactions", "DL", "UL"]);
+            }
+            ResourceKind::Ban => {
+                table.add_row(row!["IP", "Reason", "Expires"]);
+            }
         }
 
         #[cfg_attr(rustfmt, rustfmt_skip)]
@@ -264,6 +311,24 @@ pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Re
                     let ru = fmt_bytes(s.rate_up as f64) + "/s";
                     table.add_row(row![rd, ru]);
                 }
+                ResourceKind::Dht => {
+                    let d = res.as_dht();
+                    table.add_row(row![
+                                  d.good_nodes,
+                                  d.questionable_nodes,
+                                  d.bad_nodes,
+                                  d.transactions,
+                                  fmt_bytes(d.transferred_down as f64),
+                                  fmt_bytes(d.transferred_up as f64)
+                    ]);
+                }
+                ResourceKind::Ban => {
+                    let b = res.as_ban();
+                    let expires = b.expires
+                        .map(|e| e.to_rfc2822())
+                        .unwrap_or_else(|| "Never".to_owned());
+                    table.add_row(row![b.ip, b.reason.as_str(), expires]);
+                }
             }
         }
         table.printstd();
@@ -580,6 +645,30 @@ pub fn status(mut c: Client) -> Result<()> {
                 fmt_bytes(s.transferred_up as f64),
                 fmt_bytes(s.transferred_down as f64),
             );
+            println!(
+                "Alternate throttle: {}",
+                if s.alt_throttle { "on" } else { "off" }
+            );
+        }
+        _ => {
+            bail!("synapse server incorrectly reported server status!");
+        }
+    };
+    Ok(())
+}
+
+pub fn set_alt_throttle(mut c: Client, enabled: bool) -> Result<()> {
+    match search(&mut c, ResourceKind::Server, vec![])?.pop() {
+        Some(Resource::Server(s)) => {
+            let update = CMessage::UpdateResource {
+                serial: c.next_serial(),
+                resource: CResourceUpdate {
+                    id: s.id,
+                    alt_throttle: Some(enabled),
+                    ..Default::default()
+                },
+            };
+            c.send(update)?;
         }
         _ => {
             bail!("synapse server incorrectly reported server status!");
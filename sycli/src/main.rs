@@ -68,6 +68,64 @@ fn main() {
                         .required(true)
                         .index(1),
                 ),
+            SubCommand::with_name("alt")
+                .about("Toggles the alternate throttle limits.")
+                .arg(
+                    Arg::with_name("state")
+                        .help("Whether the alternate limits should be used.")
+                        .possible_values(&["on", "off"])
+                        .index(1)
+                        .required(true),
+                ),
+            SubCommand::with_name("create")
+                .about("Creates a torrent from files on the synapse server.")
+                .arg(
+                    Arg::with_name("path")
+                        .help("Path of the file or directory to create the torrent from.")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("piece size")
+                        .help("Piece size in bytes, chosen automatically if unset.")
+                        .short("s")
+                        .long("piece-size")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("trackers")
+                        .help("Tracker URIs to announce to.")
+                        .short("t")
+                        .long("trackers")
+                        .multiple(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("web seeds")
+                        .help("Web seed URIs to download from.")
+                        .short("w")
+                        .long("web-seeds")
+                        .multiple(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("private")
+                        .help("Whether or not the torrent should be private.")
+                        .long("private"),
+                )
+                .arg(
+                    Arg::with_name("comment")
+                        .help("Comment to include in the torrent.")
+                        .short("c")
+                        .long("comment")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pause")
+                        .help("Whether or not the torrent should start paused.")
+                        .short("P")
+                        .long("pause"),
+                ),
             SubCommand::with_name("del")
                 .about("Deletes torrents from synapse.")
                 .arg(
@@ -143,7 +201,9 @@ fn main() {
                 .arg(
                     Arg::with_name("kind")
                         .help("The kind of resource to list.")
-                        .possible_values(&["torrent", "peer", "file", "server", "tracker", "piece"])
+                        .possible_values(&[
+                            "torrent", "peer", "file", "server", "tracker", "piece", "dht", "ban",
+                        ])
                         .default_value("torrent")
                         .short("k")
                         .long("kind"),
@@ -339,6 +399,31 @@ fn main() {
                 process::exit(1);
             }
         }
+        "alt" => {
+            let args = matches.subcommand_matches("alt").unwrap();
+            let enabled = args.value_of("state").unwrap() == "on";
+            if let Err(e) = cmd::set_alt_throttle(client, enabled) {
+                eprintln!("Failed to set alternate throttle: {:?}", e);
+                process::exit(1);
+            }
+        }
+        "create" => {
+            let args = matches.subcommand_matches("create").unwrap();
+            let res = cmd::create(
+                client,
+                args.value_of("path").unwrap(),
+                args.value_of("piece size"),
+                args.values_of("trackers").map(|v| v.collect()).unwrap_or(vec![]),
+                args.values_of("web seeds").map(|v| v.collect()).unwrap_or(vec![]),
+                args.is_present("private"),
+                args.value_of("comment"),
+                !args.is_present("pause"),
+            );
+            if let Err(e) = res {
+                eprintln!("Failed to create torrent: {:?}", e);
+                process::exit(1);
+            }
+        }
         "del" => {
             let args = matches.subcommand_matches("del").unwrap();
             let artifacts = match args.value_of("files").unwrap() {
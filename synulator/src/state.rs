@@ -305,6 +305,12 @@ impl State {
                     reason: format!("Tracker update not supported!"),
                 }));
             }
+            CMessage::ScrapeTracker { serial, .. } => {
+                resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: format!("Tracker scrape not supported!"),
+                }));
+            }
             CMessage::ValidateResources { serial, .. } => {
                 resp.push(SMessage::InvalidRequest(Error {
                     serial: Some(serial),
@@ -329,6 +335,42 @@ impl State {
                     reason: format!("Upload not supported!"),
                 }));
             }
+            CMessage::DhtGet { serial, .. } => {
+                resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: format!("DHT get not supported!"),
+                }));
+            }
+            CMessage::DhtPut { serial, .. } => {
+                resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: format!("DHT put not supported!"),
+                }));
+            }
+            CMessage::DhtBootstrap { serial, .. } => {
+                resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: format!("DHT bootstrap not supported!"),
+                }));
+            }
+            CMessage::DhtTable { serial } => {
+                resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: format!("DHT table not supported!"),
+                }));
+            }
+            CMessage::AddBan { serial, .. } => {
+                resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: format!("Bans not supported!"),
+                }));
+            }
+            CMessage::ReloadIpFilter { serial } => {
+                resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: format!("IP filters not supported!"),
+                }));
+            }
         }
         resp
     }